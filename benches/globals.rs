@@ -0,0 +1,37 @@
+#![feature(test)]
+
+extern crate test;
+
+use std::io::sink;
+
+use test::Bencher;
+
+use rslox1::Lox;
+
+// Globals declared before the function using them are accessed by index, while forward references
+// fall back to looking them up by name.
+fn sum_globals(b: &mut Bencher, source: &str) {
+    b.iter(|| {
+        let mut lox = Lox::with_output(sink());
+        lox.eval(source).unwrap();
+        assert_eq!(lox.get_global("total").unwrap().stringify(), "49995000");
+    });
+}
+
+#[bench]
+fn declared_globals(b: &mut Bencher) {
+    sum_globals(b, r#"
+var total = 0;
+fun sum() { for (var i = 0; i < 10000; i = i + 1) total = total + i; }
+sum();
+    "#)
+}
+
+#[bench]
+fn forward_referenced_globals(b: &mut Bencher) {
+    sum_globals(b, r#"
+fun sum() { for (var i = 0; i < 10000; i = i + 1) total = total + i; }
+var total = 0;
+sum();
+    "#)
+}
@@ -0,0 +1,48 @@
+#![feature(test)]
+
+extern crate test;
+
+use std::io::sink;
+
+use test::Bencher;
+
+use rslox1::Lox;
+
+// Calling a method directly invokes it, while calling it through a variable first binds it, which
+// allocates a bound method on every iteration.
+fn count_calls(b: &mut Bencher, source: &str) {
+    b.iter(|| {
+        let mut lox = Lox::with_output(sink());
+        lox.eval(source).unwrap();
+        assert_eq!(lox.get_global("total").unwrap().stringify(), "10000");
+    });
+}
+
+#[bench]
+fn invoked_methods(b: &mut Bencher) {
+    count_calls(b, r#"
+class Counter { one() { return 1; } }
+var total = 0;
+fun count() {
+  var counter = Counter();
+  for (var i = 0; i < 10000; i = i + 1) total = total + counter.one();
+}
+count();
+    "#)
+}
+
+#[bench]
+fn bound_methods(b: &mut Bencher) {
+    count_calls(b, r#"
+class Counter { one() { return 1; } }
+var total = 0;
+fun count() {
+  var counter = Counter();
+  for (var i = 0; i < 10000; i = i + 1) {
+    var one = counter.one;
+    total = total + one();
+  }
+}
+count();
+    "#)
+}
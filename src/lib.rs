@@ -0,0 +1,17 @@
+#![feature(hash_set_entry)]
+#![feature(iter_intersperse)]
+#![feature(try_blocks)]
+#![feature(type_ascription)]
+
+#[macro_use]
+extern crate num_derive;
+extern crate num_traits;
+extern crate core;
+
+pub mod rslox;
+
+pub use rslox::compiled::lox::{
+    diagnostics, DebugAction, DebugEvent, Diagnostic, GcPolicy, GcStats, Lox, LoxError,
+    LoxResult, NativeArity, NativeContext, NativeFunctionPtr, Severity, TypeError, Value,
+    VmOptions,
+};
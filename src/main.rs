@@ -1,25 +1,30 @@
-#![feature(hash_set_entry)]
-#![feature(iter_intersperse)]
-#![feature(try_blocks)]
-#![feature(type_ascription)]
-
-#[macro_use]
-extern crate num_derive;
-extern crate num_traits;
-extern crate core;
-
 use std::env;
 
-use rslox::interpreted::prompt::run_prompt;
-use rslox::interpreted::runfile::run_file;
-
-pub mod rslox;
+use rslox1::rslox::compiled::prompt::run_prompt as run_vm_prompt;
+use rslox1::rslox::compiled::runfile::{compile_file, run_compiled_file, run_file as run_vm_file};
+use rslox1::rslox::interpreted::prompt::run_prompt;
+use rslox1::rslox::interpreted::runfile::run_file;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
     println!("{:?}", args);
-    if args.len() > 2 {
-        panic!("Usage: rslox1 [script]");
+    let trace = args.iter().any(|a| a == "--trace");
+    let optimize = args.iter().any(|a| a == "--optimize");
+    let vm = args.iter().any(|a| a == "--vm");
+    args.retain(|a| a != "--trace" && a != "--optimize" && a != "--vm");
+    if args.len() == 5 && args[1] == "compile" && args[3] == "-o" {
+        compile_file(&args[2], &args[4], optimize);
+    } else if args.len() == 2 && args[1] == "repl" {
+        run_vm_prompt();
+    } else if args.len() > 2 {
+        panic!("Usage: rslox1 [--vm [--trace]] [script] | rslox1 compile [--optimize] script -o \
+            target | rslox1 repl");
+    } else if args.len() == 2 && args[1].ends_with(".loxc") {
+        run_compiled_file(&args[1], trace);
+    } else if args.len() == 2 && vm {
+        run_vm_file(&args[1], trace);
+    } else if trace {
+        panic!("Only the VM can trace, run the script with --vm");
     } else if args.len() == 2 {
         run_file(&args[1]);
     } else {
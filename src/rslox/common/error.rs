@@ -1,14 +1,21 @@
-use std::fmt::Debug;
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Debug, Display, Formatter};
 
 use nonempty::NonEmpty;
 
 use crate::rslox::common::lexer::Token;
 
-pub trait LoxError: Debug {
+/// The errors of every stage, e.g., lexing, compiling and running. Their [Display] is the full
+/// report, e.g., `[line 3] Error: Undefined variable 'foo'.`.
+pub trait LoxError: Error {
     fn get_info(&self) -> ErrorInfo;
     fn get_message(&self) -> String;
 }
 
+// So that a LoxResult's errors can be propagated with `?` into a `Box<dyn Error>`.
+impl Error for Box<dyn LoxError> {}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct ErrorInfo {
     pub line: usize,
@@ -48,4 +55,80 @@ impl LoxError for ParserError {
     fn get_message(&self) -> String {
         self.message.to_owned()
     }
+}
+
+impl Display for ParserError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] Error: {}", self.token.line, self.message)
+    }
+}
+
+impl Error for ParserError {}
+
+impl ParserError {
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            message: self.message.to_owned(),
+            line: self.token.line,
+            col_start: self.token.span.col_start,
+            col_end: self.token.span.col_end,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Severity {
+    Error,
+    // Reported, but doesn't fail the compilation.
+    Warning,
+}
+
+/// Structured error reports, for tooling (e.g., editors) which wants more than a formatted string.
+/// Columns are the same as those of [crate::rslox::common::lexer::Span].
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub line: usize,
+    pub col_start: usize,
+    pub col_end: usize,
+}
+
+/// Renders the diagnostic along with its source line, rustc style, e.g.,
+/// ```text
+/// error: Undefined variable 'foo'.
+///   --> line 3, col 3
+/// 3 |   foo();
+///   |   ^^^
+/// ```
+/// Diagnostics without a span (see [crate::rslox::common::lexer::Span]) skip the caret line.
+pub fn render(diagnostic: &Diagnostic, source: &str) -> String {
+    let severity = format!("{:?}", diagnostic.severity).to_lowercase();
+    let line_number = diagnostic.line.to_string();
+    let gutter = " ".repeat(line_number.len());
+    let source_line = source.lines().nth(diagnostic.line.wrapping_sub(1)).unwrap_or("");
+    let mut result = format!("{}: {}\n", severity, diagnostic.message);
+    if diagnostic.col_start == 0 {
+        result.push_str(&format!("{} --> line {}\n", gutter, line_number));
+        result.push_str(&format!("{} | {}\n", line_number, source_line));
+    } else {
+        let carets = (diagnostic.col_end.saturating_sub(diagnostic.col_start)).max(1);
+        result.push_str(
+            &format!("{} --> line {}, col {}\n", gutter, line_number, diagnostic.col_start));
+        result.push_str(&format!("{} | {}\n", line_number, source_line));
+        result.push_str(&format!(
+            "{} | {}{}\n", gutter, " ".repeat(diagnostic.col_start - 1), "^".repeat(carets)));
+    }
+    result
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[line {}, columns {}-{}] {:?}: {}",
+            self.line, self.col_start, self.col_end, self.severity, self.message,
+        )
+    }
 }
\ No newline at end of file
@@ -1,3 +1,4 @@
+use std::error::Error;
 use std::fmt;
 use std::fmt::{Display, Formatter};
 
@@ -8,6 +9,12 @@ pub fn tokenize(source: &str) -> LoxResult<Vec<Token>> {
     common::error::convert_error(Lexer::new(source).get_lexems())
 }
 
+/// Like [tokenize], but without the syntax only the compiled frontend understands, e.g., `a--b`
+/// is lexed as `a - -b` rather than as a decrement.
+pub fn tokenize_interpreted(source: &str) -> LoxResult<Vec<Token>> {
+    common::error::convert_error(Lexer { compiled: false, ..Lexer::new(source) }.get_lexems())
+}
+
 
 // Only failure possible during lexing is unterminated string or multi-line comment. Therefore, at
 // most one error can occur at any given time.
@@ -20,6 +27,8 @@ pub enum TokenType {
     CloseParen,
     OpenBrace,
     CloseBrace,
+    OpenBracket,
+    CloseBracket,
     Comma,
     Question,
     Colon,
@@ -38,25 +47,40 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    MinusMinus,
+    PlusPlus,
+    DotDot,
+    DotDotEqual,
     // Keywords.
     And,
+    Break,
     Class,
+    Continue,
     Else,
     False,
     Fun,
     For,
     If,
+    Import,
     Nil,
     Or,
     Print,
     Return,
+    Static,
     Super,
     This,
     True,
     Var,
     While,
+    Write,
+    Xor,
 
     StringLiteral(String),
+    // The part of an interpolated string up to a `${`, which is followed by the tokens of the
+    // interpolated expression, and then by the rest of the string: either another Interpolation,
+    // or a StringLiteral for the part after the last expression. E.g., "a${x}b" is lexed as
+    // Interpolation("a"), Identifier("x"), StringLiteral("b").
+    Interpolation(String),
     NumberLiteral(f64),
     Identifier(String),
 
@@ -75,15 +99,32 @@ impl Display for TokenType {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// The columns a token occupies in its line, 1-based, with `col_end` being exclusive. For tokens
+/// spanning multiple lines (i.e., multi-line strings), the columns are relative to the token's first
+/// and last lines, respectively. Tokens which weren't read from a source have an empty span at
+/// column 0.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Span {
+    pub col_start: usize,
+    pub col_end: usize,
+}
+
+#[derive(Debug, Clone)]
 pub struct Token {
     pub line: usize,
     pub r#type: TokenType,
+    pub span: Span,
+}
+
+// Spans are ignored, since they're only used for diagnostics, and comparing them would make every
+// token comparison position sensitive.
+impl PartialEq for Token {
+    fn eq(&self, other: &Self) -> bool { self.line == other.line && self.r#type == other.r#type }
 }
 
 impl Token {
     pub fn new(line: usize, r#type: TokenType) -> Self {
-        Token { line, r#type }
+        Token { line, r#type, span: Span::default() }
     }
     pub fn get_type(&self) -> &TokenType { &self.r#type }
 
@@ -124,12 +165,62 @@ impl Display for LexError {
     }
 }
 
+impl Error for LexError {}
+
+/// Tokenizes lazily, for tooling which wants the tokens without compiling them, e.g., syntax
+/// highlighters. Unlike [tokenize], the last token is always a [TokenType::Eof], unless lexing
+/// failed, in which case the error is the last item.
+pub struct Scanner<'a> {
+    lexer: Lexer<'a>,
+    finished: bool,
+}
+
+impl<'a> Scanner<'a> {
+    pub fn new(source: &'a str) -> Self { Scanner { lexer: Lexer::new(source), finished: false } }
+}
+
+impl Iterator for Scanner<'_> {
+    type Item = Result<Token, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        while self.lexer.lexems.is_empty() && !self.lexer.is_at_end() {
+            if let Err(e) = self.lexer.next_lexeme() {
+                self.finished = true;
+                return Some(Err(e));
+            }
+        }
+        if self.lexer.lexems.is_empty() {
+            self.finished = true;
+            if let Err(e) = self.lexer.check_finished() {
+                return Some(Err(e));
+            }
+            let column = self.lexer.column();
+            let span = Span { col_start: column, col_end: column };
+            Some(Ok(Token { line: self.lexer.line, r#type: TokenType::Eof, span }))
+        } else {
+            Some(Ok(self.lexer.lexems.remove(0)))
+        }
+    }
+}
+
 struct Lexer<'a> {
     source: &'a str,
     current: usize,
     start: usize,
     line: usize,
+    // The index of the current line's first char.
+    line_start: usize,
+    // Unlike start, isn't modified while reading a token.
+    token_column: usize,
     lexems: Vec<Token>,
+    // For every interpolated expression being read, innermost last, the number of braces opened
+    // inside it which haven't been closed yet. Its closing brace resumes reading the string.
+    interpolations: Vec<usize>,
+    // False for the interpreted frontend, whose parser only knows the original Lox syntax.
+    compiled: bool,
 }
 
 impl<'a> Lexer<'a> {
@@ -139,24 +230,45 @@ impl<'a> Lexer<'a> {
             current: 0,
             start: 0,
             line: 1,
+            line_start: 0,
+            token_column: 0,
             lexems: Vec::new(),
+            interpolations: Vec::new(),
+            compiled: true,
         }
     }
 
     pub fn get_lexems(mut self) -> LexResult<Vec<Token>> {
-        if !self.is_at_end() {
-            while !self.is_at_end() {
-                self.start = self.current;
-                self.scan_token()?;
-            }
+        while !self.is_at_end() {
+            self.next_lexeme()?;
         }
+        self.check_finished()?;
         Ok(self.lexems)
     }
 
+    // The source can't end in the middle of an interpolated expression, since its string is
+    // unterminated.
+    fn check_finished(&self) -> LexResult<()> {
+        if self.interpolations.is_empty() { Ok(()) } else { self.error("Unterminated string.") }
+    }
+
+    // Doesn't necessarily add a token, e.g., for whitespace or comments.
+    fn next_lexeme(&mut self) -> LexResult<()> {
+        self.start = self.current;
+        self.token_column = self.column();
+        self.scan_token()
+    }
+
     fn is_at_end(&self) -> bool { self.current >= self.source.len() }
 
     fn add_token_type(&mut self, tt: TokenType) {
-        self.lexems.push(Token::new(self.line, tt));
+        let span = Span { col_start: self.token_column, col_end: self.column() };
+        self.lexems.push(Token { line: self.line, r#type: tt, span });
+    }
+    fn column(&self) -> usize { self.current - self.line_start + 1 }
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
     }
     fn matches(&mut self, expected: char) -> bool {
         let result = self.source.chars().nth(self.current) == Some(expected);
@@ -173,11 +285,35 @@ impl<'a> Lexer<'a> {
             ':' => Ok(self.add_token_type(TokenType::Colon)),
             '(' => Ok(self.add_token_type(TokenType::OpenParen)),
             ')' => Ok(self.add_token_type(TokenType::CloseParen)),
-            '{' => Ok(self.add_token_type(TokenType::OpenBrace)),
-            '}' => Ok(self.add_token_type(TokenType::CloseBrace)),
-            '.' => Ok(self.add_token_type(TokenType::Dot)),
-            '-' => Ok(self.add_token_type(TokenType::Minus)),
-            '+' => Ok(self.add_token_type(TokenType::Plus)),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                Ok(self.add_token_type(TokenType::OpenBrace))
+            }
+            '}' => match self.interpolations.last_mut() {
+                Some(0) => {
+                    self.interpolations.pop();
+                    self.read_string_literal().map(|e| self.add_token_type(e))
+                }
+                Some(depth) => {
+                    *depth -= 1;
+                    Ok(self.add_token_type(TokenType::CloseBrace))
+                }
+                None => Ok(self.add_token_type(TokenType::CloseBrace)),
+            },
+            '[' => Ok(self.add_token_type(TokenType::OpenBracket)),
+            ']' => Ok(self.add_token_type(TokenType::CloseBracket)),
+            '.' => {
+                let token = if !self.matches('.') {
+                    TokenType::Dot
+                } else if self.matches('=') {
+                    TokenType::DotDotEqual
+                } else {
+                    TokenType::DotDot
+                };
+                Ok(self.add_token_type(token))
+            }
             ';' => Ok(self.add_token_type(TokenType::Semicolon)),
             '*' => Ok(self.add_token_type(TokenType::Star)),
 
@@ -198,6 +334,14 @@ impl<'a> Lexer<'a> {
                 let m = self.matches('=');
                 Ok(self.add_token_type(if m { TokenType::GreaterEqual } else { TokenType::Greater }))
             }
+            '-' => {
+                let m = self.compiled && self.matches('-');
+                Ok(self.add_token_type(if m { TokenType::MinusMinus } else { TokenType::Minus }))
+            }
+            '+' => {
+                let m = self.compiled && self.matches('+');
+                Ok(self.add_token_type(if m { TokenType::PlusPlus } else { TokenType::Plus }))
+            }
 
             '/' =>
                 if self.matches('/') {
@@ -212,7 +356,7 @@ impl<'a> Lexer<'a> {
 
             ' ' | '\r' | '\t' => Ok(()),
             '\n' => {
-                self.line += 1;
+                self.new_line();
                 Ok(())
             }
             '"' => self.read_string_literal().map(|e| self.add_token_type(e)),
@@ -220,7 +364,7 @@ impl<'a> Lexer<'a> {
                 if c.is_ascii_digit() {
                     let num = self.read_number_literal();
                     Ok(self.add_token_type(num))
-                } else if c.is_alphabetic() {
+                } else if c.is_alphabetic() || c == '_' {
                     let ident = self.read_identifier();
                     Ok(self.add_token_type(ident))
                 } else {
@@ -265,35 +409,64 @@ impl<'a> Lexer<'a> {
     }
 
     fn read_number_literal(&mut self) -> TokenType {
-        while self.peek_test(|e: char| e.is_numeric() || e == '.') {
+        while self.peek_test(|e: char| e.is_numeric()) {
+            self.advance();
+        }
+        // A dot is only part of the number if it's followed by a digit, so that 0..10 is a range.
+        if self.peek_test('.') && self.peek_n_test(1, |e: char| e.is_numeric()) {
             self.advance();
+            while self.peek_test(|e: char| e.is_numeric()) {
+                self.advance();
+            }
         }
         TokenType::number_literal(self.current_lexeme().parse::<f64>().expect("invalid number."))
     }
 
+    // Also reads the rest of an interpolated string, after the closing brace of an expression.
     fn read_string_literal(&mut self) -> LexResult<TokenType> {
-        while self.peek_test(negated_char_test('"')) {
-            if self.peek_test('\n') {
-                self.line += 1
-            }
+        while self.peek_test(negated_char_test('"')) && !self.at_interpolation() {
+            let is_new_line = self.peek_test('\n');
             self.advance();
+            if is_new_line {
+                self.new_line();
+            }
         }
         if self.is_at_end() {
             self.error("Unterminated string.")
+        } else if self.at_interpolation() {
+            self.start += 1; // Skip opening " or }
+            let result = Ok(TokenType::Interpolation(self.current_lexeme().to_owned()));
+            self.current += 2; // Move past ${
+            self.interpolations.push(0);
+            result
         } else {
-            self.start += 1; // Skip opening "
+            self.start += 1; // Skip opening " or }
             let result = Ok(TokenType::string_literal(self.current_lexeme()));
             self.advance(); // Move past closing "
             result
         }
     }
 
+    fn at_interpolation(&self) -> bool {
+        self.compiled && self.peek_test('$') && self.peek_n_test(1, '{')
+    }
+
     fn read_identifier(&mut self) -> TokenType {
         while self.peek_test(|e: char| e.is_alphanumeric() || e == '_') {
             self.advance();
         }
         let word = self.current_lexeme();
-        Lexer::get_keyword(word).unwrap_or(TokenType::identifier(word))
+        Lexer::get_keyword(word)
+            .filter(|keyword| self.compiled || !Lexer::is_compiled_only(keyword))
+            .unwrap_or(TokenType::identifier(word))
+    }
+
+    fn is_compiled_only(keyword: &TokenType) -> bool {
+        matches!(
+            keyword,
+            TokenType::Break | TokenType::Continue | TokenType::Import | TokenType::Write
+                | TokenType::Xor | TokenType::Static
+        )
     }
 
     fn current_lexeme(&self) -> &str {
@@ -302,21 +475,27 @@ impl<'a> Lexer<'a> {
     fn get_keyword(word: &str) -> Option<TokenType> {
         match word.to_lowercase().as_str() {
             "and" => Some(TokenType::And),
+            "break" => Some(TokenType::Break),
             "class" => Some(TokenType::Class),
+            "continue" => Some(TokenType::Continue),
             "else" => Some(TokenType::Else),
             "false" => Some(TokenType::False),
             "for" => Some(TokenType::For),
             "fun" => Some(TokenType::Fun),
             "if" => Some(TokenType::If),
+            "import" => Some(TokenType::Import),
             "nil" => Some(TokenType::Nil),
             "or" => Some(TokenType::Or),
             "print" => Some(TokenType::Print),
             "return" => Some(TokenType::Return),
+            "static" => Some(TokenType::Static),
             "super" => Some(TokenType::Super),
             "this" => Some(TokenType::This),
             "true" => Some(TokenType::True),
             "var" => Some(TokenType::Var),
             "while" => Some(TokenType::While),
+            "write" => Some(TokenType::Write),
+            "xor" => Some(TokenType::Xor),
             _ => None,
         }
     }
@@ -351,6 +530,120 @@ mod tests {
         )
     }
 
+    #[test]
+    fn only_the_compiled_frontend_has_increments() {
+        let types = |tokens: Vec<Token>| tokens.into_iter().map(|t| t.r#type).collect::<Vec<_>>();
+        assert_eq!(
+            types(tokenize("a--b++").unwrap()),
+            vec![
+                TokenType::identifier("a"),
+                TokenType::MinusMinus,
+                TokenType::identifier("b"),
+                TokenType::PlusPlus,
+            ],
+        );
+        assert_eq!(
+            types(tokenize_interpreted("a--b++").unwrap()),
+            vec![
+                TokenType::identifier("a"),
+                TokenType::Minus,
+                TokenType::Minus,
+                TokenType::identifier("b"),
+                TokenType::Plus,
+                TokenType::Plus,
+            ],
+        );
+    }
+
+    #[test]
+    fn only_the_compiled_frontend_has_xor() {
+        assert_eq!(tokenize("xor").unwrap()[0].r#type, TokenType::Xor);
+        assert_eq!(tokenize_interpreted("xor").unwrap()[0].r#type, TokenType::identifier("xor"));
+    }
+
+    #[test]
+    fn only_the_compiled_frontend_has_static() {
+        assert_eq!(tokenize("static").unwrap()[0].r#type, TokenType::Static);
+        assert_eq!(
+            tokenize_interpreted("static").unwrap()[0].r#type, TokenType::identifier("static"));
+    }
+
+    #[test]
+    fn scanner_yields_tokens_lazily_and_ends_with_eof() {
+        let tokens: Vec<Token> = Scanner::new("var x = 1;").map(|t| t.unwrap()).collect();
+        assert_eq!(
+            tokens,
+            vec!(
+                Token::new(1, TokenType::Var),
+                Token::new(1, TokenType::identifier("x")),
+                Token::new(1, TokenType::Equal),
+                Token::new(1, TokenType::number_literal(1.0)),
+                Token::new(1, TokenType::Semicolon),
+                Token::new(1, TokenType::Eof),
+            ),
+        );
+        let spans: Vec<(usize, usize)> =
+            tokens.iter().map(|t| (t.span.col_start, t.span.col_end)).collect();
+        assert_eq!(spans, vec![(1, 4), (5, 6), (7, 8), (9, 10), (10, 11), (11, 11)]);
+    }
+
+    #[test]
+    fn scanner_stops_after_an_error() {
+        let mut scanner = Scanner::new("print 1; \"oops");
+        assert_eq!(scanner.next().unwrap().unwrap(), Token::new(1, TokenType::Print));
+        assert_eq!(scanner.next().unwrap().unwrap(), Token::new(1, TokenType::number_literal(1.0)));
+        assert_eq!(scanner.next().unwrap().unwrap(), Token::new(1, TokenType::Semicolon));
+        assert_eq!(scanner.next().unwrap().unwrap_err().get_message(), "Unterminated string.");
+        assert!(scanner.next().is_none());
+        assert_eq!(Scanner::new("// nothing").count(), 1);
+    }
+
+    #[test]
+    fn identifiers_can_start_with_an_underscore() {
+        assert_eq!(
+            unsafe_tokenize(vec!["_ _x"]),
+            vec!(Token::new(1, TokenType::identifier("_")), Token::new(1, TokenType::identifier("_x"))),
+        )
+    }
+
+    #[test]
+    fn brackets() {
+        assert_eq!(
+            unsafe_tokenize(vec!["x[[]]"]),
+            vec!(
+                Token::new(1, TokenType::identifier("x")),
+                Token::new(1, TokenType::OpenBracket),
+                Token::new(1, TokenType::OpenBracket),
+                Token::new(1, TokenType::CloseBracket),
+                Token::new(1, TokenType::CloseBracket),
+            ),
+        )
+    }
+
+    #[test]
+    fn interpolated_strings() {
+        assert_eq!(
+            unsafe_tokenize(vec![r#""a ${x + "${y}"} b ${{}}" "$ {}""#]),
+            vec!(
+                Token::new(1, TokenType::Interpolation("a ".to_owned())),
+                Token::new(1, TokenType::identifier("x")),
+                Token::new(1, TokenType::Plus),
+                Token::new(1, TokenType::Interpolation("".to_owned())),
+                Token::new(1, TokenType::identifier("y")),
+                Token::new(1, TokenType::string_literal("")),
+                Token::new(1, TokenType::Interpolation(" b ".to_owned())),
+                Token::new(1, TokenType::OpenBrace),
+                Token::new(1, TokenType::CloseBrace),
+                Token::new(1, TokenType::string_literal("")),
+                Token::new(1, TokenType::string_literal("$ {}")),
+            ),
+        );
+        assert_eq!(
+            tokenize(r#"print "a ${x"#).unwrap_err().head.get_message(), "Unterminated string.");
+        assert_eq!(
+            tokenize(r#"print "${x}"#).unwrap_err().head.get_message(), "Unterminated string.");
+    }
+
     #[test]
     fn test_basic_example() {
         assert_eq!(
@@ -365,6 +658,19 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_spans() {
+        let spans: Vec<(usize, Span)> = unsafe_tokenize(vec!["var xy =", "  \"ab\";"])
+            .into_iter()
+            .map(|t| (t.line, t.span))
+            .collect();
+        let span = |col_start, col_end| Span { col_start, col_end };
+        assert_eq!(
+            spans,
+            vec!((1, span(1, 4)), (1, span(5, 7)), (1, span(8, 9)), (2, span(3, 7)), (2, span(7, 8))),
+        )
+    }
+
     #[test]
     fn test_basic_expression() {
         assert_eq!(
@@ -379,6 +685,35 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_increments() {
+        assert_eq!(
+            unsafe_tokenize(vec!["++x - y--"]),
+            vec!(
+                Token::new(1, TokenType::PlusPlus),
+                Token::new(1, TokenType::identifier("x")),
+                Token::new(1, TokenType::Minus),
+                Token::new(1, TokenType::identifier("y")),
+                Token::new(1, TokenType::MinusMinus),
+            ),
+        )
+    }
+
+    #[test]
+    fn test_ranges() {
+        assert_eq!(
+            unsafe_tokenize(vec!["0..10 1.5..=2"]),
+            vec!(
+                Token::new(1, TokenType::NumberLiteral(0.0)),
+                Token::new(1, TokenType::DotDot),
+                Token::new(1, TokenType::NumberLiteral(10.0)),
+                Token::new(1, TokenType::NumberLiteral(1.5)),
+                Token::new(1, TokenType::DotDotEqual),
+                Token::new(1, TokenType::NumberLiteral(2.0)),
+            ),
+        )
+    }
+
     #[test]
     fn test_ternary() {
         assert_eq!(
@@ -2,7 +2,7 @@
 use {
     std::fmt::Debug,
 
-    crate::rslox::common::lexer::{Token, tokenize},
+    crate::rslox::common::lexer::{Token, tokenize, tokenize_interpreted},
 };
 
 #[cfg(test)]
@@ -10,6 +10,11 @@ pub fn unsafe_tokenize(program: Vec<&str>) -> Vec<Token> {
     tokenize(program.join("\n").as_ref()).expect("Failed to tokenize")
 }
 
+#[cfg(test)]
+pub fn unsafe_tokenize_interpreted(program: Vec<&str>) -> Vec<Token> {
+    tokenize_interpreted(program.join("\n").as_ref()).expect("Failed to tokenize")
+}
+
 #[cfg(test)]
 pub fn eq_vec_msg<A>(left: Vec<A>, right: Vec<A>) -> Option<String> where A: PartialEq + Debug + Clone {
     if left == right {
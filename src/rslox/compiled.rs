@@ -1,9 +1,17 @@
 
+mod bytecode;
 mod chunk;
 mod code;
 mod compiler;
+mod debugger;
+mod globals;
+pub mod lox;
 mod memory;
+mod natives;
 mod op_code;
+pub mod prompt;
+pub mod runfile;
 mod tests;
 mod value;
+mod verifier;
 mod vm;
\ No newline at end of file
@@ -0,0 +1,620 @@
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+
+use crate::rslox::compiled::chunk::{Chunk, Upvalue};
+use crate::rslox::compiled::code::Code;
+use crate::rslox::compiled::compiler::InternedStrings;
+use crate::rslox::compiled::memory::InternedString;
+use crate::rslox::compiled::op_code::OpCode;
+use crate::rslox::compiled::value::{Class, Function};
+use crate::rslox::compiled::verifier::{verify, VerifyError};
+
+const MAGIC: &[u8; 4] = b"LOXC";
+/// Bumped on every change to the encoding, since files written by older versions can't be read.
+pub const VERSION: u16 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    NotBytecode,
+    UnsupportedVersion(u16),
+    Malformed(String),
+    Invalid(VerifyError),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::NotBytecode => write!(f, "Not a Lox bytecode file"),
+            LoadError::UnsupportedVersion(v) =>
+                write!(f, "Unsupported bytecode version {}, expected {}", v, VERSION),
+            LoadError::Malformed(msg) => write!(f, "Malformed bytecode: {}", msg),
+            LoadError::Invalid(e) => write!(f, "Invalid bytecode:\n{}", e),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Invalid(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The file starts with a header of the magic bytes and the version, followed by a table of all the
+/// strings used by the script, which the code then refers to by their index. All numbers are little
+/// endian, and sizes and indices are always 64 bits wide, regardless of the platform.
+pub fn serialize(chunk: &Chunk) -> Vec<u8> {
+    let mut writer = Writer::default();
+    writer.chunk(chunk);
+    let mut result = Vec::with_capacity(writer.bytes.len());
+    result.extend_from_slice(MAGIC);
+    result.extend_from_slice(&VERSION.to_le_bytes());
+    let mut table = Writer::default();
+    table.usize(writer.strings.len());
+    for s in writer.strings.iter() {
+        table.usize(s.len());
+        table.bytes.extend_from_slice(s.as_bytes());
+    }
+    result.extend(table.bytes);
+    result.extend(writer.bytes);
+    result
+}
+
+/// Since the file might have been corrupted (or hand-crafted), the loaded chunk is also verified.
+pub fn load(bytes: &[u8]) -> Result<(Chunk, InternedStrings), LoadError> {
+    let (chunk, interned_strings) = deserialize(bytes)?;
+    // The VM grows its stack as needed, so only the structural invariants matter here.
+    verify(&chunk, usize::MAX).map_err(LoadError::Invalid)?;
+    Ok((chunk, interned_strings))
+}
+
+fn deserialize(bytes: &[u8]) -> Result<(Chunk, InternedStrings), LoadError> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(LoadError::NotBytecode);
+    }
+    let mut reader = Reader {
+        bytes,
+        position: MAGIC.len(),
+        strings: Vec::new(),
+        interned_strings: InternedStrings::default(),
+    };
+    let version = u16::from_le_bytes(reader.array()?);
+    if version != VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+    for _ in 0..reader.usize()? {
+        let length = reader.usize()?;
+        let str = String::from_utf8(reader.take(length)?.to_vec())
+            .map_err(|_| LoadError::Malformed("String isn't valid UTF-8".to_owned()))?;
+        let interned = reader.interned_strings.intern_string(str);
+        reader.strings.push(interned);
+    }
+    let chunk = reader.chunk(0)?;
+    if reader.position != bytes.len() {
+        return Err(LoadError::Malformed(format!(
+            "{} trailing bytes after the script", bytes.len() - reader.position)));
+    }
+    Ok((chunk, reader.interned_strings))
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+    strings: Vec<String>,
+    string_indices: HashMap<InternedString, usize>,
+}
+
+impl Writer {
+    fn u8(&mut self, u: u8) { self.bytes.push(u) }
+    fn bool(&mut self, b: bool) { self.u8(b as u8) }
+    fn usize(&mut self, u: usize) { self.bytes.extend_from_slice(&(u as u64).to_le_bytes()) }
+    fn f64(&mut self, f: f64) { self.bytes.extend_from_slice(&f.to_le_bytes()) }
+    fn string(&mut self, s: &InternedString) {
+        let next_index = self.strings.len();
+        let index = *self.string_indices.entry(s.clone()).or_insert(next_index);
+        if index == next_index {
+            self.strings.push(s.to_owned());
+        }
+        self.usize(index);
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) {
+        let code = chunk.get_code();
+        self.usize(code.len());
+        for (op, line) in code.iter() {
+            self.op(op);
+            self.usize(*line);
+        }
+        self.usize(chunk.function_count());
+        for i in 0..chunk.function_count() {
+            self.function(&chunk.get_function(i).upgrade().unwrap());
+        }
+        // Methods are only bound at runtime, so a class is just its name.
+        self.usize(chunk.class_count());
+        for i in 0..chunk.class_count() {
+            self.string(&chunk.get_class(i).upgrade().unwrap().name);
+        }
+        self.usize(chunk.global_names().len());
+        for name in chunk.global_names() {
+            self.string(name);
+        }
+    }
+
+    fn function(&mut self, function: &Function) {
+        self.string(&function.name);
+        self.usize(function.arity);
+        self.bool(function.is_method);
+        self.usize(function.upvalues.len());
+        for Upvalue { index, is_local, by_value } in function.upvalues.iter() {
+            self.usize(*index);
+            self.bool(*is_local);
+            self.bool(*by_value);
+        }
+        self.chunk(&function.chunk);
+    }
+
+    // New op codes get the next free tag, so the tags of existing ones never change meaning.
+    fn op(&mut self, op: &OpCode) {
+        match op {
+            OpCode::Return => self.u8(0),
+            OpCode::Pop => self.u8(1),
+            OpCode::PopN(n) => {
+                self.u8(2);
+                self.usize(*n);
+            }
+            OpCode::Dup => self.u8(3),
+            OpCode::Swap => self.u8(4),
+            OpCode::Over => self.u8(5),
+            OpCode::Print => self.u8(6),
+            OpCode::Write => self.u8(7),
+            OpCode::Function(i) => {
+                self.u8(8);
+                self.usize(*i);
+            }
+            OpCode::Class(i) => {
+                self.u8(9);
+                self.usize(*i);
+            }
+            OpCode::Method(name) => {
+                self.u8(10);
+                self.string(name);
+            }
+            OpCode::GetProperty(name) => {
+                self.u8(11);
+                self.string(name);
+            }
+            OpCode::SetProperty(name) => {
+                self.u8(12);
+                self.string(name);
+            }
+            OpCode::Module(name, exports) => {
+                self.u8(13);
+                self.string(name);
+                self.usize(exports.len());
+                for e in exports.iter() {
+                    self.string(e);
+                }
+            }
+            OpCode::CloseUpvalue => self.u8(14),
+            OpCode::DefineGlobal(name) => {
+                self.u8(15);
+                self.string(name);
+            }
+            OpCode::DefineLocal(i) => {
+                self.u8(16);
+                self.usize(*i);
+            }
+            OpCode::Number(n) => {
+                self.u8(17);
+                self.f64(*n);
+            }
+            OpCode::Bool(b) => {
+                self.u8(18);
+                self.bool(*b);
+            }
+            OpCode::String(s) => {
+                self.u8(19);
+                self.string(s);
+            }
+            OpCode::GetGlobal(name) => {
+                self.u8(20);
+                self.string(name);
+            }
+            OpCode::SetGlobal(name) => {
+                self.u8(21);
+                self.string(name);
+            }
+            OpCode::GetGlobalByIndex(i) => {
+                self.u8(22);
+                self.usize(*i);
+            }
+            OpCode::SetGlobalByIndex(i) => {
+                self.u8(23);
+                self.usize(*i);
+            }
+            OpCode::GetUpvalue(i) => {
+                self.u8(24);
+                self.usize(*i);
+            }
+            OpCode::SetUpvalue(i) => {
+                self.u8(25);
+                self.usize(*i);
+            }
+            OpCode::GetLocal(i) => {
+                self.u8(26);
+                self.usize(*i);
+            }
+            OpCode::SetLocal(i) => {
+                self.u8(27);
+                self.usize(*i);
+            }
+            OpCode::Nil => self.u8(28),
+            OpCode::Call(n) => {
+                self.u8(29);
+                self.usize(*n);
+            }
+            OpCode::Add => self.u8(30),
+            OpCode::Subtract => self.u8(31),
+            OpCode::Multiply => self.u8(32),
+            OpCode::Divide => self.u8(33),
+            OpCode::Negate => self.u8(34),
+            OpCode::Not => self.u8(35),
+            OpCode::Equals => self.u8(36),
+            OpCode::Less => self.u8(37),
+            OpCode::Greater => self.u8(38),
+            OpCode::Range(inclusive) => {
+                self.u8(39);
+                self.bool(*inclusive);
+            }
+            OpCode::MakeList(n) => {
+                self.u8(40);
+                self.usize(*n);
+            }
+            OpCode::GetIndex => self.u8(41),
+            OpCode::SetIndex => self.u8(42),
+            OpCode::IterNext(local, exit) => {
+                self.u8(43);
+                self.usize(*local);
+                self.usize(*exit);
+            }
+            OpCode::UnpatchedJump => panic!("Jumps should have been patched before serializing"),
+            OpCode::Jump(target) => {
+                self.u8(44);
+                self.usize(*target);
+            }
+            OpCode::JumpIfFalse(target) => {
+                self.u8(45);
+                self.usize(*target);
+            }
+            OpCode::Loop(offset) => {
+                self.u8(46);
+                self.usize(*offset);
+            }
+            OpCode::MakeTable(n) => {
+                self.u8(47);
+                self.usize(*n);
+            }
+            OpCode::TailCall(n) => {
+                self.u8(48);
+                self.usize(*n);
+            }
+            OpCode::ToString => self.u8(49),
+            OpCode::GetModule(path) => {
+                self.u8(50);
+                self.string(path);
+            }
+            OpCode::StaticMethod(name) => {
+                self.u8(51);
+                self.string(name);
+            }
+            OpCode::Iterate => self.u8(52),
+            OpCode::Invoke(name, n) => {
+                self.u8(53);
+                self.string(name);
+                self.usize(*n);
+            }
+            OpCode::TailInvoke(name, n) => {
+                self.u8(54);
+                self.string(name);
+                self.usize(*n);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    // Indexed by the file's string table.
+    strings: Vec<InternedString>,
+    interned_strings: InternedStrings,
+}
+
+type ReadResult<A> = Result<A, LoadError>;
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> ReadResult<&'a [u8]> {
+        let end = self.position.checked_add(length)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| LoadError::Malformed("Unexpected end of file".to_owned()))?;
+        let result = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(result)
+    }
+    fn array<const N: usize>(&mut self) -> ReadResult<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+    fn u8(&mut self) -> ReadResult<u8> { Ok(self.take(1)?[0]) }
+    fn bool(&mut self) -> ReadResult<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(LoadError::Malformed(format!("Invalid boolean {}", b))),
+        }
+    }
+    fn usize(&mut self) -> ReadResult<usize> {
+        let u = u64::from_le_bytes(self.array()?);
+        usize::try_from(u).map_err(|_| LoadError::Malformed(format!("Size {} is too large", u)))
+    }
+    fn f64(&mut self) -> ReadResult<f64> { Ok(f64::from_le_bytes(self.array()?)) }
+    fn string(&mut self) -> ReadResult<InternedString> {
+        let index = self.usize()?;
+        self.strings.get(index).cloned().ok_or_else(|| LoadError::Malformed(format!(
+            "Invalid string index {}, there are only {}", index, self.strings.len())))
+    }
+
+    // The parameters are the locals the code starts with, see Function::initial_locals.
+    fn chunk(&mut self, parameters: usize) -> ReadResult<Chunk> {
+        let mut code = Code::default();
+        for _ in 0..self.usize()? {
+            let op = self.op()?;
+            code.write(op, self.usize()?);
+        }
+        let functions = (0..self.usize()?).map(|_| self.function()).collect::<ReadResult<_>>()?;
+        let classes = (0..self.usize()?)
+            .map(|_| Ok(Class::new(self.string()?)))
+            .collect::<ReadResult<_>>()?;
+        let global_names = (0..self.usize()?).map(|_| self.string()).collect::<ReadResult<_>>()?;
+        Ok(Chunk::from_parts(code, functions, classes, global_names, parameters))
+    }
+
+    fn function(&mut self) -> ReadResult<Function> {
+        let name = self.string()?;
+        let arity = self.usize()?;
+        let is_method = self.bool()?;
+        let upvalues = (0..self.usize()?)
+            .map(|_| Ok(Upvalue {
+                index: self.usize()?, is_local: self.bool()?, by_value: self.bool()?,
+            }))
+            .collect::<ReadResult<_>>()?;
+        let parameters = arity.checked_add(is_method as usize)
+            .ok_or_else(|| LoadError::Malformed(format!("Arity {} is too large", arity)))?;
+        let chunk = self.chunk(parameters)?;
+        Ok(Function { name, arity, chunk, upvalues, is_method })
+    }
+
+    fn op(&mut self) -> ReadResult<OpCode> {
+        Ok(match self.u8()? {
+            0 => OpCode::Return,
+            1 => OpCode::Pop,
+            2 => OpCode::PopN(self.usize()?),
+            3 => OpCode::Dup,
+            4 => OpCode::Swap,
+            5 => OpCode::Over,
+            6 => OpCode::Print,
+            7 => OpCode::Write,
+            8 => OpCode::Function(self.usize()?),
+            9 => OpCode::Class(self.usize()?),
+            10 => OpCode::Method(self.string()?),
+            11 => OpCode::GetProperty(self.string()?),
+            12 => OpCode::SetProperty(self.string()?),
+            13 => {
+                let name = self.string()?;
+                let exports = (0..self.usize()?).map(|_| self.string()).collect::<ReadResult<_>>()?;
+                OpCode::Module(name, Rc::new(exports))
+            }
+            14 => OpCode::CloseUpvalue,
+            15 => OpCode::DefineGlobal(self.string()?),
+            16 => OpCode::DefineLocal(self.usize()?),
+            17 => OpCode::Number(self.f64()?),
+            18 => OpCode::Bool(self.bool()?),
+            19 => OpCode::String(self.string()?),
+            20 => OpCode::GetGlobal(self.string()?),
+            21 => OpCode::SetGlobal(self.string()?),
+            22 => OpCode::GetGlobalByIndex(self.usize()?),
+            23 => OpCode::SetGlobalByIndex(self.usize()?),
+            24 => OpCode::GetUpvalue(self.usize()?),
+            25 => OpCode::SetUpvalue(self.usize()?),
+            26 => OpCode::GetLocal(self.usize()?),
+            27 => OpCode::SetLocal(self.usize()?),
+            28 => OpCode::Nil,
+            29 => OpCode::Call(self.usize()?),
+            30 => OpCode::Add,
+            31 => OpCode::Subtract,
+            32 => OpCode::Multiply,
+            33 => OpCode::Divide,
+            34 => OpCode::Negate,
+            35 => OpCode::Not,
+            36 => OpCode::Equals,
+            37 => OpCode::Less,
+            38 => OpCode::Greater,
+            39 => OpCode::Range(self.bool()?),
+            40 => OpCode::MakeList(self.usize()?),
+            41 => OpCode::GetIndex,
+            42 => OpCode::SetIndex,
+            43 => OpCode::IterNext(self.usize()?, self.usize()?),
+            44 => OpCode::Jump(self.usize()?),
+            45 => OpCode::JumpIfFalse(self.usize()?),
+            46 => OpCode::Loop(self.usize()?),
+            47 => OpCode::MakeTable(self.usize()?),
+            48 => OpCode::TailCall(self.usize()?),
+            49 => OpCode::ToString,
+            50 => OpCode::GetModule(self.string()?),
+            51 => OpCode::StaticMethod(self.string()?),
+            52 => OpCode::Iterate,
+            53 => OpCode::Invoke(self.string()?, self.usize()?),
+            54 => OpCode::TailInvoke(self.string()?, self.usize()?),
+            tag => return Err(LoadError::Malformed(format!("Unknown op code {}", tag))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assert_deep_eq;
+    use crate::assert_msg_contains;
+    use crate::rslox::compiled::chunk::disassemble;
+    use crate::rslox::compiled::tests::unsafe_compile;
+    use crate::rslox::compiled::vm::VirtualMachine;
+
+    use super::*;
+
+    const PROGRAM: &str = r#"
+var greeting = "hello";
+fun counter(start) {
+  var count = start;
+  fun increment() {
+    count = count + 1;
+    return count;
+  }
+  return increment;
+}
+class Point {
+  init(x, y) { this.x = x; this.y = y; }
+  sum() { return this.x + this.y; }
+  total() { return this.sum(); }
+  static origin() { return Point(0, 0); }
+}
+var c = counter(1);
+var p = Point(2, 3);
+var list = [1, "two", true, nil];
+list[0] = -1.5;
+var total = 0;
+for (i in 0..=3) {
+  var y = i;
+  fun capture() use (y) { return y; }
+  total = total + capture();
+}
+while (total > 5) total = total - 1;
+print greeting;
+print c() + c();
+print p.sum();
+print Point.origin().sum();
+print list;
+print total;
+"#;
+
+    fn run(chunk: Chunk, interned_strings: InternedStrings) -> String {
+        let mut output = Vec::new();
+        VirtualMachine::run(chunk, interned_strings, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let (chunk, _interned_strings) = unsafe_compile(vec![PROGRAM]);
+        let (loaded, _loaded_strings) = load(&serialize(&chunk)).unwrap();
+        assert_deep_eq!(chunk, loaded);
+        assert_eq!(disassemble(&chunk), disassemble(&loaded));
+        assert_eq!(chunk.max_stack(), loaded.max_stack());
+    }
+
+    #[test]
+    fn loaded_chunks_run_the_same() {
+        let (chunk, interned_strings) = unsafe_compile(vec![PROGRAM]);
+        let bytes = serialize(&chunk);
+        let expected = run(chunk, interned_strings);
+        let (loaded, loaded_strings) = load(&bytes).unwrap();
+        assert_eq!(run(loaded, loaded_strings), expected);
+    }
+
+    #[test]
+    fn strings_are_stored_once() {
+        let (chunk, _interned_strings) =
+            unsafe_compile(vec![r#"print "abcdefgh"; print "abcdefgh"; print "abcdefgh";"#]);
+        let bytes = serialize(&chunk);
+        assert_eq!(bytes.windows(8).filter(|w| w == b"abcdefgh").count(), 1);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert_eq!(load(b"print 1;").unwrap_err(), LoadError::NotBytecode);
+        let (chunk, _interned_strings) = unsafe_compile(vec!["print 1;"]);
+        let mut bytes = serialize(&chunk);
+        bytes[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(load(&bytes).unwrap_err(), LoadError::UnsupportedVersion(VERSION + 1));
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let (chunk, _interned_strings) = unsafe_compile(vec![PROGRAM]);
+        let bytes = serialize(&chunk);
+        assert_eq!(
+            load(&bytes[..bytes.len() - 1]).unwrap_err(),
+            LoadError::Malformed("Unexpected end of file".to_owned()),
+        );
+        let mut extended = bytes.clone();
+        extended.push(0);
+        assert_eq!(
+            load(&extended).unwrap_err(),
+            LoadError::Malformed("1 trailing bytes after the script".to_owned()),
+        );
+    }
+
+    #[test]
+    fn max_stack_is_recomputed_on_load() {
+        let (chunk, _interned_strings) =
+            unsafe_compile(vec!["fun f(a, b, c) { return a + b * c; } print f(1, 2, 3);"]);
+        let (loaded, _loaded_strings) = load(&serialize(&chunk)).unwrap();
+        let max_stack = |chunk: &Chunk| chunk.get_function(0).upgrade().unwrap().chunk.max_stack();
+        assert_eq!(max_stack(&loaded), max_stack(&chunk));
+        assert!(max_stack(&loaded) >= 3);
+    }
+
+    #[test]
+    fn malformed_loops_are_rejected() {
+        let (mut chunk, _interned_strings) = unsafe_compile(vec!["while (false) print 1;"]);
+        let lp = chunk.get_code().iter()
+            .position(|(op, _)| matches!(op, OpCode::Loop(_)))
+            .unwrap();
+        chunk.get_mut(lp).unwrap().0 = OpCode::Loop(1000);
+        match load(&serialize(&chunk)).unwrap_err() {
+            LoadError::Invalid(e) => assert_msg_contains!(
+                e.to_string(), "Loop offset 1000 jumps before the start"),
+            e => panic!("Expected a verification error, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn huge_operands_are_rejected() {
+        let (mut chunk, _interned_strings) = unsafe_compile(vec!["print {};"]);
+        let table = chunk.get_code().iter()
+            .position(|(op, _)| matches!(op, OpCode::MakeTable(_)))
+            .unwrap();
+        chunk.get_mut(table).unwrap().0 = OpCode::MakeTable(usize::MAX);
+        match load(&serialize(&chunk)).unwrap_err() {
+            LoadError::Invalid(e) =>
+                assert_eq!(e.to_string(), "<script> at 0: MAKE_TABLE operand is too large"),
+            e => panic!("Expected a verification error, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn loaded_chunks_are_verified() {
+        let (mut chunk, _interned_strings) = unsafe_compile(vec!["if (true) print 1;"]);
+        let jump = chunk.get_code().iter()
+            .position(|(op, _)| matches!(op, OpCode::JumpIfFalse(_)))
+            .unwrap();
+        chunk.get_mut(jump).unwrap().0 = OpCode::JumpIfFalse(42);
+        match load(&serialize(&chunk)).unwrap_err() {
+            LoadError::Invalid(e) =>
+                assert_eq!(e.to_string(), "<script> at 1: Jump target 42 is out of bounds"),
+            e => panic!("Expected a verification error, got {:?}", e),
+        }
+    }
+}
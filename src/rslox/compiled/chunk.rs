@@ -1,12 +1,14 @@
-use std::borrow::Borrow;
 use std::fmt::Debug;
+use std::mem;
 use std::ops::Deref;
 use std::rc::{Rc, Weak};
 
+use crate::format_interned;
 use crate::rslox::compiled::code::Code;
+use crate::rslox::compiled::memory::InternedString;
 use crate::rslox::compiled::op_code::{CodeLocation, OpCode, StackLocation};
 use crate::rslox::compiled::tests::DeepEq;
-use crate::rslox::compiled::value::{Class, Function};
+use crate::rslox::compiled::value::{Class, Function, Mark};
 
 // Overriding for Borrow
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
@@ -20,7 +22,7 @@ impl<A> Deref for GcRc<A> {
     }
 }
 
-impl Borrow<str> for GcRc<String> {
+impl std::borrow::Borrow<str> for GcRc<String> {
     fn borrow(&self) -> &str { self.deref().as_ref() }
 }
 
@@ -33,15 +35,40 @@ pub struct Chunk {
     code: Code,
     functions: Vec<Rc<Function>>,
     classes: Vec<Rc<Class>>,
+    // Only used by the top level script: the names of all compile-time resolved global slots.
+    global_names: Vec<InternedString>,
+    max_stack: usize,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Copy, Hash)]
 pub struct Upvalue {
     pub index: StackLocation,
     pub is_local: bool,
+    // Captures a copy of the variable's value when the closure is created, instead of the variable
+    // itself.
+    pub by_value: bool,
 }
 
 impl Chunk {
+    /// Reassembles a chunk from its parts, e.g., when loading it from a bytecode file. The maximum
+    /// stack depth is recomputed from the code rather than trusted, since the VM relies on it.
+    pub fn from_parts(
+        code: Code,
+        functions: Vec<Function>,
+        classes: Vec<Class>,
+        global_names: Vec<InternedString>,
+        parameters: usize,
+    ) -> Self {
+        let mut chunk = Chunk {
+            code,
+            functions: functions.into_iter().map(Rc::new).collect(),
+            classes: classes.into_iter().map(Rc::new).collect(),
+            global_names,
+            max_stack: 0,
+        };
+        chunk.compute_max_stack(parameters);
+        chunk
+    }
     pub fn write(&mut self, op: OpCode, line: Line) -> CodeLocation {
         self.code.write(op, line)
     }
@@ -55,7 +82,7 @@ impl Chunk {
         result
     }
     pub fn add_class(&mut self, class: Class, line: Line) -> CodeLocation {
-        let index = self.functions.len();
+        let index = self.classes.len();
         let result = self.write(OpCode::Class(index), line);
         self.classes.push(Rc::new(class));
         result
@@ -64,8 +91,255 @@ impl Chunk {
     pub fn remove(&mut self, i: usize) -> (OpCode, Line) { self.code.remove(i) }
     pub fn pop(&mut self) -> (OpCode, Line) { self.code.pop() }
 
+    pub fn set_global_names(&mut self, names: Vec<InternedString>) { self.global_names = names; }
+
+    /// The maximum depth of the operand stack reached by this chunk's code, relative to its frame,
+    /// i.e., including the function's parameters. Only known once the code is complete, see
+    /// [Chunk::compute_max_stack].
+    pub fn max_stack(&self) -> usize { self.max_stack }
+
+    // Follows every path through the code, since a single pass in emission order would also walk
+    // through dead code, e.g., after a return.
+    pub fn compute_max_stack(&mut self, parameters: usize) {
+        let length = self.code.len();
+        let mut visited = vec![false; length];
+        let mut pending = vec![(0 as CodeLocation, parameters)];
+        let mut result = parameters;
+        while let Some((location, depth)) = pending.pop() {
+            if location >= length || visited[location] {
+                continue;
+            }
+            visited[location] = true;
+            let op = &self.code.get(location).unwrap().0;
+            let (pops, pushes) = op.stack_effect().unwrap_or((0, 0));
+            let next_depth = depth.saturating_sub(pops) + pushes;
+            result = result.max(next_depth);
+            match op {
+                OpCode::Return => (),
+                OpCode::Jump(target) => pending.push((*target, next_depth)),
+                // Malformed code, e.g., loaded from a file, is only rejected later by the verifier.
+                OpCode::Loop(offset) => pending.extend(
+                    location.checked_sub(*offset).map(|target| (target, next_depth))),
+                OpCode::JumpIfFalse(target) =>
+                    pending.extend([(location + 1, next_depth), (*target, next_depth)]),
+                // The exit jump doesn't push the next element.
+                OpCode::IterNext(_, exit) =>
+                    pending.extend([(location + 1, next_depth), (*exit, depth)]),
+                _ => pending.push((location + 1, next_depth)),
+            }
+        }
+        self.max_stack = result;
+    }
+
+    /// Removes unconditional jumps to the very next instruction, e.g., the then-branch's jump over an
+    /// empty else-branch, and retargets all other jumps accordingly. Removing a jump can make an
+    /// earlier one empty as well, so this repeats until there are none left.
+    pub fn remove_empty_jumps(&mut self) {
+        loop {
+            let is_empty = |i: CodeLocation, op: &OpCode| op == &OpCode::Jump(i + 1);
+            // removed_before[i] is the number of removed instructions before location i.
+            let mut removed_before = Vec::with_capacity(self.code.len() + 1);
+            let mut removed = 0;
+            for (i, (op, _)) in self.code.iter().enumerate() {
+                removed_before.push(removed);
+                if is_empty(i, op) {
+                    removed += 1;
+                }
+            }
+            removed_before.push(removed);
+            if removed == 0 {
+                return;
+            }
+            let retarget = |target: &mut CodeLocation| *target -= removed_before[*target];
+            let mut code = Code::default();
+            for (i, (op, line)) in self.code.iter().enumerate() {
+                if is_empty(i, op) {
+                    continue;
+                }
+                let mut op = op.clone();
+                match &mut op {
+                    OpCode::Jump(target) | OpCode::JumpIfFalse(target) | OpCode::IterNext(_, target) =>
+                        retarget(target),
+                    // Both ends of the loop move, so the distance shrinks by what was removed
+                    // between them.
+                    OpCode::Loop(offset) =>
+                        *offset -= removed_before[i] - removed_before[i - *offset],
+                    _ => (),
+                }
+                code.write(op, *line);
+            }
+            self.code = code;
+        }
+    }
+
+    /// Folds constant expressions, e.g., `1 + 2 * 3` becomes a single number, shortcuts jumps which
+    /// land on other jumps, and removes unreachable code, in this script's chunk and in all of its
+    /// functions. Each of these can enable the others, e.g., folding a constant condition leaves
+    /// one of the branches unreachable, so they're repeated until nothing changes.
+    pub fn optimize(&mut self) { self.optimize_with_parameters(0) }
+
+    fn optimize_with_parameters(&mut self, parameters: usize) {
+        for function in self.functions.iter_mut() {
+            let function = Rc::get_mut(function)
+                .expect("Functions shouldn't be shared before their chunk runs");
+            let parameters = function.arity + function.is_method as usize;
+            function.chunk.optimize_with_parameters(parameters);
+        }
+        while self.fold_constants() | self.thread_jumps() | self.remove_dead_code() {}
+        self.remove_empty_jumps();
+        // Folded constants take less space on the stack.
+        self.compute_max_stack(parameters);
+    }
+
+    // A peephole pass: every instruction is appended to the output, and then the output's tail is
+    // simplified for as long as possible, so folded results can be folded again. Only the first
+    // instruction of a simplified sequence may be a jump target, since jumping into its middle
+    // would skip some of the replaced instructions.
+    fn fold_constants(&mut self) -> bool {
+        let targets = self.jump_targets();
+        let mut ops: Vec<OpCode> = Vec::with_capacity(self.code.len());
+        let mut lines: Vec<Line> = Vec::with_capacity(self.code.len());
+        let mut origins: Vec<CodeLocation> = Vec::with_capacity(self.code.len());
+        let mut targeted: Vec<bool> = Vec::with_capacity(self.code.len());
+        let mut new_locations = Vec::with_capacity(self.code.len() + 1);
+        // Set when a targeted sequence was simplified away, so its jumps now land on the next one.
+        let mut pending_target = false;
+        let mut changed = false;
+        for (i, (op, line)) in self.code.iter().enumerate() {
+            new_locations.push(ops.len());
+            ops.push(op.clone());
+            lines.push(*line);
+            origins.push(i);
+            targeted.push(targets[i] || mem::take(&mut pending_target));
+            while let Some((width, replacement)) = simplify_tail(&ops) {
+                let start = ops.len() - width;
+                if targeted[start + 1..].iter().any(|t| *t) {
+                    break;
+                }
+                changed = true;
+                let line = *lines.last().unwrap();
+                let (origin, was_targeted) = (origins[start], targeted[start]);
+                ops.truncate(start);
+                lines.truncate(start);
+                origins.truncate(start);
+                targeted.truncate(start);
+                if replacement.is_empty() {
+                    pending_target |= was_targeted;
+                }
+                for op in replacement {
+                    ops.push(op);
+                    lines.push(line);
+                    origins.push(origin);
+                    targeted.push(was_targeted);
+                }
+            }
+        }
+        new_locations.push(ops.len());
+        if changed {
+            let code = ops.into_iter().zip(lines).zip(origins).map(|((o, l), i)| (o, l, i));
+            self.relocate(code.collect(), &new_locations);
+        }
+        changed
+    }
+
+    // Jumps which land on an unconditional (forward) jump can go straight to its target.
+    fn thread_jumps(&mut self) -> bool {
+        let mut changed = false;
+        for i in 0..self.code.len() {
+            let target = match self.code.get(i).unwrap().0 {
+                OpCode::Jump(target) | OpCode::JumpIfFalse(target) => target,
+                _ => continue,
+            };
+            let mut threaded = target;
+            while let Some((OpCode::Jump(next), _)) = self.code.get(threaded) {
+                if *next <= threaded {
+                    break;
+                }
+                threaded = *next;
+            }
+            if threaded != target {
+                changed = true;
+                match &mut self.code.get_mut(i).unwrap().0 {
+                    OpCode::Jump(target) | OpCode::JumpIfFalse(target) => *target = threaded,
+                    _ => unreachable!(),
+                }
+            }
+        }
+        changed
+    }
+
+    // E.g., code following a return or an unconditional jump, which nothing jumps to.
+    fn remove_dead_code(&mut self) -> bool {
+        let length = self.code.len();
+        let mut reachable = vec![false; length];
+        let mut pending = vec![0 as CodeLocation];
+        while let Some(location) = pending.pop() {
+            if location >= length || reachable[location] {
+                continue;
+            }
+            reachable[location] = true;
+            match &self.code.get(location).unwrap().0 {
+                OpCode::Return => (),
+                OpCode::Jump(target) => pending.push(*target),
+                OpCode::Loop(offset) => pending.push(location - offset),
+                OpCode::JumpIfFalse(target) | OpCode::IterNext(_, target) =>
+                    pending.extend([location + 1, *target]),
+                _ => pending.push(location + 1),
+            }
+        }
+        if reachable.iter().all(|r| *r) {
+            return false;
+        }
+        let mut new_locations = Vec::with_capacity(length + 1);
+        let mut code = Vec::with_capacity(length);
+        for (i, (op, line)) in self.code.iter().enumerate() {
+            new_locations.push(code.len());
+            if reachable[i] {
+                code.push((op.clone(), *line, i));
+            }
+        }
+        new_locations.push(code.len());
+        self.relocate(code, &new_locations);
+        true
+    }
+
+    // Whether each location, including the one past the end, is the target of some jump.
+    fn jump_targets(&self) -> Vec<bool> {
+        let mut result = vec![false; self.code.len() + 1];
+        for (i, (op, _)) in self.code.iter().enumerate() {
+            match op {
+                OpCode::Jump(target) | OpCode::JumpIfFalse(target) | OpCode::IterNext(_, target) =>
+                    result[*target] = true,
+                OpCode::Loop(offset) => result[i - offset] = true,
+                _ => (),
+            }
+        }
+        result
+    }
+
+    // Replaces the code with `code`, whose instructions remember the location they came from.
+    // new_locations[i] is where the instruction that was at location i has moved to, or, if it was
+    // removed, where the instruction that replaced it is. Jump targets are retargeted accordingly.
+    fn relocate(
+        &mut self, code: Vec<(OpCode, Line, CodeLocation)>, new_locations: &[CodeLocation]) {
+        let mut result = Code::default();
+        for (i, (mut op, line, origin)) in code.into_iter().enumerate() {
+            match &mut op {
+                OpCode::Jump(target) | OpCode::JumpIfFalse(target) | OpCode::IterNext(_, target) =>
+                    *target = new_locations[*target],
+                OpCode::Loop(offset) => *offset = i - new_locations[origin - *offset],
+                _ => (),
+            }
+            result.write(op, line);
+        }
+        self.code = result;
+    }
+
+    pub fn global_names(&self) -> &[InternedString] { &self.global_names }
+
     pub fn get_code(&self) -> &Code { &self.code }
     pub fn function_count(&self) -> usize { self.functions.len() }
+    pub fn class_count(&self) -> usize { self.classes.len() }
     pub fn get_function(&self, i: usize) -> Weak<Function> { Rc::downgrade(&self.functions[i]) }
     pub fn get_class(&self, i: usize) -> Weak<Class> { Rc::downgrade(&self.classes[i]) }
 
@@ -78,28 +352,342 @@ impl Chunk {
                 OpCode::DefineGlobal(g) => { g.mark(); }
                 OpCode::GetGlobal(g) => { g.mark(); }
                 OpCode::SetGlobal(g) => { g.mark(); }
+                OpCode::Method(n) => { n.mark(); }
+                OpCode::StaticMethod(n) => { n.mark(); }
                 OpCode::GetProperty(n) => { n.mark(); }
+                OpCode::Invoke(n, _) | OpCode::TailInvoke(n, _) => { n.mark(); }
                 OpCode::SetProperty(n) => { n.mark(); }
-                OpCode::Return | OpCode::Pop | OpCode::PopN(_) | OpCode::Print |
+                OpCode::GetModule(path) => { path.mark(); }
+                OpCode::Module(name, exports) => {
+                    name.mark();
+                    for e in exports.iter() {
+                        e.mark();
+                    }
+                }
+                OpCode::Return | OpCode::Pop | OpCode::PopN(_) | OpCode::Dup | OpCode::Swap | OpCode::Over | OpCode::Print | OpCode::Write |
                 OpCode::Function(..) | OpCode::CloseUpvalue | OpCode::DefineLocal(_) |
                 OpCode::Number(_) | OpCode::Bool(_) | OpCode::GetUpvalue(_) | OpCode::SetUpvalue(_) |
                 OpCode::GetLocal(_) | OpCode::SetLocal(_) | OpCode::Nil | OpCode::Call(_) |
+                OpCode::TailCall(_) |
                 OpCode::Add | OpCode::Subtract | OpCode::Multiply | OpCode::Divide | OpCode::Negate |
-                OpCode::Not | OpCode::Equals | OpCode::Less | OpCode::Greater |
+                OpCode::Not | OpCode::ToString | OpCode::Equals | OpCode::Less | OpCode::Greater |
                 OpCode::UnpatchedJump | OpCode::Jump(_) | OpCode::JumpIfFalse(_) |
-                OpCode::Class(..) => ()
+                OpCode::Loop(_) | OpCode::Class(..) | OpCode::GetGlobalByIndex(_) | OpCode::SetGlobalByIndex(_) |
+                OpCode::Range(_) | OpCode::IterNext(..) | OpCode::MakeList(_) |
+                OpCode::MakeTable(_) | OpCode::GetIndex | OpCode::SetIndex | OpCode::Iterate => ()
             }
         }
+        for name in self.global_names.iter() {
+            name.mark();
+        }
         for f in self.functions.iter() {
             f.name.mark();
             f.chunk.mark();
         }
+        // Classes live as long as their chunk, and so do their methods.
+        for c in self.classes.iter() {
+            c.mark();
+        }
+    }
+}
+
+// If the last instructions can be simplified, returns how many of them, and what to replace them
+// with. Only numbers and booleans are folded, since strings would have to be interned.
+fn simplify_tail(ops: &[OpCode]) -> Option<(usize, Vec<OpCode>)> {
+    for width in 2..=3 {
+        let tail = ops.len().checked_sub(width).and_then(|start| ops[start..].split_last());
+        if let Some(folded) = tail.and_then(|(op, operands)| fold_literals(operands, op)) {
+            return Some((width, vec![folded]));
+        }
+    }
+    Some(match ops {
+        // A double negation only converts its operand to a boolean, so it can be dropped if the
+        // operand is already one, or if only its truthiness matters anyway.
+        [.., OpCode::Not, OpCode::Not, OpCode::JumpIfFalse(target)] =>
+            (3, vec![OpCode::JumpIfFalse(*target)]),
+        [.., op @ (OpCode::Bool(_) | OpCode::Not | OpCode::Equals | OpCode::Less | OpCode::Greater),
+        OpCode::Not, OpCode::Not] => (3, vec![op.clone()]),
+        [.., OpCode::Bool(true) | OpCode::Number(_) | OpCode::String(_), OpCode::JumpIfFalse(_)] =>
+            (2, vec![]),
+        [.., OpCode::Bool(false) | OpCode::Nil, OpCode::JumpIfFalse(target)] =>
+            (2, vec![OpCode::Jump(*target)]),
+        _ => return None,
+    })
+}
+
+/// Evaluates `op` on its `operands` the same way the VM would, if they're all literals, e.g.,
+/// `Number(1), Number(2)` and `Add` fold into `Number(3)`, and a literal without operands is
+/// itself. None for anything else, including operations which would fail at runtime, e.g.,
+/// negating nil, so the error is still reported when the code runs.
+pub fn fold_literals(operands: &[OpCode], op: &OpCode) -> Option<OpCode> {
+    let is_literal = |op: &OpCode|
+        matches!(op, OpCode::Number(_) | OpCode::Bool(_) | OpCode::Nil | OpCode::String(_));
+    Some(match (operands, op) {
+        ([], literal) if is_literal(literal) => literal.clone(),
+        // Same as the VM's negation.
+        ([OpCode::Number(n)], OpCode::Negate) => OpCode::Number(n * -1.0),
+        ([operand], OpCode::Not) if is_literal(operand) =>
+            OpCode::Bool(matches!(operand, OpCode::Nil | OpCode::Bool(false))),
+        // Literals of different types are never equal, and strings are interned, so comparing the
+        // literals is the same as comparing their values.
+        ([lhs, rhs], OpCode::Equals) if is_literal(lhs) && is_literal(rhs) =>
+            OpCode::Bool(lhs == rhs),
+        ([OpCode::Number(lhs), OpCode::Number(rhs)], op) => match op {
+            OpCode::Add => OpCode::Number(lhs + rhs),
+            OpCode::Subtract => OpCode::Number(lhs - rhs),
+            OpCode::Multiply => OpCode::Number(lhs * rhs),
+            OpCode::Divide => OpCode::Number(lhs / rhs),
+            OpCode::Greater => OpCode::Bool(lhs > rhs),
+            OpCode::Less => OpCode::Bool(lhs < rhs),
+            _ => return None,
+        },
+        _ => return None,
+    })
+}
+
+/// Renders the code one instruction per line, followed by the code of the chunk's functions. The
+/// source line is only shown when it changes.
+#[cfg(test)]
+pub fn disassemble(chunk: &Chunk) -> Vec<String> {
+    let mut previous_line: Line = 0;
+    let mut is_first = true;
+    let mut result = Vec::new();
+    for (i, (_, line)) in chunk.get_code().iter().enumerate() {
+        let prefix = format!(
+            "{:0>2}: {:>2}",
+            i,
+            if !is_first && line == &previous_line {
+                " |".to_owned()
+            } else {
+                line.to_string()
+            },
+        );
+        result.push(format!("{} {}", prefix, disassemble_instruction(chunk, i)));
+        previous_line = *line;
+        is_first = false;
     }
+    for i in 0..chunk.function_count() {
+        let temp = chunk.get_function(i).upgrade().unwrap();
+        let name = &temp.name;
+        result.push(format_interned!("<fun {}>", name));
+        result.append(&mut disassemble(&temp.chunk));
+        result.push(format_interned!("<end {}>", name));
+    }
+    result
 }
 
+/// The instruction at location `i` with its operands, e.g., `GET_LOCAL      1`.
+pub fn disassemble_instruction(chunk: &Chunk, i: CodeLocation) -> String {
+    let (op, line) = chunk.get_code().get(i).unwrap();
+    format!("{}{}", op.to_upper_snake(), match op {
+        OpCode::Number(num) => format!("{}", num),
+        OpCode::PopN(num) => format!("{}", num),
+        OpCode::MakeList(num) => format!("{}", num),
+        OpCode::MakeTable(num) => format!("{}", num),
+        OpCode::UnpatchedJump =>
+            panic!("Jump should have been patched at line: '{}'", line),
+        OpCode::JumpIfFalse(index) => format!("{}", index),
+        OpCode::Range(inclusive) => (if *inclusive { "..=" } else { ".." }).to_owned(),
+        OpCode::IterNext(local, exit) => format!("{} {}", local, exit),
+        OpCode::Jump(index) => format!("{}", index),
+        OpCode::Loop(offset) => format!("{} (to {})", offset, i - offset),
+        OpCode::Function(i) => {
+            let function = chunk.get_function(*i).upgrade().unwrap();
+            let name = function.name.clone();
+            let upvalues = &function.upvalues;
+            format!(
+                "{} [{}]",
+                name.to_owned(),
+                upvalues.iter()
+                    .map(|e| format!(
+                        "({},{}{})",
+                        e.index,
+                        if e.is_local { "t" } else { "f" },
+                        if e.by_value { ",v" } else { "" },
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(","),
+            )
+        }
+        OpCode::Class(i) => chunk.get_class(*i).upgrade().unwrap().name.to_owned(),
+        OpCode::Method(s) => s.to_owned(),
+        OpCode::StaticMethod(s) => s.to_owned(),
+        OpCode::GetProperty(s) => s.to_owned(),
+        OpCode::GetModule(s) => s.to_owned(),
+        OpCode::Module(name, exports) => format!(
+            "{} [{}]",
+            name.to_owned(),
+            exports.iter().map(|e| e.to_owned()).intersperse(", ".to_owned()).collect::<String>(),
+        ),
+        OpCode::SetProperty(s) => s.to_owned(),
+        OpCode::DefineGlobal(name) => format_interned!("'{}'", name),
+        OpCode::GetGlobal(name) => format_interned!("'{}'", name),
+        OpCode::SetGlobal(name) => format_interned!("'{}'", name),
+        OpCode::GetGlobalByIndex(index) => format!("{}", index),
+        OpCode::SetGlobalByIndex(index) => format!("{}", index),
+        OpCode::GetUpvalue(index) => format!("'{}'", index),
+        OpCode::SetUpvalue(index) => format!("'{}'", index),
+        OpCode::DefineLocal(index) => format!("{}", index),
+        OpCode::GetLocal(index) => format!("{}", index),
+        OpCode::SetLocal(index) => format!("{}", index),
+        OpCode::Bool(bool) => format!("{}", bool),
+        OpCode::String(s) => format_interned!("'{}'", s),
+        OpCode::Call(arg_count) | OpCode::TailCall(arg_count) => format!("{}", arg_count),
+        OpCode::Invoke(s, arg_count) | OpCode::TailInvoke(s, arg_count) =>
+            format!("{} {}", s.to_owned(), arg_count),
+        OpCode::Greater | OpCode::Less | OpCode::Add | OpCode::Subtract | OpCode::Multiply |
+        OpCode::Pop | OpCode::Dup | OpCode::Swap | OpCode::Over | OpCode::Print | OpCode::Write | OpCode::Nil | OpCode::Equals | OpCode::Divide |
+        OpCode::Negate | OpCode::Not | OpCode::ToString | OpCode::CloseUpvalue | OpCode::GetIndex | OpCode::SetIndex |
+        OpCode::Iterate | OpCode::Return =>
+            "".to_owned(),
+    })
+}
+
+// Function and class operands are indices into the chunk's own pools, so they're compared by what
+// they refer to, rather than by the raw index. This way, reordering a pool doesn't break equality.
 impl DeepEq for Chunk {
     fn deep_eq(&self, other: &Self) -> bool {
-        self.code.deep_eq(&other.code) &&
-            self.functions.deep_eq(&other.functions)
+        fn pooled<A: DeepEq>(pool: &[A], i: usize, other_pool: &[A], j: usize) -> bool {
+            match (pool.get(i), other_pool.get(j)) {
+                (Some(a), Some(b)) => a.deep_eq(b),
+                (None, None) => i == j,
+                _ => false,
+            }
+        }
+        let resolved_deep_eq = |op: &OpCode, other_op: &OpCode| match (op, other_op) {
+            (OpCode::Function(i), OpCode::Function(j)) =>
+                pooled(&self.functions, *i, &other.functions, *j),
+            (OpCode::Class(i), OpCode::Class(j)) => pooled(&self.classes, *i, &other.classes, *j),
+            _ => op.deep_eq(other_op),
+        };
+        self.code.len() == other.code.len() &&
+            self.code.iter().zip(other.code.iter()).all(|((op1, line1), (op2, line2))|
+                line1 == line2 && resolved_deep_eq(op1, op2)
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assert_deep_eq;
+    use crate::rslox::compiled::memory::Managed;
+
+    use super::*;
+
+    fn function(name: &Managed<String>, arity: usize) -> Rc<Function> {
+        Rc::new(Function {
+            name: name.ptr(), arity, chunk: Chunk::default(), upvalues: vec![], is_method: false,
+        })
+    }
+
+    fn chunk(functions: Vec<Rc<Function>>, indices: Vec<usize>) -> Chunk {
+        let mut result = Chunk::default();
+        for i in indices {
+            result.write(OpCode::Function(i), 1);
+        }
+        result.functions = functions;
+        result
+    }
+
+    #[test]
+    fn removing_empty_jumps_retargets_the_others() {
+        let mut chunk = Chunk::default();
+        chunk.write(OpCode::Bool(true), 1);
+        chunk.write(OpCode::JumpIfFalse(5), 1);
+        // Only empty once the jump after it is removed.
+        chunk.write(OpCode::Jump(4), 2);
+        chunk.write(OpCode::Jump(4), 2);
+        chunk.write(OpCode::Nil, 3);
+        chunk.write(OpCode::Jump(0), 3);
+        chunk.remove_empty_jumps();
+        let code: Vec<OpCode> = chunk.get_code().iter().map(|(op, _)| op.clone()).collect();
+        assert_eq!(
+            code,
+            vec![OpCode::Bool(true), OpCode::JumpIfFalse(3), OpCode::Nil, OpCode::Jump(0)],
+        );
+    }
+
+    fn ops(chunk: &Chunk) -> Vec<OpCode> {
+        chunk.get_code().iter().map(|(op, _)| op.clone()).collect()
+    }
+
+    #[test]
+    fn optimizing_threads_jumps_and_removes_dead_code() {
+        let mut chunk = Chunk::default();
+        chunk.write(OpCode::GetGlobalByIndex(0), 1);
+        chunk.write(OpCode::JumpIfFalse(4), 1);
+        chunk.write(OpCode::Nil, 2);
+        chunk.write(OpCode::Print, 2);
+        chunk.write(OpCode::Jump(6), 2);
+        chunk.write(OpCode::Nil, 3);
+        chunk.write(OpCode::Nil, 4);
+        chunk.write(OpCode::Print, 4);
+        chunk.optimize();
+        assert_eq!(
+            ops(&chunk),
+            vec![
+                OpCode::GetGlobalByIndex(0),
+                OpCode::JumpIfFalse(4),
+                OpCode::Nil,
+                OpCode::Print,
+                OpCode::Nil,
+                OpCode::Print,
+            ],
+        );
+    }
+
+    #[test]
+    fn constants_are_not_folded_across_jump_targets() {
+        let mut chunk = Chunk::default();
+        chunk.write(OpCode::GetGlobalByIndex(0), 1);
+        chunk.write(OpCode::JumpIfFalse(4), 1);
+        chunk.write(OpCode::Number(1.0), 1);
+        chunk.write(OpCode::Jump(5), 1);
+        chunk.write(OpCode::Number(2.0), 1);
+        chunk.write(OpCode::Number(3.0), 1);
+        chunk.write(OpCode::Add, 1);
+        chunk.write(OpCode::Print, 1);
+        let expected = ops(&chunk);
+        chunk.optimize();
+        assert_eq!(ops(&chunk), expected);
+    }
+
+    #[test]
+    fn folding_literals() {
+        let fold = |operands: &[OpCode], op| fold_literals(operands, &op);
+        assert_eq!(
+            fold(&[OpCode::Number(1.0), OpCode::Number(2.0)], OpCode::Add),
+            Some(OpCode::Number(3.0)),
+        );
+        assert_eq!(fold(&[OpCode::Nil, OpCode::Nil], OpCode::Equals), Some(OpCode::Bool(true)));
+        assert_eq!(
+            fold(&[OpCode::Number(0.0), OpCode::Bool(false)], OpCode::Equals),
+            Some(OpCode::Bool(false)),
+        );
+        assert_eq!(fold(&[OpCode::Nil], OpCode::Not), Some(OpCode::Bool(true)));
+        assert_eq!(fold(&[OpCode::Number(0.0)], OpCode::Not), Some(OpCode::Bool(false)));
+        assert_eq!(fold(&[], OpCode::Nil), Some(OpCode::Nil));
+        // Fails at runtime, so it's left for the VM to report.
+        assert_eq!(fold(&[OpCode::Nil], OpCode::Negate), None);
+        assert_eq!(fold(&[OpCode::Number(1.0)], OpCode::Add), None);
+        assert_eq!(fold(&[], OpCode::GetGlobalByIndex(0)), None);
+    }
+
+    #[test]
+    fn permuted_function_pools_are_deep_equal() {
+        let foo = Managed::new("foo".to_owned());
+        let bar = Managed::new("bar".to_owned());
+        assert_deep_eq!(
+            chunk(vec![function(&foo, 0), function(&bar, 1)], vec![0, 1]),
+            chunk(vec![function(&bar, 1), function(&foo, 0)], vec![1, 0]),
+        );
+    }
+
+    #[test]
+    fn same_indices_into_permuted_pools_are_not_deep_equal() {
+        let foo = Managed::new("foo".to_owned());
+        let bar = Managed::new("bar".to_owned());
+        assert!(!chunk(vec![function(&foo, 0), function(&bar, 1)], vec![0, 1])
+            .deep_eq(&chunk(vec![function(&bar, 1), function(&foo, 0)], vec![0, 1])));
     }
 }
@@ -1,46 +1,145 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fs::{canonicalize, read_to_string};
 use std::mem;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
+use either::Either;
 use either::Either::{Left, Right};
 use nonempty::NonEmpty;
 use num_traits::FromPrimitive;
 
 use crate::format_interned;
-use crate::rslox::common::error::{convert_errors, LoxResult, ParserError};
-use crate::rslox::common::lexer::{Token, TokenType};
-use crate::rslox::compiled::chunk::{Chunk, Upvalue};
+use crate::rslox::common::error::{convert_errors, Diagnostic, LoxResult, ParserError, Severity};
+use crate::rslox::common::lexer::{Token, tokenize, TokenType};
+use crate::rslox::compiled::chunk::{Chunk, fold_literals, Upvalue};
 use crate::rslox::compiled::code::Line;
-use crate::rslox::compiled::memory::{InternedString, Managed, Pointer};
-use crate::rslox::compiled::op_code::{ArgCount, CodeLocation, OpCode, StackLocation};
-use crate::rslox::compiled::value::{Class, Function};
+use crate::rslox::compiled::memory::{InternedString, Managed, managed_size, Pointer};
+use crate::rslox::compiled::op_code::{ArgCount, CodeLocation, GlobalIndex, OpCode, StackLocation};
+use crate::rslox::compiled::value::{Class, Function, INITIALIZER};
 
 type CompilerError = ParserError;
+type CompilerResult = Result<(Chunk, InternedStrings), NonEmpty<CompilerError>>;
 
 impl From<CompilerError> for NonEmpty<CompilerError> {
     fn from(value: CompilerError) -> Self { NonEmpty::new(value) }
 }
 
+#[cfg(test)]
 pub fn compile(tokens: Vec<Token>) -> LoxResult<(Chunk, InternedStrings)> {
-    convert_errors(Compiler::new(tokens).compile())
+    compile_in_mode(tokens, CompileMode::Script)
+}
+
+#[cfg(test)]
+pub fn compile_in_mode(
+    tokens: Vec<Token>, mode: CompileMode,
+) -> LoxResult<(Chunk, InternedStrings)> {
+    let mut compiler = Compiler::new(tokens);
+    compiler.mode = mode;
+    convert_errors(compiler.compile_with_warnings().0)
+}
+
+/// Like [compile], followed by [Chunk::optimize].
+#[cfg(test)]
+pub fn compile_optimized(tokens: Vec<Token>) -> LoxResult<(Chunk, InternedStrings)> {
+    let mut compiler = Compiler::new(tokens);
+    compiler.optimize = true;
+    convert_errors(compiler.compile_with_warnings().0)
+}
+
+/// Compiles a script, along with its warnings. Modules imported by name, e.g., `import util;`,
+/// which aren't next to their importer are looked up in the `search_path` directories, in order.
+/// With `optimize`, the code is also optimized, see [Chunk::optimize].
+pub fn compile_with_search_path(
+    tokens: Vec<Token>, search_path: Vec<PathBuf>, optimize: bool,
+) -> LoxResult<(Chunk, InternedStrings, Vec<Diagnostic>)> {
+    let mut compiler = Compiler::new(tokens);
+    compiler.modules.search_path = search_path;
+    compiler.optimize = optimize;
+    let (result, warnings) = compiler.compile_with_warnings();
+    convert_errors(result.map(|(chunk, strings)| (chunk, strings, to_warnings(&warnings))))
+}
+
+/// Compiles one input of a REPL session, see [CompileMode::Repl]. The input shares the strings and
+/// globals of the previous ones: `global_names` are the slots of the VM which ran them, so globals
+/// it already knows about can be accessed by index. Also returns the input's warnings.
+pub fn compile_repl_input(
+    tokens: Vec<Token>, global_names: &[InternedString], interned_strings: &mut InternedStrings,
+) -> LoxResult<(Chunk, Vec<Diagnostic>)> {
+    let mut compiler = Compiler::new(tokens);
+    compiler.mode = CompileMode::Repl;
+    compiler.interned_strings = mem::take(interned_strings);
+    for name in global_names {
+        compiler.globals.insert(name.clone(), compiler.global_names.len());
+        compiler.global_names.push(name.clone());
+    }
+    let result = compiler.compile_script();
+    *interned_strings = mem::take(&mut compiler.interned_strings);
+    convert_errors(result.map(|chunk| (chunk, to_warnings(&compiler.warnings))))
+}
+
+/// Compiles only for the sake of reporting errors and warnings, e.g., for editor integrations.
+pub fn diagnostics(tokens: Vec<Token>) -> Vec<Diagnostic> {
+    let (result, warnings) = Compiler::new(tokens).compile_with_warnings();
+    let errors = match result {
+        Ok(_) => Vec::new(),
+        Err(errors) => errors.iter().map(|e| e.to_diagnostic()).collect(),
+    };
+    errors.into_iter().chain(to_warnings(&warnings)).collect()
+}
+
+fn to_warnings(warnings: &[CompilerError]) -> Vec<Diagnostic> {
+    warnings.iter()
+        .map(|w| Diagnostic { severity: Severity::Warning, ..w.to_diagnostic() })
+        .collect()
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum CompileMode {
+    #[default]
+    Script,
+    /// The value of a final top-level expression statement is left on the stack, so the REPL can
+    /// print it. The statement's semicolon is optional.
+    Repl,
 }
 
 #[derive(Debug, Default)]
 pub struct InternedStrings {
     strings: HashSet<Managed<String>>,
+    // Kept up to date, since the VM checks it far more often than strings are interned.
+    allocated_bytes: usize,
 }
 
 impl InternedStrings {
     pub fn intern_string(&mut self, str: String) -> InternedString {
         let managed = Managed::new(str);
+        if let Some(existing) = self.strings.get(&managed) {
+            return existing.ptr();
+        }
+        self.allocated_bytes += InternedStrings::size(&managed);
         self.strings.get_or_insert(managed).ptr()
     }
 
     pub fn sweep(&mut self) {
-        self.strings.retain(|s| s.get_and_reset_mark())
+        let mut freed = 0;
+        self.strings.retain(|s| {
+            let is_used = s.get_and_reset_mark();
+            if !is_used {
+                freed += InternedStrings::size(s);
+            }
+            is_used
+        });
+        self.allocated_bytes -= freed;
     }
 
     #[allow(dead_code)]
     pub fn len(&self) -> usize { self.strings.len() }
+
+    pub fn allocated_bytes(&self) -> usize { self.allocated_bytes }
+
+    fn size(string: &Managed<String>) -> usize {
+        managed_size::<String>() + string.as_ref().capacity()
+    }
 }
 
 
@@ -56,6 +155,34 @@ struct Compiler {
     depth: Depth,
     current: TokenPointer,
     interned_strings: InternedStrings,
+    // Globals declared so far, which can therefore be accessed by index rather than by name.
+    globals: HashMap<InternedString, GlobalIndex>,
+    global_names: Vec<InternedString>,
+    // The classes currently being compiled, innermost last.
+    classes: Vec<ClassContext>,
+    // The file being compiled, if any; imports are resolved relative to it.
+    path: Option<PathBuf>,
+    modules: Modules,
+    mode: CompileMode,
+    optimize: bool,
+    warnings: Vec<CompilerError>,
+    // Assignments to globals which weren't declared yet when they were compiled. Only those which
+    // are never declared at all are warned about, since functions can refer to later globals.
+    implicit_globals: Vec<(InternedString, CompilerError)>,
+}
+
+// Modules are compiled into the importing chunk, as a function which is called once, at the point
+// of import, with the module's own globals. Each module is only ever compiled once, and later
+// imports of the same file only bind it again. The module is bound to its alias, e.g.,
+// import "foo.lox" as bar; binds bar, or else to its name, i.e., the file name without its
+// extension, and its top-level declarations are accessed through it, e.g., bar.baz. Modules
+// imported by name (import foo;) are looked up next to their importer, and then in the search path.
+#[derive(Debug, Default)]
+struct Modules {
+    imported: HashSet<PathBuf>,
+    // Used for cycle detection.
+    importing: Vec<PathBuf>,
+    search_path: Vec<PathBuf>,
 }
 
 impl Compiler {
@@ -67,18 +194,41 @@ impl Compiler {
             depth: 0,
             current: 0,
             interned_strings: Default::default(),
+            globals: HashMap::new(),
+            global_names: Vec::new(),
+            classes: Vec::new(),
+            path: None,
+            modules: Default::default(),
+            mode: CompileMode::Script,
+            optimize: false,
+            warnings: Vec::new(),
+            implicit_globals: Vec::new(),
         }
     }
 
-    pub fn compile(mut self) -> Result<(Chunk, InternedStrings), NonEmpty<CompilerError>> {
+    pub fn compile_with_warnings(mut self) -> (CompilerResult, Vec<CompilerError>) {
+        let result = self.compile_script();
+        let interned_strings = self.interned_strings;
+        (result.map(|chunk| (chunk, interned_strings)), self.warnings)
+    }
+
+    fn compile_script(&mut self) -> Result<Chunk, NonEmpty<CompilerError>> {
         let mut errors = Vec::new();
         while !self.is_at_end() {
             self.declaration(&mut errors);
         }
+        self.warn_on_implicit_globals();
         match NonEmpty::from_vec(errors) {
             None => {
                 assert_eq!(self.frames.len(), 1);
-                Ok((self.frames.head.chunk, self.interned_strings))
+                let mut chunk = mem::take(&mut self.frames.head.chunk);
+                chunk.set_global_names(mem::take(&mut self.global_names));
+                chunk.remove_empty_jumps();
+                chunk.compute_max_stack(0);
+                if self.optimize {
+                    chunk.optimize();
+                }
+                Ok(chunk)
             }
             Some(errs) => Err(errs),
         }
@@ -116,7 +266,8 @@ impl Compiler {
         while !self.is_at_end() && !self.matches(TokenType::Semicolon).is_some() {
             let should_continue = match self.peek_type() {
                 TokenType::Class | TokenType::Fun | TokenType::Var | TokenType::For | TokenType::If
-                | TokenType::While | TokenType::Print | TokenType::Return => false,
+                | TokenType::While | TokenType::Print | TokenType::Write | TokenType::Return
+                | TokenType::Import | TokenType::Break | TokenType::Continue => false,
                 _ => true,
             };
             if should_continue { self.advance(); } else { return; }
@@ -155,6 +306,26 @@ impl Compiler {
                     None
                 }
             }
+        } else if self.is_global_declaration() {
+            match self.declare_explicit_global() {
+                Ok(line) => Some(line),
+                Err(e) => {
+                    errors.push(e);
+                    self.synchronize();
+                    None
+                }
+            }
+        } else if let Some(line) = self.matches(TokenType::Import) {
+            match self.import_module(line) {
+                Ok(l) => Some(l),
+                Err(errs) => {
+                    for err in errs {
+                        errors.push(err);
+                    }
+                    self.synchronize();
+                    None
+                }
+            }
         } else {
             match self.statement() {
                 Ok(l) => Some(l),
@@ -174,8 +345,18 @@ impl Compiler {
             self.compile_expression()?;
             self.write(OpCode::Print, line);
             line
+        } else if let Some(line) = self.matches(TokenType::Write) {
+            self.compile_expression()?;
+            self.write(OpCode::Write, line);
+            line
         } else if let Some(line) = self.matches(TokenType::Return) {
             return self.return_stmt(line).map_err(|e| e.into());
+        } else if let Some(line) = self.matches(TokenType::Break) {
+            self.break_stmt(line)?;
+            line
+        } else if let Some(line) = self.matches(TokenType::Continue) {
+            self.continue_stmt(line)?;
+            line
         } else if let Some(line) = self.matches(TokenType::If) {
             return self.if_stmt(line); // Skips semicolon
         } else if let Some(line) = self.matches(TokenType::While) {
@@ -185,23 +366,181 @@ impl Compiler {
         } else if let Some(_) = self.matches(TokenType::OpenBrace) {
             return self.block();
         } else {
-            self.expression_statement()?
+            let line = self.compile_expression()?;
+            if self.is_repl_result() {
+                self.matches(TokenType::Semicolon);
+                return Ok(line);
+            }
+            self.write(OpCode::Pop, line);
+            line
         };
         self.consume(TokenType::Semicolon, None)?;
         Ok(line)
     }
 
+    // Whether the expression statement just compiled is the last top-level one of a REPL input.
+    fn is_repl_result(&self) -> bool {
+        self.mode == CompileMode::Repl
+            && self.frames.len() == 1
+            && self.depth == 0
+            && (self.is_at_end()
+            || self.peek_type() == &TokenType::Semicolon && self.current + 1 == self.tokens.len())
+    }
+
     fn return_stmt(&mut self, line: Line) -> Result<Line, CompilerError> {
+        if self.active_frame().kind == FunctionKind::Script {
+            return Err(CompilerError::new(
+                "Can't return from top-level code.", Token::new(line, TokenType::Return)));
+        }
+        let is_initializer = self.active_frame().kind == FunctionKind::Initializer;
         if let Some(line) = self.matches(TokenType::Semicolon) {
-            self.write(OpCode::Nil, line);
+            // Initializers always return their instance.
+            self.write(if is_initializer { OpCode::GetLocal(0) } else { OpCode::Nil }, line);
+        } else if is_initializer {
+            return Err(CompilerError::new(
+                "Can't return a value from an initializer.", Token::new(line, TokenType::Return)));
         } else {
             self.compile_expression()?;
+            self.consume(TokenType::Semicolon, None)?;
+            // Even when the call is only one of the expression's branches, e.g., `a or f()`, the
+            // other branches jump past it, straight to the Return.
+            let chunk = self.active_chunk_mut();
+            let (op, _) = chunk.get_mut(chunk.get_code().len() - 1).unwrap();
+            match op {
+                OpCode::Call(arg_count) => *op = OpCode::TailCall(*arg_count),
+                OpCode::Invoke(name, arg_count) =>
+                    *op = OpCode::TailInvoke(name.clone(), *arg_count),
+                _ => (),
+            }
         }
-        self.consume(TokenType::Semicolon, None)?;
         self.write(OpCode::Return, line);
         Ok(line)
     }
 
+    fn import_module(&mut self, line: Line) -> Result<Line, NonEmpty<CompilerError>> {
+        let import_token = Token::new(line, TokenType::Import);
+        let error = |msg: String| CompilerError::new(msg, import_token.clone());
+        let (relative_path, name) = match self.advance().r#type {
+            TokenType::StringLiteral(path) => Ok((path, None)),
+            TokenType::Identifier(name) => Ok((format!("{}.lox", name), Some(name))),
+            e => Err(error(format!("Expected a module path or name after import, got '{:?}'", e))),
+        }?;
+        // "as" is only a keyword in this context.
+        let alias = if self.peek_type() == &TokenType::identifier("as") {
+            self.advance();
+            Some(self.parse_variable()?.0)
+        } else {
+            None
+        };
+        self.consume(TokenType::Semicolon, None)?;
+        if self.depth > 0 || self.frames.len() > 1 {
+            return Err(error("Can only import from top-level code.".to_owned()).into());
+        }
+        let alias = match alias {
+            Some(alias) => alias,
+            None => {
+                let default_name = name.clone().unwrap_or_else(|| Path::new(&relative_path)
+                    .file_stem().unwrap_or_default().to_string_lossy().into_owned());
+                let is_identifier = matches!(
+                    tokenize(&default_name).as_deref(),
+                    Ok([Token { r#type: TokenType::Identifier(_), .. }]),
+                );
+                if !is_identifier {
+                    return Err(error(format!(
+                        "Module '{}' needs an alias, e.g., import \"{}\" as name;",
+                        relative_path, relative_path,
+                    )).into());
+                }
+                let interned = self.intern_string(default_name);
+                self.declare_global(&interned);
+                interned
+            }
+        };
+        let next_to_importer = self.path.as_ref()
+            .and_then(|p| p.parent())
+            .map(|dir| dir.join(&relative_path))
+            .unwrap_or_else(|| PathBuf::from(&relative_path));
+        let path = match (canonicalize(&next_to_importer), name) {
+            (Ok(path), _) => path,
+            (Err(e), None) =>
+                return Err(error(format!("Can't open module '{}': {}", relative_path, e)).into()),
+            // Only modules imported by name are looked up in the search path.
+            (Err(_), Some(name)) => self.modules.search_path.iter()
+                .find_map(|dir| canonicalize(dir.join(&relative_path)).ok())
+                .ok_or_else(|| error(format!(
+                    "Can't find module '{}' next to its importer or in the search path", name)))?,
+        };
+        let key = self.intern_string(path.to_string_lossy().into_owned());
+        if !self.modules.imported.contains(&path) {
+            if self.modules.importing.contains(&path) {
+                return Err(error(format!("Circular import of '{}'", relative_path)).into());
+            }
+            self.compile_module(path, key.clone(), &relative_path, line)?;
+        }
+        self.write(OpCode::GetModule(key), line);
+        self.define_variable(alias, line)?;
+        Ok(line)
+    }
+
+    fn compile_module(
+        &mut self, path: PathBuf, key: InternedString, relative_path: &str, line: Line,
+    ) -> Result<(), NonEmpty<CompilerError>> {
+        let module_error = |line: Line, msg: String| CompilerError::new(
+            format!("Error in module '{}' at line {}: {}", relative_path, line, msg),
+            Token::new(line, TokenType::Import),
+        );
+        let source = read_to_string(&path)
+            .map_err(|e| module_error(line, format!("Can't open module: {}", e)))?;
+        let tokens = tokenize(&source).map_err(
+            |errs| errs.map(|e| module_error(e.get_info().line, e.get_message())))?;
+
+        let mut module = Compiler::new(tokens);
+        module.path = Some(path.clone());
+        self.share_state(&mut module);
+        module.modules.importing.push(path.clone());
+        let mut errors = Vec::new();
+        while !module.is_at_end() {
+            module.declaration(&mut errors);
+        }
+        module.warn_on_implicit_globals();
+        module.modules.importing.pop();
+        module.share_state(self);
+        match NonEmpty::from_vec(errors) {
+            None => (),
+            Some(errs) => return Err(errs.map(|e| module_error(e.token.line, e.message))),
+        }
+        for warning in mem::take(&mut module.warnings) {
+            self.warnings.push(CompilerError::new(
+                format!("In module '{}' at line {}: {}", relative_path, warning.token.line, warning.message),
+                Token::new(line, TokenType::Import),
+            ));
+        }
+        self.modules.imported.insert(path);
+
+        let (chunk, upvalues) = module.frames.head.finish(line, 0);
+        let name = self.intern_string(relative_path.to_owned());
+        let function = Function { name, arity: 0, chunk, upvalues, is_method: false };
+        self.active_chunk_mut().add_function(function, line);
+        self.write(OpCode::Module(key, Rc::new(module.global_names)), line);
+        self.write(OpCode::Call(0), line);
+        self.write(OpCode::Pop, line);
+        Ok(())
+    }
+
+    fn warn_on_implicit_globals(&mut self) {
+        for (name, warning) in mem::take(&mut self.implicit_globals) {
+            if !self.globals.contains_key(&name) {
+                self.warnings.push(warning);
+            }
+        }
+    }
+
+    // Moves all the state that has to outlive a single module to other.
+    fn share_state(&mut self, other: &mut Compiler) {
+        other.interned_strings = mem::take(&mut self.interned_strings);
+        other.modules = mem::take(&mut self.modules);
+    }
+
     fn block(&mut self) -> Result<Line, NonEmpty<CompilerError>> {
         self.begin_scope();
         let ending_line = self.multi_statements()?;
@@ -221,35 +560,124 @@ impl Compiler {
         }
     }
 
+    // `else if` chains are compiled in a loop rather than recursively, so arbitrarily long chains
+    // don't grow the compiler's stack. Every arm which can fall through jumps straight to the end
+    // of the entire chain.
     fn if_stmt(&mut self, line: Line) -> Result<Line, NonEmpty<CompilerError>> {
-        self.consume(TokenType::OpenParen, None)?;
-        self.compile_expression()?;
-        self.consume(TokenType::CloseParen, None)?;
-        let jump_pos = self.jumping_body(line, 0 as JumpOffset, OpCode::JumpIfFalse)?;
-        if let Some(line) = self.matches(TokenType::Else) {
-            self.jumping_body(line, 0 as JumpOffset, OpCode::Jump)?;
-            // Since we added a Jump, we need to fix the JumpIfFalse target.
-            let current_jump = self.active_chunk().get_code().get(jump_pos).unwrap().0.clone();
-            (*self.active_chunk_mut().get_mut(jump_pos).unwrap()).0 = match current_jump {
-                OpCode::JumpIfFalse(to) => OpCode::JumpIfFalse(to + 1),
-                e => panic!("Expected JumpIfFalse, was {:?}", e),
+        let mut end_jumps = Vec::new();
+        let mut arm_line = line;
+        loop {
+            self.consume(TokenType::OpenParen, None)?;
+            self.compile_expression()?;
+            self.consume(TokenType::CloseParen, None)?;
+            let jump_pos = self.write(OpCode::UnpatchedJump, arm_line);
+            self.statement()?;
+            // Checked before patching, since the patched jump would target the next instruction.
+            let then_returns = !self.active_frame().can_fall_through();
+            let Some(else_line) = self.matches(TokenType::Else) else {
+                self.active_frame_mut().patch_jump(jump_pos, 0 as JumpOffset, OpCode::JumpIfFalse);
+                break;
             };
+            // There's no need to jump over the rest of the chain if the then branch never ends.
+            if !then_returns {
+                end_jumps.push(self.write(OpCode::UnpatchedJump, else_line));
+            }
+            self.active_frame_mut().patch_jump(jump_pos, 0 as JumpOffset, OpCode::JumpIfFalse);
+            match self.matches(TokenType::If) {
+                Some(line) => arm_line = line,
+                None => {
+                    self.statement()?;
+                    break;
+                }
+            }
+        }
+        for jump in end_jumps {
+            self.active_frame_mut().patch_jump(jump, 0 as JumpOffset, OpCode::Jump);
         }
         Ok(line)
     }
 
+    fn break_stmt(&mut self, line: Line) -> Result<(), CompilerError> {
+        let depth = match self.active_frame().loops.last() {
+            Some(l) => l.depth,
+            None => return Err(CompilerError::new(
+                "Can't use 'break' outside of a loop.", Token::new(line, TokenType::Break))),
+        };
+        self.pop_loop_locals(depth, line);
+        let jump = self.write(OpCode::UnpatchedJump, line);
+        self.active_frame_mut().loops.last_mut().unwrap().breaks.push(jump);
+        Ok(())
+    }
+
+    fn continue_stmt(&mut self, line: Line) -> Result<(), CompilerError> {
+        let (depth, start) = match self.active_frame().loops.last() {
+            Some(l) => (l.depth, l.start),
+            None => return Err(CompilerError::new(
+                "Can't use 'continue' outside of a loop.", Token::new(line, TokenType::Continue))),
+        };
+        self.pop_loop_locals(depth, line);
+        self.write_loop(start, line);
+        Ok(())
+    }
+
+    // Like end_scope, but for all the scopes inside the loop, and without removing their locals,
+    // since the code following the break or continue is still inside those scopes.
+    fn pop_loop_locals(&mut self, depth: Depth, line: Line) {
+        let mut pop_n_counter = 0;
+        for i in (0..self.active_locals().len()).rev() {
+            let local = &self.active_locals()[i];
+            if local.depth <= depth {
+                break;
+            }
+            if local.is_captured {
+                self.write_pop(pop_n_counter, line);
+                pop_n_counter = 0;
+                self.write(OpCode::CloseUpvalue, line);
+            } else {
+                pop_n_counter += 1;
+            }
+        }
+        self.write_pop(pop_n_counter, line);
+    }
+
+    // Compiles the body of a loop, where a continue jumps to `start`, and all locals deeper than
+    // `depth` are popped before jumping. Returns the unpatched jumps of the body's breaks.
+    fn loop_body(
+        &mut self, start: CodeLocation, depth: Depth,
+    ) -> Result<Vec<CodeLocation>, NonEmpty<CompilerError>> {
+        self.active_frame_mut().loops.push(LoopContext { depth, start, breaks: Vec::new() });
+        let result = self.statement();
+        let context = self.active_frame_mut().loops.pop().unwrap();
+        result.map(|_| context.breaks)
+    }
+
+    fn patch_breaks(&mut self, breaks: Vec<CodeLocation>) {
+        for jump in breaks {
+            self.active_frame_mut().patch_jump(jump, 0 as JumpOffset, OpCode::Jump);
+        }
+    }
+
     fn while_stmt(&mut self, line: Line) -> Result<Line, NonEmpty<CompilerError>> {
         self.consume(TokenType::OpenParen, None)?;
         let body_start = self.active_chunk().get_code().next_location();
         self.compile_expression()?;
         self.consume(TokenType::CloseParen, None)?;
-        self.jumping_body(line, 1 as JumpOffset, OpCode::JumpIfFalse)?;
-        self.write(OpCode::Jump(body_start), line);
+        let exit = self.write(OpCode::UnpatchedJump, line);
+        let breaks = self.loop_body(body_start, self.depth)?;
+        self.active_frame_mut().patch_jump(exit, 1 as JumpOffset, OpCode::JumpIfFalse);
+        self.write_loop(body_start, line);
+        self.patch_breaks(breaks);
         Ok(line)
     }
 
     fn for_stmt(&mut self, line: Line) -> Result<Line, NonEmpty<CompilerError>> {
         self.consume(TokenType::OpenParen, None)?;
+        // "in" is only a keyword in this context.
+        if matches!(self.peek_type(), TokenType::Identifier(_))
+            && self.tokens.get(self.current + 1).map(|t| &t.r#type)
+            == Some(&TokenType::identifier("in")) {
+            return self.for_in_stmt(line);
+        }
         // Initializer
         self.begin_scope();
         if self.matches(TokenType::Semicolon).is_some() {
@@ -278,77 +706,210 @@ impl Compiler {
                 let result = self.active_chunk().get_code().next_location();
                 self.compile_expression()?;
                 self.write(OpCode::Pop, line);
-                self.write(OpCode::Jump(body_start), line);
+                self.write_loop(body_start, line);
                 self.active_frame_mut().patch_jump(
                     jump_over_increment, 0 as JumpOffset, OpCode::Jump);
                 self.consume(TokenType::CloseParen, None)?;
                 Ok(Some(result))
             }?;
-        self.statement()?;
+        let continue_target = increment.unwrap_or(body_start);
+        let breaks = self.loop_body(continue_target, self.depth)?;
         if let Some(cond) = condition_jump {
             self.active_frame_mut().patch_jump(cond, 1 as JumpOffset, OpCode::JumpIfFalse);
         }
-        self.write(OpCode::Jump(increment.unwrap_or(body_start)), line);
+        self.write_loop(continue_target, line);
+        self.patch_breaks(breaks);
         self.end_scope(line);
         Ok(line)
     }
 
-    // If new elements are added after this function has finished running, the jump should be after
-    // those.
-    fn jumping_body<F: FnOnce(CodeLocation) -> OpCode>(
-        &mut self, line: Line, offset: JumpOffset, ctor: F,
-    ) -> Result<CodeLocation, NonEmpty<CompilerError>> {
-        let jump_pos = self.write(OpCode::UnpatchedJump, line);
-        self.statement()?;
-        self.active_frame_mut().patch_jump(jump_pos, offset, ctor);
-        Ok(jump_pos)
+    fn for_in_stmt(&mut self, line: Line) -> Result<Line, NonEmpty<CompilerError>> {
+        let name = match self.advance().r#type {
+            TokenType::Identifier(name) => self.intern_string(name),
+            e => panic!("Expected an identifier, got '{:?}'", e),
+        };
+        self.advance(); // Skips "in".
+        self.begin_scope();
+        self.compile_expression()?;
+        self.write(OpCode::Iterate, line);
+        self.consume(TokenType::CloseParen, None)?;
+        // The iterator is stored in a local whose name can't clash with a user variable.
+        let iterator_name = self.intern_string("(iterator)".to_owned());
+        self.active_locals_mut().push(Local::new(iterator_name));
+        self.mark_initialized();
+        let iterator = self.active_locals().len() - 1;
+        let loop_start = self.write(OpCode::UnpatchedJump, line);
+        // The loop variable is scoped to a single iteration, so closures capture a fresh copy.
+        self.begin_scope();
+        self.active_locals_mut().push(Local::new(name));
+        self.mark_initialized();
+        let breaks = self.loop_body(loop_start, self.depth - 1)?;
+        self.end_scope(line);
+        self.write_loop(loop_start, line);
+        self.active_frame_mut().patch_jump(
+            loop_start, 0 as JumpOffset, |exit| OpCode::IterNext(iterator, exit));
+        self.patch_breaks(breaks);
+        self.end_scope(line);
+        Ok(line)
     }
 
     fn declare_class(&mut self) -> Result<Line, NonEmpty<CompilerError>> {
         let (name, line) = self.parse_variable()?;
         self.mark_initialized();
+        self.active_chunk_mut().add_class(Class::new(name.clone()), line);
+        self.define_variable(name.clone(), line)?;
+        self.classes.push(ClassContext { has_superclass: false, in_static_method: false });
+        let body = self.class_body(name, line);
+        self.classes.pop();
+        body
+    }
+
+    fn class_body(
+        &mut self, name: InternedString, line: Line,
+    ) -> Result<Line, NonEmpty<CompilerError>> {
         self.consume(TokenType::OpenBrace, None)?;
-        self.consume(TokenType::CloseBrace, None)?;
-        let class = Class { name: name.clone() };
-        self.active_chunk_mut().add_class(class, line);
-        self.define_variable(name, line)?;
-        Ok(line)
+        if self.is_at_end() || self.peek_type() == &TokenType::CloseBrace {
+            return Ok(self.consume(TokenType::CloseBrace, None)?);
+        }
+        // Loads the class again, so the methods can be bound to it.
+        let (_, getter) = self.resolve_variable(name.to_owned(), line)?;
+        self.write(getter, line);
+        while !self.is_at_end() && self.peek_type() != &TokenType::CloseBrace {
+            self.method()?;
+        }
+        let end_line = self.consume(TokenType::CloseBrace, None)?;
+        self.write(OpCode::Pop, end_line);
+        Ok(end_line)
+    }
+
+    fn method(&mut self) -> Result<(), NonEmpty<CompilerError>> {
+        let is_static = self.matches(TokenType::Static).is_some();
+        let Token { r#type, line, span } = self.advance();
+        let name = match r#type {
+            TokenType::Identifier(name) => name,
+            e => return Err(CompilerError::new(
+                format!("Expected method name, got '{:?}'", e), Token { r#type: e, line, span },
+            ).into()),
+        };
+        let kind = match (is_static, name == INITIALIZER) {
+            (true, true) => return Err(CompilerError::new(
+                "Initializers can't be static.", Token { r#type: TokenType::Static, line, span },
+            ).into()),
+            (true, false) => FunctionKind::StaticMethod,
+            (false, true) => FunctionKind::Initializer,
+            (false, false) => FunctionKind::Method,
+        };
+        let name = self.intern_string(name);
+        self.classes.last_mut().unwrap().in_static_method = is_static;
+        let result = self.function(name.clone(), kind, line);
+        self.classes.last_mut().unwrap().in_static_method = false;
+        result?;
+        self.write(if is_static { OpCode::StaticMethod(name) } else { OpCode::Method(name) }, line);
+        Ok(())
     }
 
     fn declare_function(&mut self) -> Result<Line, NonEmpty<CompilerError>> {
         let (name, line) = self.parse_variable()?;
         self.mark_initialized();
-        let mut arity = 0;
+        let end_line = self.function(name.clone(), FunctionKind::Function, line)?;
+        self.define_variable(name, line)?;
+        Ok(end_line)
+    }
+
+    // Compiles the parameters and body of a function, and pushes its closure.
+    fn function(
+        &mut self, name: InternedString, kind: FunctionKind, line: Line,
+    ) -> Result<Line, NonEmpty<CompilerError>> {
         self.depth += 1;
-        self.frames.push(FunctionContext::default());
+        self.frames.push(FunctionContext::new(kind));
+        let result = self.function_body(kind);
+        let frame = self.frames.pop().unwrap();
+        self.depth -= 1;
+        // The frame is popped even on errors, so the following code isn't compiled into it.
+        let (end_line, arity) = result?;
+        let (chunk, upvalues) = frame.finish(end_line, arity);
+        let function = Function { name, arity, chunk, upvalues, is_method: kind.has_receiver() };
+        self.active_chunk_mut().add_function(function, line);
+        Ok(end_line)
+    }
+
+    fn function_body(
+        &mut self, kind: FunctionKind) -> Result<(Line, ArgCount), NonEmpty<CompilerError>> {
+        let mut arity = 0;
+        if kind.has_receiver() {
+            // Since "this" is a keyword, this local can't clash with a user variable.
+            let this = self.intern_string(THIS.to_owned());
+            self.active_locals_mut().push(Local::new(this));
+            self.mark_initialized();
+        }
         self.consume(TokenType::OpenParen, None)?;
         if self.peek_type() != &TokenType::CloseParen {
             loop {
                 arity += 1;
                 let (var_name, line) = self.parse_variable()?;
                 self.define_variable(var_name, line)?;
-                if self.matches(TokenType::Comma).is_none() {
+                if !self.matches_separator(TokenType::CloseParen) {
                     break;
                 }
             }
         }
         self.consume(TokenType::CloseParen, None)?;
+        self.value_captures()?;
         self.consume(TokenType::OpenBrace, None)?;
         // Functions don't explicitly clean up after themselves; instead, each return statement
         // knows how many elements to drop from the call stack.
         let end_line = self.multi_statements()?;
-        let (chunk, upvalues) = self.frames.pop().unwrap().finish(end_line);
-        self.depth -= 1;
-        let function = Function { name: name.clone(), arity, chunk, upvalues };
-        self.active_chunk_mut().add_function(function, line);
-        self.define_variable(name, line)?;
-        Ok(end_line)
+        Ok((end_line, arity))
+    }
+
+    // E.g., `fun foo(x) use (y, z) { ... }` captures copies of y and z, taken when the closure is
+    // created, so later assignments to either side aren't shared. "use" is only a keyword in this
+    // context.
+    fn value_captures(&mut self) -> Result<(), CompilerError> {
+        if self.is_at_end() || self.peek_type() != &TokenType::identifier("use") {
+            return Ok(());
+        }
+        self.advance();
+        self.consume(TokenType::OpenParen, None)?;
+        loop {
+            let Token { r#type, line, span } = self.advance();
+            let name = match r#type {
+                TokenType::Identifier(name) => self.intern_string(name),
+                e => return Err(CompilerError::new(
+                    format!("Expected Identifier for captured variable, got '{:?}'", e),
+                    Token { r#type: e, line, span },
+                )),
+            };
+            let enclosing = self.frames.len() - 2;
+            let upvalue = if let Some(index) = self.frames[enclosing].resolve_local(&name, line)? {
+                Upvalue { index, is_local: true, by_value: true }
+            } else if let Some(index) = self.resolve_upvalue_aux(&name, enclosing) {
+                Upvalue { index, is_local: false, by_value: true }
+            } else {
+                return Err(CompilerError::new(
+                    format_interned!("Can only capture local variables by value, got '{}'", name),
+                    Token { r#type: TokenType::identifier(name.to_owned()), line, span },
+                ));
+            };
+            let index = self.active_frame_mut().insert_upvalue(upvalue);
+            self.active_frame_mut().value_captures.push((name, index));
+            if !self.matches_separator(TokenType::CloseParen) {
+                break;
+            }
+        }
+        self.consume(TokenType::CloseParen, None)?;
+        Ok(())
     }
 
     fn declare_variable(&mut self, can_assign: bool) -> Result<(), CompilerError> {
         let (name, line) = self.parse_variable()?;
         if can_assign && self.matches(TokenType::Equal).is_some() {
-            self.compile_expression()
+            let start = self.active_chunk().get_code().next_location();
+            let result = self.compile_expression();
+            if result.is_ok() && self.depth == 0 {
+                self.fold_constant_initializer(start);
+            }
+            result
         } else {
             self.write(OpCode::Nil, line);
             Ok(line)
@@ -359,25 +920,75 @@ impl Compiler {
         })
     }
 
+    // Top-level initializers which only combine literals, e.g., `var TWO_PI = 2 * 3.14159;`, are
+    // evaluated at compile time, so the script doesn't have to compute them on startup.
+    fn fold_constant_initializer(&mut self, start: CodeLocation) {
+        let code = &self.active_chunk().get_code().instructions()[start..];
+        if code.len() < 2 {
+            return;
+        }
+        let line = code[0].1;
+        if let Some(folded) = fold_constant(code) {
+            while self.active_chunk().get_code().len() > start {
+                self.active_chunk_mut().pop();
+            }
+            self.write(folded, line);
+        }
+    }
+
+    // `global x = 1;` defines (or overwrites) the top-level x, regardless of the enclosing scope.
+    // "global" is only a keyword when followed by an identifier.
+    fn is_global_declaration(&self) -> bool {
+        !self.is_at_end()
+            && self.peek_type() == &TokenType::identifier("global")
+            && matches!(
+                self.tokens.get(self.current + 1).map(|t| &t.r#type),
+                Some(TokenType::Identifier(_)),
+            )
+    }
+
+    fn declare_explicit_global(&mut self) -> Result<Line, CompilerError> {
+        self.advance();
+        let Token { r#type: TokenType::Identifier(name), line, .. } = self.advance() else {
+            unreachable!("Checked by is_global_declaration")
+        };
+        let name = self.intern_string(name);
+        if self.matches(TokenType::Equal).is_some() {
+            self.compile_expression()?;
+        } else {
+            self.write(OpCode::Nil, line);
+        }
+        self.consume(TokenType::Semicolon, None)?;
+        self.declare_global(&name);
+        self.write(OpCode::DefineGlobal(name), line);
+        Ok(line)
+    }
+
     fn parse_variable(&mut self) -> Result<(InternedString, Line), CompilerError> {
-        let Token { r#type, line } = self.advance();
+        let Token { r#type, line, span } = self.advance();
         let name = match r#type {
             TokenType::Identifier(name) => Ok(name),
             e => Err(CompilerError {
                 message: format!("Expected Identifier for variable, got '{:?}'", e),
-                token: Token { r#type: e, line },
+                token: Token { r#type: e, line, span },
             })
         }?;
+        let is_discarded = name == DISCARD;
         let interned = self.intern_string(name);
         if self.depth > 0 {
             self.active_locals_mut().push(Local::new(interned.clone()))
+        } else if !is_discarded {
+            self.declare_global(&interned);
         }
         Ok((interned, line))
     }
 
     fn define_variable(&mut self, name: InternedString, line: Line) -> Result<(), CompilerError> {
         if self.depth == 0 {
-            self.write(OpCode::DefineGlobal(name), line);
+            self.write(
+                if name.apply(|s| s == DISCARD) { OpCode::Pop } else { OpCode::DefineGlobal(name) },
+                line,
+            );
             Ok(())
         } else {
             // Skipping the first element because that is the current (uninitialized) local.
@@ -385,13 +996,10 @@ impl Compiler {
                 if depth < &self.depth {
                     break;
                 }
-                if &name == local_name {
+                if &name == local_name && !name.apply(|s| s == DISCARD) {
                     return Err(CompilerError {
                         message: format_interned!("Redefined variable '{}' in same scope", name),
-                        token: Token {
-                            r#type: TokenType::identifier(name.to_owned()),
-                            line,
-                        },
+                        token: Token::new(line, TokenType::identifier(name.to_owned())),
                     });
                 }
             }
@@ -401,6 +1009,16 @@ impl Compiler {
         }
     }
 
+    // Globals are assigned their slot on declaration rather than definition, so recursive functions
+    // can also use the faster access. Accessing the slot before the definition has been *executed*
+    // is still a runtime error, just like for the name-based lookup.
+    fn declare_global(&mut self, name: &InternedString) {
+        if !self.globals.contains_key(name) {
+            self.globals.insert(name.clone(), self.global_names.len());
+            self.global_names.push(name.clone());
+        }
+    }
+
     fn mark_initialized(&mut self) {
         // No need to initialize globals.
         if self.depth != 0 {
@@ -420,7 +1038,7 @@ impl Compiler {
 
     fn compile_precedence(&mut self, precedence: Precedence) -> Result<Line, CompilerError> {
         let can_assign = precedence <= Precedence::Assignment;
-        let Token { line, r#type } = self.advance();
+        let Token { line, r#type, span } = self.advance();
         match r#type {
             TokenType::Minus => {
                 self.compile_precedence(Precedence::Unary)?;
@@ -435,26 +1053,50 @@ impl Compiler {
             }
             TokenType::Identifier(id) => {
                 let is_assignment = can_assign && self.matches(TokenType::Equal).is_some();
-                let name = self.intern_string(id);
-                let (setter, getter) =
-                    if let Some(index) = self.active_frame().resolve_local(&name, line)? {
-                        (OpCode::SetLocal(index), OpCode::GetLocal(index))
-                    } else if let Some(index) = self.resolve_upvalue(&name) {
-                        (OpCode::SetUpvalue(index), OpCode::GetUpvalue(index))
-                    } else {
-                        (OpCode::SetGlobal(name.clone()), OpCode::GetGlobal(name))
-                    };
+                let (setter, getter) = self.resolve_variable(id.clone(), line)?;
                 if is_assignment {
+                    if self.frames.len() > 1 && matches!(setter, OpCode::SetGlobal(_)) {
+                        let name = self.intern_string(id.clone());
+                        let warning = CompilerError::new(
+                            format!(
+                                "Assigning to undeclared '{}' implicitly creates a global; use \
+                                'global {} = ...' instead",
+                                id, id,
+                            ),
+                            Token { r#type: TokenType::Identifier(id), line, span },
+                        );
+                        self.implicit_globals.push((name, warning));
+                    }
                     self.compile_expression()?;
                     self.write(setter, line);
+                } else if let Some(op) = self.matches_increment() {
+                    // The duplicated old value is left as the result of the expression.
+                    self.write(getter, line);
+                    self.write(OpCode::Dup, line);
+                    self.write(OpCode::Number(1.0), line);
+                    self.write(op, line);
+                    self.write(setter, line);
+                    self.write(OpCode::Pop, line);
                 } else {
                     self.write(getter, line);
                 }
             }
+            TokenType::PlusPlus | TokenType::MinusMinus => {
+                let (setter, getter) = match self.advance() {
+                    Token { r#type: TokenType::Identifier(id), line, .. } =>
+                        self.resolve_variable(id, line),
+                    token => Err(CompilerError::new("Invalid increment target.", token)),
+                }?;
+                self.write(getter, line);
+                self.write(OpCode::Number(1.0), line);
+                self.write(if r#type == TokenType::PlusPlus { OpCode::Add } else { OpCode::Subtract }, line);
+                self.write(setter, line);
+            }
             TokenType::StringLiteral(str) => {
                 let interned = self.intern_string(str);
                 self.write(OpCode::String(interned), line);
             }
+            TokenType::Interpolation(prefix) => self.interpolation(prefix, line)?,
             TokenType::True | TokenType::False | TokenType::Nil => {
                 let op = match &r#type {
                     TokenType::True => OpCode::Bool(true),
@@ -468,17 +1110,77 @@ impl Compiler {
                 self.compile_expression()?;
                 self.consume(TokenType::CloseParen, None)?;
             }
-            e => return Err(
-                CompilerError::new(format!("Unexpected '{:?}'", e), Token { r#type: e, line })),
+            TokenType::OpenBracket => {
+                let mut count = 0;
+                if self.is_at_end() || self.peek_type() != &TokenType::CloseBracket {
+                    loop {
+                        self.compile_expression()?;
+                        count += 1;
+                        if !self.matches_separator(TokenType::CloseBracket) {
+                            break;
+                        }
+                    }
+                }
+                self.consume(TokenType::CloseBracket, None)?;
+                self.write(OpCode::MakeList(count), line);
+            }
+            // Statements starting with a brace are blocks, so tables only parse as expressions.
+            TokenType::OpenBrace => {
+                let mut count = 0;
+                if self.is_at_end() || self.peek_type() != &TokenType::CloseBrace {
+                    loop {
+                        self.compile_expression()?;
+                        self.consume(TokenType::Colon, None)?;
+                        self.compile_expression()?;
+                        count += 1;
+                        if !self.matches_separator(TokenType::CloseBrace) {
+                            break;
+                        }
+                    }
+                }
+                self.consume(TokenType::CloseBrace, None)?;
+                self.write(OpCode::MakeTable(count), line);
+            }
+            TokenType::This if self.classes.is_empty() => return Err(CompilerError::new(
+                "Can't use 'this' outside of a class.", Token { r#type, line, span })),
+            TokenType::This if self.classes.last().unwrap().in_static_method =>
+                return Err(CompilerError::new(
+                    "Can't use 'this' in a static method.", Token { r#type, line, span })),
+            TokenType::This => {
+                let (_, getter) = self.resolve_variable(THIS.to_owned(), line)?;
+                self.write(getter, line);
+            }
+            TokenType::Super => return Err(CompilerError::new(
+                match self.classes.last() {
+                    None => "Can't use 'super' outside of a class.",
+                    Some(ClassContext { has_superclass: false, .. }) =>
+                        "Can't use 'super' in a class with no superclass.",
+                    // Classes can't inherit yet, so there's nothing to compile `super` to.
+                    Some(ClassContext { has_superclass: true, .. }) => "Unexpected 'Super'",
+                },
+                Token { r#type, line, span })),
+            TokenType::Eof => return Err(CompilerError::new(
+                "Expected expression, but encountered end of file", Token { r#type, line, span })),
+            e => return Err(CompilerError::new(
+                format!("Unexpected '{:?}'", e), Token { r#type: e, line, span })),
         }
 
         let mut last_line = line;
         while !self.is_at_end() && precedence <= Precedence::from(self.peek_type()) {
-            let Token { line, r#type } = self.advance();
+            let Token { line, r#type, span } = self.advance();
             let next_precedence = Precedence::from(&r#type).next().unwrap();
             let op = match r#type {
+                TokenType::PlusPlus | TokenType::MinusMinus => return Err(CompilerError::new(
+                    "Invalid increment target.", Token { r#type, line, span })),
                 TokenType::OpenParen => self.argument_list().map(|c| Left(OpCode::Call(c)))?,
+                TokenType::And | TokenType::Or | TokenType::Xor => {
+                    self.logical(&r#type, line, next_precedence)?;
+                    last_line = line;
+                    self.check_assignment_target(can_assign)?;
+                    continue;
+                }
                 TokenType::Dot => Left(OpCode::GetProperty(Pointer::null())),
+                TokenType::OpenBracket => Left(OpCode::GetIndex),
                 TokenType::Minus => Left(OpCode::Subtract),
                 TokenType::Plus => Left(OpCode::Add),
                 TokenType::Slash => Left(OpCode::Divide),
@@ -486,6 +1188,8 @@ impl Compiler {
                 TokenType::EqualEqual => Left(OpCode::Equals),
                 TokenType::Less => Left(OpCode::Less),
                 TokenType::Greater => Left(OpCode::Greater),
+                TokenType::DotDot => Left(OpCode::Range(false)),
+                TokenType::DotDotEqual => Left(OpCode::Range(true)),
                 TokenType::BangEqual => Right(OpCode::Equals),
                 TokenType::LessEqual => Right(OpCode::Greater),
                 TokenType::GreaterEqual => Right(OpCode::Less),
@@ -497,50 +1201,104 @@ impl Compiler {
                     self.write(OpCode::Call(c), line);
                 }
                 Left(OpCode::GetProperty(_)) => {
-                    let name = match self.advance().r#type {
-                        TokenType::Identifier(name) => Ok(name),
-                        e => Err(CompilerError {
+                    let name = match self.advance() {
+                        Token { r#type: TokenType::Identifier(name), .. } => Ok(name),
+                        Token { r#type: e, span, .. } => Err(CompilerError {
                             message: format!("Expected Identifier after dot, got '{:?}'", e),
-                            token: Token { r#type: e, line },
+                            token: Token { r#type: e, line, span },
                         })
                     }?;
                     let interned_name = self.intern_string(name);
                     if can_assign && self.matches(TokenType::Equal).is_some() {
                         let line = self.compile_expression()?;
                         self.write(OpCode::SetProperty(interned_name), line);
+                    } else if self.matches(TokenType::OpenParen).is_some() {
+                        let arg_count = self.argument_list()?;
+                        self.consume(TokenType::CloseParen, None)?;
+                        self.write(OpCode::Invoke(interned_name, arg_count), line);
                     } else {
                         self.write(OpCode::GetProperty(interned_name), line);
                     }
                 }
+                Left(OpCode::GetIndex) => {
+                    self.compile_expression()?;
+                    self.consume(TokenType::CloseBracket, None)?;
+                    if can_assign && self.matches(TokenType::Equal).is_some() {
+                        let line = self.compile_expression()?;
+                        self.write(OpCode::SetIndex, line);
+                    } else {
+                        self.write(OpCode::GetIndex, line);
+                    }
+                }
                 _ => {
-                    self.compile_precedence(next_precedence)?;
-                    match op {
-                        Left(op) => { self.write(op, line); }
-                        Right(op) => {
-                            self.write(op, line);
-                            self.write(OpCode::Not, line);
-                        }
+                    if Precedence::from(&r#type) == Precedence::Comparison {
+                        self.comparison(op, line)?;
+                    } else {
+                        self.compile_precedence(next_precedence)?;
+                        self.write_binary(op, line);
                     }
                     last_line = line;
-                    if !self.is_at_end() && can_assign && self.peek_type() == &TokenType::Equal {
-                        return Err(CompilerError::new(
-                            "Invalid assignment target.",
-                            self.tokens[self.current].clone(),
-                        ));
-                    }
+                    self.check_assignment_target(can_assign)?;
                 }
             }
         }
         Ok(last_line)
     }
 
+    fn check_assignment_target(&self, can_assign: CanAssign) -> Result<(), CompilerError> {
+        if !self.is_at_end() && can_assign && self.peek_type() == &TokenType::Equal {
+            return Err(CompilerError::new(
+                "Invalid assignment target.",
+                self.tokens[self.current].clone(),
+            ));
+        }
+        Ok(())
+    }
+
+    // `and` and `or` short-circuit, and result in the operand which decided the result, e.g.,
+    // `nil or 2` is 2. `xor` can't short-circuit, since a single operand never decides it, so it
+    // always evaluates both operands, and results in a bool.
+    fn logical(
+        &mut self, operator: &TokenType, line: Line, precedence: Precedence,
+    ) -> Result<(), CompilerError> {
+        match operator {
+            TokenType::And => {
+                // JumpIfFalse pops the duplicate, leaving the left operand if it's falsey.
+                self.write(OpCode::Dup, line);
+                let end = self.write(OpCode::UnpatchedJump, line);
+                self.write(OpCode::Pop, line);
+                self.compile_precedence(precedence)?;
+                self.active_frame_mut().patch_jump(end, 0 as JumpOffset, OpCode::JumpIfFalse);
+            }
+            TokenType::Or => {
+                self.write(OpCode::Dup, line);
+                let right = self.write(OpCode::UnpatchedJump, line);
+                let end = self.write(OpCode::UnpatchedJump, line);
+                self.active_frame_mut().patch_jump(right, 0 as JumpOffset, OpCode::JumpIfFalse);
+                self.write(OpCode::Pop, line);
+                self.compile_precedence(precedence)?;
+                self.active_frame_mut().patch_jump(end, 0 as JumpOffset, OpCode::Jump);
+            }
+            TokenType::Xor => {
+                // !a != !b, which also converts both operands to bools.
+                self.write(OpCode::Not, line);
+                self.compile_precedence(precedence)?;
+                self.write(OpCode::Not, line);
+                self.write(OpCode::Equals, line);
+                self.write(OpCode::Not, line);
+            }
+            e => panic!("Not a logical operator: {:?}", e),
+        }
+        Ok(())
+    }
+
     fn argument_list(&mut self) -> Result<ArgCount, CompilerError> {
         let mut arity = 0;
         if self.peek_type() != &TokenType::CloseParen {
             loop {
                 self.compile_expression()?;
                 arity += 1;
-                if self.matches(TokenType::Comma).is_none() {
+                if !self.matches_separator(TokenType::CloseParen) {
                     break;
                 }
             }
@@ -568,15 +1326,19 @@ impl Compiler {
         if frame_index == 0 {
             return None;
         }
+        let value_captures = &self.frames[frame_index].value_captures;
+        if let Some((_, index)) = value_captures.iter().find(|(captured, _)| captured == name) {
+            return Some(*index);
+        }
         if let Some(enclosing) = self.frames.get_mut(frame_index - 1) {
             if let Some(local_index) = enclosing.resolve_local_for_upvalue(name) {
                 return Some(
                     self.frames[frame_index].insert_upvalue(
-                        Upvalue { index: local_index, is_local: true }));
+                        Upvalue { index: local_index, is_local: true, by_value: false }));
             } else if let Some(local_index) = self.resolve_upvalue_aux(name, frame_index - 1) {
                 return Some(
                     self.frames[frame_index].insert_upvalue(
-                        Upvalue { index: local_index, is_local: false }));
+                        Upvalue { index: local_index, is_local: false, by_value: false }));
             }
         }
         return None;
@@ -618,25 +1380,169 @@ impl Compiler {
         }
     }
 
-    fn advance(&mut self) -> Token {
-        let result = mem::replace(
-            self.tokens.get_mut(self.current).unwrap(),
-            Token::new(0, TokenType::Eof),
-        );
-        self.current += 1;
-        result
+    // Comparisons can be chained, e.g., a < b <= c means a < b and b <= c, with b evaluated only
+    // once. Each non-final link keeps a copy of its right operand for the next one, and jumps to
+    // the end with false if it fails.
+    fn comparison(
+        &mut self, first: Either<OpCode, OpCode>, line: Line,
+    ) -> Result<(), CompilerError> {
+        let (mut op, mut line) = (first, line);
+        let mut short_circuits = Vec::new();
+        loop {
+            self.compile_precedence(Precedence::Comparison.next().unwrap())?;
+            if self.is_at_end() || Precedence::from(self.peek_type()) != Precedence::Comparison {
+                self.write_binary(op, line);
+                break;
+            }
+            self.write(OpCode::Swap, line);
+            self.write(OpCode::Over, line);
+            self.write_binary(op, line);
+            short_circuits.push(self.write(OpCode::UnpatchedJump, line));
+            let next = self.advance();
+            op = Compiler::comparison_op(&next.r#type);
+            line = next.line;
+        }
+        if !short_circuits.is_empty() {
+            let end = self.write(OpCode::UnpatchedJump, line);
+            for jump in short_circuits {
+                self.active_frame_mut().patch_jump(jump, 0 as JumpOffset, OpCode::JumpIfFalse);
+            }
+            self.write(OpCode::Pop, line);
+            self.write(OpCode::Bool(false), line);
+            self.active_frame_mut().patch_jump(end, 0 as JumpOffset, OpCode::Jump);
+        }
+        Ok(())
     }
 
-    fn is_at_end(&self) -> bool {
-        self.current == self.tokens.len()
+    // Right ops are negated after being applied.
+    fn comparison_op(tt: &TokenType) -> Either<OpCode, OpCode> {
+        match tt {
+            TokenType::Less => Left(OpCode::Less),
+            TokenType::Greater => Left(OpCode::Greater),
+            TokenType::LessEqual => Right(OpCode::Greater),
+            TokenType::GreaterEqual => Right(OpCode::Less),
+            e => panic!("Not a comparison: {:?}", e),
+        }
     }
 
-    fn peek_type(&self) -> &TokenType { &self.tokens[self.current].r#type }
-
+    fn write_binary(&mut self, op: Either<OpCode, OpCode>, line: Line) {
+        match op {
+            Left(op) => { self.write(op, line); }
+            Right(op) => {
+                self.write(op, line);
+                self.write(OpCode::Not, line);
+            }
+        }
+    }
+
+    // Returns the setter and getter op codes for the named variable.
+    fn resolve_variable(
+        &mut self, name: String, line: Line,
+    ) -> Result<(OpCode, OpCode), CompilerError> {
+        if name == DISCARD {
+            return Err(CompilerError::new(
+                "'_' discards values, so it can't be used as a variable",
+                Token::new(line, TokenType::identifier(name)),
+            ));
+        }
+        let name = self.intern_string(name);
+        Ok(if let Some(index) = self.active_frame().resolve_local(&name, line)? {
+            (OpCode::SetLocal(index), OpCode::GetLocal(index))
+        } else if let Some(index) = self.resolve_upvalue(&name) {
+            (OpCode::SetUpvalue(index), OpCode::GetUpvalue(index))
+        } else if let Some(index) = self.globals.get(&name) {
+            (OpCode::SetGlobalByIndex(*index), OpCode::GetGlobalByIndex(*index))
+        } else {
+            (OpCode::SetGlobal(name.clone()), OpCode::GetGlobal(name))
+        })
+    }
+
+    // Concatenates the string's parts and its stringified expressions, skipping empty parts, e.g.,
+    // "${x}!" compiles to x, TO_STRING, "!" and ADD.
+    fn interpolation(&mut self, prefix: String, line: Line) -> Result<(), CompilerError> {
+        let mut has_parts = self.string_part(prefix, false, line);
+        loop {
+            self.compile_expression()?;
+            self.write(OpCode::ToString, line);
+            if has_parts {
+                self.write(OpCode::Add, line);
+            }
+            let (part, is_last) = match self.advance() {
+                Token { r#type: TokenType::Interpolation(part), .. } => (part, false),
+                Token { r#type: TokenType::StringLiteral(part), .. } => (part, true),
+                token => return Err(CompilerError::new(
+                    format!("Expected end of interpolated expression, got '{:?}'", token.r#type),
+                    token)),
+            };
+            self.string_part(part, true, line);
+            if is_last {
+                return Ok(());
+            }
+            has_parts = true;
+        }
+    }
+
+    // Returns whether the part was written, i.e., unless it's empty.
+    fn string_part(&mut self, part: String, concatenated: bool, line: Line) -> bool {
+        if part.is_empty() {
+            return false;
+        }
+        let interned = self.intern_string(part);
+        self.write(OpCode::String(interned), line);
+        if concatenated {
+            self.write(OpCode::Add, line);
+        }
+        true
+    }
+
+    // Whether another element of a comma separated list, which ends with the closing token, follows.
+    // A single trailing comma before the closing token is allowed, e.g., `f(x, y,)` or `[x, y,]`.
+    fn matches_separator(&mut self, closing: TokenType) -> bool {
+        self.matches(TokenType::Comma).is_some()
+            && (self.is_at_end() || self.peek_type() != &closing)
+    }
+
+    // Returns the arithmetic op of a postfix increment or decrement, if there is one.
+    fn matches_increment(&mut self) -> Option<OpCode> {
+        if self.matches(TokenType::PlusPlus).is_some() {
+            Some(OpCode::Add)
+        } else if self.matches(TokenType::MinusMinus).is_some() {
+            Some(OpCode::Subtract)
+        } else {
+            None
+        }
+    }
+
+    // Past the end, e.g., after a dangling operator, this is an Eof on the last token's line, so
+    // the caller reports what it expected instead of panicking.
+    fn advance(&mut self) -> Token {
+        if self.is_at_end() {
+            return Token::new(self.tokens.last().map_or(0, |t| t.line), TokenType::Eof);
+        }
+        let line = self.tokens[self.current].line;
+        let result = mem::replace(
+            self.tokens.get_mut(self.current).unwrap(),
+            Token::new(line, TokenType::Eof),
+        );
+        self.current += 1;
+        result
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.current == self.tokens.len()
+    }
+
+    fn peek_type(&self) -> &TokenType { &self.tokens[self.current].r#type }
+
     fn write(&mut self, code: OpCode, line: Line) -> CodeLocation {
         self.active_frame_mut().chunk.write(code, line)
     }
 
+    fn write_loop(&mut self, loop_start: CodeLocation, line: Line) -> CodeLocation {
+        let offset = self.active_chunk().get_code().next_location() - loop_start;
+        self.write(OpCode::Loop(offset), line)
+    }
+
     fn intern_string(&mut self, str: String) -> InternedString {
         self.interned_strings.intern_string(str)
     }
@@ -659,22 +1565,62 @@ impl Local {
     pub fn is_uninitialized(&self) -> bool { self.depth == Local::UNINITIALIZED }
 }
 
+#[derive(Debug)]
+struct ClassContext {
+    has_superclass: bool,
+    // Static methods have no receiver, including in the functions nested in them.
+    in_static_method: bool,
+}
+
+#[derive(Debug)]
+struct LoopContext {
+    // The depth of the scope enclosing the loop's body.
+    depth: Depth,
+    // Where a continue jumps to.
+    start: CodeLocation,
+    breaks: Vec<CodeLocation>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+enum FunctionKind {
+    #[default]
+    Script,
+    Function,
+    Method,
+    // Called on the class itself, so it has no receiver.
+    StaticMethod,
+    // The `init` method, which always returns its instance.
+    Initializer,
+}
+
+impl FunctionKind {
+    fn has_receiver(self) -> bool { matches!(self, FunctionKind::Method | FunctionKind::Initializer) }
+}
+
 #[derive(Debug, Default)]
 struct FunctionContext {
+    kind: FunctionKind,
     locals: Vec<Local>,
     chunk: Chunk,
     upvalues: Vec<Upvalue>,
+    // The variables captured by value, and their upvalue indices.
+    value_captures: Vec<(InternedString, StackLocation)>,
+    // The loops enclosing the code being compiled, innermost last.
+    loops: Vec<LoopContext>,
 }
 
 impl FunctionContext {
-    pub fn finish(mut self, line: Line) -> (Chunk, Vec<Upvalue>) {
-        if self.chunk.get_code().last().iter().any(|e| match &e.0 {
-            OpCode::Return => false,
-            _ => true,
-        }) {
-            self.chunk.write(OpCode::Nil, line);
+    pub fn new(kind: FunctionKind) -> Self { FunctionContext { kind, ..Default::default() } }
+
+    pub fn finish(mut self, line: Line, arity: ArgCount) -> (Chunk, Vec<Upvalue>) {
+        if self.can_fall_through() {
+            let result =
+                if self.kind == FunctionKind::Initializer { OpCode::GetLocal(0) } else { OpCode::Nil };
+            self.chunk.write(result, line);
             self.chunk.write(OpCode::Return, line);
         }
+        self.chunk.remove_empty_jumps();
+        self.chunk.compute_max_stack(arity + self.kind.has_receiver() as usize);
         (self.chunk, self.upvalues)
     }
 
@@ -712,6 +1658,17 @@ impl FunctionContext {
         })
     }
 
+    // Whether execution can reach the next written instruction, i.e., the code so far doesn't end
+    // with a return, or something jumps past that return.
+    fn can_fall_through(&self) -> bool {
+        let code = self.chunk.get_code();
+        let next = code.next_location();
+        !matches!(code.last(), Some((OpCode::Return, _))) || code.iter().any(|(op, _)| match op {
+            OpCode::Jump(to) | OpCode::JumpIfFalse(to) | OpCode::IterNext(_, to) => *to == next,
+            _ => false,
+        })
+    }
+
     pub fn patch_jump<F: FnOnce(CodeLocation) -> OpCode>(
         &mut self, source: CodeLocation, offset: JumpOffset, ctor: F,
     ) {
@@ -735,9 +1692,12 @@ enum Precedence {
     TopLevel,
     Assignment,
     Or,
+    // Between the two, so `a or b xor c and d` is `a or (b xor (c and d))`.
+    Xor,
     And,
     Equality /* == != */,
     Comparison /* < > <= >= */,
+    Range /* .. ..= */,
     Term /* + - */,
     Factor /* * / */,
     Unary /* ! - */,
@@ -756,6 +1716,9 @@ impl From<&TokenType> for Precedence {
         match tt {
             TokenType::OpenParen => Precedence::Call,
             TokenType::Dot => Precedence::Call,
+            TokenType::OpenBracket => Precedence::Call,
+            TokenType::PlusPlus => Precedence::Call,
+            TokenType::MinusMinus => Precedence::Call,
             TokenType::Minus => Precedence::Term,
             TokenType::Plus => Precedence::Term,
             TokenType::Slash => Precedence::Factor,
@@ -766,6 +1729,11 @@ impl From<&TokenType> for Precedence {
             TokenType::LessEqual => Precedence::Comparison,
             TokenType::Greater => Precedence::Comparison,
             TokenType::GreaterEqual => Precedence::Comparison,
+            TokenType::DotDot => Precedence::Range,
+            TokenType::DotDotEqual => Precedence::Range,
+            TokenType::Or => Precedence::Or,
+            TokenType::Xor => Precedence::Xor,
+            TokenType::And => Precedence::And,
             _ => Precedence::TopLevel,
         }
     }
@@ -774,75 +1742,24 @@ impl From<&TokenType> for Precedence {
 type CanAssign = bool;
 type JumpOffset = i8;
 
-#[cfg(test)]
-pub fn disassemble(chunk: &Chunk) -> Vec<String> {
-    let mut previous_line: Line = 0;
-    let mut is_first = true;
-    let mut result = Vec::new();
-    for (i, (op, line)) in chunk.get_code().iter().enumerate() {
-        let prefix = format!(
-            "{:0>2}: {:>2}",
-            i,
-            if !is_first && line == &previous_line {
-                " |".to_owned()
-            } else {
-                line.to_string()
-            },
-        );
-
-        let command: String = format!("{} {}{}", prefix, op.to_upper_snake(), match op {
-            OpCode::Number(num) => format!("{}", num),
-            OpCode::PopN(num) => format!("{}", num),
-            OpCode::UnpatchedJump =>
-                panic!("Jump should have been patched at line: '{}'", line),
-            OpCode::JumpIfFalse(index) => format!("{}", index),
-            OpCode::Jump(index) => format!("{}", index),
-            OpCode::Function(i) => {
-                let function = chunk.get_function(*i).upgrade().unwrap();
-                let name = function.name.clone();
-                let upvalues = &function.upvalues;
-                format!(
-                    "{} [{}]",
-                    name.to_owned(),
-                    upvalues.iter()
-                        .map(|e| format!("({},{})", e.index, if e.is_local { "t" } else { "f" }))
-                        .collect::<Vec<_>>()
-                        .join(","),
-                )
-            }
-            OpCode::Class(i) => chunk.get_class(*i).upgrade().unwrap().name.to_owned(),
-            OpCode::GetProperty(s) => s.to_owned(),
-            OpCode::SetProperty(s) => s.to_owned(),
-            OpCode::DefineGlobal(name) => format_interned!("'{}'", name),
-            OpCode::GetGlobal(name) => format_interned!("'{}'", name),
-            OpCode::SetGlobal(name) => format_interned!("'{}'", name),
-            OpCode::GetUpvalue(index) => format!("'{}'", index),
-            OpCode::SetUpvalue(index) => format!("'{}'", index),
-            OpCode::DefineLocal(index) => format!("{}", index),
-            OpCode::GetLocal(index) => format!("{}", index),
-            OpCode::SetLocal(index) => format!("{}", index),
-            OpCode::Bool(bool) => format!("{}", bool),
-            OpCode::String(s) => format_interned!("'{}'", s),
-            OpCode::Call(arg_count) => format!("{}", arg_count),
-            OpCode::Greater | OpCode::Less | OpCode::Add | OpCode::Subtract | OpCode::Multiply |
-            OpCode::Pop | OpCode::Print | OpCode::Nil | OpCode::Equals | OpCode::Divide |
-            OpCode::Negate | OpCode::Not | OpCode::CloseUpvalue | OpCode::Return =>
-                "".to_owned(),
-        });
-        result.push(command);
-        previous_line = *line;
-        is_first = false;
-    }
-    for i in 0..chunk.function_count() {
-        let temp = chunk.get_function(i).upgrade().unwrap();
-        let name = &temp.name;
-        result.push(format_interned!("<fun {}>", name));
-        result.append(&mut disassemble(&temp.chunk));
-        result.push(format_interned!("<end {}>", name));
-    }
-    result
+// Evaluates code which only operates on literals, see [fold_literals], or None for any other code.
+fn fold_constant(code: &[(OpCode, Line)]) -> Option<OpCode> {
+    let mut stack: Vec<OpCode> = Vec::new();
+    for (op, _) in code {
+        let (pops, _) = op.stack_effect()?;
+        let operands = stack.split_off(stack.len().checked_sub(pops)?);
+        stack.push(fold_literals(&operands, op)?);
+    }
+    if stack.len() == 1 { stack.pop() } else { None }
 }
 
+// Binding a value to `_` discards it: `_` can be bound any number of times in the same scope, e.g.,
+// as a parameter or a for-in loop variable, but can never be read or assigned.
+const DISCARD: &str = "_";
+
+// The local holding a method's receiver.
+const THIS: &str = "this";
+
 #[cfg(test)]
 mod tests {
     use std::ops::Deref;
@@ -850,10 +1767,12 @@ mod tests {
     use lazy_static::lazy_static;
     use regex::Regex;
 
-    use crate::assert_msg_contains;
+    use crate::{assert_deep_eq, assert_msg_contains};
+    use crate::rslox::common::error::{render, Severity};
     use crate::rslox::common::tests::unsafe_tokenize;
     use crate::rslox::common::utils::SliceExt;
-    use crate::rslox::compiled::tests::unsafe_compile;
+    use crate::rslox::compiled::chunk::disassemble;
+    use crate::rslox::compiled::tests::{unsafe_compile, write_module};
 
     use super::*;
 
@@ -869,6 +1788,66 @@ mod tests {
         )
     }
 
+    fn optimized(code: &str) -> (Chunk, InternedStrings) {
+        compile_optimized(unsafe_tokenize(vec![code])).expect("Failed to compile")
+    }
+
+    #[test]
+    fn sweeping_interned_strings_frees_their_bytes() {
+        let mut strings = InternedStrings::default();
+        let kept = strings.intern_string("kept".to_owned());
+        let kept_bytes = strings.allocated_bytes();
+        strings.intern_string("swept".to_owned());
+        strings.intern_string("kept".to_owned());
+        assert!(strings.allocated_bytes() > kept_bytes);
+        kept.mark();
+        strings.sweep();
+        assert_eq!(strings.allocated_bytes(), kept_bytes);
+        assert_eq!(strings.len(), 1);
+    }
+
+    #[test]
+    fn optimized_constant_expressions() {
+        let (chunk, _) =
+            optimized("print -(1 + 2) * 3 < 8 == !nil; print !!(1 < 2); print 10 / 4;");
+        assert_eq!(
+            TRIMMER.replace_all(&disassemble(&chunk).join("\n"), "\n").trim(),
+            r#"
+00:  1 BOOL           true
+01:  | PRINT
+02:  | BOOL           true
+03:  | PRINT
+04:  | NUMBER         2.5
+05:  | PRINT"#.trim(),
+        )
+    }
+
+    #[test]
+    fn constant_conditions_leave_only_the_taken_branch() {
+        let (expected, _strings) = unsafe_compile(vec!["", "print \"yes\";", "", "print 3;"]);
+        let (chunk, _optimized_strings) = optimized(r#"
+if (1 < 2) print "yes"; else print "no";
+while (false) print "never";
+if (nil) { print 0; } print 3;"#);
+        assert_deep_eq!(expected, chunk);
+    }
+
+    #[test]
+    fn optimizing_keeps_functions_correct() {
+        let (chunk, _optimized_strings) = optimized(r#"
+fun f(x) {
+  if (!!x) return 1 + 1;
+  return 3;
+  print "unreachable";
+}"#);
+        let (expected, _strings) = unsafe_compile(vec![r#"
+fun f(x) {
+  if (x) return 2;
+  return 3;
+}"#]);
+        assert_deep_eq!(expected, chunk);
+    }
+
     #[test]
     fn constant() {
         assert_bytecode(
@@ -963,29 +1942,483 @@ mod tests {
     }
 
     #[test]
-    fn uninitialized_variable() {
+    fn uninitialized_variable() {
+        assert_msg_contains!(
+            compile(unsafe_tokenize(vec![
+                "var a = 42;",
+                "{",
+                "  var a = a;",
+                "}",
+            ])).unwrap_err().unwrap_single().get_message(),
+            "uninitialized local variable"
+        )
+    }
+
+    #[test]
+    fn local_variable_redeclaration() {
+        assert_msg_contains!(
+            compile(unsafe_tokenize(vec![
+                "{",
+                "  var a = 42;",
+                "  var a = 54;",
+                "}",
+            ])).unwrap_err().unwrap_single().get_message(),
+            "Redefined variable 'a' in same scope"
+        )
+    }
+
+    #[test]
+    fn this_outside_of_class() {
+        let err = compile(unsafe_tokenize(vec![
+            "fun foo() {",
+            "  return this;",
+            "}",
+            "print this;",
+        ])).unwrap_err();
+        assert_eq!(
+            err.iter().map(|e| (e.get_info().line, e.get_message())).collect::<Vec<_>>(),
+            vec![
+                (2, "Can't use 'this' outside of a class.".to_owned()),
+                (4, "Can't use 'this' outside of a class.".to_owned()),
+            ],
+        )
+    }
+
+    #[test]
+    fn this_in_static_method() {
+        let err = compile(unsafe_tokenize(vec![
+            "class Foo {",
+            "  static bar() {",
+            "    class Nested { qux() { return this; } }",
+            "    return this;",
+            "  }",
+            "}",
+        ])).unwrap_err();
+        let err = err.unwrap_single();
+        assert_eq!(err.get_info().line, 4);
+        assert_eq!(err.get_message(), "Can't use 'this' in a static method.");
+        assert_msg_contains!(
+            compile(unsafe_tokenize(vec!["class Foo { static init() {} }"]))
+                .unwrap_err().unwrap_single().get_message(),
+            "Initializers can't be static."
+        );
+        compile(unsafe_tokenize(vec!["class Foo { static bar() {} baz() { return this; } }"]))
+            .expect("Failed to compile");
+    }
+
+    #[test]
+    fn break_and_continue_outside_of_loop() {
+        let err = compile(unsafe_tokenize(vec![
+            "break;",
+            "while (true) {",
+            "  fun foo() { continue; }",
+            "  break;",
+            "}",
+        ])).unwrap_err();
+        assert_eq!(
+            err.iter().map(|e| (e.get_info().line, e.get_message())).collect::<Vec<_>>(),
+            vec![
+                (1, "Can't use 'break' outside of a loop.".to_owned()),
+                (3, "Can't use 'continue' outside of a loop.".to_owned()),
+            ],
+        )
+    }
+
+    #[test]
+    fn returning_a_value_from_an_initializer() {
+        let err = compile(unsafe_tokenize(vec![
+            "class Foo {",
+            "  init() {",
+            "    return;",
+            "  }",
+            "  bar() {",
+            "    return 1;",
+            "  }",
+            "}",
+            "class Bar {",
+            "  init() {",
+            "    return 1;",
+            "  }",
+            "}",
+        ])).unwrap_err();
+        let err = err.unwrap_single();
+        assert_eq!(err.get_info().line, 11);
+        assert_eq!(err.get_message(), "Can't return a value from an initializer.");
+    }
+
+    #[test]
+    fn methods_are_bound_to_their_class() {
+        assert_bytecode(
+            "class Foo { bar() { return this; } init() {} }",
+            r#"
+00:  1 CLASS          Foo
+01:  | DEFINE_GLOBAL  'Foo'
+02:  | GET_GLOBAL_IDX 0
+03:  | FUNCTION       bar []
+04:  | METHOD         bar
+05:  | FUNCTION       init []
+06:  | METHOD         init
+07:  | POP
+<fun bar>
+00:  1 GET_LOCAL      0
+01:  | RETURN
+<end bar>
+<fun init>
+00:  1 GET_LOCAL      0
+01:  | RETURN
+<end init>
+            "#,
+        )
+    }
+
+    #[test]
+    fn super_outside_of_class() {
+        let err = compile(unsafe_tokenize(vec![
+            "var x = 1;",
+            "fun foo() {",
+            "  return super.bar;",
+            "}",
+        ])).unwrap_err();
+        let err = err.unwrap_single();
+        assert_eq!(err.get_info().line, 3);
+        assert_eq!(err.get_message(), "Can't use 'super' outside of a class.");
+    }
+
+    #[test]
+    fn return_from_top_level() {
+        let err = compile(unsafe_tokenize(vec![
+            "fun foo() {",
+            "  return 1;",
+            "}",
+            "return foo();",
+        ])).unwrap_err();
+        let err = err.unwrap_single();
+        assert_eq!(err.get_info().line, 4);
+        assert_eq!(err.get_message(), "Can't return from top-level code.");
+    }
+
+    #[test]
+    fn return_from_nested_block_in_top_level() {
+        assert_msg_contains!(
+            compile(unsafe_tokenize(vec![
+                "if (true) {",
+                "  return;",
+                "}",
+            ])).unwrap_err().unwrap_single().get_message(),
+            "Can't return from top-level code."
+        )
+    }
+
+    #[test]
+    fn circular_import() {
+        write_module("circular_import", "a.lox", r#"import "b.lox";"#);
+        let b = write_module("circular_import", "b.lox", r#"import "a.lox";"#);
+        let err = compile(unsafe_tokenize(vec![&format!(r#"import "{}";"#, b)])).unwrap_err();
+        assert_msg_contains!(err.unwrap_single().get_message(), "Circular import of 'b.lox'")
+    }
+
+    #[test]
+    fn import_inside_block() {
+        let path = write_module("import_inside_block", "empty.lox", "");
+        assert_msg_contains!(
+            compile(unsafe_tokenize(vec![&format!(r#"{{ import "{}"; print 1; }}"#, path)]))
+                .unwrap_err().unwrap_single().get_message(),
+            "Can only import from top-level code."
+        )
+    }
+
+    #[test]
+    fn import_inside_if() {
+        assert_msg_contains!(
+            compile(unsafe_tokenize(vec![r#"if (true) import "foo.lox";"#]))
+                .unwrap_err().unwrap_single().get_message(),
+            "Unexpected 'Import'"
+        )
+    }
+
+    #[test]
+    fn missing_module() {
+        assert_msg_contains!(
+            compile(unsafe_tokenize(vec![r#"import "no/such/module.lox";"#]))
+                .unwrap_err().unwrap_single().get_message(),
+            "Can't open module 'no/such/module.lox'"
+        )
+    }
+
+    #[test]
+    fn module_without_a_valid_name_must_be_aliased() {
+        let path = write_module("module_without_a_valid_name_must_be_aliased", "my-util.lox", "");
+        assert_msg_contains!(
+            compile(unsafe_tokenize(vec![&format!(r#"import "{}";"#, path)]))
+                .unwrap_err().unwrap_single().get_message(),
+            "needs an alias, e.g., import"
+        )
+    }
+
+    #[test]
+    fn missing_named_module() {
+        let lib = write_module("missing_named_module", "other.lox", "");
+        let search_path = vec![PathBuf::from(lib).parent().unwrap().to_owned()];
+        let err =
+            compile_with_search_path(unsafe_tokenize(vec!["import nope;"]), search_path, false)
+                .unwrap_err();
+        assert_msg_contains!(
+            err.unwrap_single().get_message(),
+            "Can't find module 'nope' next to its importer or in the search path"
+        )
+    }
+
+    #[test]
+    fn circular_named_import() {
+        write_module("circular_named_import", "a.lox", "import b;");
+        let b = write_module("circular_named_import", "b.lox", "import a;");
+        let search_path = vec![PathBuf::from(b).parent().unwrap().to_owned()];
+        let err = compile_with_search_path(unsafe_tokenize(vec!["import a;"]), search_path, false)
+            .unwrap_err();
+        assert_msg_contains!(err.unwrap_single().get_message(), "Circular import of 'a.lox'")
+    }
+
+    #[test]
+    fn postfix_increment() {
+        assert_bytecode(
+            "var i = 0; i++;",
+            r#"
+00:  1 NUMBER         0
+01:  | DEFINE_GLOBAL  'i'
+02:  | GET_GLOBAL_IDX 0
+03:  | DUP
+04:  | NUMBER         1
+05:  | ADD
+06:  | SET_GLOBAL_IDX 0
+07:  | POP
+08:  | POP
+            "#,
+        )
+    }
+
+    #[test]
+    fn prefix_decrement() {
+        assert_bytecode(
+            "var i = 0; --i;",
+            r#"
+00:  1 NUMBER         0
+01:  | DEFINE_GLOBAL  'i'
+02:  | GET_GLOBAL_IDX 0
+03:  | NUMBER         1
+04:  | SUBTRACT
+05:  | SET_GLOBAL_IDX 0
+06:  | POP
+            "#,
+        )
+    }
+
+    #[test]
+    fn increment_non_lvalue() {
+        for code in ["1++;", "++1;", "(x)--;"] {
+            assert_msg_contains!(
+                compile(unsafe_tokenize(vec![code])).unwrap_err().unwrap_single().get_message(),
+                "Invalid increment target."
+            )
+        }
+    }
+
+    #[test]
+    fn for_in_range() {
+        assert_bytecode(
+            "for (i in 0..n + 1) print i;",
+            r#"
+00:  1 NUMBER         0
+01:  | GET_GLOBAL     'n'
+02:  | NUMBER         1
+03:  | ADD
+04:  | RANGE          ..
+05:  | ITERATE
+06:  | ITER_NEXT      0 11
+07:  | GET_LOCAL      1
+08:  | PRINT
+09:  | POP
+10:  | LOOP           4 (to 6)
+11:  | POP
+            "#,
+        )
+    }
+
+    #[test]
+    fn loops_jump_backward() {
+        assert_bytecode(
+            "while (x) print x; for (;; x = x + 1) print x;",
+            r#"
+00:  1 GET_GLOBAL     'x'
+01:  | JUMP_IF_FALSE  5
+02:  | GET_GLOBAL     'x'
+03:  | PRINT
+04:  | LOOP           4 (to 0)
+05:  | JUMP           12
+06:  | GET_GLOBAL     'x'
+07:  | NUMBER         1
+08:  | ADD
+09:  | SET_GLOBAL     'x'
+10:  | POP
+11:  | LOOP           6 (to 5)
+12:  | GET_GLOBAL     'x'
+13:  | PRINT
+14:  | LOOP           8 (to 6)
+            "#,
+        )
+    }
+
+    #[test]
+    fn list_literals_and_indexing() {
+        assert_bytecode(
+            "var l = [1, 2,]; l[0] = l[1];",
+            r#"
+00:  1 NUMBER         1
+01:  | NUMBER         2
+02:  | MAKE_LIST      2
+03:  | DEFINE_GLOBAL  'l'
+04:  | GET_GLOBAL_IDX 0
+05:  | NUMBER         0
+06:  | GET_GLOBAL_IDX 0
+07:  | NUMBER         1
+08:  | GET_INDEX
+09:  | SET_INDEX
+10:  | POP
+            "#,
+        )
+    }
+
+    #[test]
+    fn chained_comparison() {
+        assert_bytecode(
+            "print 1 < 2 <= 3;",
+            r#"
+00:  1 NUMBER         1
+01:  | NUMBER         2
+02:  | SWAP
+03:  | OVER
+04:  | LESS
+05:  | JUMP_IF_FALSE  10
+06:  | NUMBER         3
+07:  | GREATER
+08:  | NOT
+09:  | JUMP           12
+10:  | POP
+11:  | BOOL           false
+12:  | PRINT
+            "#,
+        )
+    }
+
+    #[test]
+    fn max_stack() {
+        let (chunk, _interned_strings) = unsafe_compile(vec![r#"
+fun trivial() { return 1; }
+fun nested(a, b) { return a + b * (a - b); }
+nested(1, 2);
+        "#]);
+        let max_stack = |i: usize| chunk.get_function(i).upgrade().unwrap().chunk.max_stack();
+        assert_eq!(max_stack(0), 1);
+        // The two parameters, and then a, b, a and b again before the subtraction.
+        assert_eq!(max_stack(1), 6);
+        // The callee and its two arguments.
+        assert_eq!(chunk.max_stack(), 3);
+    }
+
+    #[test]
+    fn returned_calls_are_tail_calls() {
+        let (chunk, _interned_strings) = unsafe_compile(vec![
+            "fun foo(x) { if (x) return foo(false); return x or foo(x); return foo(x) + 1; }"]);
+        let code = chunk.get_function(0).upgrade().unwrap().chunk.get_code().instructions().iter()
+            .map(|(op, _)| op.clone())
+            .collect::<Vec<_>>();
+        let calls = code.windows(2)
+            .filter(|ops| matches!(ops[0], OpCode::Call(_) | OpCode::TailCall(_)))
+            .map(|ops| (ops[0].clone(), ops[1].clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(calls, vec![
+            (OpCode::TailCall(1), OpCode::Return),
+            (OpCode::TailCall(1), OpCode::Return),
+            (OpCode::Call(1), OpCode::Number(1.0)),
+        ]);
+    }
+
+    #[test]
+    fn max_stack_ignores_unreachable_code() {
+        let (chunk, _interned_strings) =
+            unsafe_compile(vec!["fun foo(x) { return x; print 1 + (2 + 3); }"]);
+        assert_eq!(chunk.get_function(0).upgrade().unwrap().chunk.max_stack(), 2);
+    }
+
+    #[test]
+    fn logical_operators() {
+        assert_bytecode(
+            "print 1 and 2; print 1 or 2; print 1 xor 2;",
+            r#"
+00:  1 NUMBER         1
+01:  | DUP
+02:  | JUMP_IF_FALSE  5
+03:  | POP
+04:  | NUMBER         2
+05:  | PRINT
+06:  | NUMBER         1
+07:  | DUP
+08:  | JUMP_IF_FALSE  10
+09:  | JUMP           12
+10:  | POP
+11:  | NUMBER         2
+12:  | PRINT
+13:  | NUMBER         1
+14:  | NOT
+15:  | NUMBER         2
+16:  | NOT
+17:  | EQUALS
+18:  | NOT
+19:  | PRINT
+            "#,
+        )
+    }
+
+    #[test]
+    fn interpolated_strings() {
+        assert_bytecode(
+            r#"print "a${1}b${2}"; print "${nil}";"#,
+            r#"
+00:  1 STRING         'a'
+01:  | NUMBER         1
+02:  | TO_STRING
+03:  | ADD
+04:  | STRING         'b'
+05:  | ADD
+06:  | NUMBER         2
+07:  | TO_STRING
+08:  | ADD
+09:  | PRINT
+10:  | NIL
+11:  | TO_STRING
+12:  | PRINT
+            "#,
+        )
+    }
+
+    #[test]
+    fn unfinished_interpolated_expression() {
         assert_msg_contains!(
-            compile(unsafe_tokenize(vec![
-                "var a = 42;",
-                "{",
-                "  var a = a;",
-                "}",
-            ])).unwrap_err().unwrap_single().get_message(),
-            "uninitialized local variable"
+            compile(unsafe_tokenize(vec![r#"print "${1 2}";"#]))
+                .unwrap_err().unwrap_single().get_message(),
+            "Expected end of interpolated expression, got 'NumberLiteral(2.0)'"
         )
     }
 
     #[test]
-    fn local_variable_redeclaration() {
-        assert_msg_contains!(
-            compile(unsafe_tokenize(vec![
-                "{",
-                "  var a = 42;",
-                "  var a = 54;",
-                "}",
-            ])).unwrap_err().unwrap_single().get_message(),
-            "Redefined variable 'a' in same scope"
-        )
+    fn equal_string_literals_share_an_allocation() {
+        let (chunk, _interned_strings) = unsafe_compile(vec![r#"print "foo" == "foo" == "bar";"#]);
+        let strings: Vec<InternedString> = chunk.get_code().iter().flat_map(|(op, _)| match op {
+            OpCode::String(s) => Some(s.clone()),
+            _ => None,
+        }).collect();
+        assert_eq!(strings.len(), 3);
+        assert_eq!(strings[0], strings[1]);
+        assert_ne!(strings[0], strings[2]);
     }
 
     #[test]
@@ -999,11 +2432,30 @@ print x;"#,
             r#"
 00:  3 NUMBER         42
 01:  | DEFINE_GLOBAL  'x'
-02:  4 GET_GLOBAL     'x'
+02:  4 GET_GLOBAL_IDX 0
 03:  | PRINT"#,
         )
     }
 
+    #[test]
+    fn block_locals_are_popped_together() {
+        assert_bytecode(r#"
+{
+  var x = 1;
+  var y = 2;
+  var z = 3;
+}
+print 4;"#,
+                        r#"
+00:  2 NUMBER         1
+01:  3 NUMBER         2
+02:  4 NUMBER         3
+03:  5 POP_N          3
+04:  6 NUMBER         4
+05:  | PRINT"#,
+        )
+    }
+
     #[test]
     fn local_variable_order() {
         assert_bytecode(r#"
@@ -1074,6 +2526,115 @@ if (false) {
         )
     }
 
+    #[test]
+    fn empty_jumps_are_removed() {
+        assert_bytecode(
+            r#"
+if (true) print 1; else {}
+if (true) print 2;
+print 3;
+            "#,
+            r#"
+00:  1 BOOL           true
+01:  | JUMP_IF_FALSE  4
+02:  | NUMBER         1
+03:  | PRINT
+04:  2 BOOL           true
+05:  | JUMP_IF_FALSE  8
+06:  | NUMBER         2
+07:  | PRINT
+08:  3 NUMBER         3
+09:  | PRINT
+                "#,
+        )
+    }
+
+    #[test]
+    fn literals_are_pushed_without_a_constant_pool() {
+        assert_bytecode(
+            "print true; print false; print nil;",
+            r#"
+00:  1 BOOL           true
+01:  | PRINT
+02:  | BOOL           false
+03:  | PRINT
+04:  | NIL
+05:  | PRINT
+                "#,
+        )
+    }
+
+    #[test]
+    fn constant_global_initializers_are_folded() {
+        assert_bytecode(
+            r#"
+var TWO_PI = 2 * 3.5;
+var x = -(1 + 2) < 4 == !nil;
+var y = TWO_PI + 1;
+{
+  var z = 1 + 2;
+}
+            "#,
+            r#"
+00:  1 NUMBER         7
+01:  | DEFINE_GLOBAL  'TWO_PI'
+02:  2 BOOL           true
+03:  | DEFINE_GLOBAL  'x'
+04:  3 GET_GLOBAL_IDX 0
+05:  | NUMBER         1
+06:  | ADD
+07:  | DEFINE_GLOBAL  'y'
+08:  5 NUMBER         1
+09:  | NUMBER         2
+10:  | ADD
+11:  6 POP
+                "#,
+        )
+    }
+
+    #[test]
+    fn failing_constant_initializers_are_not_folded() {
+        assert_bytecode(
+            "var x = -nil;",
+            r#"
+00:  1 NIL
+01:  | NEGATE
+02:  | DEFINE_GLOBAL  'x'
+                "#,
+        )
+    }
+
+    #[test]
+    fn else_if_chains_are_flat() {
+        assert_bytecode(
+            r#"
+if (1) print 1;
+else if (2) print 2;
+else if (3) print 3;
+else print 4;
+            "#,
+            r#"
+00:  1 NUMBER         1
+01:  | JUMP_IF_FALSE  5
+02:  | NUMBER         1
+03:  | PRINT
+04:  2 JUMP           17
+05:  | NUMBER         2
+06:  | JUMP_IF_FALSE  10
+07:  | NUMBER         2
+08:  | PRINT
+09:  3 JUMP           17
+10:  | NUMBER         3
+11:  | JUMP_IF_FALSE  15
+12:  | NUMBER         3
+13:  | PRINT
+14:  4 JUMP           17
+15:  | NUMBER         4
+16:  | PRINT
+                "#,
+        )
+    }
+
     #[test]
     fn define_and_print_function() {
         assert_bytecode(
@@ -1086,7 +2647,7 @@ print areWeHavingItYet;
             r#"
 00:  1 FUNCTION       areWeHavingItYet []
 01:  | DEFINE_GLOBAL  'areWeHavingItYet'
-02:  4 GET_GLOBAL     'areWeHavingItYet'
+02:  4 GET_GLOBAL_IDX 0
 03:  | PRINT
 <fun areWeHavingItYet>
 00:  2 STRING         'Yes we are!'
@@ -1137,7 +2698,7 @@ areWeHavingItYet();
             r#"
 00:  1 FUNCTION       areWeHavingItYet []
 01:  | DEFINE_GLOBAL  'areWeHavingItYet'
-02:  4 GET_GLOBAL     'areWeHavingItYet'
+02:  4 GET_GLOBAL_IDX 0
 03:  | CALL           0
 04:  | POP
 <fun areWeHavingItYet>
@@ -1177,6 +2738,31 @@ fun areWeHavingItYet(x, y) {
         )
     }
 
+    #[test]
+    fn declared_globals_are_accessed_by_index() {
+        assert_bytecode(
+            r#"
+fun f() {
+  return g;
+}
+var g = 1;
+print g;
+            "#,
+            r#"
+00:  1 FUNCTION       f []
+01:  | DEFINE_GLOBAL  'f'
+02:  4 NUMBER         1
+03:  | DEFINE_GLOBAL  'g'
+04:  5 GET_GLOBAL_IDX 1
+05:  | PRINT
+<fun f>
+00:  2 GET_GLOBAL     'g'
+01:  | RETURN
+<end f>
+            "#,
+        )
+    }
+
     #[test]
     fn call_function_with_args() {
         assert_bytecode(
@@ -1196,9 +2782,9 @@ areWeHavingItYet(x, z);
 03:  | DEFINE_GLOBAL  'x'
 04:  6 NUMBER         12
 05:  | DEFINE_GLOBAL  'z'
-06:  7 GET_GLOBAL     'areWeHavingItYet'
-07:  | GET_GLOBAL     'x'
-08:  | GET_GLOBAL     'z'
+06:  7 GET_GLOBAL_IDX 0
+07:  | GET_GLOBAL_IDX 1
+08:  | GET_GLOBAL_IDX 2
 09:  | CALL           2
 10:  | POP
 <fun areWeHavingItYet>
@@ -1249,7 +2835,7 @@ print plus(10, 20);
             r#"
 00:  1 FUNCTION       plus []
 01:  | DEFINE_GLOBAL  'plus'
-02:  4 GET_GLOBAL     'plus'
+02:  4 GET_GLOBAL_IDX 0
 03:  | NUMBER         10
 04:  | NUMBER         20
 05:  | CALL           2
@@ -1292,6 +2878,62 @@ fun foo(x) {
         )
     }
 
+    #[test]
+    fn return_in_all_branches() {
+        assert_bytecode(
+            r#"
+fun foo(x) {
+  if (x > 0) return 1;
+  else return 2;
+}
+            "#,
+            r#"
+00:  1 FUNCTION       foo []
+01:  | DEFINE_GLOBAL  'foo'
+<fun foo>
+00:  2 GET_LOCAL      0
+01:  | NUMBER         0
+02:  | GREATER
+03:  | JUMP_IF_FALSE  6
+04:  | NUMBER         1
+05:  | RETURN
+06:  3 NUMBER         2
+07:  | RETURN
+<end foo>
+            "#,
+        )
+    }
+
+    #[test]
+    fn return_in_else_branch_only() {
+        // The then branch jumps past the else branch's return, so the implicit return is needed.
+        assert_bytecode(
+            r#"
+fun foo(x) {
+  if (x > 0) print 1;
+  else return 2;
+}
+            "#,
+            r#"
+00:  1 FUNCTION       foo []
+01:  | DEFINE_GLOBAL  'foo'
+<fun foo>
+00:  2 GET_LOCAL      0
+01:  | NUMBER         0
+02:  | GREATER
+03:  | JUMP_IF_FALSE  7
+04:  | NUMBER         1
+05:  | PRINT
+06:  3 JUMP           9
+07:  | NUMBER         2
+08:  | RETURN
+09:  4 NIL
+10:  | RETURN
+<end foo>
+            "#,
+        )
+    }
+
     #[test]
     fn basic_closure_local() {
         assert_bytecode(
@@ -1416,6 +3058,89 @@ fun foo(x) {
         )
     }
 
+    #[test]
+    fn by_value_captures_are_not_closed() {
+        assert_bytecode(
+            r#"
+{
+    var x = 1;
+    fun foo() use (x) {
+        print x;
+    }
+}
+           "#,
+            r#"
+00:  2 NUMBER         1
+01:  3 FUNCTION       foo [(0,t,v)]
+02:  6 POP_N          2
+<fun foo>
+00:  4 GET_UPVALUE    '0'
+01:  | PRINT
+02:  5 NIL
+03:  | RETURN
+<end foo>"#,
+        )
+    }
+
+    #[test]
+    fn discarded_bindings() {
+        assert_bytecode(
+            r#"
+var _ = 1;
+{
+  var _ = 2;
+  var _ = 3;
+}
+            "#,
+            r#"
+00:  1 NUMBER         1
+01:  | POP
+02:  3 NUMBER         2
+03:  4 NUMBER         3
+04:  5 POP_N          2
+                "#,
+        )
+    }
+
+    #[test]
+    fn discarded_bindings_cant_be_used() {
+        let error = |code: &str| compile(unsafe_tokenize(vec![code])).unwrap_err().head.get_message();
+        let expected = "'_' discards values, so it can't be used as a variable";
+        assert_eq!(error("var _ = 1; print _;"), expected);
+        assert_eq!(error("fun f(_) { return _; }"), expected);
+        assert_eq!(error("for (_ in 0..3) _ = 1;"), expected);
+    }
+
+    #[test]
+    fn trailing_commas() {
+        let disassembled = |code: &str| disassemble(&compile(unsafe_tokenize(vec![code])).unwrap().0);
+        assert_eq!(
+            disassembled("fun f(a, b,) { print a; } f(1, 2,);"),
+            disassembled("fun f(a, b) { print a; } f(1, 2);"),
+        );
+        assert_eq!(
+            disassembled("{ var x = 1; fun f() use (x,) { print x; } }"),
+            disassembled("{ var x = 1; fun f() use (x) { print x; } }"),
+        );
+    }
+
+    #[test]
+    fn doubled_trailing_commas() {
+        let error = |code: &str| compile(unsafe_tokenize(vec![code])).unwrap_err().head.get_message();
+        assert_eq!(error("fun f() {} f(1,,);"), "Unexpected 'Comma'");
+        assert_eq!(error("fun f(a,,) {}"), "Expected Identifier for variable, got 'Comma'");
+        assert_eq!(error("fun f() {} f(,);"), "Unexpected 'Comma'");
+    }
+
+    #[test]
+    fn capturing_a_global_by_value() {
+        assert_eq!(
+            compile(unsafe_tokenize(vec!["var x = 1; fun foo() use (x) { print x; }"]))
+                .unwrap_err().head.get_message(),
+            "Can only capture local variables by value, got 'x'",
+        );
+    }
+
     #[test]
     fn close_nested_upvalue() {
         assert_bytecode(
@@ -1473,7 +3198,7 @@ y = foo.x;
             r#"
 00:  1 CLASS          Foo
 01:  | DEFINE_GLOBAL  'Foo'
-02:  2 GET_GLOBAL     'Foo'
+02:  2 GET_GLOBAL_IDX 0
 03:  | CALL           0
 04:  | SET_GLOBAL     'foo'
 05:  | POP
@@ -1496,7 +3221,7 @@ foo.x = 3;
             r#"
 00:  1 CLASS          Foo
 01:  | DEFINE_GLOBAL  'Foo'
-02:  2 GET_GLOBAL     'Foo'
+02:  2 GET_GLOBAL_IDX 0
 03:  | CALL           0
 04:  | SET_GLOBAL     'foo'
 05:  | POP
@@ -1507,4 +3232,141 @@ foo.x = 3;
             "#,
         )
     }
+
+    #[test]
+    fn method_calls_are_invoked() {
+        assert_bytecode(
+            r#"
+foo.bar(1, 2);
+fun f() { return foo.bar(); }
+            "#,
+            r#"
+00:  1 GET_GLOBAL     'foo'
+01:  | NUMBER         1
+02:  | NUMBER         2
+03:  | INVOKE         bar 2
+04:  | POP
+05:  2 FUNCTION       f []
+06:  | DEFINE_GLOBAL  'f'
+<fun f>
+00:  2 GET_GLOBAL     'foo'
+01:  | TAIL_INVOKE    bar 0
+02:  | RETURN
+<end f>
+            "#,
+        )
+    }
+
+    #[test]
+    fn repl_mode_keeps_final_expression_on_stack() {
+        let compile_repl = |code: &str| {
+            let chunk = compile_in_mode(unsafe_tokenize(vec![code]), CompileMode::Repl).unwrap().0;
+            TRIMMER.replace_all(disassemble(&chunk).join("\n").trim(), "\n").into_owned()
+        };
+        let expected = "00:  1 NUMBER         1\n01:  | POP\n02:  | NUMBER         2";
+        assert_eq!(compile_repl("1; 2"), expected);
+        assert_eq!(compile_repl("1; 2;"), expected);
+        // Only top-level expressions are kept.
+        assert_eq!(
+            compile_repl("{ 1; }"),
+            "00:  1 NUMBER         1\n01:  | POP",
+        );
+    }
+
+    #[test]
+    fn diagnostics_span_the_offending_token() {
+        let diagnostics = diagnostics(unsafe_tokenize(vec!["var x = 1;", "print x 42;"]));
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic {
+                severity: Severity::Error,
+                message: "Expected Semicolon, but encountered NumberLiteral(42.0) at line 2".to_owned(),
+                line: 2,
+                col_start: 9,
+                col_end: 11,
+            }],
+        );
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "[line 2, columns 9-11] Error: Expected Semicolon, but encountered NumberLiteral(42.0) at line 2",
+        );
+        assert!(super::diagnostics(unsafe_tokenize(vec!["print 1;"])).is_empty());
+    }
+
+    #[test]
+    fn explicit_globals_inside_functions() {
+        assert_bytecode(
+            r#"
+fun foo() {
+    global x = 1;
+    x = 2;
+}
+           "#,
+            r#"
+00:  1 FUNCTION       foo []
+01:  | DEFINE_GLOBAL  'foo'
+<fun foo>
+00:  2 NUMBER         1
+01:  | DEFINE_GLOBAL  'x'
+02:  3 NUMBER         2
+03:  | SET_GLOBAL_IDX 1
+04:  | POP
+05:  4 NIL
+06:  | RETURN
+<end foo>"#,
+        )
+    }
+
+    #[test]
+    fn implicit_globals_inside_functions_are_warnings() {
+        let diagnostics = diagnostics(unsafe_tokenize(vec!["fun foo() {", "  x = 1;", "}"]));
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic {
+                severity: Severity::Warning,
+                message: "Assigning to undeclared 'x' implicitly creates a global; use 'global x = ...' instead".to_owned(),
+                line: 2,
+                col_start: 3,
+                col_end: 4,
+            }],
+        );
+        assert!(super::diagnostics(unsafe_tokenize(vec!["x = 1;"])).is_empty());
+        assert!(super::diagnostics(
+            unsafe_tokenize(vec!["var x;", "fun foo() { x = 1; global y = 2; y = 3; }"])).is_empty());
+    }
+
+    #[test]
+    fn globals_declared_after_the_function_are_not_warnings() {
+        assert!(super::diagnostics(
+            unsafe_tokenize(vec!["fun foo() { x = 1; }", "var x = 0;", "foo();"])).is_empty());
+        assert!(super::diagnostics(
+            unsafe_tokenize(vec!["fun foo() { bar(); }", "fun bar() { foo = nil; }"])).is_empty());
+    }
+
+    #[test]
+    fn global_is_only_a_keyword_before_an_identifier() {
+        assert_bytecode(
+            "var global = 1; global = 2;",
+            r#"
+00:  1 NUMBER         1
+01:  | DEFINE_GLOBAL  'global'
+02:  | NUMBER         2
+03:  | SET_GLOBAL_IDX 0
+04:  | POP"#,
+        )
+    }
+
+    #[test]
+    fn rendered_diagnostics_point_at_the_offending_token() {
+        let source = "var x = 1;\n{\n  print this;\n}";
+        let diagnostics = diagnostics(unsafe_tokenize(vec![source]));
+        assert_eq!(
+            render(diagnostics.unwrap_single(), source),
+            r#"error: Can't use 'this' outside of a class.
+  --> line 3, col 9
+3 |   print this;
+  |         ^^^^
+"#,
+        );
+    }
 }
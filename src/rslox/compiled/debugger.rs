@@ -0,0 +1,119 @@
+use std::collections::HashSet;
+use std::fmt::{Debug, Formatter};
+
+use crate::rslox::compiled::code::Line;
+use crate::rslox::compiled::value::Value;
+
+/// What the VM should do after reporting a [DebugEvent] to the debug hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DebugAction {
+    /// Runs until the next breakpoint. Until the hook says otherwise, this is what the VM does.
+    #[default]
+    Continue,
+    /// Stops at the next instruction.
+    Step,
+    /// Stops at the next instruction on another line, or in another call.
+    StepLine,
+    /// Returns control to the host without running the instruction, which is run once the host
+    /// resumes the VM, see `VirtualMachine::resume`.
+    Pause,
+}
+
+/// The instruction the VM stopped at, before running it.
+#[derive(Debug)]
+pub struct DebugEvent<'a> {
+    pub line: Line,
+    pub function: String,
+    /// The number of active calls, including the script itself, i.e., 1 for top level code.
+    pub depth: usize,
+    /// The values in the call's stack slots: its receiver and parameters, then the local variables
+    /// in scope, in declaration order, and finally any temporaries of the expression being
+    /// evaluated. Compiled code doesn't keep the names of locals, so only their values are known.
+    pub locals: &'a [Value],
+    /// The disassembled instruction, e.g., `GET_LOCAL      1`.
+    pub instruction: String,
+    /// False if the VM only stopped because it was stepping.
+    pub at_breakpoint: bool,
+}
+
+type DebugHook = Box<dyn FnMut(&DebugEvent) -> DebugAction>;
+
+// Decides which instructions are reported to the hook. Every instruction is checked when a hook is
+// set, so the events themselves are only built for the instructions the VM stops at.
+#[derive(Default)]
+pub struct Debugger {
+    hook: Option<DebugHook>,
+    breakpoints: HashSet<Line>,
+    // Never Pause, which sets paused instead.
+    mode: DebugAction,
+    paused: bool,
+    // Set when resuming, since the instruction the VM paused at was already reported.
+    resuming: bool,
+    // The line and call depth of the previous instruction, for noticing line changes.
+    position: Option<(Line, usize)>,
+}
+
+impl Debug for Debugger {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Debugger")
+            .field("attached", &self.hook.is_some())
+            .field("breakpoints", &self.breakpoints)
+            .field("mode", &self.mode)
+            .field("paused", &self.paused)
+            .finish()
+    }
+}
+
+impl Debugger {
+    pub fn set_hook(&mut self, hook: DebugHook) {
+        self.hook = Some(hook);
+        self.mode = DebugAction::Continue;
+    }
+    pub fn is_attached(&self) -> bool { self.hook.is_some() }
+    pub fn is_paused(&self) -> bool { self.paused }
+
+    pub fn add_breakpoint(&mut self, line: Line) { self.breakpoints.insert(line); }
+    pub fn remove_breakpoint(&mut self, line: Line) { self.breakpoints.remove(&line); }
+
+    // A new script starts over, even if it starts on the line the previous one ended on.
+    pub fn restart(&mut self) { self.position = None; }
+
+    // None if the VM shouldn't stop at the instruction, otherwise whether it's at a breakpoint.
+    // Breakpoints only stop the VM at the first instruction of their line.
+    pub fn stops_at(&mut self, line: Line, depth: usize) -> Option<bool> {
+        let is_new_line = self.position != Some((line, depth));
+        self.position = Some((line, depth));
+        if std::mem::take(&mut self.resuming) {
+            return None;
+        }
+        let at_breakpoint = is_new_line && self.breakpoints.contains(&line);
+        let stops = match self.mode {
+            DebugAction::Continue => at_breakpoint,
+            DebugAction::Step => true,
+            DebugAction::StepLine => is_new_line,
+            DebugAction::Pause => unreachable!("Pausing isn't a mode"),
+        };
+        stops.then_some(at_breakpoint)
+    }
+
+    // False if the hook paused the VM.
+    pub fn report(&mut self, event: &DebugEvent) -> bool {
+        let action = (self.hook.as_mut().expect("No debug hook"))(event);
+        self.apply(action);
+        !self.paused
+    }
+
+    pub fn resume(&mut self, action: DebugAction) {
+        self.paused = false;
+        self.resuming = true;
+        self.apply(action);
+    }
+
+    fn apply(&mut self, action: DebugAction) {
+        if action == DebugAction::Pause {
+            self.paused = true;
+        } else {
+            self.mode = action;
+        }
+    }
+}
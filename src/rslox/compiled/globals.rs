@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+
+use crate::rslox::common::utils::RcRc;
+use crate::rslox::compiled::memory::InternedString;
+use crate::rslox::compiled::op_code::GlobalIndex;
+use crate::rslox::compiled::value::{Mark, Value};
+
+/// Global variables are stored in slots, so that globals resolved at compile time can be accessed
+/// by index. The name-based accessors are used for forward references and everything else the
+/// compiler couldn't resolve, e.g., natives and globals implicitly created by assignment.
+/// Every module has its own globals, which share the same builtins, i.e., the natives.
+#[derive(Debug, Default)]
+pub struct Globals {
+    names: Vec<InternedString>,
+    values: Vec<Option<Value>>,
+    indices: HashMap<InternedString, GlobalIndex>,
+    // Looked up by name when the global itself isn't defined, so globals can shadow natives.
+    builtins: Option<RcRc<Globals>>,
+}
+
+impl Globals {
+    /// `names` is the compiler's global symbol table, i.e., the name of every pre-assigned slot.
+    pub fn new(names: &[InternedString], builtins: RcRc<Globals>) -> Self {
+        let mut result = Globals { builtins: Some(builtins), ..Default::default() };
+        result.declare(names);
+        result
+    }
+
+    /// Adds slots for the names which don't have one yet, e.g., the globals declared by another
+    /// script run in the same VM. Since new slots are appended, existing indices remain valid.
+    pub fn declare(&mut self, names: &[InternedString]) {
+        for name in names {
+            self.index_of(name);
+        }
+    }
+
+    pub fn names(&self) -> &[InternedString] { &self.names }
+
+    pub fn builtins(&self) -> Option<&RcRc<Globals>> { self.builtins.as_ref() }
+
+    pub fn get(&self, name: &InternedString) -> Option<Value> {
+        self.get_own(name).or_else(|| self.builtins.as_ref().and_then(|b| b.borrow().get(name)))
+    }
+    /// Like [Globals::get], but without falling back to the builtins.
+    pub fn get_own(&self, name: &InternedString) -> Option<Value> {
+        self.indices.get(name).and_then(|i| self.get_by_index(*i))
+    }
+    pub fn get_by_index(&self, index: GlobalIndex) -> Option<Value> {
+        self.values.get(index).cloned().flatten()
+    }
+
+    pub fn set(&mut self, name: &InternedString, value: Value) {
+        let index = self.index_of(name);
+        self.set_by_index(index, value)
+    }
+    pub fn set_by_index(&mut self, index: GlobalIndex, value: Value) {
+        self.values[index] = Some(value)
+    }
+
+    pub fn name(&self, index: GlobalIndex) -> &InternedString { &self.names[index] }
+
+    fn index_of(&mut self, name: &InternedString) -> GlobalIndex {
+        if let Some(index) = self.indices.get(name) {
+            return *index;
+        }
+        let index = self.names.len();
+        self.names.push(name.clone());
+        self.values.push(None);
+        self.indices.insert(name.clone(), index);
+        index
+    }
+}
+
+// The builtins aren't marked, since they're shared by all modules.
+impl Mark for Globals {
+    fn mark_shallow(&self, gray: &mut Vec<Value>) {
+        for name in self.names.iter() {
+            name.mark();
+        }
+        gray.extend(self.values.iter().flatten().cloned());
+    }
+}
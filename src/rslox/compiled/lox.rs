@@ -0,0 +1,341 @@
+use std::io::{Stdout, stdout, Write};
+
+pub use crate::rslox::common::error::{Diagnostic, LoxError, LoxResult, Severity};
+use crate::rslox::common::error::convert_error;
+use crate::rslox::common::lexer::tokenize;
+use crate::rslox::compiled::code::Line;
+use crate::rslox::compiled::compiler;
+use crate::rslox::compiled::compiler::compile_repl_input;
+pub use crate::rslox::compiled::debugger::{DebugAction, DebugEvent};
+pub use crate::rslox::compiled::natives::{NativeArity, NativeContext, NativeFunctionPtr};
+pub use crate::rslox::compiled::value::{TypeError, Value};
+pub use crate::rslox::compiled::memory::{GcPolicy, GcStats};
+use crate::rslox::compiled::vm::VirtualMachine;
+pub use crate::rslox::compiled::vm::VmOptions;
+
+/// For embedding Lox in Rust programs: each [Lox::eval] runs in the same VM, so the globals defined
+/// by one script can be used by the following ones, or read and written by the host using
+/// [Lox::get_global] and [Lox::set_global]. Values are converted to Rust types using their
+/// `TryFrom<&Value>` implementations, e.g., `f64::try_from(&value)`.
+///
+/// Note that values pointing to the VM's memory, e.g., strings and lists, are only guaranteed to
+/// stay valid until the next evaluation, since the garbage collector doesn't know about the values
+/// the host holds on to. Either convert them right away, or keep them in a global.
+pub struct Lox<W: Write = Stdout> {
+    vm: VirtualMachine<W>,
+    warnings: Vec<Diagnostic>,
+}
+
+/// The errors and warnings of compiling the source, without running it, e.g., for editors to
+/// underline, see [Diagnostic].
+pub fn diagnostics(source: &str) -> Vec<Diagnostic> {
+    match tokenize(source) {
+        Ok(tokens) => compiler::diagnostics(tokens),
+        // The lexer doesn't know the span of its errors, only their line.
+        Err(errors) => errors.iter().map(|e| Diagnostic {
+            severity: Severity::Error,
+            message: e.get_message(),
+            line: e.get_info().line,
+            col_start: 0,
+            col_end: 0,
+        }).collect(),
+    }
+}
+
+impl Lox<Stdout> {
+    pub fn new() -> Self { Lox::with_output(stdout()) }
+}
+
+impl Default for Lox<Stdout> {
+    fn default() -> Self { Lox::new() }
+}
+
+impl<W: Write> Lox<W> {
+    /// Program output, e.g., `print` statements, is written to `output`.
+    pub fn with_output(output: W) -> Self { Lox::with_options(output, VmOptions::default()) }
+
+    /// E.g., `VmOptions { catch_native_panics: true, ..Default::default() }` for hosts whose
+    /// natives might panic.
+    pub fn with_options(output: W, options: VmOptions) -> Self {
+        Lox { vm: VirtualMachine::session(output, options), warnings: Vec::new() }
+    }
+
+    /// The value of the source's final expression statement, which may omit its semicolon, e.g.,
+    /// `var x = 2; x * 21`. Sources which don't end with an expression evaluate to nil. All of the
+    /// source's errors are returned, e.g., every syntax error; the first one can be propagated
+    /// with `?` into a `Box<dyn Error>`, i.e., `lox.eval(source).map_err(|errors| errors.head)?`.
+    pub fn eval(&mut self, source: &str) -> LoxResult<Value> {
+        self.warnings.clear();
+        let tokens = tokenize(source)?;
+        let interned_strings = self.vm.interned_strings();
+        let (chunk, warnings) = compile_repl_input(
+            tokens, &self.vm.global_names(), &mut interned_strings.borrow_mut())?;
+        self.warnings = warnings;
+        let stack = convert_error(self.vm.run_next(chunk))?;
+        Ok(stack.last().map(|v| v.dereferenced()).unwrap_or(Value::Nil))
+    }
+
+    /// For debuggers, e.g., in editors: `hook` is called at breakpoints, and can then step through
+    /// the following instructions or lines, see [DebugAction]. [Lox::eval] returns nil if the hook
+    /// pauses the source, which [Lox::resume] then finishes.
+    pub fn set_debug_hook(&mut self, hook: impl FnMut(&DebugEvent) -> DebugAction + 'static) {
+        self.vm.set_debug_hook(hook)
+    }
+
+    pub fn add_breakpoint(&mut self, line: Line) { self.vm.add_breakpoint(line) }
+
+    pub fn remove_breakpoint(&mut self, line: Line) { self.vm.remove_breakpoint(line) }
+
+    pub fn is_paused(&self) -> bool { self.vm.is_paused() }
+
+    /// Continues the paused source as if the debug hook had returned `action`, and returns its
+    /// value like [Lox::eval].
+    pub fn resume(&mut self, action: DebugAction) -> LoxResult<Value> {
+        let stack = convert_error(self.vm.resume(action))?;
+        Ok(stack.last().map(|v| v.dereferenced()).unwrap_or(Value::Nil))
+    }
+
+    /// None if the global was never assigned.
+    pub fn get_global(&self, name: &str) -> Option<Value> { self.vm.get_global(name) }
+
+    pub fn set_global<V: Into<Value>>(&mut self, name: &str, value: V) {
+        self.vm.set_global(name, value.into())
+    }
+
+    /// A Lox string, since unlike numbers and booleans, strings are owned by the VM.
+    pub fn string(&mut self, str: &str) -> Value { self.vm.new_string(str) }
+
+    pub fn list(&mut self, elements: Vec<Value>) -> Value { self.vm.new_list(elements) }
+
+    /// Exposes a Rust function to the following sources as a global named `name`. It receives the
+    /// arguments the source called it with, and its errors become runtime errors at the call site.
+    pub fn define_native(&mut self, name: &str, arity: NativeArity, func: NativeFunctionPtr) {
+        self.vm.define_native(name, arity, func)
+    }
+
+    pub fn output(&self) -> &W { self.vm.output() }
+
+    /// Replaces [VmOptions::gc_policy], e.g., to collect less often once a host's setup is done.
+    pub fn set_gc_policy(&mut self, policy: GcPolicy) { self.vm.set_gc_policy(policy) }
+
+    pub fn gc_stats(&self) -> GcStats { self.vm.gc_stats() }
+
+    /// An estimate of the memory managed by the garbage collector, in bytes.
+    pub fn allocated_bytes(&self) -> usize { self.vm.allocated_bytes() }
+
+    /// The most values the VM's stack held so far, see [VmOptions::stack_capacity].
+    pub fn max_stack_depth(&self) -> usize { self.vm.max_stack_depth() }
+
+    /// The warnings of the last [Lox::eval], which doesn't fail because of them, e.g., for
+    /// assigning to undeclared globals in functions.
+    pub fn warnings(&self) -> &[Diagnostic] { &self.warnings }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use crate::rslox::common::utils::SliceExt;
+
+    use super::*;
+
+    #[test]
+    fn eval_returns_the_final_expression() {
+        let mut lox = Lox::with_output(Vec::new());
+        assert_eq!(lox.eval("var x = 2; x * 21").unwrap(), Value::Number(42.0));
+        assert_eq!(lox.eval("x + 1;").unwrap(), Value::Number(3.0));
+        assert_eq!(lox.eval("print x;").unwrap(), Value::Nil);
+        assert_eq!(String::from_utf8(lox.output().clone()).unwrap(), "2\n");
+    }
+
+    #[test]
+    fn globals_are_shared_with_the_host() {
+        let mut lox = Lox::with_output(Vec::new());
+        lox.set_global("limit", 3.0);
+        lox.set_global("verbose", true);
+        let greeting = lox.string("hi");
+        lox.set_global("greeting", greeting);
+        lox.eval("var total = 0; for (i in 0..limit) total = total + i;").unwrap();
+        lox.eval("var message; if (verbose) message = greeting + \" there\";").unwrap();
+        let total = lox.get_global("total").unwrap();
+        assert_eq!(f64::try_from(&total), Ok(3.0));
+        let message = lox.get_global("message").unwrap();
+        assert_eq!(String::try_from(&message), Ok("hi there".to_owned()));
+        assert_eq!(lox.get_global("nope"), None);
+    }
+
+    #[test]
+    fn lists_convert_to_vecs() {
+        let mut lox = Lox::with_output(Vec::new());
+        let list = lox.eval("[1, 2, 3]").unwrap();
+        assert_eq!(Vec::<f64>::try_from(&list), Ok(vec![1.0, 2.0, 3.0]));
+        let nested = lox.eval("[[\"a\"], [], [\"b\", \"c\"]]").unwrap();
+        assert_eq!(
+            Vec::<Vec<String>>::try_from(&nested),
+            Ok(vec![vec!["a".to_owned()], vec![], vec!["b".to_owned(), "c".to_owned()]]),
+        );
+        let mixed = lox.eval("[1, true]").unwrap();
+        assert_eq!(
+            Vec::<f64>::try_from(&mixed).unwrap_err().to_string(),
+            "Expected Value::Number, but found Bool(true)",
+        );
+        let elements = vec![Value::Number(1.0), Value::Number(2.0)];
+        let host_list = lox.list(elements);
+        lox.set_global("xs", host_list);
+        assert_eq!(lox.eval("xs[0] + xs[1]").unwrap(), Value::Number(3.0));
+    }
+
+    #[test]
+    fn strings_are_indexed_by_chars() {
+        let mut lox = Lox::with_output(Vec::new());
+        let word = lox.string("héllo");
+        lox.set_global("word", word);
+        assert_eq!(lox.eval("len(word)").unwrap(), Value::Number(5.0));
+        let part = lox.eval("substring(word, 1, 3)").unwrap();
+        assert_eq!(String::try_from(&part), Ok("él".to_owned()));
+    }
+
+    #[test]
+    fn paused_sources_are_finished_by_resuming() {
+        let mut lox = Lox::with_output(Vec::new());
+        lox.set_debug_hook(|event| {
+            assert_eq!(event.line, 2);
+            DebugAction::Pause
+        });
+        lox.add_breakpoint(2);
+        assert_eq!(lox.eval("var x = 20;\nprint x;\nx * 2 + 2").unwrap(), Value::Nil);
+        assert!(lox.is_paused());
+        assert_eq!(lox.output(), b"");
+        assert_eq!(lox.resume(DebugAction::Continue).unwrap(), Value::Number(42.0));
+        assert!(!lox.is_paused());
+        assert_eq!(String::from_utf8(lox.output().clone()).unwrap(), "20\n");
+    }
+
+    #[test]
+    fn hosts_can_define_natives() {
+        use crate::{Lox, NativeArity, NativeContext, Value};
+
+        fn shout(context: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+            match String::try_from(&args[0]) {
+                Ok(s) => Ok(context.string(&(s.to_uppercase() + "!"))),
+                Err(_) => Err(format!("shout() expects a string, got {}", args[0].stringify())),
+            }
+        }
+
+        let mut lox = Lox::with_output(Vec::new());
+        lox.define_native("shout", NativeArity::Exactly(1), shout);
+        let shouted = lox.eval("shout(\"hi\")").unwrap();
+        assert_eq!(String::try_from(&shouted), Ok("HI!".to_owned()));
+        let errors = lox.eval("shout(1)").unwrap_err();
+        assert_eq!(errors.head.get_message(), "shout() expects a string, got 1");
+        assert_eq!(lox.eval("arity(shout)").unwrap(), Value::Number(1.0));
+    }
+
+    #[test]
+    fn native_panics_can_be_caught() {
+        use crate::{Lox, NativeArity, NativeContext, Value, VmOptions};
+
+        fn explode(_: &mut NativeContext, _: &[Value]) -> Result<Value, String> { panic!("boom") }
+
+        let options = VmOptions { catch_native_panics: true, ..Default::default() };
+        let mut lox = Lox::with_options(Vec::new(), options);
+        lox.define_native("explode", NativeArity::Exactly(0), explode);
+        let errors = lox.eval("explode()").unwrap_err();
+        assert_eq!(errors.head.get_message(), "Native function explode() panicked: boom");
+        assert_eq!(lox.eval("1 + 1").unwrap(), Value::Number(2.0));
+    }
+
+    #[test]
+    fn time_natives_are_enabled_by_an_option() {
+        use crate::{Lox, Value, VmOptions};
+
+        assert!(Lox::new().eval("time()").is_err());
+        let options = VmOptions { time_natives: true, ..Default::default() };
+        let mut lox = Lox::with_options(Vec::new(), options);
+        assert_eq!(lox.eval("sleep(0)").unwrap(), Value::Nil);
+    }
+
+    #[test]
+    fn warnings_are_kept_until_the_next_eval() {
+        use crate::{Lox, Severity};
+
+        let mut lox = Lox::with_output(Vec::new());
+        lox.eval("fun f() { x = 1; }").unwrap();
+        let warning = lox.warnings().unwrap_single();
+        assert_eq!(warning.severity, Severity::Warning);
+        assert_eq!(warning.line, 1);
+        lox.eval("f();").unwrap();
+        assert!(lox.warnings().is_empty());
+    }
+
+    #[test]
+    fn errors_can_be_propagated_as_std_errors() {
+        use std::error::Error;
+
+        use crate::{Lox, Value};
+
+        fn eval(lox: &mut Lox<Vec<u8>>, source: &str) -> Result<f64, Box<dyn Error>> {
+            let value = lox.eval(source).map_err(|errors| errors.head)?;
+            Ok(f64::try_from(&value)?)
+        }
+
+        let mut lox = Lox::with_output(Vec::new());
+        assert_eq!(eval(&mut lox, "1 + 1").unwrap(), 2.0);
+        assert_eq!(
+            eval(&mut lox, "1 +").unwrap_err().to_string(),
+            "[line 1] Error: Expected expression, but encountered end of file",
+        );
+        lox.set_global("b", Value::Bool(true));
+        assert_eq!(
+            eval(&mut lox, "b").unwrap_err().to_string(),
+            "Expected Value::Number, but found Bool(true)",
+        );
+    }
+
+    #[test]
+    fn diagnostics_of_sources() {
+        use crate::{diagnostics, Severity};
+
+        let missing_semicolon = diagnostics("var x = 1;\nprint x 42;");
+        let error = missing_semicolon.unwrap_single();
+        assert_eq!(error.severity, Severity::Error);
+        assert_eq!((error.line, error.col_start, error.col_end), (2, 9, 11));
+        let unterminated = diagnostics("print 1;\n\"abc");
+        assert_eq!(unterminated.unwrap_single().line, 2);
+        assert_eq!(unterminated.unwrap_single().col_start, 0);
+        assert_eq!(diagnostics("\"abc").unwrap_single().message, "Unterminated string.");
+        assert!(diagnostics("print 1;").is_empty());
+    }
+
+    #[test]
+    fn garbage_collection_can_be_tuned() {
+        use crate::{GcPolicy, Lox};
+
+        let mut lox = Lox::with_output(Vec::new());
+        lox.set_gc_policy(GcPolicy { initial_threshold: usize::MAX, ..GcPolicy::default() });
+        let before = lox.allocated_bytes();
+        lox.eval("for (var i = 0; i < 100; i = i + 1) { var garbage = [[i], {}]; }").unwrap();
+        let grown = lox.allocated_bytes();
+        assert!(grown > before, "{} <= {}", grown, before);
+        assert_eq!(lox.gc_stats().collections, 0);
+
+        lox.set_gc_policy(GcPolicy::default());
+        lox.eval("nil").unwrap();
+        assert!(lox.gc_stats().collections > 0);
+        assert!(lox.allocated_bytes() < grown, "{} >= {}", lox.allocated_bytes(), grown);
+        assert!(lox.max_stack_depth() > 0);
+    }
+
+    #[test]
+    fn errors_are_reported_with_their_line() {
+        let mut lox = Lox::with_output(Vec::new());
+        let errors = lox.eval("var x = 1;\nvar = 2;").unwrap_err();
+        assert_eq!(errors.head.get_info().line, 2);
+        let errors = lox.eval("fun f() {\n  return -nil;\n}\nf();").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors.head.get_info().line, 2);
+        assert_eq!(errors.head.get_message(), "Expected Value::Number, but found Nil (Negate)");
+        // Failed evaluations don't affect the following ones.
+        assert_eq!(lox.eval("1 + 1").unwrap(), Value::Number(2.0));
+    }
+}
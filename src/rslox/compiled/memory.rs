@@ -1,6 +1,7 @@
 use std::cell::{Ref, RefCell, RefMut};
 use std::fmt::{Debug, Formatter};
 use std::hash::{Hash, Hasher};
+use std::mem;
 use std::ops::{Deref, DerefMut};
 use std::rc::{Rc, Weak};
 
@@ -52,6 +53,8 @@ pub struct Pointer<A>(Weak<RefCell<(A, IsUsed, Visited)>>);
 
 impl<A> Pointer<A> {
     pub fn null() -> Self { Pointer(Weak::new()) }
+    /// Whether the pointed value hasn't been collected yet.
+    pub fn is_alive(&self) -> bool { self.0.strong_count() > 0 }
     pub fn apply<B, F: FnOnce(&A) -> B>(&self, func: F) -> B {
         func(&self.unwrap_upgrade().borrow().0)
     }
@@ -119,6 +122,8 @@ impl<A: Debug> Debug for Pointer<A> {
     }
 }
 
+// Compares identity, not values. For InternedString the two are the same, since InternedStrings
+// keeps a single allocation per distinct string, so string equality never compares contents.
 impl<A> PartialEq for Pointer<A> {
     fn eq(&self, other: &Self) -> bool {
         assert!(self.0.upgrade().is_some());
@@ -133,23 +138,100 @@ impl<A> Hash for Pointer<A> {
     fn hash<H: Hasher>(&self, state: &mut H) { self.0.as_ptr().hash(state) }
 }
 
+/// The bytes a managed value owns outside of its own allocation, e.g., a list's elements.
+pub trait HeapSize {
+    fn owned_bytes(&self) -> usize { 0 }
+}
+
 #[derive(Debug)]
-pub struct Heap<A> (Vec<Managed<A>>);
+pub struct Heap<A> {
+    values: Vec<Managed<A>>,
+    bytes: usize,
+}
 
-impl<A> Heap<A> {
+impl<A: HeapSize> Heap<A> {
     pub fn push(&mut self, a: A) -> Pointer<A> { self.own(Managed::new(a)) }
 
     pub fn own(&mut self, managed: Managed<A>) -> Pointer<A> {
+        self.bytes += Heap::size(&managed);
         let ptr = managed.ptr();
-        self.0.push(managed);
+        self.values.push(managed);
         ptr
     }
 
     pub fn sweep(&mut self) {
-        self.0.retain(|m| m.get_and_reset_mark())
+        self.values.retain(|m| m.get_and_reset_mark());
+        self.bytes = self.values.iter().map(Heap::size).sum();
     }
 
-    pub fn is_empty(&self) -> bool { self.0.is_empty() }
+    fn size(managed: &Managed<A>) -> usize { managed_size::<A>() + managed.as_ref().owned_bytes() }
+}
+
+impl<A> Heap<A> {
+    pub fn is_empty(&self) -> bool { self.values.is_empty() }
+
+    /// An estimate, which is only updated on allocation and on sweep. So, e.g., the fields set on
+    /// an instance after its creation are only counted once it survives a collection.
+    pub fn allocated_bytes(&self) -> usize { self.bytes }
+}
+
+impl<A> Default for Heap<A> { fn default() -> Self { Heap { values: Vec::new(), bytes: 0 } } }
+
+pub fn managed_size<A>() -> usize { mem::size_of::<(A, IsUsed, Visited)>() }
+
+/// Garbage is collected once the managed memory reaches a threshold, which is then reset to
+/// `growth_factor` times the memory that survived the collection, but never below
+/// `initial_threshold`. The defaults (zero for both) collect at every opportunity, which is great
+/// for catching GC bugs, if not for performance.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GcPolicy {
+    pub initial_threshold: usize,
+    pub growth_factor: f64,
+    /// Adds opportunities: garbage is collected before every instruction, and therefore before
+    /// every allocation, instead of only between calls. Thresholds are ignored.
+    pub stress: bool,
+}
+
+impl GcPolicy {
+    pub const STRESS: GcPolicy =
+        GcPolicy { initial_threshold: 0, growth_factor: 0.0, stress: true };
+}
+
+/// See [GcPolicy].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub collections: usize,
 }
 
-impl<A> Default for Heap<A> { fn default() -> Self { Heap(Vec::new()) } }
+#[derive(Debug)]
+pub struct GcState {
+    policy: GcPolicy,
+    threshold: usize,
+    stats: GcStats,
+}
+
+impl GcState {
+    pub fn new(policy: GcPolicy) -> Self {
+        GcState { policy, threshold: policy.initial_threshold, stats: GcStats::default() }
+    }
+
+    pub fn should_collect(&self, allocated_bytes: usize) -> bool {
+        self.policy.stress || allocated_bytes >= self.threshold
+    }
+
+    pub fn is_stressed(&self) -> bool { self.policy.stress }
+
+    pub fn collected(&mut self, surviving_bytes: usize) {
+        self.stats.collections += 1;
+        let grown = (surviving_bytes as f64 * self.policy.growth_factor) as usize;
+        self.threshold = grown.max(self.policy.initial_threshold);
+    }
+
+    pub fn stats(&self) -> GcStats { self.stats }
+
+    /// Takes effect right away: the new initial threshold applies until the next collection.
+    pub fn set_policy(&mut self, policy: GcPolicy) {
+        self.policy = policy;
+        self.threshold = policy.initial_threshold;
+    }
+}
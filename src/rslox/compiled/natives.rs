@@ -0,0 +1,469 @@
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::rslox::common::utils::rcrc;
+use crate::rslox::compiled::compiler::InternedStrings;
+use crate::rslox::compiled::memory::Heap;
+use crate::rslox::compiled::op_code::ArgCount;
+use crate::rslox::compiled::value::{List, Memoized, Table, TableKey, Value};
+
+/// Native functions receive their arguments with all [Value::UpvaluePtr]s already dereferenced.
+/// Returned errors are converted by the VM into runtime errors at the call site.
+pub type NativeFunctionPtr = fn(&mut NativeContext, &[Value]) -> Result<Value, String>;
+
+/// The parts of the VM a native function is allowed to touch.
+pub struct NativeContext<'a> {
+    pub interned_strings: &'a mut InternedStrings,
+    pub lists: &'a mut Heap<List>,
+    pub tables: &'a mut Heap<Table>,
+}
+
+impl NativeContext<'_> {
+    /// A string owned by the VM, e.g., for returning one from a host's native.
+    pub fn string(&mut self, str: &str) -> Value {
+        Value::String(self.interned_strings.intern_string(str.to_owned()))
+    }
+
+    pub fn list(&mut self, elements: Vec<Value>) -> Value {
+        Value::List(self.lists.push(elements.into()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NativeArity {
+    Exactly(ArgCount),
+    // Variadic natives, which receive all the arguments they were called with.
+    AtLeast(ArgCount),
+}
+
+impl NativeArity {
+    pub fn accepts(&self, arg_count: ArgCount) -> bool {
+        match self {
+            NativeArity::Exactly(n) => arg_count == *n,
+            NativeArity::AtLeast(n) => arg_count >= *n,
+        }
+    }
+
+    /// The number of required arguments.
+    pub fn required(&self) -> ArgCount {
+        match self {
+            NativeArity::Exactly(n) | NativeArity::AtLeast(n) => *n,
+        }
+    }
+}
+
+impl Display for NativeArity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NativeArity::Exactly(n) => write!(f, "{}", n),
+            NativeArity::AtLeast(n) => write!(f, "at least {}", n),
+        }
+    }
+}
+
+/// The natives every VM starts with.
+pub fn standard_natives() -> Vec<(&'static str, NativeArity, NativeFunctionPtr)> {
+    use NativeArity::{AtLeast, Exactly};
+    vec![
+        ("arity", Exactly(1), arity),
+        ("name", Exactly(1), name),
+        ("len", Exactly(1), len),
+        ("keys", Exactly(1), keys),
+        ("values", Exactly(1), values),
+        ("has", Exactly(2), has),
+        ("contains", Exactly(2), contains),
+        ("freeze", Exactly(1), freeze),
+        ("withCapacity", Exactly(1), with_capacity),
+        ("capacity", Exactly(1), capacity),
+        ("enumerate", Exactly(1), enumerate),
+        ("substring", Exactly(3), substring),
+        ("join", Exactly(2), join),
+        ("bytesOf", Exactly(1), bytes_of),
+        ("stringOf", Exactly(1), string_of),
+        ("weakref", Exactly(1), weakref),
+        ("deref", Exactly(1), deref),
+        ("assertEquals", Exactly(2), assert_equals),
+        ("repr", Exactly(1), repr),
+        ("min", AtLeast(2), min),
+        ("max", AtLeast(2), max),
+        ("clamp", Exactly(3), clamp),
+        ("hash", Exactly(1), hash),
+        ("format", AtLeast(1), format),
+        ("memoize", Exactly(1), memoize),
+        ("toInt", Exactly(1), to_int),
+        ("toFloat", Exactly(1), to_float),
+        ("toBool", Exactly(1), to_bool),
+        ("clock", Exactly(0), clock),
+    ]
+}
+
+/// Natives which depend on, or block on, the wall clock. Since `sleep` blocks the whole VM, hosts
+/// have to opt into these, see `VmOptions::time_natives`.
+pub fn time_natives() -> Vec<(&'static str, NativeArity, NativeFunctionPtr)> {
+    vec![
+        ("time", NativeArity::Exactly(0), time),
+        ("sleep", NativeArity::Exactly(1), sleep),
+    ]
+}
+
+fn arity(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Closure(..) =>
+            Ok(Value::Number(args[0].try_into_closure()?.0.upgrade().unwrap().arity as f64)),
+        Value::Native(n) => Ok(Value::Number(n.arity.required() as f64)),
+        Value::Memoized(m) => Ok(Value::Number(m.closure().0.upgrade().unwrap().arity as f64)),
+        Value::BoundMethod(b) => Ok(Value::Number(b.method.function().arity as f64)),
+        // Classes take the arguments of their initializer.
+        Value::Class(c) => Ok(Value::Number(
+            c.upgrade().unwrap().initializer().map_or(0, |init| init.function().arity) as f64)),
+        e => Err(format!("arity() expects a function, got {}", e.stringify())),
+    }
+}
+
+fn name(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Closure(..) => Ok(Value::String(
+            args[0].try_into_closure()?.0.upgrade().unwrap().name.clone())),
+        Value::Native(n) => Ok(Value::String(n.name.clone())),
+        Value::Memoized(m) => Ok(Value::String(m.closure().0.upgrade().unwrap().name.clone())),
+        Value::BoundMethod(b) => Ok(Value::String(b.method.function().name.clone())),
+        Value::Class(c) => Ok(Value::String(c.upgrade().unwrap().name.clone())),
+        e => Err(format!("name() expects a function, got {}", e.stringify())),
+    }
+}
+
+/// Strings are measured in characters, not bytes; see bytesOf for the latter.
+fn len(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::String(s) => Ok(Value::Number(s.apply(|s| s.chars().count()) as f64)),
+        Value::Range(r) => r.len()
+            .map(|len| Value::Number(len as f64))
+            .ok_or_else(|| format!("len() of {} is too large", r.stringify())),
+        Value::Bytes(b) => Ok(Value::Number(b.borrow().len() as f64)),
+        Value::List(l) => Ok(Value::Number(l.apply(|l| l.len()) as f64)),
+        Value::Table(t) => Ok(Value::Number(t.apply(|t| t.len()) as f64)),
+        e => Err(format!(
+            "len() expects a string, a range, bytes, a list or a table, got {}", e.stringify())),
+    }
+}
+
+/// The characters of the string from `start` up to, but not including, `end`, counted like len().
+fn substring(context: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    let string = match &args[0] {
+        Value::String(s) => s.to_owned(),
+        e => return Err(format!("substring() expects a string, got {}", e.stringify())),
+    };
+    let len = string.chars().count();
+    let index = |value: &Value| match value {
+        Value::Number(n) if n.fract() == 0.0 && *n >= 0.0 && *n <= len as f64 => Ok(*n as usize),
+        e => Err(format!(
+            "substring() indices must be integers between 0 and {}, got {}", len, e.stringify())),
+    };
+    let (start, end) = (index(&args[1])?, index(&args[2])?);
+    if start > end {
+        return Err(format!("substring() start {} is after its end {}", start, end));
+    }
+    let result = string.chars().skip(start).take(end - start).collect();
+    Ok(Value::String(context.interned_strings.intern_string(result)))
+}
+
+/// The list's strings, with the separator between each two, e.g., join(["a", "b"], "-") is "a-b".
+/// Other elements are errors rather than stringified, since the result would depend on how they
+/// print, e.g., use interpolation to join numbers.
+fn join(context: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    let separator = match &args[1] {
+        Value::String(s) => s.to_owned(),
+        e => return Err(format!("join() separator must be a string, got {}", e.stringify())),
+    };
+    let strings = match &args[0] {
+        Value::List(l) => l.apply(|l| l.iter()
+            .map(|e| match e {
+                Value::String(s) => Ok(s.to_owned()),
+                e => Err(format!("join() expects a list of strings, got {}", e.stringify())),
+            })
+            .collect::<Result<Vec<_>, _>>())?,
+        e => return Err(format!("join() expects a list, got {}", e.stringify())),
+    };
+    Ok(context.string(&strings.join(&separator)))
+}
+
+/// A new list of the table's keys, in insertion order.
+fn keys(context: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Table(t) => {
+            let keys = t.apply(|t| t.keys().map(|k| k.to_value()).collect());
+            Ok(Value::List(context.lists.push(keys)))
+        }
+        e => Err(format!("keys() expects a table, got {}", e.stringify())),
+    }
+}
+
+/// A new list of the table's values, in insertion order, so they line up with keys().
+fn values(context: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Table(t) => {
+            let values = t.apply(|t| t.values().cloned().collect());
+            Ok(Value::List(context.lists.push(values)))
+        }
+        e => Err(format!("values() expects a table, got {}", e.stringify())),
+    }
+}
+
+/// Whether the table has the key, even if its value is nil. Keys are equal like in tables, see
+/// [TableKey].
+fn has(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    let key = TableKey::new(&args[1]).map_err(|e| format!("has() key {}", e))?;
+    match &args[0] {
+        Value::Table(t) => Ok(Value::Bool(t.apply(|t| t.contains(&key)))),
+        e => Err(format!("has() expects a table, got {}", e.stringify())),
+    }
+}
+
+/// Whether the list has an element equal to the value, using the same equality as `==`. So nested
+/// lists and tables are compared by identity, e.g., contains([[1]], [1]) is false.
+fn contains(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::List(l) => Ok(Value::Bool(l.apply(|l| l.contains(&args[1])))),
+        e => Err(format!("contains() expects a list, got {}", e.stringify())),
+    }
+}
+
+/// Makes a list or a table immutable, and returns it, e.g., `var primes = freeze([2, 3, 5]);`.
+/// Only the collection itself is frozen, not the collections it contains.
+fn freeze(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::List(l) => l.clone().mutate(|l| l.freeze()),
+        Value::Table(t) => t.clone().mutate(|t| t.freeze()),
+        e => return Err(format!("freeze() expects a list or a table, got {}", e.stringify())),
+    }
+    Ok(args[0].clone())
+}
+
+/// A new empty table with room for at least the given number of keys, so filling it doesn't have to
+/// grow it along the way. The capacity is only a hint, see capacity().
+fn with_capacity(context: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    let capacity = match &args[0] {
+        Value::Number(n) if n.fract() == 0.0 && *n >= 0.0 => *n as usize,
+        e => return Err(format!(
+            "withCapacity() expects a non-negative integer, got {}", e.stringify())),
+    };
+    let table = Table::with_capacity(capacity)
+        .map_err(|_| format!("withCapacity() can't make room for {} keys", capacity))?;
+    Ok(Value::Table(context.tables.push(table)))
+}
+
+/// The number of keys the table can hold before it grows. It's at least the capacity the table was
+/// made with, but can be more, and how much more depends on the host.
+fn capacity(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Table(t) => Ok(Value::Number(t.apply(|t| t.capacity()) as f64)),
+        e => Err(format!("capacity() expects a table, got {}", e.stringify())),
+    }
+}
+
+/// A new list of `[index, element]` pairs, e.g., for iterating over a list along with its indices.
+fn enumerate(context: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::List(l) => {
+            let elements = l.apply(|l| l.to_vec());
+            let pairs = elements.into_iter().enumerate()
+                .map(|(i, e)| context.list(vec![Value::Number(i as f64), e]))
+                .collect();
+            Ok(context.list(pairs))
+        }
+        e => Err(format!("enumerate() expects a list, got {}", e.stringify())),
+    }
+}
+
+/// The UTF-8 encoding of a string.
+fn bytes_of(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::String(s) => Ok(Value::Bytes(rcrc(s.to_owned().into_bytes()))),
+        e => Err(format!("bytesOf() expects a string, got {}", e.stringify())),
+    }
+}
+
+fn string_of(context: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Bytes(b) => {
+            let string = String::from_utf8(b.borrow().clone())
+                .map_err(|e| format!("stringOf() got invalid UTF-8: {}", e))?;
+            Ok(Value::String(context.interned_strings.intern_string(string)))
+        }
+        e => Err(format!("stringOf() expects bytes, got {}", e.stringify())),
+    }
+}
+
+fn weakref(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Instance(i) => Ok(Value::WeakRef(i.clone())),
+        e => Err(format!("weakref() expects an instance, got {}", e.stringify())),
+    }
+}
+
+/// Returns nil if the instance has already been collected.
+fn deref(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::WeakRef(i) if i.is_alive() => Ok(Value::Instance(i.clone())),
+        Value::WeakRef(_) => Ok(Value::Nil),
+        e => Err(format!("deref() expects a weakref, got {}", e.stringify())),
+    }
+}
+
+/// Like stringify, but strings are quoted, with special characters escaped.
+fn repr(context: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    let result = match &args[0] {
+        Value::String(s) => format!("{:?}", s.to_owned()),
+        e => e.stringify(),
+    };
+    Ok(Value::String(context.interned_strings.intern_string(result)))
+}
+
+fn assert_equals(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    let (expected, actual) = (&args[0], &args[1]);
+    if expected == actual {
+        Ok(Value::Nil)
+    } else {
+        Err(format!(
+            "assertEquals failed: expected {}, got {}", expected.stringify(), actual.stringify()))
+    }
+}
+
+fn numbers(native: &str, args: &[Value]) -> Result<Vec<f64>, String> {
+    args.iter().map(|arg| match arg {
+        Value::Number(n) => Ok(*n),
+        e => Err(format!("{}() expects numbers, got {}", native, e.stringify())),
+    }).collect()
+}
+
+fn min(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(numbers("min", args)?.into_iter().reduce(f64::min).unwrap()))
+}
+
+fn max(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(numbers("max", args)?.into_iter().reduce(f64::max).unwrap()))
+}
+
+/// clamp(x, lo, hi) is lo if x < lo, hi if x > hi, and x otherwise.
+fn clamp(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    let numbers = numbers("clamp", args)?;
+    let (x, lo, hi) = (numbers[0], numbers[1], numbers[2]);
+    // f64::clamp panics on both.
+    if lo.is_nan() || hi.is_nan() {
+        return Err("clamp() bounds can't be NaN".to_owned());
+    }
+    if lo > hi {
+        return Err(format!(
+            "clamp() expects lo <= hi, got {} > {}", args[1].stringify(), args[2].stringify()));
+    }
+    Ok(Value::Number(x.clamp(lo, hi)))
+}
+
+/// format(template, args...) replaces every `{}` in the template with the next stringified argument,
+/// in order. `{{` and `}}` are literal braces.
+fn format(context: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    let template = match &args[0] {
+        Value::String(s) => s.to_owned(),
+        e => return Err(format!("format() expects a string template, got {}", e.stringify())),
+    };
+    let mut pieces: Vec<String> = vec![String::new()];
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                pieces.last_mut().unwrap().push(c);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                pieces.push(String::new());
+            }
+            ('{', _) | ('}', _) => return Err(format!("format() got an unmatched '{}'", c)),
+            _ => pieces.last_mut().unwrap().push(c),
+        }
+    }
+    let (placeholders, values) = (pieces.len() - 1, &args[1..]);
+    if placeholders != values.len() {
+        return Err(format!(
+            "format() got {} placeholders but {} arguments", placeholders, values.len()));
+    }
+    let mut result = pieces[0].clone();
+    for (value, piece) in values.iter().zip(pieces.iter().skip(1)) {
+        result.push_str(&value.stringify());
+        result.push_str(piece);
+    }
+    Ok(Value::String(context.interned_strings.intern_string(result)))
+}
+
+/// Wraps a closure so repeated calls with the same arguments return the cached result instead of
+/// calling it again. The calls themselves are handled by the VM, see [Memoized].
+fn memoize(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Closure(closure) => Ok(Value::Memoized(Rc::new(Memoized::new(closure.clone())))),
+        e => Err(format!("memoize() expects a function, got {}", e.stringify())),
+    }
+}
+
+// Numbers are returned as is, and strings are parsed, ignoring surrounding whitespace.
+fn to_number(native: &str, value: &Value) -> Result<f64, String> {
+    match value {
+        Value::Number(n) => Ok(*n),
+        Value::String(s) => s.apply(|s| s.trim().parse::<f64>())
+            .map_err(|_| format!("{}() can't convert {:?} to a number", native, s.to_owned())),
+        e => Err(format!("{}() expects a number or a string, got {}", native, e.stringify())),
+    }
+}
+
+/// Truncates towards zero, e.g., toInt(-3.9) is -3.
+fn to_int(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    let n = to_number("toInt", &args[0])?;
+    if n.is_finite() {
+        Ok(Value::Number(n.trunc()))
+    } else {
+        Err(format!("toInt() can't convert {} to an integer", args[0].stringify()))
+    }
+}
+
+fn to_float(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(to_number("toFloat", &args[0])?))
+}
+
+fn to_bool(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Bool(args[0].is_truthy()))
+}
+
+/// Seconds since the first call, with a fractional part. Unlike [time], this is monotonic, so it's
+/// meant for measuring elapsed time, e.g., in benchmarks.
+fn clock(_: &mut NativeContext, _: &[Value]) -> Result<Value, String> {
+    static START: OnceLock<Instant> = OnceLock::new();
+    Ok(Value::Number(START.get_or_init(Instant::now).elapsed().as_secs_f64()))
+}
+
+/// Seconds since the Unix epoch, with a fractional part.
+fn time(_: &mut NativeContext, _: &[Value]) -> Result<Value, String> {
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map_err(|e| format!("time() found the clock before the Unix epoch: {}", e))?;
+    Ok(Value::Number(elapsed.as_secs_f64()))
+}
+
+/// Blocks the current thread for the given number of seconds.
+fn sleep(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    let duration = match &args[0] {
+        Value::Number(seconds) => Duration::try_from_secs_f64(*seconds).ok(),
+        _ => None,
+    }.ok_or_else(|| format!(
+        "sleep() expects a non-negative number of seconds, got {}", args[0].stringify()))?;
+    thread::sleep(duration);
+    Ok(Value::Nil)
+}
+
+/// The value's [TableKey::stable_hash], which unlike the hash tables use internally is the same
+/// across runs, truncated to 53 bits so it's exactly representable as a number.
+fn hash(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+    let key = TableKey::new(&args[0]).map_err(|e| format!("hash() argument {}", e))?;
+    let hash = key.stable_hash();
+    Ok(Value::Number((hash & ((1 << f64::MANTISSA_DIGITS) - 1)) as f64))
+}
@@ -1,9 +1,12 @@
+use std::rc::Rc;
+
 use crate::rslox::compiled::memory::InternedString;
 use crate::rslox::compiled::tests::DeepEq;
 
 pub type CodeLocation = usize;
 pub type StackLocation = usize;
 pub type ConstantIndex = usize;
+pub type GlobalIndex = usize;
 // including parameters
 pub type ArgCount = usize;
 
@@ -13,11 +16,29 @@ pub enum OpCode {
     Pop,
     // A more efficient variant of the above, used by function returns and block ends.
     PopN(usize),
+    // Stack manipulation primitives, for lowering constructs that need to reuse a computed value.
+    Dup,
+    Swap,
+    // Pushes a copy of the second value from the top.
+    Over,
+    // Prints with a trailing newline.
     Print,
+    // Prints without a trailing newline, so lines can be built up incrementally.
+    Write,
     Function(ConstantIndex),
     Class(ConstantIndex),
+    // Pops a closure, and binds it as the named method of the class below it.
+    Method(InternedString),
+    // Like Method, but binds a static method, which is called on the class itself.
+    StaticMethod(InternedString),
     GetProperty(InternedString),
     SetProperty(InternedString),
+    // Pops the body of the module whose canonical path is given, and pushes it bound to the module's
+    // own globals, which have slots for the given names. The module is then available to
+    // GetModule, even before its body is called.
+    Module(InternedString, Rc<Vec<InternedString>>),
+    // Pushes an already imported module, by its canonical path.
+    GetModule(InternedString),
     CloseUpvalue,
     DefineGlobal(InternedString),
     DefineLocal(StackLocation),
@@ -30,48 +51,138 @@ pub enum OpCode {
     String(InternedString),
     GetGlobal(InternedString),
     SetGlobal(InternedString),
+    // Used instead of the above for globals the compiler has already seen declared.
+    GetGlobalByIndex(GlobalIndex),
+    SetGlobalByIndex(GlobalIndex),
     GetUpvalue(StackLocation),
     SetUpvalue(StackLocation),
     GetLocal(StackLocation),
     SetLocal(StackLocation),
     Nil,
     Call(ArgCount),
+    // A call whose result is immediately returned, i.e., which is always followed by a Return. The
+    // callee's frame replaces the caller's, so tail recursion doesn't grow the frame stack.
+    TailCall(ArgCount),
+    // Calls the named method of the receiver below the arguments, without binding it first. Falls
+    // back to getting the property and calling it, e.g., for fields holding callables.
+    Invoke(InternedString, ArgCount),
+    // Invoke's counterpart of TailCall.
+    TailInvoke(InternedString, ArgCount),
     Add,
     Subtract,
     Multiply,
     Divide,
     Negate,
     Not,
+    // Replaces the top value with its string representation, as printed by Print.
+    ToString,
     Equals,
     Less,
     Greater,
+    // Pops the end and start bounds, and pushes a range; true for inclusive ranges.
+    Range(bool),
+    // Pops the given number of elements, and pushes a list of them.
+    MakeList(usize),
+    // Pops the given number of key and value pairs, each pushed key first, and pushes a table of
+    // them.
+    MakeTable(usize),
+    // Pops the index, and then the indexed list or table.
+    GetIndex,
+    // Pops the value, the index, and then the indexed list or table, and pushes the value back.
+    SetIndex,
+    // Pushes the next element of the iterator in the given local, or jumps out of the loop if it
+    // is exhausted. The stored iterator is replaced by the remaining elements.
+    IterNext(StackLocation, CodeLocation),
+    // Replaces the value a for-in loop iterates over with its iterator, i.e., calls __iter for
+    // instances. Other values are their own iterators.
+    Iterate,
     UnpatchedJump,
     Jump(CodeLocation),
     JumpIfFalse(CodeLocation),
+    // Jumps backward by the given distance, i.e., to the start of a loop. Forward jumps use the
+    // absolute Jump instead.
+    Loop(usize),
 }
 
 impl Eq for &OpCode {}
 
+// Interned strings are compared by their contents, so chunks whose strings were interned separately,
+// e.g., one loaded from a bytecode file, can still be deep equal.
 impl DeepEq for OpCode {
     fn deep_eq(&self, other: &Self) -> bool {
+        let same = |s1: &InternedString, s2: &InternedString| s1.to_owned() == s2.to_owned();
         match (&self, &other) {
-            (OpCode::DefineGlobal(s1), OpCode::DefineGlobal(s2)) => s1 == s2,
-            (OpCode::GetGlobal(s1), OpCode::GetGlobal(s2)) => s1 == s2,
-            (OpCode::String(s1), OpCode::String(s2)) => s1 == s2,
+            (OpCode::DefineGlobal(s1), OpCode::DefineGlobal(s2)) => same(s1, s2),
+            (OpCode::GetGlobal(s1), OpCode::GetGlobal(s2)) => same(s1, s2),
+            (OpCode::SetGlobal(s1), OpCode::SetGlobal(s2)) => same(s1, s2),
+            (OpCode::String(s1), OpCode::String(s2)) => same(s1, s2),
+            (OpCode::Method(s1), OpCode::Method(s2)) => same(s1, s2),
+            (OpCode::StaticMethod(s1), OpCode::StaticMethod(s2)) => same(s1, s2),
+            (OpCode::GetProperty(s1), OpCode::GetProperty(s2)) => same(s1, s2),
+            (OpCode::SetProperty(s1), OpCode::SetProperty(s2)) => same(s1, s2),
+            (OpCode::GetModule(s1), OpCode::GetModule(s2)) => same(s1, s2),
+            (OpCode::Invoke(s1, n1), OpCode::Invoke(s2, n2))
+            | (OpCode::TailInvoke(s1, n1), OpCode::TailInvoke(s2, n2)) => same(s1, s2) && n1 == n2,
+            (OpCode::Module(path1, names1), OpCode::Module(path2, names2)) =>
+                same(path1, path2) && names1.len() == names2.len()
+                    && names1.iter().zip(names2.iter()).all(|(n1, n2)| same(n1, n2)),
             _ => self == other
         }
     }
 }
 
 impl OpCode {
+    /// The number of values popped and then pushed by the instruction when it doesn't jump, or
+    /// None for unpatched jumps, whose effect isn't known yet, and for operands too large for the
+    /// effect to be counted, which only malformed chunks have. Returns are counted as popping only
+    /// the returned value, since they end the frame anyway.
+    pub fn stack_effect(&self) -> Option<(usize, usize)> {
+        Some(match self {
+            OpCode::Return => (1, 0),
+            OpCode::Pop | OpCode::Print | OpCode::Write | OpCode::CloseUpvalue
+            | OpCode::DefineGlobal(_) | OpCode::JumpIfFalse(_) => (1, 0),
+            OpCode::PopN(n) => (*n, 0),
+            OpCode::Dup => (1, 2),
+            OpCode::Over => (2, 3),
+            OpCode::Swap => (2, 2),
+            OpCode::Function(_) | OpCode::Class(_) | OpCode::GetModule(_) | OpCode::Number(_)
+            | OpCode::Bool(_) | OpCode::String(_) | OpCode::Nil | OpCode::GetGlobal(_)
+            | OpCode::GetGlobalByIndex(_) | OpCode::GetUpvalue(_) | OpCode::GetLocal(_)
+            | OpCode::IterNext(..) => (0, 1),
+            OpCode::GetProperty(_) | OpCode::DefineLocal(_) | OpCode::SetGlobal(_)
+            | OpCode::SetGlobalByIndex(_) | OpCode::SetUpvalue(_) | OpCode::SetLocal(_)
+            | OpCode::Negate | OpCode::Not | OpCode::ToString | OpCode::Module(..)
+            | OpCode::Iterate => (1, 1),
+            OpCode::SetProperty(_) | OpCode::Method(_) | OpCode::StaticMethod(_) | OpCode::Add
+            | OpCode::Subtract | OpCode::Multiply
+            | OpCode::Divide | OpCode::Equals | OpCode::Less | OpCode::Greater
+            | OpCode::Range(_) | OpCode::GetIndex => (2, 1),
+            OpCode::SetIndex => (3, 1),
+            OpCode::MakeList(n) => (*n, 1),
+            OpCode::MakeTable(n) => (n.checked_mul(2)?, 1),
+            // The callee and its arguments are replaced by the returned value.
+            OpCode::Call(n) | OpCode::TailCall(n) => (n.checked_add(1)?, 1),
+            // Likewise for the receiver.
+            OpCode::Invoke(_, n) | OpCode::TailInvoke(_, n) => (n.checked_add(1)?, 1),
+            OpCode::Jump(_) | OpCode::Loop(_) => (0, 0),
+            OpCode::UnpatchedJump => return None,
+        })
+    }
+
     pub fn to_upper_snake(&self) -> String {
         format!("{:15}", match self {
             OpCode::Return => "RETURN",
             OpCode::Pop => "POP",
             OpCode::PopN(_) => "POP_N",
+            OpCode::Dup => "DUP",
+            OpCode::Swap => "SWAP",
+            OpCode::Over => "OVER",
             OpCode::Print => "PRINT",
+            OpCode::Write => "WRITE",
             OpCode::Function(..) => "FUNCTION",
             OpCode::Class(..) => "CLASS",
+            OpCode::Method(..) => "METHOD",
+            OpCode::StaticMethod(..) => "STATIC_METHOD",
             OpCode::GetProperty(..) => "GET_PROPERTY",
             OpCode::SetProperty(..) => "SET_PROPERTY",
             OpCode::CloseUpvalue => "CLOSE_UPVALUE",
@@ -82,6 +193,13 @@ impl OpCode {
             OpCode::String(_) => "STRING",
             OpCode::GetGlobal(_) => "GET_GLOBAL",
             OpCode::SetGlobal(_) => "SET_GLOBAL",
+            OpCode::GetGlobalByIndex(_) => "GET_GLOBAL_IDX",
+            OpCode::SetGlobalByIndex(_) => "SET_GLOBAL_IDX",
+            OpCode::Module(..) => "MODULE",
+            OpCode::GetModule(_) => "GET_MODULE",
+            OpCode::Range(_) => "RANGE",
+            OpCode::IterNext(..) => "ITER_NEXT",
+            OpCode::Iterate => "ITERATE",
             OpCode::GetUpvalue(_) => "GET_UPVALUE",
             OpCode::SetUpvalue(_) => "SET_UPVALUE",
             OpCode::GetLocal(_) => "GET_LOCAL",
@@ -89,6 +207,10 @@ impl OpCode {
             OpCode::Nil => "NIL",
             OpCode::Add => "ADD",
             OpCode::Call(_) => "CALL",
+            OpCode::ToString => "TO_STRING",
+            OpCode::TailCall(_) => "TAIL_CALL",
+            OpCode::Invoke(..) => "INVOKE",
+            OpCode::TailInvoke(..) => "TAIL_INVOKE",
             OpCode::Subtract => "SUBTRACT",
             OpCode::Multiply => "MULTIPLY",
             OpCode::Divide => "DIVIDE",
@@ -100,6 +222,11 @@ impl OpCode {
             OpCode::UnpatchedJump => "UNPATCHED_JUMP",
             OpCode::Jump(_) => "JUMP",
             OpCode::JumpIfFalse(_) => "JUMP_IF_FALSE",
+            OpCode::Loop(_) => "LOOP",
+            OpCode::MakeList(_) => "MAKE_LIST",
+            OpCode::MakeTable(_) => "MAKE_TABLE",
+            OpCode::GetIndex => "GET_INDEX",
+            OpCode::SetIndex => "SET_INDEX",
         })
     }
 }
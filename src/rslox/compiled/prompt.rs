@@ -0,0 +1,210 @@
+use std::io;
+use std::io::{BufRead, Stdout, stdout, Write};
+
+use nonempty::NonEmpty;
+
+use crate::rslox::common::error::{Diagnostic, LoxError, render};
+use crate::rslox::common::lexer::{tokenize, TokenType};
+use crate::rslox::compiled::compiler::compile_repl_input;
+use crate::rslox::compiled::value::Value;
+use crate::rslox::compiled::vm::{VirtualMachine, VmOptions};
+
+/// Unlike the interpreted prompt, every input runs in the same VM, so globals (including functions
+/// and classes) declared by one input can be used by the following ones.
+pub fn run_prompt() {
+    prompt(io::stdin().lock(), &mut Repl::new(stdout()), &mut stdout())
+}
+
+// Reads inputs until the end of `lines`, writing the prompts, values and errors to `console`.
+fn prompt<W: Write>(mut lines: impl BufRead, repl: &mut Repl<W>, console: &mut impl Write) {
+    let mut input = String::new();
+    loop {
+        write!(console, "{}", if input.is_empty() { "> " } else { ". " }).expect("Not written");
+        console.flush().expect("Failed to flush the prompt");
+        let at_end = lines.read_line(&mut input).expect("Failed to read line from input") == 0;
+        // An input still incomplete at the end is submitted anyway, so its errors are reported.
+        if at_end && input.trim().is_empty() {
+            return;
+        }
+        if !at_end && is_incomplete(&input) {
+            continue;
+        }
+        let result = repl.submit(&input);
+        for warning in repl.warnings() {
+            write!(console, "{}", render(warning, &input)).expect("Not written");
+        }
+        match result {
+            Ok(Some(value)) => writeln!(console, "{}", value),
+            Ok(None) => Ok(()),
+            Err(e) => writeln!(console, "{}", e),
+        }.expect("Not written");
+        input.clear();
+        if at_end {
+            return;
+        }
+    }
+}
+
+pub struct Repl<W: Write = Stdout> {
+    vm: VirtualMachine<W>,
+    warnings: Vec<Diagnostic>,
+}
+
+impl<W: Write> Repl<W> {
+    pub fn new(output: W) -> Self {
+        Repl { vm: VirtualMachine::session(output, VmOptions::default()), warnings: Vec::new() }
+    }
+
+    /// Runs the input, returning the value of its final expression, if it has a (non-nil) one.
+    pub fn submit(&mut self, input: &str) -> Result<Option<String>, String> {
+        self.warnings.clear();
+        let tokens = tokenize(input).map_err(render_errors)?;
+        let interned_strings = self.vm.interned_strings();
+        let (chunk, warnings) = compile_repl_input(
+            tokens, &self.vm.global_names(), &mut interned_strings.borrow_mut(),
+        ).map_err(render_errors)?;
+        self.warnings = warnings;
+        let stack = self.vm.run_next(chunk).map_err(|e| e.to_string())?;
+        Ok(stack.last()
+            .map(|v| v.dereferenced())
+            .filter(|v| v != &Value::Nil)
+            .map(|v| v.stringify()))
+    }
+
+    /// The warnings of the last input, e.g., for assigning to undeclared globals in functions.
+    pub fn warnings(&self) -> &[Diagnostic] { &self.warnings }
+
+    #[cfg(test)]
+    pub fn output(&self) -> &W { self.vm.output() }
+}
+
+fn render_errors(errors: NonEmpty<Box<dyn LoxError>>) -> String {
+    errors.iter()
+        .map(|e| format!("[line {}] Error: {}", e.get_info().line, e.get_message()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Whether more lines are needed to complete the input, i.e., it has unclosed parentheses, braces
+/// or brackets, or an unterminated string. Input which is invalid for any other reason is complete,
+/// so its errors are reported right away.
+pub fn is_incomplete(input: &str) -> bool {
+    match tokenize(input) {
+        Ok(tokens) => {
+            let depth: i64 = tokens.iter().map(|t| match t.r#type {
+                TokenType::OpenParen | TokenType::OpenBrace | TokenType::OpenBracket => 1,
+                TokenType::CloseParen | TokenType::CloseBrace | TokenType::CloseBracket => -1,
+                _ => 0,
+            }).sum();
+            depth > 0
+        }
+        Err(errors) => errors.iter().any(|e| e.get_message() == "Unterminated string."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn printed(repl: &Repl<Vec<u8>>) -> String { String::from_utf8(repl.output().clone()).unwrap() }
+
+    #[test]
+    fn state_persists_across_inputs() {
+        let mut repl = Repl::new(Vec::new());
+        assert_eq!(repl.submit("var x = 40;"), Ok(None));
+        assert_eq!(repl.submit("fun add(a, b) { return a + b; }"), Ok(None));
+        assert_eq!(repl.submit("class Foo { bar() { return \"baz\"; } }"), Ok(None));
+        assert_eq!(repl.submit("add(x, 2)"), Ok(Some("42".to_owned())));
+        assert_eq!(repl.submit("Foo().bar()"), Ok(Some("baz".to_owned())));
+        assert_eq!(repl.submit("x = x + 1; print x;"), Ok(None));
+        assert_eq!(printed(&repl), "41\n");
+    }
+
+    #[test]
+    fn nil_values_are_not_printed() {
+        let mut repl = Repl::new(Vec::new());
+        assert_eq!(repl.submit("fun f() {}"), Ok(None));
+        assert_eq!(repl.submit("f()"), Ok(None));
+        assert_eq!(repl.submit("false"), Ok(Some("false".to_owned())));
+    }
+
+    #[test]
+    fn errors_dont_end_the_session() {
+        let mut repl = Repl::new(Vec::new());
+        assert_eq!(repl.submit("var x = 1;"), Ok(None));
+        assert_eq!(
+            repl.submit("var = 2;").unwrap_err(),
+            "[line 1] Error: Expected Identifier for variable, got 'Equal'",
+        );
+        assert!(repl.submit("fun f() { return -nil; } f();").is_err());
+        assert_eq!(repl.submit("x"), Ok(Some("1".to_owned())));
+        // Captured locals of failed inputs are closed, so their closures keep working.
+        assert!(repl.submit("var g; { var y = 2; fun h() { return y; } g = h; -nil; }").is_err());
+        assert_eq!(repl.submit("g()"), Ok(Some("2".to_owned())));
+    }
+
+    #[test]
+    fn functions_of_previous_inputs_survive_collection() {
+        let mut repl = Repl::new(Vec::new());
+        assert_eq!(repl.submit("fun greet(name) { return \"hi \" + name; }"), Ok(None));
+        for _ in 0..3 {
+            assert_eq!(repl.submit("greet(\"bob\")"), Ok(Some("hi bob".to_owned())));
+        }
+    }
+
+    fn session(lines: &str) -> String {
+        let mut console = Vec::new();
+        prompt(lines.as_bytes(), &mut Repl::new(Vec::new()), &mut console);
+        String::from_utf8(console).unwrap()
+    }
+
+    #[test]
+    fn dangling_operators_are_errors() {
+        let mut repl = Repl::new(Vec::new());
+        assert_eq!(
+            repl.submit("1 +").unwrap_err(),
+            "[line 1] Error: Expected expression, but encountered end of file",
+        );
+        assert_eq!(
+            repl.submit("-").unwrap_err(),
+            "[line 1] Error: Expected expression, but encountered end of file",
+        );
+        assert_eq!(repl.submit("1 + 2"), Ok(Some("3".to_owned())));
+        assert_eq!(
+            session("1 +\n-\n2\n"),
+            "> [line 1] Error: Expected expression, but encountered end of file\n\
+             > [line 1] Error: Expected expression, but encountered end of file\n\
+             > 2\n> ",
+        );
+    }
+
+    #[test]
+    fn incomplete_inputs_are_reported_at_the_end() {
+        assert_eq!(
+            session("print \"abc\ndef"),
+            "> . . [line 2] Error: Unterminated string.\n",
+        );
+        assert_eq!(session("(1 +\n2)\n"), "> . 3\n> ");
+    }
+
+    #[test]
+    fn warnings_are_printed_before_the_value() {
+        assert_eq!(
+            session("fun f() { x = 1; } 42\n"),
+            "> warning: Assigning to undeclared 'x' implicitly creates a global; use 'global x = \
+            ...' instead\n  --> line 1, col 11\n1 | fun f() { x = 1; } 42\n  |           ^\n42\n> ",
+        );
+    }
+
+    #[test]
+    fn incomplete_inputs() {
+        assert!(is_incomplete("fun f() {"));
+        assert!(is_incomplete("print (1 +"));
+        assert!(is_incomplete("var l = [1,"));
+        assert!(is_incomplete("print \"abc"));
+        assert!(!is_incomplete("fun f() {}"));
+        assert!(!is_incomplete("print 1 +"));
+        // Too many closing brackets can't be fixed by adding more lines.
+        assert!(!is_incomplete("}"));
+    }
+}
@@ -0,0 +1,73 @@
+use std::env;
+use std::fs::{read, read_to_string, write};
+use std::io::stdout;
+
+use crate::rslox::common::error::render;
+use crate::rslox::common::lexer::tokenize;
+use crate::rslox::compiled::bytecode::{load, serialize};
+use crate::rslox::compiled::chunk::Chunk;
+use crate::rslox::compiled::compiler::{compile_with_search_path, InternedStrings};
+use crate::rslox::compiled::vm::{VirtualMachine, VmOptions};
+
+/// Compiles the script in `source` to a bytecode file at `target`, which [run_compiled_file] can
+/// then run without parsing or compiling it again. With `optimize`, the code is optimized first,
+/// see `Chunk::optimize`. Modules imported by name are also looked up in the directories listed in
+/// the `LOX_PATH` environment variable, e.g., `LOX_PATH=lib:vendor/lib`.
+pub fn compile_file(source: &str, target: &str, optimize: bool) {
+    if let Some((chunk, _)) = compile_source(source, optimize) {
+        write(target, serialize(&chunk)).unwrap_or_else(|_| panic!("Cannot write file {}", target))
+    }
+}
+
+/// Compiles and runs the script in `file` right away, like [compile_file] followed by
+/// [run_compiled_file], but without writing the bytecode.
+pub fn run_file(file: &str, trace: bool) {
+    if let Some((chunk, interned_strings)) = compile_source(file, false) {
+        run(chunk, interned_strings, trace)
+    }
+}
+
+/// With `trace`, every instruction is printed along with the stack, see [VmOptions::trace].
+pub fn run_compiled_file(file: &str, trace: bool) {
+    let bytes = read(file).unwrap_or_else(|_| panic!("Cannot open file {}", file));
+    match load(&bytes) {
+        Ok((chunk, interned_strings)) => run(chunk, interned_strings, trace),
+        Err(e) => println!("{}", e),
+    }
+}
+
+// None if the script failed to compile, in which case the errors were already printed. Warnings are
+// printed to stderr either way.
+fn compile_source(source: &str, optimize: bool) -> Option<(Chunk, InternedStrings)> {
+    let code = read_to_string(source).unwrap_or_else(|_| panic!("Cannot open file {}", source));
+    let tokens = match tokenize(&code) {
+        Ok(tokens) => tokens,
+        Err(errors) => {
+            println!("{:?}", errors);
+            return None;
+        }
+    };
+    let search_path =
+        env::var_os("LOX_PATH").map(|paths| env::split_paths(&paths).collect()).unwrap_or_default();
+    match compile_with_search_path(tokens, search_path, optimize) {
+        Ok((chunk, interned_strings, warnings)) => {
+            for warning in warnings {
+                eprint!("{}", render(&warning, &code));
+            }
+            Some((chunk, interned_strings))
+        }
+        Err(errors) => {
+            println!("{:?}", errors);
+            None
+        }
+    }
+}
+
+fn run(chunk: Chunk, interned_strings: InternedStrings, trace: bool) {
+    let options = VmOptions { trace, ..Default::default() };
+    let result =
+        VirtualMachine::run_apply_with_options(chunk, interned_strings, stdout(), options, |_| ());
+    if let Err(e) = result {
+        println!("{}", e);
+    }
+}
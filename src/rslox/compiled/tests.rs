@@ -15,6 +15,16 @@ pub fn unsafe_compile(program: Vec<&str>) -> (Chunk, InternedStrings) {
     compile(unsafe_tokenize(program)).expect("Failed to compile")
 }
 
+/// Writes a module file to a per-test temporary directory, and returns its path.
+#[cfg(test)]
+pub fn write_module(test_name: &str, file_name: &str, code: &str) -> String {
+    let dir = std::env::temp_dir().join("rslox_modules").join(test_name);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(file_name);
+    std::fs::write(&path, code).unwrap();
+    path.to_str().unwrap().to_owned()
+}
+
 pub trait DeepEq: PartialEq {
     fn deep_eq(&self, other: &Self) -> bool;
 }
@@ -1,13 +1,21 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, TryReserveError};
 use std::convert::{TryFrom, TryInto};
-use std::fmt::{Debug, Formatter};
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::iter::FromIterator;
+use std::mem;
 use std::ops::Deref;
-use std::rc::Weak;
+use std::rc::{Rc, Weak};
 
 use crate::format_interned;
 use crate::rslox::common::utils::{RcRc, rcrc, WeakRc};
 use crate::rslox::compiled::chunk::{Chunk, Upvalue};
-use crate::rslox::compiled::memory::{InternedString, Pointer};
+use crate::rslox::compiled::globals::Globals;
+use crate::rslox::compiled::memory::{HeapSize, InternedString, Pointer};
+use crate::rslox::compiled::natives::{NativeArity, NativeFunctionPtr};
 use crate::rslox::compiled::op_code::StackLocation;
 use crate::rslox::compiled::tests::DeepEq;
 
@@ -19,37 +27,286 @@ use crate::rslox::compiled::tests::DeepEq;
 /// Note that other than the above mentioned primitives,
 /// [Value] doesn't own its data! Strings are owned either by individual function chunks, or by the
 /// VM for temporary strings. Closed over upvalues, i.e., upvalues whose reference is no longer on
-/// the stack in managed by the VM as well. Classes are not implemented yet.
+/// the stack in managed by the VM as well. Classes are owned by their chunk, like functions.
+///
+/// Hosts only ever see the variants a script can produce. The hidden ones are the VM's own
+/// bookkeeping, and new variants may be added, so matches outside the crate need a wildcard arm.
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub enum Value {
     Number(f64),
     Bool(bool),
     Nil,
+    #[doc(hidden)]
     TemporaryPlaceholder,
     String(InternedString),
     Closure(Closure),
+    // Boxed, like closures and ranges, so that every variant fits in a single word.
+    Native(Rc<NativeFunction>),
     Class(Weak<Class>),
     // Unlike closures, instances can have cyclic references.
     Instance(Pointer<Instance>),
+    // A method accessed on an instance, e.g., `foo.bar`, which remembers its receiver.
+    BoundMethod(Rc<BoundMethod>),
+    Module(Rc<Module>),
+    Range(Rc<Range>),
+    // Only ever stored in the hidden local of a for-in loop over a list.
+    #[doc(hidden)]
+    ListIterator(Rc<ListIterator>),
+    // Mutable buffers, so unlike strings, these aren't interned.
+    Bytes(RcRc<Vec<u8>>),
+    // Like instances, lists can have cyclic references, e.g., by containing themselves.
+    List(Pointer<List>),
+    Table(Pointer<Table>),
+    Memoized(Rc<Memoized>),
+    // Doesn't keep the instance alive, i.e., isn't followed when marking.
+    WeakRef(Pointer<Instance>),
+    #[doc(hidden)]
     UpvaluePtr(Pointer<PointedUpvalue>),
 }
 
 #[derive(Clone)]
 // We can use a Weak reference to the function, since it exists in the bytecode and will never
-// be collected.
-pub struct Closure(Weak<Function>, ClosedOverValues);
+// be collected. All parts are behind a single pointer, to keep Value small. The globals are those of
+// the module the closure was created in, which it keeps using when called from other modules.
+pub struct Closure(Rc<ClosureParts>);
+
+pub type ClosureParts = (Weak<Function>, ClosedOverValues, RcRc<Globals>);
+
+impl Closure {
+    pub fn new(
+        function: Weak<Function>, upvalues: ClosedOverValues, globals: RcRc<Globals>,
+    ) -> Self {
+        Closure(Rc::new((function, upvalues, globals)))
+    }
+    pub fn parts(&self) -> ClosureParts { self.0.deref().clone() }
+    pub fn function(&self) -> Rc<Function> { self.0.0.upgrade().unwrap() }
+    fn upvalues(&self) -> &ClosedOverValues { &self.0.1 }
+
+    /// For debugging: describes each captured upvalue, e.g., "open3: 42" or "closed: 42".
+    #[cfg(test)]
+    pub fn captured_upvalues(&self) -> Vec<String> {
+        self.upvalues().upvalues.borrow().iter()
+            .map(|u| format!("{}: {}", u.apply(|p| p.pp_debug()), u.deep_apply(|v| v.stringify())))
+            .collect()
+    }
+}
 
 impl Debug for Closure {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Class")
-            .field("function_name", &self.0.upgrade().unwrap().name.to_owned())
+            .field("function_name", &self.function().name.to_owned())
             .finish()
     }
 }
 
 impl PartialEq for Closure {
     fn eq(&self, other: &Self) -> bool {
-        self.0.ptr_eq(&other.0) && self.1 == other.1
+        self.0.0.ptr_eq(&other.0.0) && self.upvalues() == other.upvalues()
+    }
+}
+
+/// Derefs to its elements, which can only be modified through [List::set]. Frozen lists, see
+/// [List::freeze], aren't modified by the VM, which checks [List::is_frozen] first.
+#[derive(Debug, Clone, Default)]
+pub struct List {
+    elements: Vec<Value>,
+    frozen: bool,
+}
+
+impl List {
+    pub fn set(&mut self, index: usize, value: Value) { self.elements[index] = value }
+
+    /// There's no unfreezing, so freezing an already frozen list does nothing.
+    pub fn freeze(&mut self) { self.frozen = true }
+    pub fn is_frozen(&self) -> bool { self.frozen }
+}
+
+impl From<Vec<Value>> for List {
+    fn from(elements: Vec<Value>) -> Self { List { elements, frozen: false } }
+}
+
+impl FromIterator<Value> for List {
+    fn from_iter<I: IntoIterator<Item=Value>>(iter: I) -> Self {
+        iter.into_iter().collect::<Vec<_>>().into()
+    }
+}
+
+impl Deref for List {
+    type Target = [Value];
+    fn deref(&self) -> &[Value] { &self.elements }
+}
+
+/// A hash map from strings and numbers to values, which iterates in insertion order. Since strings
+/// are interned, looking up a string key only hashes and compares its pointer.
+#[derive(Debug, Clone, Default)]
+pub struct Table {
+    entries: Vec<(TableKey, Value)>,
+    indices: HashMap<TableKey, usize>,
+    frozen: bool,
+}
+
+/// The values which can be used as keys, by tables as well as by [Value::hash_key]. Equality
+/// follows IEEE, so NaN, which isn't equal to anything (including itself), isn't a valid key, while
+/// 0.0 and -0.0 are the same key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TableKey {
+    String(InternedString),
+    // The bits of the number, with -0.0 normalized to 0.0, since they're equal.
+    Number(u64),
+    Bool(bool),
+    Nil,
+}
+
+impl TableKey {
+    /// The error describes why the value can't be a key, following the name of whatever expected
+    /// one, e.g., "Table key can't be nan, since it isn't equal to itself".
+    pub fn new(value: &Value) -> Result<Self, String> {
+        match value {
+            Value::String(s) => Ok(TableKey::String(s.clone())),
+            Value::Number(n) if n.is_nan() =>
+                Err("can't be nan, since it isn't equal to itself".to_owned()),
+            Value::Number(n) =>
+                Ok(TableKey::Number((if *n == 0.0 { 0.0 } else { *n }).to_bits())),
+            Value::Bool(b) => Ok(TableKey::Bool(*b)),
+            Value::Nil => Ok(TableKey::Nil),
+            Value::UpvaluePtr(v) => v.deep_apply(TableKey::new),
+            e => Err(format!("must be a number, string, bool or nil, got {}", e.stringify())),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            TableKey::String(s) => Value::String(s.clone()),
+            TableKey::Number(bits) => Value::Number(f64::from_bits(*bits)),
+            TableKey::Bool(b) => Value::Bool(*b),
+            TableKey::Nil => Value::Nil,
+        }
+    }
+
+    /// Unlike the derived [Hash], which only hashes the pointers of strings, this hashes their
+    /// contents, so it's stable across runs.
+    pub fn stable_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        match self {
+            TableKey::Number(bits) => {
+                0.hash(&mut hasher);
+                bits.hash(&mut hasher);
+            }
+            TableKey::String(s) => {
+                1.hash(&mut hasher);
+                s.apply(|s| s.hash(&mut hasher));
+            }
+            TableKey::Bool(b) => {
+                2.hash(&mut hasher);
+                b.hash(&mut hasher);
+            }
+            TableKey::Nil => 3.hash(&mut hasher),
+        }
+        hasher.finish()
+    }
+
+    /// Quoted for strings, like the keys of a stringified table.
+    pub fn stringify(&self) -> String { stringify_nested(&self.to_value(), &mut Vec::new()) }
+}
+
+impl Table {
+    /// A table with room for at least `capacity` keys. The capacity is only a hint: adding more
+    /// keys grows the table as usual.
+    pub fn with_capacity(capacity: usize) -> Result<Self, TryReserveError> {
+        let mut table = Table::default();
+        table.entries.try_reserve(capacity)?;
+        table.indices.try_reserve(capacity)?;
+        Ok(table)
+    }
+
+    /// The number of keys the table can hold before it has to grow, and rehash its keys.
+    pub fn capacity(&self) -> usize { self.indices.capacity() }
+
+    pub fn get(&self, key: &TableKey) -> Option<&Value> {
+        self.indices.get(key).map(|i| &self.entries[*i].1)
+    }
+
+    pub fn contains(&self, key: &TableKey) -> bool { self.indices.contains_key(key) }
+
+    /// Overwriting an existing key keeps its original position.
+    pub fn set(&mut self, key: TableKey, value: Value) {
+        match self.indices.get(&key) {
+            Some(i) => self.entries[*i].1 = value,
+            None => {
+                self.indices.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    /// See [List::freeze].
+    pub fn freeze(&mut self) { self.frozen = true }
+    pub fn is_frozen(&self) -> bool { self.frozen }
+
+    pub fn keys(&self) -> impl Iterator<Item=&TableKey> { self.entries.iter().map(|(k, _)| k) }
+
+    pub fn values(&self) -> impl Iterator<Item=&Value> { self.entries.iter().map(|(_, v)| v) }
+
+    pub fn len(&self) -> usize { self.entries.len() }
+}
+
+/// Calling a bound method runs its closure with the receiver as `this`, see [Function::is_method].
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Pointer<Instance>,
+    pub method: Closure,
+}
+
+impl BoundMethod {
+    pub fn method(&self) -> ClosureParts { self.method.parts() }
+}
+
+/// A closure wrapped by the `memoize` native, which caches its results by its arguments. Calls with
+/// arguments which can't be hashed (see [Value::hash_key]) aren't cached, and always go through to
+/// the closure.
+#[derive(Debug)]
+pub struct Memoized {
+    closure: Closure,
+    cache: RefCell<MemoizedResults>,
+}
+
+// Keyed by the combined hash of the arguments. The arguments are kept as well, to tell apart
+// different arguments which happen to share a hash.
+type MemoizedResults = HashMap<u64, Vec<(Vec<Value>, Value)>>;
+
+impl Memoized {
+    pub fn new(closure: Closure) -> Self { Memoized { closure, cache: RefCell::default() } }
+
+    pub fn closure(&self) -> ClosureParts { self.closure.parts() }
+
+    /// None if any of the arguments can't be hashed.
+    pub fn key(args: &[Value]) -> Option<u64> {
+        let mut hasher = DefaultHasher::new();
+        for arg in args {
+            arg.hash_key()?.hash(&mut hasher);
+        }
+        Some(hasher.finish())
+    }
+
+    pub fn get(&self, key: u64, args: &[Value]) -> Option<Value> {
+        self.cache.borrow().get(&key)?.iter()
+            .find(|(cached_args, _)| cached_args.as_slice() == args)
+            .map(|(_, result)| result.clone())
+    }
+
+    pub fn insert(&self, key: u64, args: Vec<Value>, result: Value) {
+        self.cache.borrow_mut().entry(key).or_default().push((args, result));
+    }
+}
+
+impl Mark for Memoized {
+    fn mark_shallow(&self, gray: &mut Vec<Value>) {
+        self.closure.upvalues().mark_shallow(gray);
+        for (args, result) in self.cache.borrow().values().flatten() {
+            gray.extend(args.iter().cloned());
+            gray.push(result.clone());
+        }
     }
 }
 
@@ -61,6 +318,7 @@ impl Instance {
     pub fn new(class: Weak<Class>) -> Self { Instance(class, rcrc(HashMap::new())) }
 
     pub fn name(&self) -> InternedString { self.0.upgrade().unwrap().name.clone() }
+    pub fn class(&self) -> Rc<Class> { self.0.upgrade().unwrap() }
 
     pub fn get(&self, name: InternedString) -> Option<Value> { self.1.borrow().get(&name).cloned() }
     pub fn set(&self, name: InternedString, value: Value) {
@@ -76,18 +334,92 @@ impl Debug for Instance {
     }
 }
 
+/// Ranges are lazy, i.e., iterating over them doesn't allocate their elements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+    pub start: i64,
+    pub end: i64,
+    pub inclusive: bool,
+}
+
+impl Range {
+    /// None if the number of elements doesn't fit in a usize, e.g., for `0..=9223372036854775807`
+    /// on 64-bit targets.
+    pub fn len(&self) -> Option<usize> { usize::try_from(self.span().max(0)).ok() }
+
+    pub fn is_empty(&self) -> bool { self.span() <= 0 }
+
+    // Computed in i128, since the distance between two i64s, let alone one more than it, might not
+    // fit in an i64.
+    fn span(&self) -> i128 {
+        self.end as i128 - self.start as i128 + self.inclusive as i128
+    }
+
+    /// Removes the first element, leaving the range of all the remaining elements.
+    pub fn pop_first(&mut self) -> Option<i64> {
+        if self.is_empty() {
+            return None;
+        }
+        let first = self.start;
+        match first.checked_add(1) {
+            Some(next) => self.start = next,
+            // Only possible for the last element of a range ending at i64::MAX inclusively.
+            None => self.inclusive = false,
+        }
+        Some(first)
+    }
+
+    pub fn stringify(&self) -> String {
+        format!("{}{}{}", self.start, if self.inclusive { "..=" } else { ".." }, self.end)
+    }
+}
+
+/// The position of a for-in loop in the list it's iterating over. The list isn't copied, so changes
+/// to its elements during the loop are seen by later iterations.
+#[derive(Debug, Clone)]
+pub struct ListIterator {
+    list: Pointer<List>,
+    index: usize,
+}
+
+impl ListIterator {
+    pub fn new(list: Pointer<List>) -> Self { ListIterator { list, index: 0 } }
+
+    pub fn pop_first(&mut self) -> Option<Value> {
+        let next = self.list.apply(|l| l.get(self.index).cloned());
+        if next.is_some() {
+            self.index += 1;
+        }
+        next
+    }
+}
+
+/// The globals of an imported module, i.e., its top-level declarations. They're read when accessed,
+/// so assignments made after the import are seen.
+#[derive(Debug)]
+pub struct Module {
+    pub name: InternedString,
+    pub globals: RcRc<Globals>,
+}
+
+impl Module {
+    pub fn stringify(&self) -> String { format_interned!("<module {}>", self.name) }
+}
+
 impl Pointer<PointedUpvalue> {
     fn deep_apply<B, F: FnOnce(&Value) -> B>(&self, f: F) -> B { self.apply(|p| p.apply(f)) }
     fn deep_set(&mut self, new_value: Value) { self.mutate(|p| p.set(new_value)) }
 }
 
 impl Value {
-    pub fn closure(function: Weak<Function>, upvalues: ClosedOverValues) -> Self {
-        Value::Closure(Closure(function, upvalues))
+    pub fn closure(
+        function: Weak<Function>, upvalues: ClosedOverValues, globals: RcRc<Globals>,
+    ) -> Self {
+        Value::Closure(Closure::new(function, upvalues, globals))
     }
 
-    pub fn try_into_closure(&self) -> Result<(Weak<Function>, ClosedOverValues), String> { self.try_into() }
-    pub fn try_into_class(&self) -> Result<Weak<Class>, String> { self.try_into() }
+    pub fn try_into_closure(&self) -> Result<ClosureParts, TypeError> { self.try_into() }
+    pub fn try_into_class(&self) -> Result<Weak<Class>, TypeError> { self.try_into() }
 
     pub fn is_string(&self) -> bool {
         match &self {
@@ -97,6 +429,14 @@ impl Value {
         }
     }
 
+    /// Follows [Value::UpvaluePtr] indirections, returning a (shallow) copy of the actual value.
+    pub fn dereferenced(&self) -> Value {
+        match &self {
+            Value::UpvaluePtr(v) => v.deep_apply(|v| v.dereferenced()),
+            e => (*e).clone(),
+        }
+    }
+
     pub fn set(&mut self, value: Value) {
         assert_ne!(value, Value::TemporaryPlaceholder);
         match self {
@@ -107,19 +447,54 @@ impl Value {
 
     pub fn stringify(&self) -> String {
         match self {
-            Value::Number(f) => f.to_string(),
+            Value::Number(f) => stringify_number(*f),
             Value::Bool(b) => b.to_string(),
             Value::Nil => "nil".to_owned(),
             Value::String(s) => s.to_owned(),
             Value::Class(c) => c.upgrade().unwrap().stringify(),
-            Value::Closure(Closure(f, _)) => f.upgrade().unwrap().stringify(),
+            Value::Closure(c) => c.function().stringify(),
+            Value::Native(n) => n.stringify(),
+            Value::Module(m) => m.stringify(),
+            Value::Range(r) => r.stringify(),
+            Value::ListIterator(_) => "<list iterator>".to_owned(),
+            Value::Bytes(b) => stringify_bytes(&b.borrow()),
+            Value::List(_) | Value::Table(_) => stringify_nested(self, &mut Vec::new()),
+            Value::Memoized(m) =>
+                format_interned!("<memoized fn {}>", m.closure.function().name),
+            Value::WeakRef(_) => "<weakref>".to_owned(),
             Value::Instance(instance) =>
                 format_interned!("{} instance", instance.apply(|i| i.name())),
+            Value::BoundMethod(bound) => bound.method.function().stringify(),
             Value::UpvaluePtr(value) => value.deep_apply(|e| e.stringify()),
             Value::TemporaryPlaceholder => panic!("TemporaryPlaceholder found!"),
         }
     }
 
+    /// The user facing name of the value's type, for error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Number(_) => "number",
+            Value::Bool(_) => "bool",
+            Value::Nil => "nil",
+            Value::String(_) => "string",
+            Value::Closure(_) => "function",
+            Value::Native(_) => "native function",
+            Value::Class(_) => "class",
+            Value::Instance(_) => "instance",
+            Value::BoundMethod(_) => "function",
+            Value::Module(_) => "module",
+            Value::Range(_) => "range",
+            Value::ListIterator(_) => "list iterator",
+            Value::Bytes(_) => "bytes",
+            Value::List(_) => "list",
+            Value::Table(_) => "table",
+            Value::Memoized(_) => "function",
+            Value::WeakRef(_) => "weakref",
+            Value::UpvaluePtr(value) => value.deep_apply(|e| e.type_name()),
+            Value::TemporaryPlaceholder => panic!("TemporaryPlaceholder found!"),
+        }
+    }
+
     pub fn pp_debug(&self) -> String {
         match self {
             Value::UpvaluePtr(v) => format!("upv: {}", v.deep_apply(|e| e.pp_debug())),
@@ -139,6 +514,62 @@ impl Value {
         }
     }
     pub fn is_upvalue_ptr(&self) -> bool { matches!(self, Value::UpvaluePtr(..)) }
+
+    /// The hash of the value's [TableKey], or None if it can't be a key. Equal values hash equally,
+    /// and since the hasher isn't randomly seeded, the hash is also stable across runs.
+    pub fn hash_key(&self) -> Option<u64> { TableKey::new(self).ok().map(|k| k.stable_hash()) }
+}
+
+// Follows clox (i.e., "%g") in spirit: integral numbers are printed without a decimal point, and
+// very large or very small magnitudes fall back to scientific notation.
+fn stringify_number(f: f64) -> String {
+    if f.is_nan() {
+        "nan".to_owned()
+    } else if f.is_infinite() {
+        (if f > 0.0 { "inf" } else { "-inf" }).to_owned()
+    } else if f != 0.0 && (f.abs() >= 1e16 || f.abs() < 1e-4) {
+        format!("{:e}", f)
+    } else if f.fract() == 0.0 {
+        format!("{:.0}", f)
+    } else {
+        f.to_string()
+    }
+}
+
+// E.g., b"Hi\n\xff".
+fn stringify_bytes(bytes: &[u8]) -> String {
+    let escaped: String =
+        bytes.iter().flat_map(|b| std::ascii::escape_default(*b)).map(char::from).collect();
+    format!("b\"{}\"", escaped)
+}
+
+// Strings are quoted, so e.g. ["1"] and [1] can be told apart. Lists and tables which (indirectly)
+// contain themselves are printed as [...] or {...} the second time around, and so are those nested
+// more than MAX_NESTING levels deep, so stringifying them doesn't overflow the stack.
+fn stringify_nested(value: &Value, enclosing: &mut Vec<Value>) -> String {
+    const MAX_NESTING: usize = 1000;
+    let is_elided = enclosing.len() >= MAX_NESTING || enclosing.contains(value);
+    match value {
+        Value::String(s) => format!("{:?}", s.to_owned()),
+        Value::List(_) if is_elided => "[...]".to_owned(),
+        Value::Table(_) if is_elided => "{...}".to_owned(),
+        Value::List(list) => {
+            enclosing.push(value.clone());
+            let elements: Vec<String> =
+                list.apply(|l| l.iter().map(|v| stringify_nested(v, enclosing)).collect());
+            enclosing.pop();
+            format!("[{}]", elements.join(", "))
+        }
+        Value::Table(table) => {
+            enclosing.push(value.clone());
+            let entries: Vec<String> = table.apply(|t| t.entries.iter()
+                .map(|(k, v)| format!("{}: {}", k.stringify(), stringify_nested(v, enclosing)))
+                .collect());
+            enclosing.pop();
+            format!("{{{}}}", entries.join(", "))
+        }
+        e => e.stringify(),
+    }
 }
 
 impl PartialEq<Self> for Value {
@@ -148,83 +579,143 @@ impl PartialEq<Self> for Value {
             (Value::Bool(b1), Value::Bool(b2)) => b1 == b2,
             (Value::Nil, Value::Nil) => true,
             (Value::String(s1), Value::String(s2)) => s1 == s2,
+            (Value::Range(r1), Value::Range(r2)) => r1 == r2,
+            (Value::Bytes(b1), Value::Bytes(b2)) => b1 == b2,
+            // Compared by identity, since comparing cyclic lists by value wouldn't terminate.
+            (Value::List(l1), Value::List(l2)) => l1 == l2,
+            (Value::Table(t1), Value::Table(t2)) => t1 == t2,
             _ => false,
         }
     }
 }
 
+/// Returned when a [Value] can't be converted to the requested variant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeError {
+    pub expected: &'static str,
+    pub found: String,
+}
+
+impl TypeError {
+    fn new(expected: &'static str, found: &Value) -> Self {
+        TypeError { expected, found: format!("{:?}", found) }
+    }
+}
+
+impl Display for TypeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Expected Value::{}, but found {}", self.expected, self.found)
+    }
+}
+
+impl Error for TypeError {}
+
+// Natives report their errors as plain strings.
+impl From<TypeError> for String {
+    fn from(value: TypeError) -> Self { value.to_string() }
+}
+
 impl TryFrom<&Value> for f64 {
-    type Error = String;
+    type Error = TypeError;
 
     fn try_from(value: &Value) -> Result<Self, Self::Error> {
         match &value {
             Value::Number(f) => Ok(*f),
             Value::UpvaluePtr(v) => v.deep_apply(|e| e.try_into()),
-            e => Err(format!("Expected Value::Number, but found {:?}", e)),
+            e => Err(TypeError::new("Number", e)),
         }
     }
 }
 
-impl<'a> TryFrom<&'a Value> for (Weak<Function>, ClosedOverValues) {
-    type Error = String;
+impl<'a> TryFrom<&'a Value> for ClosureParts {
+    type Error = TypeError;
 
     fn try_from(value: &Value) -> Result<Self, Self::Error> {
         match &value {
-            Value::Closure(Closure(f, uv)) => Ok((f.clone(), uv.clone())),
+            Value::Closure(c) => Ok(c.parts()),
             Value::UpvaluePtr(v) => v.deep_apply(|e| e.try_into()),
-            e => Err(format!("Expected Value::Closure, but found {:?}", e)),
+            e => Err(TypeError::new("Closure", e)),
         }
     }
 }
 
 impl<'a> TryFrom<&'a Value> for Weak<Class> {
-    type Error = String;
+    type Error = TypeError;
 
     fn try_from(value: &Value) -> Result<Self, Self::Error> {
         match &value {
             Value::Class(class) => Ok(class.clone()),
             Value::UpvaluePtr(v) => v.deep_apply(|e| e.try_into()),
-            e => Err(format!("Expected Value::Class, but found {:?}", e)),
+            e => Err(TypeError::new("Class", e)),
         }
     }
 }
 
 impl<'a> TryFrom<&'a Value> for Pointer<Instance> {
-    type Error = String;
+    type Error = TypeError;
 
     fn try_from(value: &Value) -> Result<Self, Self::Error> {
         match &value {
             Value::Instance(instance) => Ok(instance.clone()),
             Value::UpvaluePtr(v) => v.deep_apply(|e| e.try_into()),
-            e => Err(format!("Expected Value::Instance, but found {:?}", e)),
+            e => Err(TypeError::new("Instance", e)),
         }
     }
 }
 
 impl<'a> TryFrom<&'a Value> for bool {
-    type Error = String;
+    type Error = TypeError;
 
     fn try_from(value: &'a Value) -> Result<Self, Self::Error> {
         match &value {
             Value::Bool(b) => Ok(*b),
             Value::UpvaluePtr(v) => v.deep_apply(|e| e.try_into()),
-            e => Err(format!("Expected Value::Bool, but found {:?}", e)),
+            e => Err(TypeError::new("Bool", e)),
         }
     }
 }
 
 impl<'a> TryFrom<&'a Value> for InternedString {
-    type Error = String;
+    type Error = TypeError;
 
     fn try_from(value: &'a Value) -> Result<Self, Self::Error> {
         match &value {
             Value::String(s) => Ok(s.clone()),
             Value::UpvaluePtr(v) => v.deep_apply(|e| e.try_into()),
-            e => Err(format!("Expected Value::String, but found {:?}", e)),
+            e => Err(TypeError::new("String", e)),
         }
     }
 }
 
+impl<'a> TryFrom<&'a Value> for String {
+    type Error = TypeError;
+
+    fn try_from(value: &'a Value) -> Result<Self, Self::Error> {
+        InternedString::try_from(value).map(|s| s.to_owned())
+    }
+}
+
+/// Converts the elements as well, failing on the first element which can't be converted.
+impl<'a, A: for<'b> TryFrom<&'b Value, Error=TypeError>> TryFrom<&'a Value> for Vec<A> {
+    type Error = TypeError;
+
+    fn try_from(value: &'a Value) -> Result<Self, Self::Error> {
+        match &value {
+            Value::List(list) => list.apply(|l| l.iter().map(A::try_from).collect()),
+            Value::UpvaluePtr(v) => v.deep_apply(|e| e.try_into()),
+            e => Err(TypeError::new("List", e)),
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self { Value::Number(value) }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self { Value::Bool(value) }
+}
+
 /// An encapsulating wrapper on top of [PointedUpvalueImpl].
 #[derive(Debug, Clone)]
 pub struct PointedUpvalue(PointedUpvalueImpl);
@@ -258,6 +749,8 @@ impl PointedUpvalue {
         PointedUpvalue(PointedUpvalueImpl::Open(index, stack))
     }
 
+    pub fn closed(value: Value) -> Self { PointedUpvalue(PointedUpvalueImpl::Closed(rcrc(value))) }
+
     pub fn close(&mut self) {
         match &mut self.0 {
             PointedUpvalueImpl::Open(i, ref mut v) => {
@@ -310,27 +803,97 @@ pub struct Function {
     pub arity: usize,
     pub chunk: Chunk,
     pub upvalues: Vec<Upvalue>,
+    // Methods get their receiver, i.e., `this`, as an extra local before the parameters, which
+    // isn't counted in their arity.
+    pub is_method: bool,
 }
 
 impl Function {
     pub fn stringify(&self) -> String { format_interned!("<fn {}>", self.name) }
+
+    /// The number of locals the function starts with, i.e., its parameters and receiver.
+    pub fn initial_locals(&self) -> usize { self.arity + self.is_method as usize }
 }
 
 impl DeepEq for Function {
     fn deep_eq(&self, other: &Self) -> bool {
         self.name.to_owned() == other.name.to_owned()
             && self.arity == other.arity
+            && self.upvalues == other.upvalues
+            && self.is_method == other.is_method
             && self.chunk.deep_eq(&other.chunk)
     }
 }
 
+/// A function implemented in Rust. Its name is interned so it can be handed back to Lox programs.
+#[derive(Clone)]
+pub struct NativeFunction {
+    pub name: InternedString,
+    pub arity: NativeArity,
+    pub func: NativeFunctionPtr,
+}
+
+impl NativeFunction {
+    pub fn stringify(&self) -> String { format_interned!("<native fn {}>", self.name) }
+}
+
+impl Debug for NativeFunction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NativeFunction")
+            .field("name", &self.name.to_owned())
+            .field("arity", &self.arity)
+            .finish()
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Class {
     pub name: InternedString,
+    // Methods are closures, so they're only bound when the class declaration runs, see
+    // OpCode::Method. Since the class itself is shared by all runs of its declaration, e.g., one
+    // nested in a function, rerunning the declaration rebinds the methods of existing instances as
+    // well.
+    methods: RefCell<HashMap<InternedString, Closure>>,
+    // Called on the class itself, so they're never bound to an instance.
+    static_methods: RefCell<HashMap<InternedString, Closure>>,
 }
 
+pub const INITIALIZER: &str = "init";
+// For-in loops over an instance iterate over what its __iter method returns. An instance iterator
+// is advanced by calling its __next method, until it returns nil.
+pub const ITERATOR: &str = "__iter";
+pub const NEXT: &str = "__next";
+// Printing or interpolating an instance with a __str method prints or interpolates what it returns.
+pub const STRINGIFIER: &str = "__str";
+
 impl Class {
+    pub fn new(name: InternedString) -> Self {
+        Class { name, methods: RefCell::default(), static_methods: RefCell::default() }
+    }
+
     pub fn stringify(&self) -> String { format_interned!("{}", self.name) }
+
+    pub fn method(&self, name: &InternedString) -> Option<Closure> {
+        self.methods.borrow().get(name).cloned()
+    }
+    pub fn initializer(&self) -> Option<Closure> {
+        self.methods.borrow().iter()
+            .find(|(name, _)| name.apply(|n| n == INITIALIZER))
+            .map(|(_, closure)| closure.clone())
+    }
+    pub fn set_method(&self, name: InternedString, closure: Closure) {
+        self.methods.borrow_mut().insert(name, closure);
+    }
+    pub fn static_method(&self, name: &InternedString) -> Option<Closure> {
+        self.static_methods.borrow().get(name).cloned()
+    }
+    pub fn set_static_method(&self, name: InternedString, closure: Closure) {
+        self.static_methods.borrow_mut().insert(name, closure);
+    }
+    pub fn clear_methods(&self) {
+        self.methods.borrow_mut().clear();
+        self.static_methods.borrow_mut().clear();
+    }
 }
 
 impl DeepEq for Class {
@@ -346,7 +909,7 @@ impl DeepEq for Class {
 /// Since closures, i.e., function values, follow reference semantics (i.e., one can assign the same
 /// closure to multiple values), this clone is also shallow. Of course, closures also share data,
 /// so this is mandatory:
-/// ```
+/// ```text
 /// fun foo(x) {
 ///   fun bar() {
 ///     x = x + 1;
@@ -370,59 +933,209 @@ impl ClosedOverValues {
         ClosedOverValues { upvalues: rcrc(upvalues) }
     }
 
-    pub fn get(&self, index: StackLocation) -> Pointer<PointedUpvalue> {
-        self.upvalues.borrow()[index].clone()
+    pub fn len(&self) -> usize { self.upvalues.borrow().len() }
+
+    pub fn get(&self, index: StackLocation) -> Option<Pointer<PointedUpvalue>> {
+        self.upvalues.borrow().get(index).cloned()
     }
 
     // The &mut self is here to protected against modifications, since we do modify the internal
     // upvalue.
-    pub fn set(&mut self, index: StackLocation, value: Value) {
-        self.upvalues.borrow_mut()[index].deep_set(value)
+    pub fn set(&mut self, index: StackLocation, value: Value) -> Option<()> {
+        self.upvalues.borrow_mut().get_mut(index).map(|u| u.deep_set(value))
     }
 }
 
+// Marking is iterative, since values can be nested deeply enough, e.g., a list of a list of ... to
+// overflow the stack if marked recursively. Instead, the values referenced by a marked value are
+// pushed onto the gray stack, to be marked in turn.
 pub trait Mark {
-    fn mark(&self);
+    fn mark_shallow(&self, gray: &mut Vec<Value>);
+
+    fn mark(&self) {
+        let mut gray = Vec::new();
+        self.mark_shallow(&mut gray);
+        while let Some(value) = gray.pop() {
+            value.mark_shallow(&mut gray);
+        }
+    }
 }
 
 impl Mark for Value {
-    fn mark(&self) {
+    fn mark_shallow(&self, gray: &mut Vec<Value>) {
         match self {
             Value::Number(_) => (),
             Value::Bool(_) => (),
             Value::Nil => (),
             Value::TemporaryPlaceholder => panic!("TemporaryPlaceholder found!"),
             Value::String(s) => { s.mark(); }
-            Value::Class(c) => { c.upgrade().unwrap().name.mark(); }
+            Value::Class(c) => c.upgrade().unwrap().mark_shallow(gray),
             Value::Instance(i_ptr) => {
                 if i_ptr.mark() {
-                    i_ptr.apply(|i| i.mark());
+                    i_ptr.apply(|i| i.mark_shallow(gray));
+                }
+            }
+            Value::Closure(c) => c.upvalues().mark_shallow(gray),
+            Value::BoundMethod(bound) => {
+                gray.push(Value::Instance(bound.receiver.clone()));
+                bound.method.upvalues().mark_shallow(gray);
+            }
+            Value::Native(n) => { n.name.mark(); }
+            Value::Range(_) => (),
+            Value::ListIterator(i) => gray.push(Value::List(i.list.clone())),
+            Value::Bytes(_) => (),
+            Value::List(l) => {
+                if l.mark() {
+                    l.apply(|l| gray.extend(l.iter().cloned()));
+                }
+            }
+            Value::Table(t) => {
+                if t.mark() {
+                    t.apply(|t| t.mark_shallow(gray));
+                }
+            }
+            Value::Memoized(m) => m.mark_shallow(gray),
+            Value::WeakRef(_) => (),
+            // The VM marks the globals of all modules, since their closures can outlive them.
+            Value::Module(m) => { m.name.mark(); }
+            Value::UpvaluePtr(p) => {
+                if p.apply(|upv| upv.is_closed()) && p.mark() {
+                    p.deep_apply(|v| gray.push(v.clone()));
                 }
             }
-            Value::Closure(Closure(_, upvalues)) => upvalues.mark(),
-            Value::UpvaluePtr(p) => { p.mark(); }
         }
     }
 }
 
 impl<V: Mark> Mark for HashMap<InternedString, V> {
-    fn mark(&self) {
+    fn mark_shallow(&self, gray: &mut Vec<Value>) {
         for (k, v) in self.into_iter() {
             k.mark();
-            v.mark();
+            v.mark_shallow(gray);
+        }
+    }
+}
+
+impl Mark for Table {
+    fn mark_shallow(&self, gray: &mut Vec<Value>) {
+        for (key, value) in self.entries.iter() {
+            if let TableKey::String(s) = key {
+                s.mark();
+            }
+            gray.push(value.clone());
         }
     }
 }
 
 impl Mark for Instance {
-    fn mark(&self) { self.1.borrow().deref().mark() }
+    fn mark_shallow(&self, gray: &mut Vec<Value>) { self.1.borrow().deref().mark_shallow(gray) }
+}
+
+impl HeapSize for List {
+    fn owned_bytes(&self) -> usize { self.elements.capacity() * mem::size_of::<Value>() }
+}
+
+impl HeapSize for Table {
+    fn owned_bytes(&self) -> usize {
+        self.entries.capacity() * mem::size_of::<(TableKey, Value)>()
+            + self.indices.capacity() * mem::size_of::<(TableKey, usize)>()
+    }
+}
+
+impl HeapSize for Instance {
+    fn owned_bytes(&self) -> usize {
+        self.1.borrow().capacity() * mem::size_of::<(InternedString, Value)>()
+    }
+}
+
+impl HeapSize for PointedUpvalue {}
+
+impl Mark for Class {
+    fn mark_shallow(&self, gray: &mut Vec<Value>) {
+        self.name.mark();
+        let methods = self.methods.borrow();
+        let static_methods = self.static_methods.borrow();
+        for (name, closure) in methods.iter().chain(static_methods.iter()) {
+            name.mark();
+            closure.upvalues().mark_shallow(gray);
+        }
+    }
 }
 
 impl Mark for ClosedOverValues {
-    fn mark(&self) {
-        // We only need to mark closed upvalues, since open upvalues will never be collected.
-        self.upvalues.borrow_mut().iter_mut().for_each(|p| if p.apply(|upv| upv.is_closed()) {
-            p.mark();
+    fn mark_shallow(&self, gray: &mut Vec<Value>) {
+        // We only need to mark closed upvalues, since open upvalues will never be collected. Their
+        // values have to be marked as well, since e.g., closures outlive the values they captured.
+        self.upvalues.borrow().iter().for_each(|p| if p.apply(|upv| upv.is_closed()) && p.mark() {
+            p.deep_apply(|v| gray.push(v.clone()));
         })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stringified(f: f64) -> String { Value::Number(f).stringify() }
+
+    #[test]
+    fn ranges_with_extreme_bounds() {
+        let everything = Range { start: i64::MIN, end: i64::MAX, inclusive: true };
+        assert_eq!(everything.len(), None);
+        assert!(!everything.is_empty());
+        assert_eq!(Range { inclusive: false, ..everything }.len(), Some(usize::MAX));
+        assert_eq!(Range { start: i64::MAX, end: i64::MIN, inclusive: true }.len(), Some(0));
+        let mut last = Range { start: i64::MAX - 1, end: i64::MAX, inclusive: true };
+        assert_eq!(last.pop_first(), Some(i64::MAX - 1));
+        assert_eq!(last.pop_first(), Some(i64::MAX));
+        assert_eq!(last.pop_first(), None);
+    }
+
+    #[test]
+    fn values_fit_in_two_words() {
+        assert_eq!(std::mem::size_of::<Value>(), 16);
+    }
+
+    #[test]
+    fn integral_numbers_have_no_decimal_point() {
+        assert_eq!(stringified(5.0), "5");
+        assert_eq!(stringified(-12.0), "-12");
+        assert_eq!(stringified(0.0), "0");
+    }
+
+    #[test]
+    fn fractional_numbers_use_minimal_digits() {
+        assert_eq!(stringified(2.75), "2.75");
+        assert_eq!(stringified(-0.5), "-0.5");
+    }
+
+    #[test]
+    fn extreme_magnitudes_use_scientific_notation() {
+        assert_eq!(stringified(1e21), "1e21");
+        assert_eq!(stringified(-2.5e20), "-2.5e20");
+        assert_eq!(stringified(1e-7), "1e-7");
+    }
+
+    #[test]
+    fn non_finite_numbers() {
+        assert_eq!(stringified(f64::INFINITY), "inf");
+        assert_eq!(stringified(f64::NEG_INFINITY), "-inf");
+        assert_eq!(stringified(f64::NAN), "nan");
+    }
+
+    #[test]
+    fn hash_keys_are_consistent_with_equality() {
+        assert_eq!(Value::Number(0.0).hash_key(), Value::Number(-0.0).hash_key());
+        assert_eq!(Value::Nil.hash_key(), Value::Nil.hash_key());
+        assert_ne!(Value::Number(0.0).hash_key(), Value::Bool(false).hash_key());
+        assert_ne!(Value::Bool(true).hash_key(), Value::Bool(false).hash_key());
+        assert_eq!(Value::Bytes(rcrc(vec![1])).hash_key(), None);
+    }
+
+    #[test]
+    fn nan_is_not_a_hash_key() {
+        assert_ne!(Value::Number(f64::NAN), Value::Number(f64::NAN));
+        assert_eq!(Value::Number(f64::NAN).hash_key(), None);
+        assert_eq!(Value::Number(-f64::NAN).hash_key(), None);
+    }
+}
@@ -0,0 +1,356 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+use crate::rslox::compiled::chunk::{Chunk, Upvalue};
+use crate::rslox::compiled::op_code::{CodeLocation, OpCode};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub function: String,
+    pub location: CodeLocation,
+    pub message: String,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at {}: {}", self.function, self.location, self.message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub violations: Vec<Violation>,
+}
+
+/// One violation per line.
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let lines: Vec<String> = self.violations.iter().map(|v| v.to_string()).collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl Error for VerifyError {}
+
+type StackDepth = usize;
+
+/// Checks the structural invariants the VM assumes, so malformed (e.g., hand-crafted or corrupted)
+/// bytecode is rejected up front, instead of panicking, or worse, halfway through its execution:
+/// * Function, class, global, upvalue and local indices are in range.
+/// * Jumps land inside the code, and conditional jumps only jump forward.
+/// * Every instruction is reached with the same stack depth on all paths, which never underflows,
+///   and never exceeds `max_stack_depth`. Depths are relative to the frame, i.e., a function
+///   starts with its parameters (and a method with its receiver as well).
+/// * Functions never fall off the end of their code, i.e., they always return.
+pub fn verify(chunk: &Chunk, max_stack_depth: StackDepth) -> Result<(), VerifyError> {
+    let mut verifier = Verifier {
+        max_stack_depth,
+        global_count: chunk.global_names().len(),
+        violations: Vec::new(),
+    };
+    verifier.verify_function("<script>".to_owned(), chunk, 0, 0, true);
+    if verifier.violations.is_empty() {
+        Ok(())
+    } else {
+        Err(VerifyError { violations: verifier.violations })
+    }
+}
+
+struct Verifier {
+    max_stack_depth: StackDepth,
+    // Only the top level script has global slots, but all functions can access them.
+    global_count: usize,
+    violations: Vec<Violation>,
+}
+
+struct Frame<'a> {
+    chunk: &'a Chunk,
+    upvalue_count: usize,
+    is_script: bool,
+}
+
+impl Verifier {
+    fn verify_function(
+        &mut self,
+        name: String,
+        chunk: &Chunk,
+        locals: usize,
+        upvalue_count: usize,
+        is_script: bool,
+    ) {
+        let frame = Frame { chunk, upvalue_count, is_script };
+        let length = chunk.get_code().len();
+        let mut depths: Vec<Option<StackDepth>> = vec![None; length];
+        let mut pending = vec![(0 as CodeLocation, locals as StackDepth)];
+        while let Some((location, depth)) = pending.pop() {
+            if location == length {
+                if !is_script {
+                    self.violation(&name, location, "Falls off the end of the function".to_owned());
+                }
+                continue;
+            }
+            match depths[location] {
+                Some(previous) if previous == depth => continue,
+                Some(previous) => {
+                    self.violation(&name, location, format!(
+                        "Reached with inconsistent stack depths {} and {}", previous, depth));
+                    continue;
+                }
+                None => depths[location] = Some(depth),
+            }
+            match self.successors(&frame, location, depth) {
+                Ok(successors) => pending.extend(successors),
+                Err(msg) => self.violation(&name, location, msg),
+            }
+        }
+        for i in 0..chunk.function_count() {
+            let function = chunk.get_function(i).upgrade().unwrap();
+            self.verify_function(
+                function.name.to_owned(),
+                &function.chunk,
+                function.initial_locals(),
+                function.upvalues.len(),
+                false,
+            );
+        }
+    }
+
+    fn violation(&mut self, function: &str, location: CodeLocation, message: String) {
+        self.violations.push(Violation { function: function.to_owned(), location, message });
+    }
+
+    // The locations execution can continue to from this instruction, with their stack depths.
+    fn successors(
+        &self, frame: &Frame, location: CodeLocation, depth: StackDepth,
+    ) -> Result<Vec<(CodeLocation, StackDepth)>, String> {
+        let (op, _) = frame.chunk.get_code().get(location).unwrap();
+        self.check_indices(frame, op, depth)?;
+        let (pops, pushes) = op.stack_effect().ok_or_else(|| match op {
+            OpCode::UnpatchedJump => "Unpatched jump".to_owned(),
+            _ => format!("{} operand is too large", op.to_upper_snake().trim()),
+        })?;
+        if depth < pops {
+            return Err(format!(
+                "{} needs {} values, but the stack only has {}",
+                op.to_upper_snake().trim(), pops, depth,
+            ));
+        }
+        let next_depth = depth - pops + pushes;
+        if next_depth > self.max_stack_depth {
+            return Err(format!(
+                "Stack depth {} exceeds the maximum of {}", next_depth, self.max_stack_depth));
+        }
+        let jump = |target: CodeLocation, depth: StackDepth| {
+            if target > frame.chunk.get_code().len() {
+                Err(format!("Jump target {} is out of bounds", target))
+            } else {
+                Ok((target, depth))
+            }
+        };
+        let forward_jump = |target: CodeLocation, depth: StackDepth| {
+            if target <= location {
+                Err(format!("Conditional jump target {} isn't after the jump", target))
+            } else {
+                jump(target, depth)
+            }
+        };
+        Ok(match op {
+            OpCode::Return => vec![],
+            OpCode::Jump(target) => vec![jump(*target, next_depth)?],
+            OpCode::Loop(offset) => vec![location.checked_sub(*offset)
+                .map(|target| (target, next_depth))
+                .ok_or_else(|| format!("Loop offset {} jumps before the start", offset))?],
+            OpCode::JumpIfFalse(target) =>
+                vec![(location + 1, next_depth), forward_jump(*target, next_depth)?],
+            // The exit jump doesn't push the next element.
+            OpCode::IterNext(_, exit) =>
+                vec![(location + 1, next_depth), forward_jump(*exit, depth)?],
+            _ => vec![(location + 1, next_depth)],
+        })
+    }
+
+    fn check_indices(&self, frame: &Frame, op: &OpCode, depth: StackDepth) -> Result<(), String> {
+        let in_range = |what: &str, index: usize, count: usize| if index < count {
+            Ok(())
+        } else {
+            Err(format!("Invalid {} index {}, there are only {}", what, index, count))
+        };
+        match op {
+            OpCode::Function(i) => {
+                in_range("function", *i, frame.chunk.function_count())?;
+                let function = frame.chunk.get_function(*i).upgrade().unwrap();
+                for Upvalue { index, is_local, .. } in function.upvalues.iter() {
+                    if *is_local {
+                        in_range("captured local", *index, depth)?;
+                    } else {
+                        in_range("captured upvalue", *index, frame.upvalue_count)?;
+                    }
+                }
+                Ok(())
+            }
+            OpCode::Class(i) => in_range("class", *i, frame.chunk.class_count()),
+            OpCode::GetGlobalByIndex(i) | OpCode::SetGlobalByIndex(i) =>
+                in_range("global", *i, self.global_count),
+            OpCode::GetUpvalue(i) | OpCode::SetUpvalue(i) =>
+                in_range("upvalue", *i, frame.upvalue_count),
+            OpCode::GetLocal(i) | OpCode::SetLocal(i) | OpCode::DefineLocal(i)
+            | OpCode::IterNext(i, _) =>
+                in_range("local", *i, depth),
+            OpCode::Return if frame.is_script => Err("Can't return from the script".to_owned()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assert_msg_contains;
+    use crate::rslox::compiled::memory::Managed;
+    use crate::rslox::compiled::tests::unsafe_compile;
+    use crate::rslox::compiled::value::Function;
+
+    use super::*;
+
+    const MAX_DEPTH: StackDepth = 256;
+
+    fn violations(chunk: &Chunk) -> Vec<String> {
+        match verify(chunk, MAX_DEPTH) {
+            Ok(()) => vec![],
+            Err(e) => e.violations.iter().map(|v| v.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn compiled_chunks_pass() {
+        let (chunk, _interned_strings) = unsafe_compile(vec![r#"
+var total = 0;
+fun counter(start) {
+  var count = start;
+  fun increment() {
+    count = count + 1;
+    return count;
+  }
+  return increment;
+}
+var c = counter(1);
+for (var i = 0; i < 3; i = i + 1) {
+  if (i == 1 and c() > 1 or false) {
+    total = total + c();
+  } else {
+    print 1 < i <= 3;
+  }
+}
+for (x in 1..3) {
+  var y = x;
+  fun capture() use (y) { return y; }
+  total = total + capture();
+}
+fun sign(n) {
+  if (n < 0) return -1; else return 1;
+}
+class Foo {
+  init(x) { this.x = x; }
+  get() { return this.x; }
+}
+var foo = Foo(1);
+foo.bar = sign(total) + foo.get();
+while (total > 0) total = total - 1;
+print foo.bar;
+        "#]);
+        assert_eq!(violations(&chunk), Vec::<String>::new());
+    }
+
+    #[test]
+    fn bad_jump_targets_are_rejected() {
+        let (mut chunk, _interned_strings) = unsafe_compile(vec!["if (true) print 1;"]);
+        let jump = chunk.get_code().iter()
+            .position(|(op, _)| matches!(op, OpCode::JumpIfFalse(_)))
+            .unwrap();
+        chunk.get_mut(jump).unwrap().0 = OpCode::JumpIfFalse(42);
+        assert_eq!(violations(&chunk), vec!["<script> at 1: Jump target 42 is out of bounds"]);
+    }
+
+    #[test]
+    fn stack_underflow_and_overflow() {
+        let mut chunk = Chunk::default();
+        chunk.write(OpCode::Number(1.0), 1);
+        chunk.write(OpCode::Add, 1);
+        assert_eq!(
+            violations(&chunk),
+            vec!["<script> at 1: ADD needs 2 values, but the stack only has 1"],
+        );
+
+        let mut chunk = Chunk::default();
+        chunk.write(OpCode::Nil, 1);
+        chunk.write(OpCode::Nil, 1);
+        assert_eq!(
+            verify(&chunk, 1).unwrap_err().violations[0].message,
+            "Stack depth 2 exceeds the maximum of 1",
+        );
+    }
+
+    #[test]
+    fn huge_operands_are_rejected() {
+        for op in [OpCode::MakeTable(usize::MAX), OpCode::Call(usize::MAX)] {
+            let mut chunk = Chunk::default();
+            chunk.write(op, 1);
+            let violations = violations(&chunk);
+            assert_eq!(violations.len(), 1);
+            assert_msg_contains!(violations[0], "operand is too large");
+        }
+    }
+
+    #[test]
+    fn inconsistent_stack_depths_are_rejected() {
+        let mut chunk = Chunk::default();
+        chunk.write(OpCode::Bool(true), 1);
+        chunk.write(OpCode::JumpIfFalse(3), 1);
+        chunk.write(OpCode::Nil, 1);
+        chunk.write(OpCode::Nil, 1);
+        assert_eq!(
+            violations(&chunk),
+            vec!["<script> at 3: Reached with inconsistent stack depths 0 and 1"],
+        );
+    }
+
+    #[test]
+    fn functions_must_return() {
+        let name = Managed::new("foo".to_owned());
+        let mut body = Chunk::default();
+        body.write(OpCode::Nil, 1);
+        body.write(OpCode::Print, 1);
+        let mut chunk = Chunk::default();
+        let function = Function {
+            name: name.ptr(), arity: 0, chunk: body, upvalues: vec![], is_method: false,
+        };
+        chunk.add_function(function, 1);
+        assert_eq!(violations(&chunk), vec!["foo at 2: Falls off the end of the function"]);
+    }
+
+    #[test]
+    fn out_of_range_indices_are_rejected() {
+        let mut chunk = Chunk::default();
+        chunk.write(OpCode::GetUpvalue(0), 1);
+        chunk.write(OpCode::GetLocal(3), 1);
+        chunk.write(OpCode::Class(0), 1);
+        assert_eq!(
+            violations(&chunk),
+            vec!["<script> at 0: Invalid upvalue index 0, there are only 0"],
+        );
+        let mut chunk = Chunk::default();
+        chunk.write(OpCode::Nil, 1);
+        chunk.write(OpCode::GetLocal(1), 1);
+        chunk.write(OpCode::Class(0), 1);
+        assert_eq!(
+            violations(&chunk),
+            vec!["<script> at 1: Invalid local index 1, there are only 1"],
+        );
+        let mut chunk = Chunk::default();
+        chunk.write(OpCode::Nil, 1);
+        chunk.write(OpCode::DefineLocal(1), 1);
+        assert_eq!(
+            violations(&chunk),
+            vec!["<script> at 1: Invalid local index 1, there are only 1"],
+        );
+    }
+}
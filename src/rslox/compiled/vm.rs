@@ -1,98 +1,325 @@
+use std::any::Any;
 use std::borrow::ToOwned;
+use std::cmp::Ordering;
 use std::collections::{HashMap, VecDeque};
 use std::convert::{TryFrom, TryInto};
-use std::io::Write;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::{Stdout, Write};
 use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
 use std::rc::{Rc, Weak};
 
 use linked_list::{Cursor, LinkedList};
 use nonempty::NonEmpty;
 
 use crate::format_interned;
+use crate::rslox::common::error::{ErrorInfo, LoxError};
 use crate::rslox::common::utils::{RcRc, rcrc, Truncateable};
-use crate::rslox::compiled::chunk::{Chunk, Upvalue};
+use crate::rslox::compiled::chunk::{Chunk, disassemble_instruction, Upvalue};
 use crate::rslox::compiled::code::Line;
-use crate::rslox::compiled::memory::{Heap, InternedString, Managed, Pointer};
-use crate::rslox::compiled::op_code::{OpCode, StackLocation};
-use crate::rslox::compiled::value::{ClosedOverValues, Function, Instance, Mark, PointedUpvalue, Value};
+use crate::rslox::compiled::debugger::{DebugAction, DebugEvent, Debugger};
+use crate::rslox::compiled::globals::Globals;
+use crate::rslox::compiled::memory::{GcPolicy, GcState, GcStats, Heap, InternedString, Managed, Pointer};
+use crate::rslox::compiled::natives::{
+    NativeArity, NativeContext, NativeFunctionPtr, standard_natives, time_natives,
+};
+use crate::rslox::compiled::op_code::{ArgCount, CodeLocation, OpCode, StackLocation};
+use crate::rslox::compiled::value::{BoundMethod, Class, ClosedOverValues, ClosureParts, Function, Instance, ITERATOR, List, ListIterator, Mark, Memoized, Module, NativeFunction, NEXT, PointedUpvalue, Range, STRINGIFIER, Table, TableKey, TypeError, Value};
 
 use super::compiler::InternedStrings;
 
 type FunctionName = String;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-struct VmError {
+pub struct VmError {
     msg: String,
     stack_trace: Box<VecDeque<(FunctionName, Line)>>,
+    // The underlying failure, if the error was caused by one, e.g., a failed value conversion.
+    source: Option<TypeError>,
 }
 
 impl VmError {
     pub fn new(msg: String, function_name: FunctionName, line: Line) -> Self {
         let mut stack_trace: VecDeque<(FunctionName, Line)> = Default::default();
         stack_trace.push_back((function_name, line));
-        VmError { msg, stack_trace: Box::new(stack_trace) }
+        VmError { msg, stack_trace: Box::new(stack_trace), source: None }
     }
+    fn with_source(self, source: TypeError) -> Self { VmError { source: Some(source), ..self } }
     pub fn prepend(&mut self, function_name: FunctionName, line: Line) {
         self.stack_trace.push_back((function_name, line));
     }
 }
 
+/// The message, followed by the stack trace, innermost call first.
+impl Display for VmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)?;
+        for (function_name, line) in self.stack_trace.iter() {
+            write!(f, "\n  in {} at line {}", function_name, line)?;
+        }
+        Ok(())
+    }
+}
+
+impl LoxError for VmError {
+    fn get_info(&self) -> ErrorInfo { ErrorInfo { line: self.stack_trace.front().unwrap().1 } }
+
+    fn get_message(&self) -> String { self.msg.to_owned() }
+}
+
+impl Error for VmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_ref().map(|e| e as &(dyn Error + 'static))
+    }
+}
+
+/// All program output (e.g., `print` statements) is written to [VirtualMachine::output], so hosts
+/// and tests can capture it instead of scraping the process's stdout.
 #[derive(Debug)]
-struct VirtualMachine {
+pub struct VirtualMachine<W: Write = Stdout> {
     frames: NonEmpty<CallFrame>,
+    output: W,
+    // The deepest the stack has been, across all frames.
+    max_stack_depth: usize,
+    gc: GcState,
+    debugger: Debugger,
+    // Call frames only hold weak references to their function, so someone has to own the scripts.
+    // All of them are kept, since the functions they declared might still be referenced.
+    scripts: Vec<Rc<Function>>,
+    _script_name: Managed<String>,
+}
+
+// Enough for most programs to never reallocate the stack.
+pub const DEFAULT_STACK_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy)]
+pub struct VmOptions {
+    /// The stack is pre-allocated with this many values, and only reallocates if the program goes
+    /// deeper than that.
+    pub stack_capacity: usize,
+    pub gc_policy: GcPolicy,
+    /// Turns panics in native functions into runtime errors at the call site, instead of unwinding
+    /// through the VM. Off by default, since a panicking native might leave whatever it was
+    /// mutating in an inconsistent state, and since it doesn't help when panics abort.
+    pub catch_native_panics: bool,
+    /// Also defines the wall clock natives, `time()` and `sleep(seconds)`. Off by default, since
+    /// `sleep` blocks the whole VM.
+    pub time_natives: bool,
+    /// Writes the stack and the instruction about to be executed to the output before every step,
+    /// for debugging the compiler's output, e.g., jump offsets and upvalue indices.
+    pub trace: bool,
+}
+
+impl Default for VmOptions {
+    fn default() -> Self {
+        VmOptions {
+            stack_capacity: DEFAULT_STACK_CAPACITY,
+            gc_policy: GcPolicy::default(),
+            catch_native_panics: false,
+            time_natives: false,
+            trace: false,
+        }
+    }
 }
 
-impl VirtualMachine {
+impl<W: Write> VirtualMachine<W> {
+    #[cfg(test)]
     pub fn run(
-        chunk: Chunk, interned_strings: InternedStrings, writer: &mut impl Write,
+        chunk: Chunk, interned_strings: InternedStrings, output: W,
     ) -> Result<Vec<Value>, VmError> {
-        VirtualMachine::run_apply(chunk, interned_strings, writer, |vm| vm.frames.head.stack.take())
+        VirtualMachine::run_apply(chunk, interned_strings, output, |vm| vm.frames.head.stack.take())
+    }
+
+    #[cfg(test)]
+    pub fn run_apply<A, F: FnOnce(VirtualMachine<W>) -> A>(
+        chunk: Chunk, interned_strings: InternedStrings, output: W, f: F,
+    ) -> Result<A, VmError> {
+        VirtualMachine::run_apply_with_options(
+            chunk, interned_strings, output, VmOptions::default(), f)
     }
 
-    pub fn run_apply<A, F: FnOnce(VirtualMachine) -> A>(
-        chunk: Chunk, interned_strings: InternedStrings, writer: &mut impl Write, f: F,
+    pub fn run_apply_with_options<A, F: FnOnce(VirtualMachine<W>) -> A>(
+        chunk: Chunk,
+        interned_strings: InternedStrings,
+        output: W,
+        options: VmOptions,
+        f: F,
     ) -> Result<A, VmError> {
+        let mut vm = VirtualMachine::new(chunk, interned_strings, output, options);
+        vm.run_to_end()?;
+        Ok(f(vm))
+    }
+
+    fn new(
+        chunk: Chunk, interned_strings: InternedStrings, output: W, options: VmOptions,
+    ) -> Self {
         let name = Managed::new("<script>".to_owned());
-        let script = Rc::new(Function { name: name.ptr(), arity: 0, chunk, upvalues: Vec::new() });
-        let stack: RcRc<Vec<Value>> = Default::default();
-        let globals: RcRc<HashMap<InternedString, Value>> = Default::default();
+        let globals = rcrc(Globals::new(chunk.global_names(), rcrc(Globals::default())));
+        let script = Rc::new(
+            Function { name: name.ptr(), arity: 0, chunk, upvalues: Vec::new(), is_method: false });
+        let stack = rcrc(Vec::with_capacity(options.stack_capacity));
         let upvalues = ClosedOverValues::new(Vec::new());
         let open_upvalues = rcrc(LinkedList::new());
         let closed_upvalues = rcrc(Heap::default());
         let rc_interned_strings = rcrc(interned_strings);
         let objects = rcrc(Heap::default());
-        let top_frame = CallFrame::new(
+        let lists = rcrc(Heap::default());
+        let tables = rcrc(Heap::default());
+        let mut top_frame = CallFrame::new(
             0 as InstructionPointer,
             Rc::downgrade(&script),
             0 as StackLocation,
             upvalues,
             stack.clone(),
             globals,
+            rcrc(HashMap::new()),
             rc_interned_strings,
             open_upvalues,
             closed_upvalues,
             objects,
+            lists,
+            tables,
         );
-        let mut vm = VirtualMachine { frames: NonEmpty::new(top_frame) };
-        while vm.unfinished() {
-            // I'm calling it at pretty arbitrary times, since I just want to make sure it works,
-            // and I'm too lazy to implement a proper mechanism for checking the current code use.
-            vm.collect_garbage();
-            match vm.go(writer) {
+        top_frame.catch_native_panics = options.catch_native_panics;
+        top_frame.trace = options.trace;
+        let mut vm = VirtualMachine {
+            frames: NonEmpty::new(top_frame),
+            output,
+            max_stack_depth: 0,
+            gc: GcState::new(options.gc_policy),
+            debugger: Debugger::default(),
+            scripts: vec![script],
+            _script_name: name,
+        };
+        let time_natives = if options.time_natives { time_natives() } else { Vec::new() };
+        for (name, arity, func) in standard_natives().into_iter().chain(time_natives) {
+            vm.define_native(name, arity, func);
+        }
+        vm
+    }
+
+    /// A VM for running the inputs of a REPL session one at a time, see [VirtualMachine::run_next].
+    pub fn session(output: W, options: VmOptions) -> Self {
+        VirtualMachine::new(Chunk::default(), InternedStrings::default(), output, options)
+    }
+
+    /// Runs another script after the previous ones, sharing their globals, heap and interned
+    /// strings, and returns the values it left on the stack, e.g., a REPL input's value. Even if the
+    /// script fails, the VM is left ready to run the next one. If the debug hook pauses the script,
+    /// this returns right away without any values, and [VirtualMachine::resume] finishes it.
+    pub fn run_next(&mut self, chunk: Chunk) -> Result<Vec<Value>, VmError> {
+        assert!(!self.is_paused(), "The previous script is paused, resume it first");
+        self.frames.head.globals.borrow_mut().declare(chunk.global_names());
+        let script = Rc::new(Function {
+            name: self._script_name.ptr(), arity: 0, chunk, upvalues: Vec::new(), is_method: false,
+        });
+        self.frames.head.function = Rc::downgrade(&script);
+        self.frames.head.ip = 0;
+        self.scripts.push(script);
+        self.debugger.restart();
+        let result = self.run_to_end();
+        self.finish_script(result)
+    }
+
+    /// Continues a script paused by the debug hook, doing `action` as if the hook had returned it,
+    /// e.g., [DebugAction::Step] runs the instruction it paused at and stops at the next one.
+    /// Returns like [VirtualMachine::run_next], i.e., without any values if it pauses again.
+    pub fn resume(&mut self, action: DebugAction) -> Result<Vec<Value>, VmError> {
+        assert!(self.is_paused(), "Only paused scripts can be resumed");
+        self.debugger.resume(action);
+        let result = self.run_to_end();
+        self.finish_script(result)
+    }
+
+    fn finish_script(&mut self, result: Result<(), VmError>) -> Result<Vec<Value>, VmError> {
+        if self.is_paused() {
+            return Ok(Vec::new());
+        }
+        while self.frames.pop().is_some() {}
+        let top_frame = &mut self.frames.head;
+        top_frame.close_upvalues(0);
+        let stack = top_frame.stack.take();
+        result.map(|_| stack)
+    }
+
+    /// The globals the VM has slots for, in the order of their indices.
+    pub fn global_names(&self) -> Vec<InternedString> {
+        self.frames.head.globals.borrow().names().to_vec()
+    }
+
+    pub fn interned_strings(&self) -> RcRc<InternedStrings> {
+        self.frames.head.interned_strings.clone()
+    }
+
+    /// None if the global was never assigned.
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        let top_frame = &self.frames.head;
+        let name = top_frame.interned_strings.borrow_mut().intern_string(name.to_owned());
+        top_frame.globals.borrow().get(&name)
+    }
+
+    /// Defines the global if it doesn't exist yet, so scripts run afterwards can use it.
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        let top_frame = &self.frames.head;
+        let name = top_frame.interned_strings.borrow_mut().intern_string(name.to_owned());
+        top_frame.globals.borrow_mut().set(&name, value);
+    }
+
+    /// Allocates a string owned by the VM, e.g., for passing to [VirtualMachine::set_global].
+    pub fn new_string(&mut self, str: &str) -> Value {
+        Value::String(self.frames.head.interned_strings.borrow_mut().intern_string(str.to_owned()))
+    }
+
+    pub fn new_list(&mut self, elements: Vec<Value>) -> Value {
+        Value::List(self.frames.head.lists.borrow_mut().push(elements.into()))
+    }
+
+    pub fn output(&self) -> &W { &self.output }
+
+    /// Calls `hook` before running some of the instructions, so hosts, e.g., editors, can inspect
+    /// the program while it runs: at first only at breakpoints, see
+    /// [VirtualMachine::add_breakpoint], and afterwards based on the [DebugAction] it returns.
+    pub fn set_debug_hook(&mut self, hook: impl FnMut(&DebugEvent) -> DebugAction + 'static) {
+        self.debugger.set_hook(Box::new(hook))
+    }
+
+    /// The debug hook is called before running the first instruction of `line`.
+    pub fn add_breakpoint(&mut self, line: Line) { self.debugger.add_breakpoint(line) }
+
+    pub fn remove_breakpoint(&mut self, line: Line) { self.debugger.remove_breakpoint(line) }
+
+    /// Whether the debug hook paused the script, see [VirtualMachine::resume].
+    pub fn is_paused(&self) -> bool { self.debugger.is_paused() }
+
+    fn run_to_end(&mut self) -> Result<(), VmError> {
+        while self.unfinished() && !self.is_paused() {
+            // I'm calling it at pretty arbitrary times, since I just want to make sure it works.
+            self.maybe_collect_garbage();
+            match self.go() {
                 Err(ref mut err) => {
-                    for f in vm.frames.iter().rev().skip(1) {
-                        err.prepend(f.function.upgrade().unwrap().name.to_owned(), f.current_line())
+                    for f in self.frames.iter().rev().skip(1) {
+                        err.prepend(f.function.upgrade().unwrap().name.to_owned(), f.call_line())
                     }
                     return Err(err.clone());
                 }
                 _ => (),
             }
         }
-        Ok(f(vm))
+        Ok(())
+    }
+
+    /// Exposes a Rust function to Lox programs as a global named `name`.
+    pub fn define_native(&mut self, name: &str, arity: NativeArity, func: NativeFunctionPtr) {
+        let top_frame = &self.frames.head;
+        let name = top_frame.interned_strings.borrow_mut().intern_string(name.to_owned());
+        let native = Value::Native(Rc::new(NativeFunction { name: name.clone(), arity, func }));
+        top_frame.globals.borrow().builtins().unwrap().borrow_mut().set(&name, native);
     }
 
-    fn go(&mut self, writer: &mut impl Write) -> Result<(), VmError> {
-        self.collect_garbage();
+    fn go(&mut self) -> Result<(), VmError> {
+        self.maybe_collect_garbage();
         if self.frames.len() > MAX_FRAMES {
             let active_frame = self.frames.last();
             let line = active_frame.current_line();
@@ -102,27 +329,88 @@ impl VirtualMachine {
                 line,
             ));
         };
-        let result = self.frames.last_mut().run(writer).map(|maybe_cf| match maybe_cf {
+        let depth = self.frames.len();
+        let gc_stress = self.gc.is_stressed();
+        let maybe_cf = self.frames.last_mut().run(
+            &mut self.output, &mut self.max_stack_depth, &mut self.debugger, depth, gc_stress)?;
+        match maybe_cf {
+            // Paused by the debug hook, before running the frame's next instruction.
+            None if self.frames.last().unfinished() => (),
             None => {
-                if let Some(stack_index) = self.frames.pop().map(|f| f.stack_index) {
-                    self.frames.last_mut().stack.borrow_mut().truncate(stack_index);
+                if let Some(frame) = self.frames.pop() {
+                    let caller = self.frames.last_mut();
+                    let mut stack = caller.stack.borrow_mut();
+                    stack.truncate(frame.stack_index);
+                    if let Some((memoized, key, args)) = frame.memoized {
+                        memoized.insert(key, args, stack.last().unwrap().dereferenced());
+                    }
+                    if let Some(exit) = frame.for_in_exit {
+                        if matches!(stack.last().unwrap().dereferenced(), Value::Nil) {
+                            stack.pop();
+                            caller.ip = exit;
+                        }
+                    }
+                    if frame.stringifies {
+                        let result = stack.last().unwrap().dereferenced();
+                        if let Value::String(_) = result {
+                            caller.ip -= 1;
+                        } else {
+                            return Err(VmError::new(
+                                format!("__str must return a string, got {}", result.stringify()),
+                                caller.function.upgrade().unwrap().name.to_owned(),
+                                caller.call_line(),
+                            ));
+                        }
+                    }
                 }
             }
+            Some(cf) if cf.replaces_caller => *self.frames.last_mut() = cf,
             Some(cf) => self.frames.push(cf),
-        })?;
-        self.collect_garbage();
-        Ok(result)
+        }
+        self.maybe_collect_garbage();
+        Ok(())
     }
 
     fn unfinished(&self) -> bool { self.frames.last().unfinished() }
 
+    /// The most values the stack held so far, e.g., for picking [VmOptions::stack_capacity].
+    pub fn max_stack_depth(&self) -> usize { self.max_stack_depth }
+
+    /// For debugging: the stack slots currently captured by open upvalues, and their values.
+    #[cfg(test)]
+    pub fn open_upvalues(&self) -> Vec<(StackLocation, String)> {
+        let top_frame = &self.frames.head;
+        let stack = top_frame.stack.borrow();
+        top_frame.open_upvalues.borrow().iter()
+            .map(|u| u.as_ref().open_location())
+            .map(|i| (i, stack[i].stringify()))
+            .collect()
+    }
+
     fn _debug_stack(&self) -> () {
         self.frames.first()._debug_stack();
     }
 
-    fn collect_garbage(&mut self) {
-        self.mark();
-        self.sweep();
+    pub fn gc_stats(&self) -> GcStats { self.gc.stats() }
+
+    pub fn set_gc_policy(&mut self, policy: GcPolicy) { self.gc.set_policy(policy) }
+
+    fn maybe_collect_garbage(&mut self) {
+        if self.gc.should_collect(self.allocated_bytes()) {
+            self.mark();
+            self.sweep();
+            self.gc.collected(self.allocated_bytes());
+        }
+    }
+
+    /// An estimate of the managed memory, i.e., what [GcPolicy]'s thresholds are compared against.
+    pub fn allocated_bytes(&self) -> usize {
+        let top_frame = &self.frames.head;
+        top_frame.interned_strings.borrow().allocated_bytes()
+            + top_frame.closed_upvalues.borrow().allocated_bytes()
+            + top_frame.objects.borrow().allocated_bytes()
+            + top_frame.lists.borrow().allocated_bytes()
+            + top_frame.tables.borrow().allocated_bytes()
     }
 
     fn mark(&mut self) {
@@ -130,8 +418,15 @@ impl VirtualMachine {
         for local in top_frame.stack.borrow().iter() {
             local.mark();
         }
-        top_frame.function.upgrade().unwrap().chunk.mark();
-        top_frame.globals.borrow().mark();
+        for script in self.scripts.iter() {
+            script.chunk.mark();
+        }
+        let globals = top_frame.globals.borrow();
+        globals.mark();
+        globals.builtins().unwrap().borrow().mark();
+        for module in top_frame.modules.borrow().values() {
+            module.globals.borrow().mark();
+        }
     }
 
     fn sweep(&mut self) {
@@ -139,10 +434,14 @@ impl VirtualMachine {
         top_frame.interned_strings.borrow_mut().sweep();
         top_frame.closed_upvalues.borrow_mut().sweep();
         top_frame.objects.borrow_mut().sweep();
+        top_frame.lists.borrow_mut().sweep();
+        top_frame.tables.borrow_mut().sweep();
     }
 }
 
 type InstructionPointer = usize;
+// The modules imported so far, by their canonical path.
+type Modules = HashMap<InternedString, Rc<Module>>;
 
 #[derive(Debug)]
 struct CallFrame {
@@ -151,14 +450,45 @@ struct CallFrame {
     function: Weak<Function>,
     stack: RcRc<Vec<Value>>,
     closure_upvalues: ClosedOverValues,
-    globals: RcRc<HashMap<InternedString, Value>>,
+    globals: RcRc<Globals>,
+    modules: RcRc<Modules>,
     open_upvalues: RcRc<LinkedList<Managed<PointedUpvalue>>>,
     closed_upvalues: RcRc<Heap<PointedUpvalue>>,
     objects: RcRc<Heap<Instance>>,
+    lists: RcRc<Heap<List>>,
+    tables: RcRc<Heap<Table>>,
     stack_index: usize,
+    // Set for calls of memoized closures which weren't cached, so the result can be cached when the
+    // call returns: the memoized function, the key and the arguments of the call.
+    memoized: Option<(Rc<Memoized>, u64, Vec<Value>)>,
+    // Set for calls of __next by for-in loops, the loop's exit, which the caller jumps to if the
+    // call returns nil.
+    for_in_exit: Option<CodeLocation>,
+    // Set for calls of __str, whose caller reruns the instruction stringifying the instance, this
+    // time on the returned string.
+    stringifies: bool,
+    // Set for tail calls, whose frame replaces the calling one instead of being pushed on top.
+    replaces_caller: bool,
+    // See VmOptions.
+    catch_native_panics: bool,
+    trace: bool,
 }
 
 static MAX_FRAMES: usize = 100;
+// The longest string repetition can create, in bytes, so a huge count is a runtime error instead of
+// an allocation failure aborting the process.
+static MAX_REPEATED_LENGTH: usize = 1 << 30;
+
+// Panics raised with a message, i.e., almost all of them, carry either a &str or a String.
+fn panic_message(payload: &Box<dyn Any + Send>) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "<unknown>".to_owned()
+    }
+}
 
 impl CallFrame {
     pub fn new(
@@ -167,11 +497,14 @@ impl CallFrame {
         stack_index: StackLocation,
         upvalues: ClosedOverValues,
         stack: RcRc<Vec<Value>>,
-        globals: RcRc<HashMap<InternedString, Value>>,
+        globals: RcRc<Globals>,
+        modules: RcRc<Modules>,
         interned_strings: RcRc<InternedStrings>,
         open_upvalues: RcRc<LinkedList<Managed<PointedUpvalue>>>,
         closed_upvalues: RcRc<Heap<PointedUpvalue>>,
         objects: RcRc<Heap<Instance>>,
+        lists: RcRc<Heap<List>>,
+        tables: RcRc<Heap<Table>>,
     ) -> Self {
         CallFrame {
             ip,
@@ -180,24 +513,62 @@ impl CallFrame {
             stack,
             closure_upvalues: upvalues,
             globals,
+            modules,
             stack_index,
             open_upvalues,
             closed_upvalues,
             objects,
+            lists,
+            tables,
+            memoized: None,
+            for_in_exit: None,
+            stringifies: false,
+            replaces_caller: false,
+            catch_native_panics: false,
+            trace: false,
         }
     }
     pub fn current_line(&self) -> Line {
         self.function.upgrade().unwrap().chunk.get_code().get(self.ip).unwrap().1
     }
+    // Callers have already moved past their call instruction, which might have been their last one,
+    // e.g., a REPL input's final expression.
+    fn call_line(&self) -> Line {
+        self.function.upgrade().unwrap().chunk.get_code().get(self.ip - 1).unwrap().1
+    }
     pub fn unfinished(&self) -> bool {
         self.ip < self.chunk_length()
     }
     fn chunk_length(&self) -> usize {
         self.function.upgrade().unwrap().chunk.get_code().len()
     }
-    pub fn run(&mut self, writer: &mut impl Write) -> Result<Option<CallFrame>, VmError> {
-        while self.ip < self.chunk_length() {
-            if let Some(cf) = self.next(writer)? {
+    // Returns None once the frame is finished, or if the debugger paused it before its next
+    // instruction. When `gc_stress` is set, it also returns None after every instruction, so the
+    // VM can collect garbage while all live values are on the stack.
+    pub fn run(
+        &mut self,
+        writer: &mut impl Write,
+        max_stack_depth: &mut usize,
+        debugger: &mut Debugger,
+        depth: usize,
+        gc_stress: bool,
+    ) -> Result<Option<CallFrame>, VmError> {
+        // Upgraded once for the whole frame instead of for every instruction, since the function
+        // outlives its call anyway.
+        let function = self.function.upgrade().unwrap();
+        let length = function.chunk.get_code().len();
+        let mut ran = false;
+        while self.ip < length {
+            if gc_stress && ran {
+                return Ok(None);
+            }
+            ran = true;
+            if debugger.is_attached() && !self.debug(&function, debugger, depth) {
+                return Ok(None);
+            }
+            let next = self.next(&function.chunk, writer)?;
+            *max_stack_depth = (*max_stack_depth).max(self.stack.borrow().len());
+            if let Some(cf) = next {
                 return Ok(Some(cf));
             }
         }
@@ -205,13 +576,30 @@ impl CallFrame {
         Ok(None)
     }
 
-    fn next(&mut self, writer: &mut impl Write) -> Result<Option<CallFrame>, VmError> {
-        let chunk = &&self.function.upgrade().unwrap().chunk;
+    fn next(
+        &mut self, chunk: &Chunk, writer: &mut impl Write,
+    ) -> Result<Option<CallFrame>, VmError> {
         let code = chunk.get_code();
         let instructions = code.instructions();
         let stack = self.stack.clone();
         let globals = self.globals.clone();
         let (op, line) = instructions.get(self.ip).unwrap();
+        if self.trace {
+            self.write_trace(writer, chunk, *line);
+        }
+        // Compiled code never pops values which don't belong to its frame, but malformed (e.g.,
+        // hand-crafted) chunks could, so this is checked once here instead of by every pop. See
+        // also verifier.rs, which rejects such chunks when they're loaded, but not those built in
+        // code, so this check is kept in release builds as well.
+        if let Some((pops, _)) = op.stack_effect() {
+            let depth = stack.borrow().len().saturating_sub(self.stack_index);
+            if depth < pops {
+                return Err(self.err(format!(
+                    "Stack underflow: {} needs {} values, but the frame only has {}",
+                    op.to_upper_snake().trim(), pops, depth,
+                ), *line));
+            }
+        }
         macro_rules! binary {
                 ($l:tt) => {{
                     let popped = stack.borrow_mut().pop().unwrap();
@@ -220,6 +608,8 @@ impl CallFrame {
                 }}
             }
         match op {
+            OpCode::Return if self.stack_index == 0 =>
+                return Err(self.err("Can't return from the script".to_owned(), *line)),
             OpCode::Return => {
                 let len = self.stack.borrow().len();
                 // Patch return value
@@ -233,7 +623,35 @@ impl CallFrame {
                 assert!(len >= *n);
                 stack.borrow_mut().popn(*n);
             }
+            OpCode::Dup => {
+                // Both copies are dereferenced, so the lower one isn't affected by a later write
+                // through the upvalue.
+                let top = stack.borrow().last().unwrap().dereferenced();
+                *stack.borrow_mut().last_mut().unwrap() = top.clone();
+                stack.borrow_mut().push(top);
+            }
+            OpCode::Over => {
+                // Like Dup, both copies are dereferenced.
+                let len = stack.borrow().len();
+                let second = stack.borrow()[len - 2].dereferenced();
+                stack.borrow_mut()[len - 2] = second.clone();
+                stack.borrow_mut().push(second);
+            }
+            OpCode::Swap => {
+                let len = stack.borrow().len();
+                stack.borrow_mut().swap(len - 1, len - 2);
+            }
             OpCode::Print => {
+                if let Some(frame) = self.call_str(*line)? {
+                    return Ok(Some(frame));
+                }
+                let expr = stack.borrow_mut().pop().unwrap();
+                writeln!(writer, "{}", expr.stringify()).expect("Not written");
+            }
+            OpCode::Write => {
+                if let Some(frame) = self.call_str(*line)? {
+                    return Ok(Some(frame));
+                }
                 let expr = stack.borrow_mut().pop().unwrap();
                 write!(writer, "{}", expr.stringify()).expect("Not written");
             }
@@ -242,40 +660,82 @@ impl CallFrame {
                 let function_chunk = &self.function.upgrade().unwrap().chunk;
                 let upvalues = &function_chunk.get_function(*i).upgrade().unwrap().upvalues;
                 let upvalue_ptrs: Vec<Pointer<PointedUpvalue>> =
-                    upvalues.into_iter().map(|Upvalue { index, is_local }|
-                        if *is_local {
-                            self.capture_upvalue(self.stack_index + *index)
+                    upvalues.into_iter().map(|Upvalue { index, is_local, by_value }|
+                        if *by_value {
+                            // A fresh upvalue, which is already closed over a copy of the value.
+                            let value = if *is_local {
+                                self.stack.borrow()[self.stack_index + *index].dereferenced()
+                            } else {
+                                Value::UpvaluePtr(self.enclosing_upvalue(*index, *line)?).dereferenced()
+                            };
+                            Ok(self.closed_upvalues.borrow_mut().push(PointedUpvalue::closed(value)))
+                        } else if *is_local {
+                            Ok(self.capture_upvalue(self.stack_index + *index))
                         } else {
-                            let enclosing_func = &self.stack.borrow_mut()[self.stack_index - 1];
-                            let enclosing_upvalues = enclosing_func.try_into_closure().unwrap().1;
-                            enclosing_upvalues.get(*index)
+                            self.enclosing_upvalue(*index, *line)
                         }
-                    ).collect();
+                    ).collect::<Result<_, _>>()?;
                 stack.borrow_mut().push(
                     Value::closure(
                         function_chunk.get_function(*i),
                         ClosedOverValues::new(upvalue_ptrs),
+                        globals.clone(),
                     ));
             }
             OpCode::Class(i) => {
-                self.stack.borrow_mut().push(
-                    Value::Class(self.function.upgrade().unwrap().chunk.get_class(*i)),
-                )
+                let class = self.function.upgrade().unwrap().chunk.get_class(*i);
+                // The methods of a previous run of the declaration are bound again right after.
+                class.upgrade().unwrap().clear_methods();
+                self.stack.borrow_mut().push(Value::Class(class))
+            }
+            OpCode::Method(n) | OpCode::StaticMethod(n) => {
+                let method = match stack.borrow_mut().pop().unwrap().dereferenced() {
+                    Value::Closure(closure) => closure,
+                    e => return Err(self.err(format!(
+                        "Expected a closure for method '{}', got {}", n.to_owned(), e.type_name(),
+                    ), *line)),
+                };
+                let class = self.try_into_err::<Weak<Class>>(
+                    stack.borrow().last().unwrap(), "Method", *line)?.upgrade().unwrap();
+                if let OpCode::StaticMethod(_) = op {
+                    class.set_static_method(n.clone(), method);
+                } else {
+                    class.set_method(n.clone(), method);
+                }
             }
             OpCode::GetProperty(n) => {
-                let instance: Pointer<Instance> = self.try_into_err(
-                    &self.stack.borrow_mut().pop().unwrap(), "get_property", *line)?;
-                let value = instance.apply(|i| i.get(n.clone())).ok_or(
-                    self.err(format_interned!("Undefined property '{}'.", n), *line))?;
-                stack.borrow_mut().push(value.clone());
+                let value = self.stack.borrow_mut().pop().unwrap().dereferenced();
+                let result = self.get_property(value, n, *line)?;
+                stack.borrow_mut().push(result);
             }
             OpCode::SetProperty(n) => {
                 let value = self.stack.borrow_mut().pop().unwrap();
-                let mut instance: Pointer<Instance> = self.try_into_err(
-                    &self.stack.borrow_mut().pop().unwrap(), "set_property", *line)?;
-                instance.mutate(|i| i.set(n.clone(), value.clone()));
+                let target = self.stack.borrow_mut().pop().unwrap().dereferenced();
+                match target {
+                    Value::Instance(mut instance) =>
+                        instance.mutate(|i| i.set(n.clone(), value.clone())),
+                    e => return Err(self.not_an_instance(&e, *line)),
+                }
                 stack.borrow_mut().push(value);
             }
+            OpCode::Module(path, global_names) => {
+                // The module's body is run with its own globals, so it's called right after.
+                let builtins = globals.borrow().builtins().unwrap().clone();
+                let module_globals = rcrc(Globals::new(global_names, builtins));
+                let (function, upvalues, _) = self.try_into_err::<ClosureParts>(
+                    &stack.borrow_mut().pop().unwrap(), "Module", *line)?;
+                let stem = Path::new(&path.to_owned()).file_stem().unwrap_or_default()
+                    .to_string_lossy().into_owned();
+                let name = self.interned_strings.borrow_mut().intern_string(stem);
+                let module = Module { name, globals: module_globals.clone() };
+                self.modules.borrow_mut().insert(path.clone(), Rc::new(module));
+                stack.borrow_mut().push(Value::closure(function, upvalues, module_globals));
+            }
+            OpCode::GetModule(path) => {
+                let module = self.modules.borrow().get(path).cloned().ok_or_else(|| self.err(
+                    format_interned!("Module '{}' wasn't imported.", path), *line))?;
+                stack.borrow_mut().push(Value::Module(module));
+            }
             OpCode::CloseUpvalue => {
                 self.close_upvalues(self.stack_index);
                 self.stack.borrow_mut().pop().unwrap();
@@ -290,30 +750,52 @@ impl CallFrame {
             }
             OpCode::Jump(index) =>
                 self.ip = *index - 1, // ip will increase by one after we exit this pattern match.
+            OpCode::Loop(offset) => {
+                let target = self.ip.checked_sub(*offset).ok_or_else(|| self.err(
+                    format!("Loop offset {} jumps before the start of the chunk", offset), *line))?;
+                self.ip = target;
+                return Ok(None);
+            }
             OpCode::GetGlobal(name) => {
-                let value = globals.borrow().get(name).cloned().ok_or_else(
+                let value = globals.borrow().get(name).ok_or_else(
                     || self.err(format_interned!("Unrecognized identifier '{}'", name), *line))?;
-                stack.borrow_mut().push(value.clone());
+                stack.borrow_mut().push(value);
+            }
+            OpCode::GetGlobalByIndex(index) => {
+                let value = globals.borrow().get_by_index(*index).ok_or_else(|| self.err(
+                    format_interned!("Unrecognized identifier '{}'", globals.borrow().name(*index)),
+                    *line,
+                ))?;
+                stack.borrow_mut().push(value);
             }
             OpCode::DefineGlobal(name) => {
                 let value = stack.borrow_mut().pop().unwrap();
-                globals.borrow_mut().insert(name.clone(), value);
+                globals.borrow_mut().set(name, value);
             }
             OpCode::SetGlobal(name) => {
                 // We not pop on assignment, to allow for chaining.
                 let value = stack.borrow().last().cloned().unwrap();
-                globals.borrow_mut().insert(name.clone(), value.clone());
+                globals.borrow_mut().set(name, value);
+            }
+            OpCode::SetGlobalByIndex(index) => {
+                let value = stack.borrow().last().cloned().unwrap();
+                globals.borrow_mut().set_by_index(*index, value);
+            }
+            OpCode::GetUpvalue(index) => {
+                let upvalue = self.closure_upvalues.get(*index)
+                    .ok_or_else(|| self.invalid_upvalue(*index, &self.closure_upvalues, *line))?;
+                stack.borrow_mut().push(Value::UpvaluePtr(upvalue))
             }
-            OpCode::GetUpvalue(index) =>
-                stack.borrow_mut().push(Value::UpvaluePtr(self.closure_upvalues.get(*index).clone())),
 
             OpCode::SetUpvalue(index) => {
                 // We don't pop on assignment, to allow for chaining.
                 let value = stack.borrow().last().cloned().unwrap();
-                self.closure_upvalues.set(*index, value);
+                if self.closure_upvalues.set(*index, value).is_none() {
+                    return Err(self.invalid_upvalue(*index, &self.closure_upvalues, *line));
+                }
             }
             OpCode::DefineLocal(index) =>
-                (*stack.borrow_mut().get_mut(*index).unwrap()) =
+                (*stack.borrow_mut().get_mut(*index + self.stack_index).unwrap()) =
                     stack.borrow().last().unwrap().clone(),
             OpCode::GetLocal(index) => {
                 let value = stack.borrow().get(*index + self.stack_index).unwrap().clone();
@@ -333,51 +815,24 @@ impl CallFrame {
                 let old_v2 = stack.borrow().last().cloned().unwrap();
                 *stack.borrow_mut().last_mut().unwrap() = Value::Bool(v1 == old_v2);
             }
-            OpCode::Greater => {
-                let v1: f64 = self.try_into_err(&stack.borrow_mut().pop().unwrap(), "Greater lhs", *line)?;
-                let v2: f64 = self.try_into_err(
-                    &stack.borrow().last().cloned().unwrap(), "Greater rhs", *line)?;
-                *(stack.borrow_mut().last_mut().unwrap()) = Value::Bool(v2 > v1);
-            }
-            OpCode::Less => {
-                let v1: f64 = self.try_into_err(&stack.borrow_mut().pop().unwrap(), "Less lhs", *line)?;
-                let v2: f64 = self.try_into_err(
-                    &stack.borrow().last().cloned().unwrap(), "Less rhs", *line)?;
-                *(stack.borrow_mut().last_mut().unwrap()) = Value::Bool(v2 < v1);
-            }
-            OpCode::Call(arg_count) => {
-                let func_index = stack.borrow().len() - arg_count - 1;
-                let value = stack.borrow().get(func_index).cloned().unwrap();
-                if let Ok((function, upvalues)) = value.try_into_closure() {
-                    let arity = &function.upgrade().unwrap().arity;
-                    if arity != arg_count {
-                        return Err(self.err(
-                            format!("Expected {} arguments but got {}", arity, arg_count),
-                            *line));
-                    }
-                    self.ip += 1;
-                    return Ok(Some(CallFrame::new(
-                        0 as InstructionPointer,
-                        function,
-                        stack.borrow().len() - *arity as StackLocation,
-                        upvalues,
-                        stack.clone(),
-                        globals.clone(),
-                        self.interned_strings.clone(),
-                        self.open_upvalues.clone(),
-                        self.closed_upvalues.clone(),
-                        self.objects.clone(),
-                    )));
-                } else if let Ok(class) = value.try_into_class() {
-                    assert_eq!(*arg_count, 0);
-                    assert_eq!(func_index, stack.borrow().len() - 1);
-                    let instance_ptr = self.objects.borrow_mut().push(Instance::new(class));
-                    *stack.borrow_mut().last_mut().unwrap() = Value::Instance(instance_ptr);
-                } else {
-                    return Err(self.err(
-                        format!("Expected closure or class, got {}", value.stringify()), *line));
-                }
-            }
+            OpCode::Greater => self.compare(*line, Ordering::Greater)?,
+            OpCode::Less => self.compare(*line, Ordering::Less)?,
+            // The callee's frame can replace this one, since returning the callee's result is all
+            // that's left to do. The script's frame is never replaced, and neither are the frames
+            // of memoized calls, which still have to cache their result when the callee returns,
+            // or of for-in loops' __next and __str calls, whose results are still checked.
+            OpCode::TailCall(arg_count) if self.replaceable() =>
+                return self.tail_call(*arg_count, |frame| frame.call(*arg_count, *line)),
+            OpCode::TailInvoke(name, arg_count) if self.replaceable() =>
+                return self.tail_call(*arg_count, |frame| frame.invoke(name, *arg_count, *line)),
+            OpCode::Call(arg_count) | OpCode::TailCall(arg_count) =>
+                if let Some(frame) = self.call(*arg_count, *line)? {
+                    return Ok(Some(frame));
+                },
+            OpCode::Invoke(name, arg_count) | OpCode::TailInvoke(name, arg_count) =>
+                if let Some(frame) = self.invoke(name, *arg_count, *line)? {
+                    return Ok(Some(frame));
+                },
             OpCode::Add =>
                 if stack.borrow().last().unwrap().is_string() {
                     let popped = &stack.borrow_mut().pop().unwrap();
@@ -391,28 +846,586 @@ impl CallFrame {
                     binary!(+)?
                 },
             OpCode::Subtract => binary!(-)?,
-            OpCode::Multiply => binary!(*)?,
+            OpCode::MakeList(n) => {
+                let len = stack.borrow().len();
+                let elements: List = stack.borrow_mut().drain(len - n..)
+                    .map(|v| v.dereferenced())
+                    .collect();
+                let list = self.lists.borrow_mut().push(elements);
+                stack.borrow_mut().push(Value::List(list));
+            }
+            OpCode::MakeTable(n) => {
+                let len = stack.borrow().len();
+                let mut table = Table::default();
+                let entries: Vec<Value> = stack.borrow_mut().drain(len - 2 * n..).collect();
+                for entry in entries.chunks(2) {
+                    table.set(self.table_key(&entry[0], *line)?, entry[1].dereferenced());
+                }
+                let table = self.tables.borrow_mut().push(table);
+                stack.borrow_mut().push(Value::Table(table));
+            }
+            OpCode::GetIndex => {
+                let index = stack.borrow_mut().pop().unwrap();
+                let indexed = stack.borrow_mut().pop().unwrap().dereferenced();
+                let value = if let Value::Table(table) = indexed {
+                    let key = self.table_key(&index, *line)?;
+                    match table.apply(|t| t.get(&key).cloned()) {
+                        Some(value) => value,
+                        None => return Err(
+                            self.err(format!("Undefined key {}.", key.stringify()), *line)),
+                    }
+                } else if let Value::Bytes(bytes) = indexed {
+                    let len = bytes.borrow().len();
+                    let index = self.checked_index(&index, len, ("Byte", "bytes"), *line)?;
+                    Value::Number(bytes.borrow()[index] as f64)
+                } else {
+                    let (list, index) = self.list_index(&indexed, &index, *line)?;
+                    list.apply(|l| l[index].clone())
+                };
+                stack.borrow_mut().push(value);
+            }
+            OpCode::SetIndex => {
+                let value = stack.borrow_mut().pop().unwrap().dereferenced();
+                let index = stack.borrow_mut().pop().unwrap();
+                let indexed = stack.borrow_mut().pop().unwrap().dereferenced();
+                if let Value::Table(mut table) = indexed {
+                    let key = self.table_key(&index, *line)?;
+                    self.check_unfrozen(table.apply(|t| t.is_frozen()), *line)?;
+                    table.mutate(|t| t.set(key, value.clone()));
+                } else if let Value::Bytes(bytes) = indexed {
+                    let len = bytes.borrow().len();
+                    let index = self.checked_index(&index, len, ("Byte", "bytes"), *line)?;
+                    bytes.borrow_mut()[index] = match value {
+                        Value::Number(n) if n.fract() == 0.0 && (0.0..256.0).contains(&n) =>
+                            n as u8,
+                        e => return Err(self.err(
+                            format!("Bytes must be integers from 0 to 255, got {}", e.stringify()),
+                            *line,
+                        )),
+                    };
+                } else {
+                    let (mut list, index) = self.list_index(&indexed, &index, *line)?;
+                    self.check_unfrozen(list.apply(|l| l.is_frozen()), *line)?;
+                    list.mutate(|l| l.set(index, value.clone()));
+                }
+                stack.borrow_mut().push(value);
+            }
+            OpCode::Range(inclusive) => {
+                let end = self.range_bound(stack.borrow_mut().pop().unwrap(), *line)?;
+                let start = self.range_bound(stack.borrow_mut().pop().unwrap(), *line)?;
+                let range = Range { start, end, inclusive: *inclusive };
+                stack.borrow_mut().push(Value::Range(Rc::new(range)));
+            }
+            OpCode::Iterate => {
+                let iterable = stack.borrow().last().unwrap().dereferenced();
+                if let Value::Instance(instance) = iterable {
+                    stack.borrow_mut().pop();
+                    // The iterator replaces the instance once __iter returns.
+                    if let Some(frame) = self.call_protocol_method(instance, ITERATOR, *line)? {
+                        return Ok(Some(frame));
+                    }
+                }
+            }
+            OpCode::IterNext(local, exit) if matches!(
+                stack.borrow()[*local + self.stack_index].dereferenced(), Value::Instance(_),
+            ) => {
+                let instance = self.try_into_err(
+                    &stack.borrow()[*local + self.stack_index], "IterNext", *line)?;
+                match self.call_protocol_method(instance, NEXT, *line)? {
+                    Some(mut frame) => {
+                        frame.for_in_exit = Some(*exit);
+                        return Ok(Some(frame));
+                    }
+                    // E.g., a native in a field, which has already returned.
+                    None => if matches!(stack.borrow().last().unwrap(), Value::Nil) {
+                        stack.borrow_mut().pop();
+                        self.ip = *exit - 1;
+                    },
+                }
+            }
+            OpCode::IterNext(local, exit) => {
+                let index = *local + self.stack_index;
+                // The iterator is advanced in place, so it's only copied if it's shared, i.e., on
+                // the first iteration over a range stored elsewhere. Since its local can't be
+                // captured, it's never an upvalue. A list is replaced by an iterator over it on the
+                // first iteration, and a table by an iterator over a list of its keys, so tables
+                // are iterated in insertion order, like keys().
+                let next = {
+                    let mut stack = stack.borrow_mut();
+                    let iterator = &mut stack[index];
+                    if let Value::Table(table) = iterator {
+                        let keys = table.apply(|t| t.keys().map(|k| k.to_value()).collect());
+                        *iterator = Value::List(self.lists.borrow_mut().push(keys));
+                    }
+                    if let Value::List(list) = iterator {
+                        let list = list.clone();
+                        *iterator = Value::ListIterator(Rc::new(ListIterator::new(list)));
+                    }
+                    match iterator {
+                        Value::Range(range) =>
+                            Ok(Rc::make_mut(range).pop_first().map(|n| Value::Number(n as f64))),
+                        Value::ListIterator(list) => Ok(Rc::make_mut(list).pop_first()),
+                        e => Err(e.stringify()),
+                    }
+                };
+                match next {
+                    Ok(Some(next)) => stack.borrow_mut().push(next),
+                    // ip will increase by one after we exit this pattern match.
+                    Ok(None) => self.ip = *exit - 1,
+                    Err(e) => return Err(self.err(
+                        format!("Can only iterate over ranges, lists and tables, got {}", e),
+                        *line,
+                    )),
+                }
+            }
+            OpCode::Multiply => {
+                let len = stack.borrow().len();
+                let lhs = stack.borrow()[len - 2].dereferenced();
+                let rhs = stack.borrow()[len - 1].dereferenced();
+                match (&lhs, &rhs) {
+                    // Multiplying a string by a count repeats it, e.g., "ab" * 3 is "ababab".
+                    (Value::String(s), count @ Value::Number(_))
+                    | (count @ Value::Number(_), Value::String(s)) => {
+                        let result = self.repeat(s, count, *line)?;
+                        stack.borrow_mut().pop();
+                        *stack.borrow_mut().last_mut().unwrap() = Value::String(result);
+                    }
+                    _ => binary!(*)?,
+                }
+            }
             OpCode::Divide => binary!(/)?,
             OpCode::Negate => self.update_top_number("Negate", *line, |v| v * -1.0)?,
             OpCode::Not => {
                 let result = stack.borrow().last().unwrap().is_falsey();
                 *stack.borrow_mut().last_mut().unwrap() = Value::Bool(result)
             }
+            OpCode::ToString => {
+                if let Some(frame) = self.call_str(*line)? {
+                    return Ok(Some(frame));
+                }
+                let string = match stack.borrow().last().unwrap().dereferenced() {
+                    Value::String(s) => s,
+                    value => self.interned_strings.borrow_mut().intern_string(value.stringify()),
+                };
+                *stack.borrow_mut().last_mut().unwrap() = Value::String(string);
+            }
         };
         self.ip += 1;
         Ok(None)
     }
 
+    // Reports the next instruction to the debug hook if the VM stops there. False if the hook
+    // paused the VM.
+    fn debug(&self, function: &Function, debugger: &mut Debugger, depth: usize) -> bool {
+        let line = function.chunk.get_code().get(self.ip).unwrap().1;
+        match debugger.stops_at(line, depth) {
+            None => true,
+            Some(at_breakpoint) => {
+                let stack = self.stack.borrow();
+                let instruction = disassemble_instruction(&function.chunk, self.ip);
+                let event = DebugEvent {
+                    line,
+                    function: function.name.to_owned(),
+                    depth,
+                    locals: &stack[self.stack_index..],
+                    instruction: instruction.trim_end().to_owned(),
+                    at_breakpoint,
+                };
+                debugger.report(&event)
+            }
+        }
+    }
+
+    // The whole stack, i.e., of all frames, followed by the next instruction, much like clox's
+    // DEBUG_TRACE_EXECUTION.
+    fn write_trace(&self, writer: &mut impl Write, chunk: &Chunk, line: Line) {
+        let stack: String =
+            self.stack.borrow().iter().map(|v| format!("[ {} ]", v.pp_debug())).collect();
+        writeln!(writer, "{}", format!("          {}", stack).trim_end()).expect("Not written");
+        writeln!(
+            writer,
+            "{} {:0>2}: {:>2} {}",
+            self.function.upgrade().unwrap().name.to_owned(),
+            self.ip,
+            line,
+            disassemble_instruction(chunk, self.ip).trim_end(),
+        ).expect("Not written");
+    }
+
+    // Replaces the top two values with whether their ordering is the expected one. Unlike
+    // equality, ordering is only defined between two numbers or two strings.
+    fn compare(&self, line: Line, expected: Ordering) -> Result<(), VmError> {
+        let rhs = self.stack.borrow_mut().pop().unwrap().dereferenced();
+        let lhs = self.stack.borrow().last().unwrap().dereferenced();
+        let ordering = match (&lhs, &rhs) {
+            (Value::Number(n1), Value::Number(n2)) => n1.partial_cmp(n2),
+            (Value::String(s1), Value::String(s2)) => Some(s1.to_owned().cmp(&s2.to_owned())),
+            _ => return Err(self.err("Operands must be numbers or strings.".to_owned(), line)),
+        };
+        *self.stack.borrow_mut().last_mut().unwrap() = Value::Bool(ordering == Some(expected));
+        Ok(())
+    }
+
+    // An upvalue of the closure currently being executed.
+    fn enclosing_upvalue(
+        &self, index: StackLocation, line: Line,
+    ) -> Result<Pointer<PointedUpvalue>, VmError> {
+        let enclosing_func = &self.stack.borrow()[self.stack_index - 1];
+        let enclosing_upvalues = enclosing_func.try_into_closure().unwrap().1;
+        enclosing_upvalues.get(index)
+            .ok_or_else(|| self.invalid_upvalue(index, &enclosing_upvalues, line))
+    }
+
+    // The compiler never emits these, but hand-crafted (or corrupted) bytecode might.
+    fn invalid_upvalue(
+        &self, index: StackLocation, upvalues: &ClosedOverValues, line: Line,
+    ) -> VmError {
+        self.err(
+            format!("Invalid upvalue index {}, closure only has {} upvalues", index, upvalues.len()),
+            line,
+        )
+    }
+
+    // Returns the callee's frame, if it has one, i.e., unless it's a native (or a cached memoized
+    // call, or a class without an initializer), whose result replaces the callee on the stack.
+    fn call(&mut self, arg_count: ArgCount, line: Line) -> Result<Option<CallFrame>, VmError> {
+        let stack = self.stack.clone();
+        let func_index = stack.borrow().len() - arg_count - 1;
+        let value = stack.borrow().get(func_index).cloned().unwrap();
+        if let Ok(closure) = value.try_into_closure() {
+            return self.call_closure(closure, arg_count, line).map(Some);
+        } else if let Value::BoundMethod(bound) = value.dereferenced() {
+            let frame = self.call_closure(bound.method(), arg_count, line)?;
+            self.insert_receiver(func_index, bound.receiver.clone());
+            return Ok(Some(frame));
+        } else if let Value::Memoized(memoized) = value.dereferenced() {
+            let args: Vec<Value> = stack.borrow()[func_index + 1..].iter()
+                .map(|v| v.dereferenced())
+                .collect();
+            let key = Memoized::key(&args);
+            if let Some(result) = key.and_then(|key| memoized.get(key, &args)) {
+                stack.borrow_mut().truncate(func_index);
+                stack.borrow_mut().push(result);
+            } else {
+                // The memoized value itself stays in the callee's slot for the duration of
+                // the call, which keeps its cache reachable for the GC.
+                let mut frame = self.call_closure(memoized.closure(), arg_count, line)?;
+                frame.memoized = key.map(|key| (memoized, key, args));
+                return Ok(Some(frame));
+            }
+        } else if let Value::Native(native) = value.dereferenced() {
+            if !native.arity.accepts(arg_count) {
+                return Err(self.err(
+                    format!("Expected {} arguments but got {}", native.arity, arg_count),
+                    line));
+            }
+            let args: Vec<Value> = stack.borrow()[func_index + 1..].iter()
+                .map(|v| v.dereferenced())
+                .collect();
+            let call = || (native.func)(
+                &mut NativeContext {
+                    interned_strings: &mut self.interned_strings.borrow_mut(),
+                    lists: &mut self.lists.borrow_mut(),
+                    tables: &mut self.tables.borrow_mut(),
+                },
+                &args,
+            );
+            let result = if self.catch_native_panics {
+                panic::catch_unwind(AssertUnwindSafe(call)).unwrap_or_else(|payload| {
+                    Err(format!(
+                        "Native function {}() panicked: {}",
+                        native.name.to_owned(),
+                        panic_message(&payload),
+                    ))
+                })
+            } else {
+                call()
+            }.map_err(|msg| self.err(msg, line))?;
+            stack.borrow_mut().truncate(func_index);
+            stack.borrow_mut().push(result);
+        } else if let Ok(class) = value.try_into_class() {
+            let initializer = class.upgrade().unwrap().initializer();
+            // Without an initializer, classes are always called without arguments.
+            if initializer.is_none() && arg_count != 0 {
+                return Err(self.err(
+                    format!("Expected 0 arguments but got {}", arg_count), line));
+            }
+            let instance_ptr = self.objects.borrow_mut().push(Instance::new(class));
+            if let Some(initializer) = initializer {
+                let frame = self.call_closure(initializer.parts(), arg_count, line)?;
+                self.insert_receiver(func_index, instance_ptr);
+                return Ok(Some(frame));
+            }
+            assert_eq!(func_index, stack.borrow().len() - 1);
+            *stack.borrow_mut().last_mut().unwrap() = Value::Instance(instance_ptr);
+        } else {
+            return Err(self.err(
+                format!("Can only call functions and classes, got {}.", value.type_name()),
+                line));
+        }
+        Ok(None)
+    }
+
+    // Returns the callee's frame; its arguments are already on the stack.
+    fn call_closure(
+        &mut self,
+        (function, upvalues, globals): ClosureParts,
+        arg_count: ArgCount,
+        line: Line,
+    ) -> Result<CallFrame, VmError> {
+        let callee = function.upgrade().unwrap();
+        if callee.arity != arg_count {
+            return Err(self.err(
+                format!("Expected {} arguments but got {}", callee.arity, arg_count), line));
+        }
+        // The stack is shared by all frames, so this makes room for the callee's up front.
+        self.stack.borrow_mut().reserve(callee.chunk.max_stack().saturating_sub(callee.arity));
+        self.ip += 1;
+        let mut frame = CallFrame::new(
+            0 as InstructionPointer,
+            function,
+            self.stack.borrow().len() - arg_count as StackLocation,
+            upvalues,
+            self.stack.clone(),
+            globals,
+            self.modules.clone(),
+            self.interned_strings.clone(),
+            self.open_upvalues.clone(),
+            self.closed_upvalues.clone(),
+            self.objects.clone(),
+            self.lists.clone(),
+            self.tables.clone(),
+        );
+        frame.catch_native_panics = self.catch_native_panics;
+        frame.trace = self.trace;
+        Ok(frame)
+    }
+
+    // Calls the receiver's method directly, which spares binding it, unless a field shadows it.
+    fn invoke(
+        &mut self, name: &InternedString, arg_count: ArgCount, line: Line,
+    ) -> Result<Option<CallFrame>, VmError> {
+        let receiver_index = self.stack.borrow().len() - arg_count - 1;
+        let receiver = self.stack.borrow()[receiver_index].dereferenced();
+        if let Value::Instance(instance) = &receiver {
+            let method = instance.apply(|i| match i.get(name.clone()) {
+                Some(_) => None,
+                None => i.class().method(name),
+            });
+            if let Some(method) = method {
+                let frame = self.call_closure(method.parts(), arg_count, line)?;
+                self.insert_receiver(receiver_index, instance.clone());
+                return Ok(Some(frame));
+            }
+        }
+        let callee = self.get_property(receiver, name, line)?;
+        self.stack.borrow_mut()[receiver_index] = callee;
+        self.call(arg_count, line)
+    }
+
+    // The callee's frame can replace this one, since returning the callee's result is all that's
+    // left to do. The script's frame is never replaced, and neither are the frames of memoized
+    // calls, which still have to cache their result when the callee returns, or of for-in loops'
+    // __next and __str calls, whose results are still checked.
+    fn replaceable(&self) -> bool {
+        self.stack_index > 0 && self.memoized.is_none() && self.for_in_exit.is_none()
+            && !self.stringifies
+    }
+
+    fn tail_call(
+        &mut self,
+        arg_count: ArgCount,
+        call: impl FnOnce(&mut Self) -> Result<Option<CallFrame>, VmError>,
+    ) -> Result<Option<CallFrame>, VmError> {
+        self.close_upvalues(self.stack_index);
+        // Moves the callee and its arguments over the frame's own callee and locals.
+        let callee_index = self.stack.borrow().len() - arg_count - 1;
+        self.stack.borrow_mut().drain(self.stack_index - 1..callee_index);
+        match call(self)? {
+            Some(mut frame) => {
+                frame.replaces_caller = true;
+                Ok(Some(frame))
+            }
+            // E.g., natives, whose result is already where this frame's return value goes.
+            None => {
+                self.ip = self.chunk_length() + 1;
+                Ok(None)
+            }
+        }
+    }
+
+    fn get_property(
+        &self, value: Value, n: &InternedString, line: Line,
+    ) -> Result<Value, VmError> {
+        match value {
+            Value::Module(module) => {
+                let module_name = &module.name;
+                module.globals.borrow().get_own(n).ok_or_else(|| self.err(
+                    format_interned!("Undefined name '{}' in module '{}'.", n, module_name),
+                    line,
+                ))
+            }
+            Value::Instance(instance) => Self::property(instance, n).ok_or_else(
+                || self.err(format_interned!("Undefined property '{}'.", n), line)),
+            Value::Class(class) => class.upgrade().unwrap().static_method(n)
+                .map(Value::Closure)
+                .ok_or_else(|| self.err(
+                    format_interned!("Undefined static method '{}'.", n), line)),
+            e => Err(self.not_an_instance(&e, line)),
+        }
+    }
+
+    // Methods get their receiver as their first local, i.e., right before the arguments, which
+    // call_closure has already accounted for in the new frame's stack index.
+    fn insert_receiver(&self, func_index: StackLocation, receiver: Pointer<Instance>) {
+        self.stack.borrow_mut().insert(func_index + 1, Value::Instance(receiver));
+    }
+
+    // Fields shadow methods.
+    fn property(instance: Pointer<Instance>, name: &InternedString) -> Option<Value> {
+        instance.apply(|i| i.get(name.clone()))
+            .or_else(|| instance.apply(|i| i.class().method(name)).map(|method|
+                Value::BoundMethod(Rc::new(BoundMethod { receiver: instance, method }))))
+    }
+
+    // Calls the instance's method, or the callable in its field, which iterator protocol methods
+    // can't do without. Returns the callee's frame, like call.
+    fn call_protocol_method(
+        &mut self, instance: Pointer<Instance>, name: &str, line: Line,
+    ) -> Result<Option<CallFrame>, VmError> {
+        let interned = self.interned_strings.borrow_mut().intern_string(name.to_owned());
+        let method = Self::property(instance.clone(), &interned).ok_or_else(|| self.err(
+            format!(
+                "Can't iterate over {}, it has no {} method.",
+                Value::Instance(instance.clone()).stringify(), name,
+            ),
+            line,
+        ))?;
+        self.stack.borrow_mut().push(method);
+        self.call(0, line)
+    }
+
+    // Replaces an instance with a __str method on top of the stack with the method's result.
+    // Returns the method's frame, like call, after which the current instruction runs again.
+    fn call_str(&mut self, line: Line) -> Result<Option<CallFrame>, VmError> {
+        let instance = match self.stack.borrow().last().unwrap().dereferenced() {
+            Value::Instance(instance) => instance,
+            _ => return Ok(None),
+        };
+        let name = self.interned_strings.borrow_mut().intern_string(STRINGIFIER.to_owned());
+        let method = match Self::property(instance, &name) {
+            Some(method) => method,
+            None => return Ok(None),
+        };
+        *self.stack.borrow_mut().last_mut().unwrap() = method;
+        match self.call(0, line)? {
+            Some(mut frame) => {
+                frame.stringifies = true;
+                Ok(Some(frame))
+            }
+            // E.g., a native in a field, which has already returned.
+            None => match self.stack.borrow().last().unwrap().dereferenced() {
+                Value::String(_) => Ok(None),
+                result => Err(self.err(
+                    format!("__str must return a string, got {}", result.stringify()), line)),
+            },
+        }
+    }
+
+    // Lists and tables are checked by every instruction modifying them.
+    fn check_unfrozen(&self, frozen: bool, line: Line) -> Result<(), VmError> {
+        if frozen {
+            Err(self.err("Cannot modify a frozen collection.".to_owned(), line))
+        } else {
+            Ok(())
+        }
+    }
+
+    // The indexed list, and the checked index into it.
+    fn list_index(
+        &self, list: &Value, index: &Value, line: Line,
+    ) -> Result<(Pointer<List>, usize), VmError> {
+        let list = match list.dereferenced() {
+            Value::List(list) => list,
+            e => return Err(self.err(
+                format!("Only lists, tables and bytes can be indexed, got {}.", e.type_name()),
+                line,
+            )),
+        };
+        let index = self.checked_index(index, list.apply(|l| l.len()), ("List", "a list"), line)?;
+        Ok((list, index))
+    }
+
+    // The index into a sequence of length len, e.g., a list, which is named in the errors.
+    fn checked_index(
+        &self, index: &Value, len: usize, (kind, sequence): (&str, &str), line: Line,
+    ) -> Result<usize, VmError> {
+        let index = match index.dereferenced() {
+            Value::Number(n) if n.fract() == 0.0 => n,
+            e => return Err(self.err(
+                format!("{} indices must be integers, got {}", kind, e.stringify()), line)),
+        };
+        if index < 0.0 || index >= len as f64 {
+            return Err(self.err(
+                format!(
+                    "{} index {} is out of bounds for {} of length {}", kind, index, sequence, len),
+                line,
+            ));
+        }
+        Ok(index as usize)
+    }
+
+    fn table_key(&self, key: &Value, line: Line) -> Result<TableKey, VmError> {
+        TableKey::new(key).map_err(|e| self.err(format!("Table key {}", e), line))
+    }
+
     fn err(&self, msg: String, line: Line) -> VmError {
         // Comment this in to make errs panics, e.g., in case tests are failing when they shouldn't.
         // panic!("{} @ {}", msg, line);
         VmError::new(msg, self.function.upgrade().unwrap().name.to_owned(), line)
     }
 
-    fn try_into_err<'a, A: TryFrom<&'a Value, Error=String>>(
+    fn try_into_err<'a, A: TryFrom<&'a Value, Error=TypeError>>(
         &self, value: &'a Value, location: &str, line: Line,
     ) -> Result<A, VmError> {
-        value.try_into().map_err(|s| self.err(format!("{} ({})", s, location), line))
+        value.try_into()
+            .map_err(|e: TypeError| self.err(format!("{} ({})", e, location), line).with_source(e))
+    }
+
+    fn not_an_instance(&self, value: &Value, line: Line) -> VmError {
+        self.err(format!("Only instances have properties, got {}.", value.type_name()), line)
+    }
+
+    fn range_bound(&self, value: Value, line: Line) -> Result<i64, VmError> {
+        let bound: f64 = self.try_into_err(&value, "range", line)?;
+        // i64::MAX itself isn't representable as a float, so a bound rounded to it is too large.
+        if bound.fract() != 0.0 {
+            Err(self.err(format!("Range bounds must be integers, got {}", value.stringify()), line))
+        } else if bound < i64::MIN as f64 || bound >= i64::MAX as f64 {
+            Err(self.err(format!("Range bound {} is too large", value.stringify()), line))
+        } else {
+            Ok(bound as i64)
+        }
+    }
+
+    fn repeat(
+        &self, string: &InternedString, count: &Value, line: Line,
+    ) -> Result<InternedString, VmError> {
+        let n: f64 = self.try_into_err(count, "String repeat", line)?;
+        if n < 0.0 || n.fract() != 0.0 {
+            return Err(self.err(
+                format!("Repeat count must be a non-negative integer, got {}", count.stringify()),
+                line,
+            ));
+        }
+        // Counts too large for a usize saturate, and are then too long anyway.
+        let length = string.apply(|s| s.len()).checked_mul(n as usize);
+        if !length.is_some_and(|length| length <= MAX_REPEATED_LENGTH) {
+            return Err(self.err(
+                format!("Repeating a string {} times is too long", count.stringify()), line));
+        }
+        let result = string.apply(|s| s.repeat(n as usize));
+        Ok(self.interned_strings.borrow_mut().intern_string(result))
     }
 
     // Updates the to value of the stack to be the new number.
@@ -471,13 +1484,21 @@ impl CallFrame {
 // Copies all interned data locally.
 #[cfg(test)]
 mod tests {
+    use std::cell::RefCell;
     use std::convert::identity;
-    use std::io::{Cursor, sink};
+    use std::io::sink;
+    use std::mem;
+    use std::path::PathBuf;
 
     use crate::assert_eq_vec;
     use crate::rslox::common::utils::SliceExt;
+    use crate::rslox::compiled::memory::managed_size;
     use crate::rslox::compiled::op_code::OpCode;
-    use crate::rslox::compiled::tests::unsafe_compile;
+    use crate::assert_msg_contains;
+    use crate::rslox::common::lexer::Token;
+    use crate::rslox::common::tests::unsafe_tokenize;
+    use crate::rslox::compiled::compiler::{compile, compile_in_mode, compile_optimized, compile_with_search_path, CompileMode};
+    use crate::rslox::compiled::tests::{unsafe_compile, write_module};
 
     use super::*;
 
@@ -497,8 +1518,18 @@ mod tests {
                 Value::Nil => TracedValue::Nil,
                 Value::String(s) => TracedValue::String(s.to_owned()),
                 Value::Instance(..) => panic!("instances don't have a traced value"),
+                Value::BoundMethod(..) => panic!("Bound methods don't have a traced value"),
                 Value::Class(..) => panic!("Classes don't have a traced value"),
                 Value::Closure(..) => panic!("Closures don't have a traced value"),
+                Value::Native(..) => panic!("Natives don't have a traced value"),
+                Value::Module(..) => panic!("Modules don't have a traced value"),
+                Value::Range(..) => panic!("Ranges don't have a traced value"),
+                Value::ListIterator(..) => panic!("List iterators don't have a traced value"),
+                Value::Bytes(..) => panic!("Bytes don't have a traced value"),
+                Value::List(..) => panic!("Lists don't have a traced value"),
+                Value::Table(..) => panic!("Tables don't have a traced value"),
+                Value::Memoized(..) => panic!("Memoized functions don't have a traced value"),
+                Value::WeakRef(..) => panic!("Weak references don't have a traced value"),
                 Value::UpvaluePtr(..) => panic!("Upvalues don't have a traced value"),
                 Value::TemporaryPlaceholder => panic!("TemporaryPlaceholder found!")
             }
@@ -521,7 +1552,7 @@ mod tests {
     fn final_res(lines: Vec<&str>) -> TracedValue {
         let (mut compiled, interned_strings) = unsafe_compile(lines);
         // // Comment this in for debugging the compiled program.
-        // eprintln!("disassembled:\n{}", crate::rslox::compiled::compiler::disassemble(&compiled).join("\n"));
+        // eprintln!("disassembled:\n{}", crate::rslox::compiled::chunk::disassemble(&compiled).join("\n"));
         let code = compiled.get_code();
         // Remove the final POP to ensure the stack isn't empty
         assert_eq!(code.last().unwrap().0, OpCode::Pop);
@@ -530,26 +1561,103 @@ mod tests {
         stack.unwrap_single().into()
     }
 
-    fn run(code: &str) -> (VirtualMachine, String) {
-        let mut buff = Cursor::new(Vec::new());
+    fn run(code: &str) -> (VirtualMachine<Vec<u8>>, String) {
         let (chunk, interned_strings) = unsafe_compile(vec![code]);
         // // Comment this in for debugging the compiled program.
-        // eprintln!("disassembled:\n{}", crate::rslox::compiled::compiler::disassemble(&chunk).join("\n"));
-        let vm = VirtualMachine::run_apply(chunk, interned_strings, &mut buff, identity).unwrap();
-        (vm, buff.get_ref().into_iter().map(|i| *i as char).collect())
+        // eprintln!("disassembled:\n{}", crate::rslox::compiled::chunk::disassemble(&chunk).join("\n"));
+        let vm = VirtualMachine::run_apply(chunk, interned_strings, Vec::new(), identity).unwrap();
+        let printed = String::from_utf8(vm.output.clone()).unwrap();
+        (vm, printed)
     }
 
     fn printed_string(code: &str) -> String { run(code).1 }
 
     fn assert_printed(code: &str, expected: &str) {
-        assert_eq!(printed_string(code), expected)
+        assert_eq!(printed_string(code), expected);
+        // Optimizations shouldn't change what programs do.
+        let (chunk, interned_strings) =
+            compile_optimized(unsafe_tokenize(vec![code])).expect("Failed to compile");
+        let vm = VirtualMachine::run_apply(chunk, interned_strings, Vec::new(), identity).unwrap();
+        assert_eq!(String::from_utf8(vm.output).unwrap(), expected, "Optimized output differs");
     }
 
-    fn single_error(code: &str) -> VmError {
-        let (chunk, interned_strings) = unsafe_compile(vec![code.trim()]);
+    // Like assert_printed, but also collects garbage before every instruction, to catch values
+    // which aren't reachable from the roots.
+    fn assert_printed_under_gc_stress(code: &str, expected: &str) {
+        assert_printed(code, expected);
+        let run_with = |gc_policy: GcPolicy| {
+            let (chunk, interned_strings) = unsafe_compile(vec![code]);
+            let options = VmOptions { gc_policy, ..Default::default() };
+            VirtualMachine::run_apply_with_options(
+                chunk, interned_strings, Vec::new(), options,
+                |vm| (String::from_utf8(vm.output.clone()).unwrap(), vm.gc_stats().collections),
+            ).unwrap()
+        };
+        let (output, stressed_collections) = run_with(GcPolicy::STRESS);
+        assert_eq!(output, expected, "Output differs under GC stress");
+        let (_, collections) = run_with(GcPolicy::default());
+        assert!(stressed_collections > collections, "{} <= {}", stressed_collections, collections);
+    }
+
+    fn single_error(code: &str) -> VmError {
+        let (chunk, interned_strings) = unsafe_compile(vec![code.trim()]);
         VirtualMachine::run(chunk, interned_strings, &mut sink()).unwrap_err()
     }
 
+    fn run_ops(ops: Vec<OpCode>) -> Vec<TracedValue> {
+        let mut chunk = Chunk::default();
+        for op in ops {
+            chunk.write(op, 1);
+        }
+        let stack = VirtualMachine::run(chunk, InternedStrings::default(), sink()).unwrap();
+        stack.iter().map(|v| v.into()).collect()
+    }
+
+    #[test]
+    fn out_of_range_upvalue_indices() {
+        let error = |ops: Vec<OpCode>| {
+            let mut chunk = Chunk::default();
+            for op in ops {
+                chunk.write(op, 1);
+            }
+            VirtualMachine::run(chunk, InternedStrings::default(), sink()).unwrap_err().msg
+        };
+        assert_eq!(
+            error(vec![OpCode::GetUpvalue(3)]),
+            "Invalid upvalue index 3, closure only has 0 upvalues",
+        );
+        assert_eq!(
+            error(vec![OpCode::Nil, OpCode::SetUpvalue(0)]),
+            "Invalid upvalue index 0, closure only has 0 upvalues",
+        );
+    }
+
+    #[test]
+    fn dup_duplicates_the_top_value() {
+        assert_eq!(
+            run_ops(vec![OpCode::Number(1.0), OpCode::Number(2.0), OpCode::Dup]),
+            vec![TracedValue::Number(1.0), TracedValue::Number(2.0), TracedValue::Number(2.0)],
+        )
+    }
+
+    #[test]
+    fn over_copies_the_second_value() {
+        assert_eq!(
+            run_ops(vec![OpCode::Number(1.0), OpCode::Number(2.0), OpCode::Over]),
+            vec![TracedValue::Number(1.0), TracedValue::Number(2.0), TracedValue::Number(1.0)],
+        )
+    }
+
+    #[test]
+    fn swap_reverses_the_top_two_values() {
+        assert_eq!(
+            run_ops(vec![
+                OpCode::Number(1.0), OpCode::Number(2.0), OpCode::Number(3.0), OpCode::Swap,
+            ]),
+            vec![TracedValue::Number(1.0), TracedValue::Number(3.0), TracedValue::Number(2.0)],
+        )
+    }
+
     #[test]
     fn trivial_precedence_final() {
         assert_eq!(
@@ -660,915 +1768,3328 @@ mod tests {
 
     #[test]
     fn string_concat() {
-        assert_printed(r#"print "abc" + "def";"#, "abcdef")
+        assert_printed(r#"print "abc" + "def";"#, "abcdef\n")
     }
 
     #[test]
-    fn stack_is_empty_after_statement() {
-        let (chunk, interned_strings) = unsafe_compile(vec!["1 + 2;"]);
-        let stack = VirtualMachine::run(chunk, interned_strings, &mut sink()).unwrap();
-        assert_eq!(stack.len(), 0);
+    fn output_is_written_to_the_injected_writer() {
+        let (chunk, interned_strings) = unsafe_compile(vec!["print 1; print 2;"]);
+        let output =
+            VirtualMachine::run_apply(chunk, interned_strings, Vec::new(), |vm| vm.output).unwrap();
+        assert_eq!(output, b"1\n2\n".to_vec());
     }
 
     #[test]
-    fn basic_variable_access() {
+    fn write_does_not_append_a_newline() {
         assert_printed(r#"
-var x = 4;
-print x;
-"#,
-                       "4",
+write "abc";
+write 42;
+print "";
+        "#,
+                       "abc42\n",
         )
     }
 
     #[test]
-    fn global_variable_redeclaration() {
+    fn forward_referenced_globals_still_resolve_by_name() {
         assert_printed(r#"
-var x = 4;
-print x;
+fun f() { return g() + x; }
+fun g() { return 1; }
 var x = 2;
-print x;
-"#,
-                       "42",
+print f();
+x = 3;
+print f();
+        "#,
+                       "3\n4\n",
         )
     }
 
     #[test]
-    fn global_variable_assignment() {
-        assert_printed(r#"
-var x = 4;
-print x;
-x = 2;
-print x;
-        "#,
-                       "42",
-        )
+    fn global_slot_is_undefined_until_its_definition_runs() {
+        assert_eq!(
+            single_error("var x = x;").msg,
+            "Unrecognized identifier 'x'",
+        );
     }
 
     #[test]
-    fn local_variable_assignment() {
-        assert_printed(r#"
+    fn imported_declarations_are_bound_to_the_module() {
+        let path = write_module("imported_declarations_are_bound_to_the_module", "util.lox", r#"
+fun add(x, y) { return x + y; }
+var greeting = "hello";
 {
-  var x = 4;
-  print x;
-  x = 2;
-  print x;
+  var local = "not exported";
+  greeting = greeting + " world";
 }
-        "#,
-                       "42",
-        )
+        "#);
+        assert_printed(
+            &format!(r#"
+import "{}";
+print util.add(1, 2);
+print util.greeting;
+            "#, path),
+            "3\nhello world\n",
+        );
+        assert_eq!(
+            single_error(&format!(r#"import "{}"; print greeting;"#, path)).msg,
+            "Unrecognized identifier 'greeting'",
+        );
     }
 
     #[test]
-    fn referencing_outer_blocks() {
-        assert_printed(r#"
-var x = 1;
-{
-  var y = x + 1;
-  var z = y + 1;
-  print x;
-  print y;
-  print z;
-  y = x = z = 4;
-  print x;
-  print y;
-  print z;
-}
-        "#,
-                       "123444",
+    fn modules_run_once() {
+        let path = write_module("modules_run_once", "loud.lox", r#"print "loaded";"#);
+        assert_printed(
+            &format!(r#"
+import "{}";
+import "{}";
+print "done";
+            "#, path, path),
+            "loaded\ndone\n",
         )
     }
 
     #[test]
-    fn chaining_assignment() {
-        assert_printed(r#"
+    fn qualified_access_to_module() {
+        let path = write_module("qualified_access_to_module", "util.lox", r#"
+fun helper() { return "helped"; }
 var x = 1;
-{
-  var y = 2;
-  var z = 3;
-  y = x = z = 4;
-  print x;
-  print y;
-  print z;
-}
-        "#,
-                       "444",
+        "#);
+        assert_printed(
+            &format!(r#"
+import "{}" as util;
+print util.helper();
+print util.x;
+print util;
+            "#, path),
+            "helped\n1\n<module util>\n",
         )
     }
 
     #[test]
-    fn local_variables() {
-        assert_printed(r#"
-var x = 2;
-{
-  var x = 4;
-  print x;
-}
-print x;
-        "#,
-                       "42",
+    fn qualified_access_reads_the_current_value() {
+        let path = write_module(
+            "qualified_access_reads_the_current_value",
+            "counter.lox",
+            "var count = 0; fun increment() { count = count + 1; }",
+        );
+        assert_printed(
+            &format!(r#"
+import "{}";
+print counter.count;
+counter.increment();
+counter.increment();
+print counter.count;
+            "#, path),
+            "0\n2\n",
         )
     }
 
     #[test]
-    fn get_local_order() {
-        assert_printed(r#"
-{
-  var x = 1;
-  var y = 2;
-  var z = 3;
-  print x;
-  print y;
-  print z;
-}
-        "#,
-                       "123",
+    fn modules_can_declare_the_same_names() {
+        let first = write_module(
+            "modules_can_declare_the_same_names", "first.lox", r#"fun helper() { return 1; }"#);
+        let second = write_module(
+            "modules_can_declare_the_same_names", "second.lox", r#"fun helper() { return 2; }"#);
+        assert_printed(
+            &format!(r#"
+import "{}";
+import "{}";
+fun helper() {{ return 3; }}
+print first.helper();
+print second.helper();
+print helper();
+            "#, first, second),
+            "1\n2\n3\n",
         )
     }
 
     #[test]
-    fn get_global_order() {
-        assert_printed(r#"
-var x = 1;
-var y = 2;
-var z = 3;
-print x;
-print y;
-print z;
-        "#,
-                       "123",
-        )
+    fn modules_have_their_own_globals() {
+        let helpers =
+            write_module("modules_have_their_own_globals", "helpers.lox", "var secret = 42;");
+        let path = write_module(
+            "modules_have_their_own_globals",
+            "util.lox",
+            &format!(r#"import "{}"; fun reveal() {{ return helpers.secret; }}"#, helpers),
+        );
+        assert_printed(
+            &format!(r#"
+import "{}";
+var helpers = "shadowed";
+print util.reveal();
+print helpers;
+            "#, path),
+            "42\nshadowed\n",
+        );
+        assert_eq!(
+            single_error(&format!(r#"import "{}" as util; print util.secret;"#, path)).msg,
+            "Undefined name 'secret' in module 'util'.",
+        );
     }
 
     #[test]
-    fn local_variable_order_operation() {
-        assert_printed(r#"
-{
-  var x = 1;
-  var y = 2;
-  print x - y;
-}
-        "#,
-                       "-1",
-        )
+    fn modules_see_natives() {
+        let path =
+            write_module("modules_see_natives", "util.lox", r#"fun f() { return len("abc"); }"#);
+        assert_printed(&format!(r#"import "{}"; print util.f();"#, path), "3\n")
     }
 
     #[test]
-    fn if_true_no_else() {
-        assert_printed(r#"
-var x = 2;
-if (x) {
-  print 42;
-}
-        "#,
-                       "42",
+    fn aliasing_an_already_imported_module() {
+        let path =
+            write_module("aliasing_an_already_imported_module", "util.lox", "var x = 42;");
+        assert_printed(
+            &format!(r#"
+import "{}";
+import "{}" as util;
+print util.x;
+            "#, path, path),
+            "42\n",
         )
     }
 
     #[test]
-    fn if_false_no_else() {
-        assert_printed(r#"
-var x = 2;
-if (!x)
-  print 42;
+    fn modules_imported_by_name() {
+        write_module("modules_imported_by_name", "strings.lox", r#"
+fun shout(s) { return s + "!"; }
+        "#);
+        // Found next to its importer, which is found in the search path.
+        let greet = write_module("modules_imported_by_name", "greet.lox", r#"
+import strings;
+fun greet(name) { return strings.shout("hi " + name); }
+        "#);
+        let search_path = vec![PathBuf::from(greet).parent().unwrap().to_owned()];
+        let code = r#"
+import greet;
+import strings as s;
+print greet.greet("bob");
+print s.shout("hey");
+print greet;
+        "#;
+        let (chunk, interned_strings, _) =
+            compile_with_search_path(unsafe_tokenize(vec![code]), search_path, false)
+                .expect("Failed to compile");
+        let vm = VirtualMachine::run_apply(chunk, interned_strings, Vec::new(), identity).unwrap();
+        assert_eq!(String::from_utf8(vm.output).unwrap(), "hi bob!\nhey!\n<module greet>\n");
+    }
 
-        "#,
-                       "",
-        )
+    #[test]
+    fn missing_name_in_module() {
+        let path = write_module("missing_name_in_module", "util.lox", "var x = 1;");
+        assert_eq!(
+            single_error(&format!(r#"import "{}" as util; print util.y;"#, path)).msg,
+            "Undefined name 'y' in module 'util'.",
+        );
     }
 
     #[test]
-    fn if_true_else() {
+    fn postfix_increment_returns_the_old_value() {
         assert_printed(r#"
-var x = 2;
-if (x) {
-  print 42;
-} else
-  print 2;
+var i = 0;
+while (i++ < 3) print i;
+print i;
         "#,
-                       "42",
+                       "1\n2\n3\n4\n",
         )
     }
 
     #[test]
-    fn multi_line_if() {
+    fn prefix_increment_returns_the_new_value() {
         assert_printed(r#"
-var x = 2;
-if (x) {
-  y = 42;
-  z = x + y;
-  print z * 3;
-}
-z = 15;
-print z * x;
+var i = 0;
+print ++i;
+print i;
         "#,
-                       "13230",
+                       "1\n1\n",
         )
     }
 
     #[test]
-    fn multi_line_if_false() {
+    fn decrement_locals() {
         assert_printed(r#"
-var x = 2;
-if (x < 0) {
-  y = 42;
-  z = x + y;
-  print z * 3;
+{
+  var j = 5;
+  print j--;
+  print --j;
+  print j;
 }
-z = 15;
-print z * x;
         "#,
-                       "30",
+                       "5\n3\n3\n",
         )
     }
 
     #[test]
-    fn if_false_else() {
+    fn increment_upvalues() {
         assert_printed(r#"
-var x = 2;
-if (!x)
-  print 42;
-else { print 54; }
+fun counter() {
+  var c = 0;
+  fun inc() { return c++; }
+  return inc;
+}
+var f = counter();
+print f();
+print f();
+print f();
         "#,
-                       "54",
+                       "0\n1\n2\n",
         )
     }
 
     #[test]
-    fn while_loop() {
-        assert_printed(r#"
-var x = 0;
-while (x < 3) {
-  print x;
-  x = x + 1;
-}
-        "#,
-                       "012",
-        )
+    fn increment_non_number() {
+        assert_msg_contains!(single_error(r#"var s = "a"; s++;"#).msg, "Expected Value::Number")
     }
 
     #[test]
-    fn for_loop() {
+    fn iterate_exclusive_range() {
         assert_printed(r#"
-for (var x = 0; x < 3; x = x + 1) {
-  print x;
-}
+var count = 0;
+for (i in 0..10) count = count + 1;
+print count;
+for (i in 2..5) write i;
+print "";
         "#,
-                       "012",
+                       "10\n234\n",
         )
     }
 
     #[test]
-    fn for_loop_no_init() {
+    fn iterate_inclusive_range() {
         assert_printed(r#"
-var x = 0;
-for (; x < 3; x = x + 1) {
-  print x;
-}
+var count = 0;
+var n = 10;
+for (i in 0..=n) count = count + 1;
+print count;
+for (i in 5..=3) print "never";
+print 1..=3;
         "#,
-                       "012",
+                       "11\n1..=3\n",
         )
     }
 
     #[test]
-    fn for_loop_no_post() {
+    fn iterating_a_stored_range_doesnt_consume_it() {
         assert_printed(r#"
-for (var x = 0; x < 3;) {
-  print x;
-  x = x + 1;
-}
+var r = 1..4;
+for (i in r) write i;
+for (i in r) write i;
+print "";
+print r;
+print len(r);
         "#,
-                       "012",
+                       "123123\n1..4\n3\n",
         )
     }
 
     #[test]
-    fn for_loop_no_init_no_post() {
+    fn range_loop_variables_are_fresh_per_iteration() {
         assert_printed(r#"
-var x = 0;
-for (;x < 3;) {
-  print x;
-  x = x + 1;
+var first;
+for (i in 0..3) {
+  fun f() { return i; }
+  if (i == 0) first = f;
 }
+print first();
         "#,
-                       "012",
+                       "0\n",
         )
     }
 
     #[test]
-    fn printing_a_function_value() {
+    fn range_len() {
         assert_printed(r#"
-fun areWeHavingItYet() {
-  print "Yes we are!";
-}
-print areWeHavingItYet;
+print len(0..10);
+print len(0..=10);
+print len(3..1);
         "#,
-                       "<fn areWeHavingItYet>",
+                       "10\n11\n0\n",
         )
     }
 
     #[test]
-    fn calling_a_function() {
+    fn bytes_round_trip() {
         assert_printed(r#"
-fun areWeHavingItYet() {
-  print "Yes we are!";
-}
-areWeHavingItYet();
+var bytes = bytesOf("Hello, world!");
+print bytes;
+print len(bytes);
+print stringOf(bytes);
+print stringOf(bytes) == "Hello, world!";
+print bytesOf("") == bytesOf("");
         "#,
-                       "Yes we are!",
+                       "b\"Hello, world!\"\n13\nHello, world!\ntrue\ntrue\n",
         )
     }
 
     #[test]
-    fn calling_a_function_with_local_computation() {
-        assert_printed(r#"
-fun areWeHavingItYet() {
-  var x = 1;
-  var y = 2;
-  print x + y;
-}
-areWeHavingItYet();
+    fn indexing_bytes() {
+        assert_printed(
+            r#"
+var bytes = bytesOf("Hi!");
+print bytes[0];
+print bytes[2];
+bytes[1] = 255;
+print bytes[1];
+print len(bytes);
+bytes[1] = 105;
+print stringOf(bytes);
         "#,
-                       "3",
-        )
+            "72
+33
+255
+3
+Hi!
+",
+        );
+        assert_eq!(
+            single_error(r#"bytesOf("ab")[2];"#).msg,
+            "Byte index 2 is out of bounds for bytes of length 2",
+        );
+        assert_eq!(
+            single_error(r#"bytesOf("ab")[-1] = 0;"#).msg,
+            "Byte index -1 is out of bounds for bytes of length 2",
+        );
+        assert_eq!(
+            single_error(r#"bytesOf("ab")[0.5];"#).msg, "Byte indices must be integers, got 0.5");
+        assert_eq!(
+            single_error(r#"bytesOf("ab")[0] = 256;"#).msg,
+            "Bytes must be integers from 0 to 255, got 256",
+        );
+        assert_eq!(
+            single_error(r#"bytesOf("ab")[0] = "a";"#).msg,
+            "Bytes must be integers from 0 to 255, got a",
+        );
     }
 
     #[test]
-    fn nested_functions() {
-        assert_printed(r#"
-fun areWeHavingItYet() {
-  fun one() {
-    var x = 1;
-    var y = 2;
-    print x + y;
-  }
-  fun two() {
-    var x = 10;
-    var y = 20;
-    print x * y;
-  }
-  one();
-  two();
-}
-areWeHavingItYet();
-        "#,
-                       "3200",
-        )
+    fn bytes_of_a_non_string() {
+        assert_eq!(single_error("bytesOf(1);").msg, "bytesOf() expects a string, got 1");
+        assert_eq!(single_error("stringOf(\"a\");").msg, "stringOf() expects bytes, got a");
     }
 
     #[test]
-    fn calling_a_function_with_arguments() {
-        assert_printed(r#"
-fun areWeHavingItYet(x, y) {
-  var z = "3";
-  print x + y + z;
-}
-var x = "1";
-var z = "2";
-areWeHavingItYet(x, z);
-        "#,
-                       "123",
-        )
+    fn non_integer_range_bounds() {
+        assert_eq!(single_error("0..1.5;").msg, "Range bounds must be integers, got 1.5");
     }
 
     #[test]
-    fn user_error_on_not_enough_arguments() {
+    fn extreme_range_bounds() {
+        assert_printed(r#"
+var r = -9223372036854775808..9223372036854774784;
+print len(r) > 18446744073709000000;
+for (i in 9223372036854774784..=9223372036854774784) print i == 9223372036854774784;
+        "#,
+                       "true\ntrue\n",
+        );
+        assert_eq!(single_error("0..100000000000000000000;").msg, "Range bound 1e20 is too large");
         assert_eq!(
-            single_error(r#"
-fun areWeHavingItYet(x, y) {
-  var z = "3";
-  print x + y + z;
-}
-areWeHavingItYet();
-"#).stack_trace.unwrap_single().1,
-            5,
-        )
+            single_error("-9223372036854775808..=9223372036854775807;").msg,
+            "Range bound 9.223372036854776e18 is too large",
+        );
     }
 
     #[test]
-    fn user_error_on_stack_overflow() {
+    fn iterating_a_non_range() {
         assert_eq!(
-            single_error(r#"
-fun foo() {
-  foo();
-}
-foo();
-"#).msg,
-            "Stack overflow! Wheeeee!".to_owned(),
-        )
+            single_error("for (i in 42) print i;").msg,
+            "Can only iterate over ranges, lists and tables, got 42",
+        );
     }
 
     #[test]
-    fn prints_stack_trace_on_error() {
-        let err = single_error(
+    fn iterating_over_a_list() {
+        assert_printed(
             r#"
-fun a() { b(); }
-fun b() { c(); }
-fun c() {
-  c("too", "many");
-}
-a();
-"#);
-        assert_eq!(err.msg, "Expected 0 arguments but got 2");
-        let vec: Vec<(FunctionName, Line)> = Vec::from(err.stack_trace.deref().clone());
-        assert_eq_vec!(
-            vec,
-            vec![
-                ("c".to_owned(), 4 as usize),
-                ("b".to_owned(), 2 as usize),
-                ("a".to_owned(), 1 as usize),
-                ("<script>".to_owned(), 6 as usize),
-            ],
+var xs = [1, 2, 3];
+var count = 0;
+for (_ in xs) count = count + 1;
+print count;
+for (x in xs) { xs[2] = 30; write x; }
+print "";
+            "#,
+            "3\n1230\n",
         )
     }
 
     #[test]
-    fn manual_factorial() {
-        assert_printed(r#"
-fun a(x) {
-  return x * b(x - 1);
+    fn iterating_over_instances() {
+        assert_printed_under_gc_stress(
+            r#"
+class Countdown {
+  init(from) { this.from = from; }
+  __iter() { return CountdownIterator(this.from); }
 }
-fun b(x) {
-  return x * c(x - 1);
+class CountdownIterator {
+  init(n) { this.n = n; }
+  __next() {
+    if (this.n == 0) return nil;
+    this.n = this.n - 1;
+    return this.n + 1;
+  }
 }
-fun c(x) {
-  return 1;
+for (i in Countdown(3)) write i;
+print "";
+for (i in Countdown(5)) {
+  if (i == 2) break;
+  if (i == 4) continue;
+  write i;
 }
-print a(2);
-        "#,
-                       "2",
-        )
+print "";
+class Letters { __iter() { return ["a", "b"]; } }
+for (letter in Letters()) write letter;
+print "";
+           "#,
+            "321\n53\nab\n",
+        );
+        assert_eq!(
+            single_error("class Foo {} for (x in Foo()) print x;").msg,
+            "Can't iterate over Foo instance, it has no __iter method.",
+        );
+        assert_eq!(
+            single_error("class Foo { __iter() { return this; } } for (x in Foo()) print x;").msg,
+            "Can't iterate over Foo instance, it has no __next method.",
+        );
     }
 
     #[test]
-    fn functions_calling_functions_calling_functions_hard() {
-        assert_printed(r#"
-fun a(x) {
-  b(x + x, x * x);
-  b(x - x, x / x);
-}
-fun b(x, y) {
-  c(x * y, x + y, x - y);
-  c(x, y, x / y);
+    fn stringifying_instances() {
+        assert_printed_under_gc_stress(
+            r#"
+class Point {
+  init(x, y) { this.x = x; this.y = y; }
+  __str() { return "(${this.x}, ${this.y})"; }
 }
-fun c(x, y, z) {
-  print x;
-  print y;
-  print z;
+class Plain {}
+var p = Point(1, 2);
+print p;
+write p;
+print "";
+print "at ${p}";
+print Plain();
+class Nested { __str() { return "${Point(3, 4)}"; } }
+print Nested();
+class Tail {
+  __str() { return this.describe(); }
+  describe() { return "tail"; }
 }
-a(1);
-        "#,
-                       "23121201-1010",
-        )
+print Tail();
+           "#,
+            "(1, 2)\n(1, 2)\nat (1, 2)\nPlain instance\n(3, 4)\ntail\n",
+        );
+        assert_eq!(
+            single_error("class Foo { __str() { return 1; } } print Foo();").msg,
+            "__str must return a string, got 1",
+        );
     }
 
     #[test]
-    fn implicit_function_return() {
-        assert_printed(r#"
-fun a(x) {
-  print x * x;
-}
-print a(16);
-        "#,
-                       "256nil",
-        )
+    fn ordering_requires_numbers_or_strings() {
+        assert_eq!(single_error("nil < 1;").msg, "Operands must be numbers or strings.");
+        assert_eq!(single_error("true > false;").msg, "Operands must be numbers or strings.");
+        assert_eq!(single_error("1 < \"2\";").msg, "Operands must be numbers or strings.");
+        assert_printed(
+            "print nil == 1; print nil != false; print \"ab\" < \"b\"; print \"b\" <= \"ab\";",
+            "false\ntrue\ntrue\nfalse\n",
+        );
     }
 
     #[test]
-    fn implicit_function_return2() {
+    fn chained_comparisons() {
         assert_printed(r#"
-fun a(x) {
-  print x * x;
-}
-res = a(16);
-print res;
+print 1 < 2 < 3;
+print 1 < 3 < 2;
+print 3 < 1 < 2;
+print 1 <= 1 < 2 >= 2 > 1;
         "#,
-                       "256nil",
+                       "true\nfalse\nfalse\ntrue\n",
         )
     }
 
     #[test]
-    fn basic_explicit_return() {
+    fn chained_comparison_evaluates_middle_operand_once() {
         assert_printed(r#"
-fun plus() {
-  return 42;
+var calls = 0;
+fun middle() {
+  calls = calls + 1;
+  return 2;
 }
-print plus();
+print 1 < middle() < 3;
+print calls;
+print 3 < middle() < 4;
+print calls;
         "#,
-                       "42",
+                       "true\n1\nfalse\n2\n",
         )
     }
 
     #[test]
-    fn basic_explicit_return_with_args() {
+    fn chained_comparison_short_circuits() {
         assert_printed(r#"
-fun plus(x, y) {
-  return x + y;
+fun fail() {
+  print "should not be called";
+  return 0;
 }
-print plus(10, 20);
+print 2 < 1 < fail();
         "#,
-                       "30",
+                       "false\n",
         )
     }
 
     #[test]
-    fn factorial_0() {
+    fn and_or_result_in_the_deciding_operand() {
         assert_printed(r#"
-fun factorial(x) {
-  if (x == 0) {return 1;}
-  else {return x * factorial(x - 1);}
-}
-print factorial(0);
+print nil and 1;
+print 0 and "foo";
+print false or nil;
+print nil or 0;
+print 1 or 2;
         "#,
-                       "1",
+                       "nil\nfoo\nnil\n0\n1\n",
         )
     }
 
     #[test]
-    fn factorial_1() {
+    fn and_or_short_circuit() {
         assert_printed(r#"
-fun factorial(x) {
-  if (x == 0) {return 1;}
-  else {return x * factorial(x - 1);}
+fun fail() {
+  print "should not be called";
+  return 0;
 }
-print factorial(1);
+print false and fail();
+print true or fail();
         "#,
-                       "1",
+                       "false\ntrue\n",
         )
     }
 
     #[test]
-    fn factorial_5() {
+    fn xor_truth_table() {
         assert_printed(r#"
-fun factorial(x) {
-  if (x == 0) {return 1;}
-  else {return x * factorial(x - 1);}
-}
-print factorial(5);
+print true xor true;
+print true xor false;
+print false xor true;
+print false xor false;
+print 0 xor nil;
+print nil xor 0;
+print 0 xor "";
+print nil xor false;
         "#,
-                       "120",
+                       "false\ntrue\ntrue\nfalse\ntrue\ntrue\nfalse\nfalse\n",
         )
     }
 
     #[test]
-    fn factorial_5_early_exit() {
+    fn xor_evaluates_both_operands() {
         assert_printed(r#"
-fun factorial(x) {
-  if (x == 0) {
-    return 1;
-  }
-  return x * factorial(x - 1);
+var calls = 0;
+fun call(result) {
+  calls = calls + 1;
+  return result;
 }
-print factorial(5);
+print call(true) xor call(true);
+print calls;
         "#,
-                       "120",
+                       "false\n2\n",
         )
     }
 
     #[test]
-    fn fib() {
+    fn logical_operator_precedence() {
+        // Parsed as true or (false xor (true and false)), and (true xor true) or true.
         assert_printed(r#"
-fun fib(x) {
-  if (x < 2) {return x;}
-  return fib(x - 1) + fib(x - 2);
-}
-print fib(10);
+print true or false xor true and false;
+print true xor true or true;
+print nil and 1 xor 1;
         "#,
-                       "55",
+                       "true\ntrue\ntrue\n",
         )
     }
 
     #[test]
-    fn functions_returning_functions_no_closure() {
-        assert_printed(r#"
-fun f() {
-  print "f1";
-  fun g() {
-    print "g";
-  }
-  print "f2";
-  return g;
-}
-f()();
-        "#,
-                       "f1f2g",
-        )
+    fn repl_mode_leaves_a_value_to_print() {
+        let run_in_mode = |code: &str, mode: CompileMode| {
+            let (chunk, interned_strings) =
+                compile_in_mode(unsafe_tokenize(vec![code]), mode).unwrap();
+            let mut output = Vec::new();
+            let stack = VirtualMachine::run(chunk, interned_strings, &mut output).unwrap();
+            (stack.iter().map(|v| v.into()).collect::<Vec<TracedValue>>(), output)
+        };
+        assert_eq!(run_in_mode("1+2", CompileMode::Repl), (vec![TracedValue::Number(3.0)], vec![]));
+        assert_eq!(run_in_mode("1+2;", CompileMode::Script), (vec![], vec![]));
     }
 
     #[test]
-    fn basic_function_closure() {
+    fn interned_string_equality() {
         assert_printed(r#"
-{
-  var x = 42;
-  fun foo() {
-    print x;
-  }
-  foo();
-}
+var a = "foo";
+var b = "foo";
+print a == b;
+print a == "bar";
+print "fo" + "o" == a;
         "#,
-                       "42",
+                       "true\nfalse\ntrue\n",
         )
     }
 
     #[test]
-    fn basic_function_set_closure() {
-        assert_printed(r#"
-{
-  var x = 42;
-  fun foo() {
-    x = 1;
-  }
-  foo();
-  print x;
-}
-        "#,
-                       "1",
-        )
+    fn folded_global_initializers() {
+        assert_printed(
+            r#"
+var TWO_PI = 2 * 3.14159;
+var a = 1 / 0;
+var b = 0 / 0 == 0 / 0;
+var c = 3 >= 3;
+var d = "a" + "b";
+print TWO_PI;
+print a;
+print b;
+print c;
+print d;
+           "#,
+            "6.28318\ninf\nfalse\ntrue\nab\n",
+        );
+        assert_msg_contains!(single_error("var x = 1 + true;").msg, "Expected Value::Number");
     }
 
     #[test]
-    fn basic_function_set_from_get_closure() {
-        assert_printed(r#"
-{
-  var x = 42;
-  fun foo() {
-    x = x + 1;
-  }
-  foo();
-  print x;
-}
-        "#,
-                       "43",
-        )
+    fn max_stack_depth() {
+        let (chunk, interned_strings) =
+            unsafe_compile(vec!["var x = 1;", "print x + (2 + (3 + 4));"]);
+        let (depth, capacity) = VirtualMachine::run_apply_with_options(
+            chunk,
+            interned_strings,
+            Vec::new(),
+            VmOptions { stack_capacity: 16, ..Default::default() },
+            |vm| (vm.max_stack_depth(), vm.frames.head.stack.borrow().capacity()),
+        ).unwrap();
+        assert_eq!(depth, 4);
+        assert_eq!(capacity, 16);
     }
 
     #[test]
-    fn nested_function_closure() {
-        assert_printed(r#"
-fun foo(x) {
-  fun bar() {
-    print x;
-  }
-  bar();
-}
-foo(42);
-        "#,
-                       "42",
-        )
+    fn max_stack_depth_includes_called_frames() {
+        let (chunk, interned_strings) = unsafe_compile(vec![
+            "fun f(n) { if (n > 0) return 1 + f(n - 1); return 0; }",
+            "print f(10);",
+        ]);
+        let depth = VirtualMachine::run_apply(
+            chunk, interned_strings, Vec::new(), |vm| vm.max_stack_depth()).unwrap();
+        assert!(depth > 20, "Expected more than 20, got {}", depth);
     }
 
     #[test]
-    fn access_closed_variable_locally() {
-        assert_printed(r#"
-fun foo(x) {
-  x = x + 1;
-  fun bar() {
-    x = x * 2;
-  }
-  fun bazz() {
-    x = x + 1;
-  }
-  x = x + 2;
-  bar();
-  bazz();
-  bazz();
-  bar();
-  print x;
-}
-foo(5);
-        "#,
-                       "36",
-        )
+    fn many_distinct_constants() {
+        // Constants are stored inline in the op codes, so there is no constant pool to overflow.
+        let mut program = "var sum = 0;\nvar s = \"\";\n".to_owned();
+        let mut expected_string = String::new();
+        for i in 0..300 {
+            program.push_str(&format!("sum = sum + {};\ns = s + \"s{}\";\n", i, i));
+            expected_string.push_str(&format!("s{}", i));
+        }
+        program.push_str("print sum;\nprint s;");
+        assert_printed(&program, &format!("44850\n{}\n", expected_string))
     }
 
     #[test]
-    fn basic_closed_upvalues() {
-        assert_printed(r#"
+    fn deeply_nested_lists() {
+        let (chunk, interned_strings) = unsafe_compile(vec![
+            "var list = [];",
+            "for (var i = 0; i < 100000; i = i + 1) { list = [list]; }",
+            "print list;",
+        ]);
+        let options = VmOptions {
+            gc_policy: GcPolicy { initial_threshold: 1, growth_factor: 2.0, stress: false },
+            ..Default::default()
+        };
+        let (output, collections) = VirtualMachine::run_apply_with_options(
+            chunk, interned_strings, Vec::new(), options,
+            |vm| (String::from_utf8(vm.output.clone()).unwrap(), vm.gc_stats().collections),
+        ).unwrap();
+        assert!(collections > 1);
+        assert_eq!(output, format!("{}[...]{}\n", "[".repeat(1000), "]".repeat(1000)));
+    }
 
-{
-  var x = 42;
-  fun bar() {
+    #[test]
+    fn gc_threshold_controls_collection_frequency() {
+        let collections = |initial_threshold: usize| {
+            let (chunk, interned_strings) = unsafe_compile(vec![
+                "var s = \"\";",
+                "for (var i = 0; i < 100; i = i + 1) { s = s + \"x\"; }",
+                "print len(bytesOf(s));",
+            ]);
+            let options = VmOptions {
+                gc_policy: GcPolicy { initial_threshold, growth_factor: 2.0, stress: false },
+                ..Default::default()
+            };
+            VirtualMachine::run_apply_with_options(
+                chunk, interned_strings, Vec::new(), options,
+                |vm| (
+                    String::from_utf8(vm.output.clone()).unwrap(),
+                    vm.gc_stats().collections,
+                    vm.allocated_bytes(),
+                ),
+            ).unwrap()
+        };
+        let (tiny_output, tiny_collections, tiny_bytes) = collections(1);
+        let (large_output, large_collections, large_bytes) = collections(1 << 30);
+        assert_eq!(tiny_output, "100\n");
+        assert_eq!(large_output, "100\n");
+        assert!(tiny_collections > 1);
+        assert_eq!(large_collections, 0);
+        // Without collections, all 99 intermediate strings are still around.
+        assert!(
+            tiny_bytes + 99 * managed_size::<String>() <= large_bytes,
+            "{} vs. {}", tiny_bytes, large_bytes,
+        );
+    }
+
+    #[test]
+    fn allocated_bytes_include_list_elements() {
+        let allocated_bytes = |length: usize| {
+            let list = vec!["nil"; length].join(", ");
+            let (chunk, interned_strings) = unsafe_compile(vec![&format!("var xs = [{}];", list)]);
+            VirtualMachine::run_apply(chunk, interned_strings, Vec::new(), identity)
+                .unwrap()
+                .allocated_bytes()
+        };
+        assert!(allocated_bytes(100) >= allocated_bytes(1) + 99 * mem::size_of::<Value>());
+    }
+
+    #[test]
+    fn jumps_over_huge_bodies() {
+        // Jump targets are absolute code locations, so bodies longer than any fixed width operand
+        // (here, over 2^16 op codes) don't need special long jumps. The body is spliced at the
+        // token level, since lexing a source this long takes ages.
+        let tokens = |code: &str| unsafe_tokenize(vec![code]);
+        let statement = tokens("x = 1;");
+        let huge_body: Vec<Token> =
+            statement.iter().cycle().take(statement.len() * 25_000).cloned().collect();
+        let program: Vec<Token> = [
+            tokens("var x = 0; if (false) {"),
+            huge_body.clone(),
+            tokens("} else { print \"else\"; } if (true) print \"then\"; else {"),
+            huge_body,
+            tokens("} print x;"),
+        ].concat();
+        let (chunk, interned_strings) = compile(program).unwrap();
+        assert!(chunk.get_code().len() > 2 * u16::MAX as usize);
+        let vm = VirtualMachine::run_apply(chunk, interned_strings, Vec::new(), identity).unwrap();
+        assert_eq!(String::from_utf8(vm.output).unwrap(), "else\nthen\n0\n");
+    }
+
+    #[test]
+    fn string_repetition() {
+        assert_printed(
+            r#"
+var s = "ab";
+print s * 3;
+print 3 * s;
+print "[" + "x" * 0 + "]";
+print "ab" * 3 == "ababab";
+print 2 * 3;
+           "#,
+            "ababab\nababab\n[]\ntrue\n6\n",
+        );
+    }
+
+    #[test]
+    fn string_repetition_errors() {
+        assert_eq!(
+            single_error(r#""ab" * -1;"#).msg,
+            "Repeat count must be a non-negative integer, got -1",
+        );
+        assert_eq!(
+            single_error(r#"1.5 * "ab";"#).msg,
+            "Repeat count must be a non-negative integer, got 1.5",
+        );
+        assert_msg_contains!(single_error(r#""ab" * "ab";"#).msg, "Expected Value::Number");
+        assert_eq!(
+            single_error(r#""ab" * 4611686018427387904;"#).msg,
+            "Repeating a string 4.611686018427388e18 times is too long",
+        );
+        assert_eq!(
+            single_error(r#""ab" * 1000000000;"#).msg,
+            "Repeating a string 1000000000 times is too long",
+        );
+        assert_printed(r#"print len("" * 100000000000);"#, "0\n");
+    }
+
+    #[test]
+    fn discarded_bindings() {
+        assert_printed(
+            r#"
+var count = 0;
+for (_ in 0..4) count = count + 1;
+print count;
+fun second(_, y, _) { return y; }
+print second(1, 2, 3);
+var _ = count = 10;
+print count;
+           "#,
+            "4\n2\n10\n",
+        );
+    }
+
+    #[test]
+    fn empty_else_branches() {
+        assert_printed(
+            r#"
+for (var i = 0; i < 3; i = i + 1) {
+  if (i == 1) print "one"; else {}
+  if (i != 1) {} else {}
+  print i;
+}
+           "#,
+            "0\none\n1\n2\n",
+        );
+    }
+
+    #[test]
+    fn else_if_chains_run_a_single_arm() {
+        let program = |x: i32| format!(r#"
+var calls = 0;
+fun arm(n) {{
+  calls = calls + 1;
+  return n;
+}}
+var x = {};
+if (x == arm(0)) print "zero";
+else if (x == arm(1)) print "one";
+else if (x == arm(2)) print "two";
+else if (x == arm(3)) print "three";
+else print "other";
+print calls;
+        "#, x);
+        assert_printed(&program(0), "zero\n1\n");
+        assert_printed(&program(2), "two\n3\n");
+        assert_printed(&program(3), "three\n4\n");
+        assert_printed(&program(7), "other\n4\n");
+    }
+
+    #[test]
+    fn very_long_else_if_chains() {
+        // Spliced at the token level, for the same reason as above.
+        let tokens = |code: &str| unsafe_tokenize(vec![code]);
+        let arm = tokens("else if (x == 1) print 1;");
+        let arms: Vec<Token> = arm.iter().cycle().take(arm.len() * 20_000).cloned().collect();
+        let program: Vec<Token> = [
+            tokens("var x = 2; if (x == 0) print 0;"),
+            arms,
+            tokens("else if (x == 2) print 2; else print 3;"),
+        ].concat();
+        let (chunk, interned_strings) = compile(program).unwrap();
+        let vm = VirtualMachine::run_apply(chunk, interned_strings, Vec::new(), identity).unwrap();
+        assert_eq!(String::from_utf8(vm.output).unwrap(), "2\n");
+    }
+
+    #[test]
+    fn stack_is_empty_after_statement() {
+        let (chunk, interned_strings) = unsafe_compile(vec!["1 + 2;"]);
+        let stack = VirtualMachine::run(chunk, interned_strings, &mut sink()).unwrap();
+        assert_eq!(stack.len(), 0);
+    }
+
+    #[test]
+    fn returns_in_some_or_all_branches() {
+        let (vm, printed) = run(r#"
+fun all(x) {
+  if (x) return "then"; else return "else";
+}
+fun some(x) {
+  if (x) print "printed"; else return "else";
+}
+print all(true);
+print all(false);
+print some(true);
+print some(false);
+        "#);
+        assert_eq!(printed, "then\nelse\nprinted\nnil\nelse\n");
+        assert!(vm.frames.head.stack.borrow().is_empty());
+    }
+
+    #[test]
+    fn block_locals_are_popped_together() {
+        let (vm, printed) = run(r#"
+var a = "global";
+{
+  var x = 1;
+  var y = 2;
+  var z = 3;
+  print x + y + z;
+}
+print a;
+        "#);
+        assert_eq!(printed, "6\nglobal\n");
+        assert!(vm.frames.head.stack.borrow().is_empty());
+    }
+
+    #[test]
+    fn basic_variable_access() {
+        assert_printed(r#"
+var x = 4;
+print x;
+"#,
+                       "4\n",
+        )
+    }
+
+    #[test]
+    fn global_variable_redeclaration() {
+        assert_printed(r#"
+var x = 4;
+print x;
+var x = 2;
+print x;
+"#,
+                       "4\n2\n",
+        )
+    }
+
+    #[test]
+    fn global_variable_assignment() {
+        assert_printed(r#"
+var x = 4;
+print x;
+x = 2;
+print x;
+        "#,
+                       "4\n2\n",
+        )
+    }
+
+    #[test]
+    fn local_variable_assignment() {
+        assert_printed(r#"
+{
+  var x = 4;
+  print x;
+  x = 2;
+  print x;
+}
+        "#,
+                       "4\n2\n",
+        )
+    }
+
+    #[test]
+    fn referencing_outer_blocks() {
+        assert_printed(r#"
+var x = 1;
+{
+  var y = x + 1;
+  var z = y + 1;
+  print x;
+  print y;
+  print z;
+  y = x = z = 4;
+  print x;
+  print y;
+  print z;
+}
+        "#,
+                       "1\n2\n3\n4\n4\n4\n",
+        )
+    }
+
+    #[test]
+    fn chaining_assignment() {
+        assert_printed(r#"
+var x = 1;
+{
+  var y = 2;
+  var z = 3;
+  y = x = z = 4;
+  print x;
+  print y;
+  print z;
+}
+        "#,
+                       "4\n4\n4\n",
+        )
+    }
+
+    #[test]
+    fn local_variables() {
+        assert_printed(r#"
+var x = 2;
+{
+  var x = 4;
+  print x;
+}
+print x;
+        "#,
+                       "4\n2\n",
+        )
+    }
+
+    #[test]
+    fn get_local_order() {
+        assert_printed(r#"
+{
+  var x = 1;
+  var y = 2;
+  var z = 3;
+  print x;
+  print y;
+  print z;
+}
+        "#,
+                       "1\n2\n3\n",
+        )
+    }
+
+    #[test]
+    fn get_global_order() {
+        assert_printed(r#"
+var x = 1;
+var y = 2;
+var z = 3;
+print x;
+print y;
+print z;
+        "#,
+                       "1\n2\n3\n",
+        )
+    }
+
+    #[test]
+    fn local_variable_order_operation() {
+        assert_printed(r#"
+{
+  var x = 1;
+  var y = 2;
+  print x - y;
+}
+        "#,
+                       "-1\n",
+        )
+    }
+
+    #[test]
+    fn if_true_no_else() {
+        assert_printed(r#"
+var x = 2;
+if (x) {
+  print 42;
+}
+        "#,
+                       "42\n",
+        )
+    }
+
+    #[test]
+    fn if_false_no_else() {
+        assert_printed(r#"
+var x = 2;
+if (!x)
+  print 42;
+
+        "#,
+                       "",
+        )
+    }
+
+    #[test]
+    fn if_true_else() {
+        assert_printed(r#"
+var x = 2;
+if (x) {
+  print 42;
+} else
+  print 2;
+        "#,
+                       "42\n",
+        )
+    }
+
+    #[test]
+    fn multi_line_if() {
+        assert_printed(r#"
+var x = 2;
+if (x) {
+  y = 42;
+  z = x + y;
+  print z * 3;
+}
+z = 15;
+print z * x;
+        "#,
+                       "132\n30\n",
+        )
+    }
+
+    #[test]
+    fn multi_line_if_false() {
+        assert_printed(r#"
+var x = 2;
+if (x < 0) {
+  y = 42;
+  z = x + y;
+  print z * 3;
+}
+z = 15;
+print z * x;
+        "#,
+                       "30\n",
+        )
+    }
+
+    #[test]
+    fn if_false_else() {
+        assert_printed(r#"
+var x = 2;
+if (!x)
+  print 42;
+else { print 54; }
+        "#,
+                       "54\n",
+        )
+    }
+
+    #[test]
+    fn while_loop() {
+        assert_printed(r#"
+var x = 0;
+while (x < 3) {
+  print x;
+  x = x + 1;
+}
+        "#,
+                       "0\n1\n2\n",
+        )
+    }
+
+    #[test]
+    fn for_loop() {
+        assert_printed(r#"
+for (var x = 0; x < 3; x = x + 1) {
+  print x;
+}
+        "#,
+                       "0\n1\n2\n",
+        )
+    }
+
+    #[test]
+    fn for_loop_no_init() {
+        assert_printed(r#"
+var x = 0;
+for (; x < 3; x = x + 1) {
+  print x;
+}
+        "#,
+                       "0\n1\n2\n",
+        )
+    }
+
+    #[test]
+    fn for_loop_no_post() {
+        assert_printed(r#"
+for (var x = 0; x < 3;) {
+  print x;
+  x = x + 1;
+}
+        "#,
+                       "0\n1\n2\n",
+        )
+    }
+
+    #[test]
+    fn for_loop_no_init_no_post() {
+        assert_printed(r#"
+var x = 0;
+for (;x < 3;) {
+  print x;
+  x = x + 1;
+}
+        "#,
+                       "0\n1\n2\n",
+        )
+    }
+
+    #[test]
+    fn loops_run_the_right_number_of_iterations() {
+        assert_printed(r#"
+var count = 0;
+while (count < 5) count = count + 1;
+for (var i = 0; i < 7; i = i + 1) count = count + 1;
+for (i in 0..3) count = count + 1;
+fun loop(n) {
+  var total = 0;
+  while (total < n) total = total + 1;
+  return total;
+}
+print count;
+print loop(4);
+        "#,
+                       "15\n4\n",
+        )
+    }
+
+    #[test]
+    fn break_and_continue() {
+        assert_printed(r#"
+var i = 0;
+while (true) {
+  i = i + 1;
+  var x = i * 10;
+  if (i == 2) continue;
+  if (i > 3) break;
+  print x;
+}
+for (var j = 0; j < 10; j = j + 1) {
+  var y = "skip";
+  if (j == 1) continue;
+  { var z = j; if (z == 3) break; }
+  print j;
+}
+for (k in 0..10) {
+  if (k == 1) continue;
+  if (k == 3) break;
+  print k;
+}
+print "done";
+        "#,
+                       "10\n30\n0\n2\n0\n2\ndone\n",
+        )
+    }
+
+    #[test]
+    fn break_and_continue_in_nested_loops() {
+        assert_printed(r#"
+for (i in 0..3) {
+  var j = 0;
+  while (true) {
+    j = j + 1;
+    if (j == 2) continue;
+    if (j > 3) break;
+    write i * 10 + j;
+    write " ";
+  }
+  if (i == 1) break;
+}
+        "#,
+                       "1 3 11 13 ",
+        )
+    }
+
+    #[test]
+    fn break_and_continue_close_captured_locals() {
+        assert_printed(r#"
+var f1;
+var f2;
+var i = 0;
+while (i < 4) {
+  var x = i;
+  fun f() { return x; }
+  i = i + 1;
+  if (x == 1) { f1 = f; continue; }
+  if (x == 2) { f2 = f; break; }
+}
+print f1();
+print f2();
+        "#,
+                       "1\n2\n",
+        )
+    }
+
+    #[test]
+    fn printing_a_function_value() {
+        assert_printed(r#"
+fun areWeHavingItYet() {
+  print "Yes we are!";
+}
+print areWeHavingItYet;
+        "#,
+                       "<fn areWeHavingItYet>\n",
+        )
+    }
+
+    #[test]
+    fn calling_a_function() {
+        assert_printed(r#"
+fun areWeHavingItYet() {
+  print "Yes we are!";
+}
+areWeHavingItYet();
+        "#,
+                       "Yes we are!\n",
+        )
+    }
+
+    #[test]
+    fn calling_a_function_with_local_computation() {
+        assert_printed(r#"
+fun areWeHavingItYet() {
+  var x = 1;
+  var y = 2;
+  print x + y;
+}
+areWeHavingItYet();
+        "#,
+                       "3\n",
+        )
+    }
+
+    #[test]
+    fn nested_functions() {
+        assert_printed(r#"
+fun areWeHavingItYet() {
+  fun one() {
+    var x = 1;
+    var y = 2;
+    print x + y;
+  }
+  fun two() {
+    var x = 10;
+    var y = 20;
+    print x * y;
+  }
+  one();
+  two();
+}
+areWeHavingItYet();
+        "#,
+                       "3\n200\n",
+        )
+    }
+
+    #[test]
+    fn calling_a_function_with_arguments() {
+        assert_printed(r#"
+fun areWeHavingItYet(x, y) {
+  var z = "3";
+  print x + y + z;
+}
+var x = "1";
+var z = "2";
+areWeHavingItYet(x, z);
+        "#,
+                       "123\n",
+        )
+    }
+
+    #[test]
+    fn trailing_commas_in_parameters_and_arguments() {
+        assert_printed(r#"
+fun concat(x, y,) {
+  return x + y;
+}
+print concat("a", "b",);
+print concat("a", "b");
+print arity(concat);
+print max(1, 3, 2,);
+        "#,
+                       "ab\nab\n2\n3\n",
+        )
+    }
+
+    #[test]
+    fn calling_non_callables() {
+        let error = |code: &str| single_error(code).msg;
+        assert_eq!(error("var x = 5; x();"), "Can only call functions and classes, got number.");
+        assert_eq!(error(r#""foo"(1);"#), "Can only call functions and classes, got string.");
+        assert_eq!(error("nil();"), "Can only call functions and classes, got nil.");
+        assert_eq!(
+            error("class Foo {} var foo = Foo(); foo();"),
+            "Can only call functions and classes, got instance.",
+        );
+        assert_eq!(error("(0..3)();"), "Can only call functions and classes, got range.");
+        assert_eq!(
+            error("fun f() { var x = 1; fun g() { return x(); } return g(); } f();"),
+            "Can only call functions and classes, got number.",
+        );
+    }
+
+    #[test]
+    fn user_error_on_not_enough_arguments() {
+        assert_eq!(
+            single_error(r#"
+fun areWeHavingItYet(x, y) {
+  var z = "3";
+  print x + y + z;
+}
+areWeHavingItYet();
+"#).stack_trace.unwrap_single().1,
+            5,
+        )
+    }
+
+    #[test]
+    fn user_error_on_stack_overflow() {
+        assert_eq!(
+            single_error(r#"
+fun foo() {
+  foo();
+}
+foo();
+"#).msg,
+            "Stack overflow! Wheeeee!".to_owned(),
+        )
+    }
+
+    #[test]
+    fn tail_recursion_runs_in_constant_frame_space() {
+        assert_printed(
+            r#"
+fun sum(n, acc) {
+  if (n == 0) return acc;
+  return sum(n - 1, acc + n);
+}
+// Way more than MAX_FRAMES.
+print sum(10000, 0);
+fun isEven(n) {
+  if (n == 0) return true;
+  return isOdd(n - 1);
+}
+fun isOdd(n) {
+  if (n == 0) return false;
+  return isEven(n - 1);
+}
+print isEven(10001);
+"#,
+            "50005000\nfalse\n",
+        )
+    }
+
+    #[test]
+    fn tail_calls_close_captured_locals() {
+        assert_printed(
+            r#"
+fun collect(n, getters) {
+  if (n == 0) return getters;
+  var local = n * 10;
+  fun get() { return local; }
+  getters[n - 1] = get;
+  return collect(n - 1, getters);
+}
+var getters = collect(3, [nil, nil, nil]);
+print getters[0]() + getters[1]() + getters[2]();
+fun zero() { return 0; }
+fun chain(n, f) {
+  if (n == 0) return f();
+  var x = n;
+  fun g() { return x + f(); }
+  return chain(n - 1, g);
+}
+print chain(3, zero);
+"#,
+            "60\n6\n",
+        )
+    }
+
+    #[test]
+    fn tail_calls_to_methods_classes_and_natives() {
+        assert_printed(
+            r#"
+class Counter {
+  init(n) { this.n = n; }
+  down(k) {
+    if (k == 0) return this.n;
+    return this.down(k - 1);
+  }
+}
+print Counter(5).down(1000);
+fun make(n) { return Counter(n); }
+print make(7).n;
+fun size(list) { return len(list); }
+print size([1, 2]) + 1;
+"#,
+            "5\n7\n3\n",
+        )
+    }
+
+    #[test]
+    fn invoking_methods() {
+        assert_printed_under_gc_stress(
+            r#"
+class Greeter {
+  init(greeting) { this.greeting = greeting; }
+  greet(name) { return "${this.greeting}, ${name}"; }
+  shadowed() { return "method"; }
+  static make() { return Greeter("hi"); }
+}
+var g = Greeter.make();
+print g.greet("bob");
+fun double(x) { return x * 2; }
+g.callback = double;
+print g.callback(21);
+g.size = len;
+print g.size([1, 2, 3]);
+fun field() { return "field"; }
+g.shadowed = field;
+print g.shadowed();
+"#,
+            "hi, bob\n42\n3\nfield\n",
+        );
+        assert_eq!(
+            single_error("class Foo {} Foo().bar();").msg, "Undefined property 'bar'.");
+        assert_eq!(
+            single_error("class Foo { bar(x) {} } Foo().bar();").msg,
+            "Expected 1 arguments but got 0",
+        );
+        assert_eq!(
+            single_error("var x = 1; x.bar();").msg, "Only instances have properties, got number.");
+    }
+
+    #[test]
+    fn fluent_method_chains() {
+        assert_printed_under_gc_stress(
+            r#"
+class Pair {
+  setA(a) { this.a = a; return this; }
+  setB(b) { this.b = b; return this; }
+  swapped() { return Pair().setA(this.b).setB(this.a); }
+}
+var pair = Pair();
+var chained = pair.setA(1).setB(2);
+print "${pair.a} ${pair.b}";
+chained.a = 0;
+print pair.a;
+var swapped = pair.swapped().setA(3);
+print "${swapped.a} ${swapped.b} ${pair.a} ${pair.b}";
+var setB = Pair().setA(4).setB;
+print setB(5).a;
+"#,
+            "1 2\n0\n3 0 0 2\n4\n",
+        )
+    }
+
+    #[test]
+    fn memoized_functions_still_cache_tail_calls() {
+        assert_printed(
+            r#"
+var calls = 0;
+fun id(x) {
+  calls = calls + 1;
+  return x;
+}
+fun f(x) { return id(x); }
+var m = memoize(f);
+print m(1) + m(1);
+print calls;
+"#,
+            "2\n1\n",
+        )
+    }
+
+    #[test]
+    fn errors_can_be_boxed() {
+        let err: Box<dyn Error> = Box::new(single_error(r#"
+fun foo() {
+  return -nil;
+}
+foo();
+"#));
+        assert_eq!(
+            err.to_string(),
+            "Expected Value::Number, but found Nil (Negate)\n  in foo at line 2\n  in <script> at line 4",
+        );
+        assert_eq!(
+            err.source().unwrap().to_string(),
+            "Expected Value::Number, but found Nil",
+        );
+        assert!(single_error("foo;").source().is_none());
+    }
+
+    #[test]
+    fn prints_stack_trace_on_error() {
+        let err = single_error(
+            r#"
+fun a() { b(); }
+fun b() { c(); }
+fun c() {
+  c("too", "many");
+}
+a();
+"#);
+        assert_eq!(err.msg, "Expected 0 arguments but got 2");
+        let vec: Vec<(FunctionName, Line)> = Vec::from(err.stack_trace.deref().clone());
+        assert_eq_vec!(
+            vec,
+            vec![
+                ("c".to_owned(), 4 as usize),
+                ("b".to_owned(), 2 as usize),
+                ("a".to_owned(), 1 as usize),
+                ("<script>".to_owned(), 6 as usize),
+            ],
+        )
+    }
+
+    #[test]
+    fn callers_report_the_line_of_their_call() {
+        let err = single_error(
+            r#"
+fun fail() { return -nil; }
+print
+  fail();
+"#);
+        assert_eq!(err.msg, "Expected Value::Number, but found Nil (Negate)");
+        let vec: Vec<(FunctionName, Line)> = Vec::from(err.stack_trace.deref().clone());
+        assert_eq_vec!(vec, vec![("fail".to_owned(), 1), ("<script>".to_owned(), 3)]);
+        assert_eq!(
+            err.to_string(),
+            "Expected Value::Number, but found Nil (Negate)\n  in fail at line 1\n  in <script> at line 3",
+        );
+    }
+
+    #[test]
+    fn native_errors_report_the_call_site() {
+        let err = single_error(
+            r#"
+fun limit(x) {
+  var hi = 10;
+  return clamp(x, "lo", hi);
+}
+print limit(
+  5);
+"#);
+        assert_eq!(err.msg, "clamp() expects numbers, got lo");
+        let vec: Vec<(FunctionName, Line)> = Vec::from(err.stack_trace.deref().clone());
+        assert_eq_vec!(
+            vec,
+            vec![("limit".to_owned(), 3), ("<script>".to_owned(), 5)],
+        );
+        assert_eq!(
+            single_error("print 1;\nprint 2;\nprint max(1, nil);").stack_trace.unwrap_single().1,
+            3,
+        );
+    }
+
+    #[test]
+    fn arity_and_name_of_a_function() {
+        assert_printed(r#"
+fun areWeHavingItYet(x, y) {
+  return x + y;
+}
+print arity(areWeHavingItYet);
+print name(areWeHavingItYet);
+        "#,
+                       "2\nareWeHavingItYet\n",
+        )
+    }
+
+    #[test]
+    fn arity_and_name_of_a_native() {
+        assert_printed("print arity(name); print name(arity);", "1\narity\n")
+    }
+
+    #[test]
+    fn arity_of_a_non_callable_is_an_error() {
+        assert_eq!(
+            single_error("arity(42);").msg,
+            "arity() expects a function, got 42".to_owned(),
+        )
+    }
+
+    #[test]
+    fn manual_factorial() {
+        assert_printed(r#"
+fun a(x) {
+  return x * b(x - 1);
+}
+fun b(x) {
+  return x * c(x - 1);
+}
+fun c(x) {
+  return 1;
+}
+print a(2);
+        "#,
+                       "2\n",
+        )
+    }
+
+    #[test]
+    fn functions_calling_functions_calling_functions_hard() {
+        assert_printed(r#"
+fun a(x) {
+  b(x + x, x * x);
+  b(x - x, x / x);
+}
+fun b(x, y) {
+  c(x * y, x + y, x - y);
+  c(x, y, x / y);
+}
+fun c(x, y, z) {
+  print x;
+  print y;
+  print z;
+}
+a(1);
+        "#,
+                       "2\n3\n1\n2\n1\n2\n0\n1\n-1\n0\n1\n0\n",
+        )
+    }
+
+    #[test]
+    fn implicit_function_return() {
+        assert_printed(r#"
+fun a(x) {
+  print x * x;
+}
+print a(16);
+        "#,
+                       "256\nnil\n",
+        )
+    }
+
+    #[test]
+    fn implicit_function_return2() {
+        assert_printed(r#"
+fun a(x) {
+  print x * x;
+}
+res = a(16);
+print res;
+        "#,
+                       "256\nnil\n",
+        )
+    }
+
+    #[test]
+    fn basic_explicit_return() {
+        assert_printed(r#"
+fun plus() {
+  return 42;
+}
+print plus();
+        "#,
+                       "42\n",
+        )
+    }
+
+    #[test]
+    fn basic_explicit_return_with_args() {
+        assert_printed(r#"
+fun plus(x, y) {
+  return x + y;
+}
+print plus(10, 20);
+        "#,
+                       "30\n",
+        )
+    }
+
+    #[test]
+    fn factorial_0() {
+        assert_printed(r#"
+fun factorial(x) {
+  if (x == 0) {return 1;}
+  else {return x * factorial(x - 1);}
+}
+print factorial(0);
+        "#,
+                       "1\n",
+        )
+    }
+
+    #[test]
+    fn factorial_1() {
+        assert_printed(r#"
+fun factorial(x) {
+  if (x == 0) {return 1;}
+  else {return x * factorial(x - 1);}
+}
+print factorial(1);
+        "#,
+                       "1\n",
+        )
+    }
+
+    #[test]
+    fn factorial_5() {
+        assert_printed(r#"
+fun factorial(x) {
+  if (x == 0) {return 1;}
+  else {return x * factorial(x - 1);}
+}
+print factorial(5);
+        "#,
+                       "120\n",
+        )
+    }
+
+    #[test]
+    fn factorial_5_early_exit() {
+        assert_printed(r#"
+fun factorial(x) {
+  if (x == 0) {
+    return 1;
+  }
+  return x * factorial(x - 1);
+}
+print factorial(5);
+        "#,
+                       "120\n",
+        )
+    }
+
+    #[test]
+    fn fib() {
+        assert_printed(r#"
+fun fib(x) {
+  if (x < 2) {return x;}
+  return fib(x - 1) + fib(x - 2);
+}
+print fib(10);
+        "#,
+                       "55\n",
+        )
+    }
+
+    #[test]
+    fn functions_returning_functions_no_closure() {
+        assert_printed(r#"
+fun f() {
+  print "f1";
+  fun g() {
+    print "g";
+  }
+  print "f2";
+  return g;
+}
+f()();
+        "#,
+                       "f1\nf2\ng\n",
+        )
+    }
+
+    #[test]
+    fn basic_function_closure() {
+        assert_printed(r#"
+{
+  var x = 42;
+  fun foo() {
+    print x;
+  }
+  foo();
+}
+        "#,
+                       "42\n",
+        )
+    }
+
+    #[test]
+    fn basic_function_set_closure() {
+        assert_printed(r#"
+{
+  var x = 42;
+  fun foo() {
+    x = 1;
+  }
+  foo();
+  print x;
+}
+        "#,
+                       "1\n",
+        )
+    }
+
+    #[test]
+    fn basic_function_set_from_get_closure() {
+        assert_printed(r#"
+{
+  var x = 42;
+  fun foo() {
+    x = x + 1;
+  }
+  foo();
+  print x;
+}
+        "#,
+                       "43\n",
+        )
+    }
+
+    #[test]
+    fn nested_function_closure() {
+        assert_printed(r#"
+fun foo(x) {
+  fun bar() {
+    print x;
+  }
+  bar();
+}
+foo(42);
+        "#,
+                       "42\n",
+        )
+    }
+
+    #[test]
+    fn access_closed_variable_locally() {
+        assert_printed(r#"
+fun foo(x) {
+  x = x + 1;
+  fun bar() {
+    x = x * 2;
+  }
+  fun bazz() {
+    x = x + 1;
+  }
+  x = x + 2;
+  bar();
+  bazz();
+  bazz();
+  bar();
+  print x;
+}
+foo(5);
+        "#,
+                       "36\n",
+        )
+    }
+
+    #[test]
+    fn basic_closed_upvalues() {
+        assert_printed(r#"
+
+{
+  var x = 42;
+  fun bar() {
+    print x;
+  }
+  f = bar;
+}
+f();
+        "#,
+                       "42\n",
+        )
+    }
+
+    #[test]
+    fn multiple_closures() {
+        assert_printed(r#"
+{
+  fun foo(a, b) {
+    fun bar(c, d) {
+        fun bazz(e, f) {
+            print a + b + c + d + e + f;
+        }
+        return bazz("e", "f");
+    }
+    return bar("c", "d");
+  }
+  f = foo;
+}
+f("a", "b");
+        "#,
+                       "abcdef\n",
+        )
+    }
+
+    #[test]
+    fn non_local_closure() {
+        assert_printed(r#"
+fun foo(x) {
+  fun bar() {
+      fun bazz() {
+        print x;
+      }
+      bazz();
+  }
+  bar();
+}
+foo(42);
+        "#,
+                       "42\n",
+        )
+    }
+
+    #[test]
+    fn upvalues_book_example() {
+        assert_printed(
+            r#"
+fun outer() {
+  var x = "outside";
+  fun inner() {
+    print x;
+  }
+  inner();
+}
+outer();
+           "#,
+            "outside\n",
+        )
+    }
+
+    #[test]
+    fn closed_upvalues_book_example() {
+        assert_printed_under_gc_stress(
+            r#"
+fun outer() {
+  var x = "outside";
+  fun inner() {
+    print x;
+  }
+
+  return inner;
+}
+
+
+closure = outer();
+closure();
+           "#,
+            "outside\n",
+        )
+    }
+
+    #[test]
+    fn values_and_variables_book_example() {
+        assert_printed_under_gc_stress(
+            r#"
+var globalSet;
+var globalGet;
+
+fun main() {
+  var a = "initial";
+
+  fun set() { a = "updated"; }
+  fun get() { print a; }
+
+  globalSet = set;
+  globalGet = get;
+}
+
+main();
+globalSet();
+globalGet();
+           "#,
+            "updated\n",
+        )
+    }
+
+    #[test]
+    fn explicit_globals_from_nested_functions() {
+        assert_printed(
+            r#"
+fun outer() {
+  var x = "local";
+  fun inner() {
+    global x = "global";
+  }
+  inner();
+  print x;
+}
+outer();
+print x;
+           "#,
+            "local\nglobal\n",
+        )
+    }
+
+    #[test]
+    fn sharing_closures() {
+        assert_printed_under_gc_stress(
+            r#"
+{
+    fun foo(x) {
+      fun bar() {
+        x = x + 1;
+        print x;
+      }
+      f = bar;
+      g = bar;
+    }
+    foo(42);
+}
+f();
+g();
+           "#,
+            "43\n44\n",
+        )
+    }
+
+    #[test]
+    fn by_value_captures_are_copies() {
+        assert_printed(
+            r#"
+{
+  var x = 1;
+  fun foo() use (x) {
+    x = x + 10;
     print x;
   }
-  f = bar;
+  x = 2;
+  foo();
+  foo();
+  print x;
+}
+           "#,
+            "11\n21\n2\n",
+        )
+    }
+
+    #[test]
+    fn by_value_captures_of_loop_variables() {
+        assert_printed(
+            r#"
+var a; var b;
+for (var i = 0; i < 2; i = i + 1) {
+  fun f() use (i) { print i; }
+  if (i == 0) a = f; else b = f;
+}
+a();
+b();
+           "#,
+            "0\n1\n",
+        )
+    }
+
+    #[test]
+    fn by_value_captures_of_upvalues() {
+        assert_printed(
+            r#"
+var f;
+fun outer() {
+  var x = 1;
+  fun middle() {
+    x = 2;
+    fun inner() use (x) { print x; }
+    x = 4;
+    return inner;
+  }
+  f = middle();
+  x = 3;
+}
+outer();
+f();
+           "#,
+            "2\n",
+        )
+    }
+
+    #[test]
+    fn basic_class_declaration() {
+        assert_printed(
+            r#"
+class Foo {}
+print Foo;
+           "#,
+            "Foo\n",
+        )
+    }
+
+    #[test]
+    fn basic_class_instantiation() {
+        assert_printed(
+            r#"
+class Foo {}
+print Foo();
+           "#,
+            "Foo instance\n",
+        )
+    }
+
+    #[test]
+    fn properties_of_non_instances() {
+        for (code, type_name) in [
+            ("(5).foo;", "number"),
+            ("nil.bar;", "nil"),
+            (r#""str".baz;"#, "string"),
+            ("(5).foo = 1;", "number"),
+            ("nil.bar = 1;", "nil"),
+            (r#""str".baz = 1;"#, "string"),
+        ] {
+            assert_eq!(
+                single_error(code).msg,
+                format!("Only instances have properties, got {}.", type_name),
+            );
+        }
+    }
+
+    #[test]
+    fn calling_a_class_with_arguments() {
+        assert_eq!(
+            single_error("class Foo {} Foo(1, 2);").msg,
+            "Expected 0 arguments but got 2",
+        );
+    }
+
+    #[test]
+    fn basic_set_and_get_property() {
+        assert_printed(
+            r#"
+class Foo {}
+foo = Foo();
+foo.x = 3;
+print foo.x;
+           "#,
+            "3\n",
+        )
+    }
+
+    #[test]
+    fn get_and_set_book_example() {
+        assert_printed(
+            r#"
+class Pair {}
+
+var pair = Pair();
+pair.first = 1;
+pair.second = 2;
+print pair.first + pair.second; // 3.
+           "#,
+            "3\n",
+        )
+    }
+
+    #[test]
+    fn nested_getting_and_setting() {
+        assert_printed(
+            r#"
+class Foo {}
+
+var foo = Foo();
+foo.foo = Foo();
+foo.foo.x = 42;
+var bar = foo.foo;
+bar.y = 43;
+print foo.foo.x;
+print foo.foo.y;
+           "#,
+            "42\n43\n",
+        )
+    }
+
+    #[test]
+    fn setting_closures() {
+        assert_printed(
+            r#"
+class Foo {}
+
+var foo = Foo();
+{
+    fun bar(x) {
+        fun bazz() {
+            return x + 1;
+        }
+        foo.bazz = bazz;
+    }
+    bar(42);
+}
+print foo.bazz();
+           "#,
+            "43\n",
+        )
+    }
+
+    #[test]
+    fn methods_and_this() {
+        assert_printed_under_gc_stress(
+            r#"
+class Scone {
+  topping(first, second) {
+    print "scone with " + first + " and " + second;
+  }
 }
-f();
-        "#,
-                       "42",
+var scone = Scone();
+scone.topping("berries", "cream");
+
+class Nested {
+  method() {
+    fun function() {
+      print this;
+    }
+    function();
+  }
+}
+Nested().method();
+           "#,
+            "scone with berries and cream\nNested instance\n",
+        )
+    }
+
+    #[test]
+    fn bound_methods_remember_their_receiver() {
+        assert_printed_under_gc_stress(
+            r#"
+class Person {
+  sayName() {
+    print this.name;
+  }
+}
+var jane = Person();
+jane.name = "Jane";
+var method = jane.sayName;
+jane.name = "Janet";
+method();
+print method;
+           "#,
+            "Janet\n<fn sayName>\n",
+        )
+    }
+
+    #[test]
+    fn fields_shadow_methods() {
+        assert_printed_under_gc_stress(
+            r#"
+class Foo {
+  bar() { return "method"; }
+}
+var foo = Foo();
+print foo.bar();
+fun field() { return "field"; }
+foo.bar = field;
+print foo.bar();
+           "#,
+            "method\nfield\n",
+        )
+    }
+
+    #[test]
+    fn initializers() {
+        assert_printed_under_gc_stress(
+            r#"
+class Brunch {
+  init(food, drink) {
+    this.food = food;
+    this.drink = drink;
+    if (food == "eggs") return;
+    this.food = food + "!";
+  }
+  describe() { return this.food + " and " + this.drink; }
+}
+print Brunch("eggs", "coffee").describe();
+var brunch = Brunch("toast", "tea");
+print brunch.describe();
+// Calling init directly also returns the instance.
+print brunch.init("beans", "juice");
+print brunch.describe();
+           "#,
+            "eggs and coffee\ntoast! and tea\nBrunch instance\nbeans! and juice\n",
+        )
+    }
+
+    #[test]
+    fn static_methods() {
+        assert_printed_under_gc_stress(
+            r#"
+class Math2 {
+  static square(x) { return x * x; }
+  static twice(f, x) { return f(f(x)); }
+  square(x) { return "instance"; }
+}
+print Math2.square(5);
+print Math2.twice(Math2.square, 3);
+print Math2().square(5);
+var square = Math2.square;
+print square;
+           "#,
+            "25\n81\ninstance\n<fn square>\n",
+        );
+        assert_eq!(
+            single_error("class Foo { bar() {} } Foo.bar();").msg,
+            "Undefined static method 'bar'.",
+        );
+        assert_eq!(
+            single_error("class Foo { static bar() {} } Foo().bar();").msg,
+            "Undefined property 'bar'.",
+        );
+    }
+
+    #[test]
+    fn calling_an_initializer_with_the_wrong_number_of_arguments() {
+        assert_eq!(
+            single_error("class Foo { init(a, b) {} } Foo(1);").msg,
+            "Expected 2 arguments but got 1",
+        );
+        assert_eq!(
+            single_error("class Foo { bar() {} } Foo().bar(1);").msg,
+            "Expected 0 arguments but got 1",
+        );
+        assert_eq!(
+            single_error("class Foo { bar() {} } Foo().baz();").msg,
+            "Undefined property 'baz'.",
+        );
+    }
+
+    #[test]
+    fn methods_survive_collection() {
+        let (chunk, interned_strings) = unsafe_compile(vec![r#"
+fun make(greeting) {
+  class Greeter {
+    greet(name) { return greeting + " " + name; }
+  }
+  return Greeter();
+}
+var greeter = make("hello");
+// Collections only happen between frames, so this makes sure there's one after the class itself
+// is no longer on the stack, and only reachable through its instance.
+fun collect() {}
+collect();
+print greeter.greet("world");
+        "#]);
+        let options = VmOptions {
+            gc_policy: GcPolicy { initial_threshold: 1, growth_factor: 1.0, stress: false },
+            ..Default::default()
+        };
+        let (output, collections) = VirtualMachine::run_apply_with_options(
+            chunk, interned_strings, Vec::new(), options,
+            |vm| (String::from_utf8(vm.output.clone()).unwrap(), vm.gc_stats().collections),
+        ).unwrap();
+        assert_eq!(output, "hello world\n");
+        assert!(collections > 1);
+    }
+
+    #[test]
+    fn bare_returns() {
+        assert_printed(
+            "fun f(x) { if (x) return; return 1; } print f(true); print f(false);",
+            "nil\n1\n",
+        )
+    }
+
+    #[test]
+    fn calling_an_empty_function() {
+        assert_printed("fun f() {} print f(); print 2;", "nil\n2\n")
+    }
+
+    #[test]
+    fn weak_references_do_not_keep_instances_alive() {
+        assert_printed(
+            r#"
+class Foo {}
+// The GC runs between call frames.
+fun collect() {}
+var foo = Foo();
+var weak = weakref(foo);
+collect();
+print deref(weak);
+print weak;
+foo = nil;
+collect();
+print deref(weak);
+           "#,
+            "Foo instance\n<weakref>\nnil\n",
         )
     }
 
     #[test]
-    fn multiple_closures() {
-        assert_printed(r#"
-{
-  fun foo(a, b) {
-    fun bar(c, d) {
-        fun bazz(e, f) {
-            print a + b + c + d + e + f;
-        }
-        return bazz("e", "f");
+    fn repr() {
+        // The lexer has no escape sequences, so that's an actual tab.
+        assert_printed(
+            "print repr(\"a\tb\"); print len(bytesOf(repr(\"a\tb\"))); print repr(1.5);",
+            "\"a\\tb\"\n6\n1.5\n",
+        );
+        assert_printed("print repr(\"a\\b\");", "\"a\\\\b\"\n");
+    }
+
+    #[test]
+    fn assert_equals() {
+        assert_printed("assertEquals(3, 1 + 2); assertEquals(\"ab\", \"a\" + \"b\"); print 1;", "1\n");
+        assert_eq!(
+            single_error("assertEquals(3, 1 + 1);").msg,
+            "assertEquals failed: expected 3, got 2",
+        );
+    }
+
+    #[test]
+    fn min_max_and_clamp() {
+        assert_printed(
+            "print max(3, 7, 2); print min(3, 7, 2); print min(4, -1); print clamp(5, 0, 3); print clamp(-1, 0, 3); print clamp(2, 0, 3);",
+            "7\n2\n-1\n3\n0\n2\n",
+        );
+        assert_printed("print arity(max); print arity(clamp);", "2\n3\n");
+    }
+
+    #[test]
+    fn min_max_and_clamp_errors() {
+        assert_eq!(single_error("max(1);").msg, "Expected at least 2 arguments but got 1");
+        assert_eq!(single_error("min(1, \"2\", 3);").msg, "min() expects numbers, got 2");
+        assert_eq!(single_error("clamp(1, nil, 3);").msg, "clamp() expects numbers, got nil");
+        assert_eq!(single_error("clamp(1, 3, 0);").msg, "clamp() expects lo <= hi, got 3 > 0");
+        assert_eq!(single_error("clamp(1, 0 / 0, 3);").msg, "clamp() bounds can't be NaN");
+        assert_eq!(single_error("clamp(1, 0, 0 / 0);").msg, "clamp() bounds can't be NaN");
+        assert_eq!(single_error("clamp(1, 3);").msg, "Expected 3 arguments but got 2");
+    }
+
+    #[test]
+    fn classes_declared_after_functions() {
+        assert_printed("fun f() {} class A {} print A;", "A\n");
+    }
+
+    #[test]
+    fn hash() {
+        assert_printed(
+            r#"
+var s = "ab";
+print hash(s) == hash("a" + "b");
+print hash("ab") == hash("ba");
+print hash(nil) == hash(nil);
+print hash(1) == hash(1.0);
+print hash(true) == hash(false);
+           "#,
+            "true\nfalse\ntrue\ntrue\nfalse\n",
+        );
+        let (_, first) = run("print hash(nil);");
+        let (_, second) = run("print hash(nil);");
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn nan_and_negative_zero_equality() {
+        assert_printed(
+            r#"
+var nan = 0 / 0;
+print nan == nan;
+print nan != nan;
+print -0 == 0;
+print hash(-0) == hash(0);
+           "#,
+            "false\ntrue\ntrue\ntrue\n",
+        );
+        assert_eq!(
+            single_error("hash(0 / 0);").msg,
+            "hash() argument can't be nan, since it isn't equal to itself",
+        );
+    }
+
+    #[test]
+    fn memoized_calls_with_nan_or_negative_zero() {
+        assert_printed(
+            r#"
+var calls = 0;
+fun id(x) {
+  calls = calls + 1;
+  return x;
+}
+var cached = memoize(id);
+cached(0 / 0);
+cached(0 / 0);
+print calls;
+cached(0);
+cached(-0);
+print calls;
+           "#,
+            "2\n3\n",
+        );
+    }
+
+    #[test]
+    fn hashing_unhashable_values() {
+        assert_eq!(
+            single_error("fun foo() {} hash(foo);").msg,
+            "hash() argument must be a number, string, bool or nil, got <fn foo>",
+        );
+        assert_eq!(
+            single_error("class Foo {} hash(Foo());").msg,
+            "hash() argument must be a number, string, bool or nil, got Foo instance",
+        );
+    }
+
+    #[test]
+    fn format() {
+        assert_printed(
+            r#"
+var a = 1;
+var b = 2;
+print format("{} + {} = {}", a, b, a + b);
+print format("{}, {}!", "Hello", nil);
+print format("no placeholders");
+           "#,
+            "1 + 2 = 3\nHello, nil!\nno placeholders\n",
+        );
+    }
+
+    #[test]
+    fn format_escaped_braces() {
+        assert_printed(r#"print format("{{{}}} {{}}", 42);"#, "{42} {}\n");
+    }
+
+    #[test]
+    fn format_errors() {
+        assert_eq!(
+            single_error(r#"format("{} and {}", 1);"#).msg,
+            "format() got 2 placeholders but 1 arguments",
+        );
+        assert_eq!(
+            single_error(r#"format("{}", 1, 2, 3);"#).msg,
+            "format() got 1 placeholders but 3 arguments",
+        );
+        assert_eq!(single_error(r#"format("{x}", 1);"#).msg, "format() got an unmatched '{'");
+        assert_eq!(single_error(r#"format("}", 1);"#).msg, "format() got an unmatched '}'");
+        assert_eq!(
+            single_error("format(1);").msg,
+            "format() expects a string template, got 1",
+        );
+    }
+
+    #[test]
+    fn memoized_functions_are_called_once_per_argument() {
+        assert_printed(
+            r#"
+var calls = 0;
+fun square(x) {
+  calls = calls + 1;
+  return x * x;
+}
+var cached = memoize(square);
+print cached(3);
+print cached(3);
+print cached(4);
+print cached(3);
+print calls;
+print cached;
+print arity(cached);
+           "#,
+            "9\n9\n16\n9\n2\n<memoized fn square>\n1\n",
+        );
+    }
+
+    #[test]
+    fn memoized_functions_distinguish_argument_lists() {
+        assert_printed(
+            r#"
+var calls = 0;
+fun join(a, b) {
+  calls = calls + 1;
+  return a + b;
+}
+var cached = memoize(join);
+print cached("a", "b");
+print cached("ab", "");
+print cached("a", "b");
+print calls;
+           "#,
+            "ab\nab\nab\n2\n",
+        );
+    }
+
+    #[test]
+    fn memoized_recursion() {
+        assert_printed(
+            r#"
+var calls = 0;
+fun fib(n) {
+  calls = calls + 1;
+  if (n < 2) return n;
+  return fib(n - 1) + fib(n - 2);
+}
+fib = memoize(fib);
+print fib(30);
+print calls;
+           "#,
+            "832040\n31\n",
+        );
+    }
+
+    #[test]
+    fn memoized_functions_with_unhashable_arguments_are_not_cached() {
+        assert_printed(
+            r#"
+var calls = 0;
+fun id(x) {
+  calls = calls + 1;
+  return x;
+}
+class Foo {}
+var foo = Foo();
+var cached = memoize(id);
+print cached(foo);
+print cached(foo);
+print calls;
+           "#,
+            "Foo instance\nFoo instance\n2\n",
+        );
+    }
+
+    #[test]
+    fn memoized_results_survive_collection() {
+        let (chunk, interned_strings) = unsafe_compile(vec![r#"
+var calls = 0;
+fun greet(name) {
+  calls = calls + 1;
+  return "hello " + name;
+}
+var cached = memoize(greet);
+for (var i = 0; i < 20; i = i + 1) { cached("a" + "b"); }
+// Collections only happen between frames, so this makes sure there's one while the cached result
+// is no longer on the stack.
+fun collect() {}
+collect();
+print cached("ab");
+print calls;
+        "#]);
+        let options = VmOptions {
+            gc_policy: GcPolicy { initial_threshold: 1, growth_factor: 1.0, stress: false },
+            ..Default::default()
+        };
+        let (output, collections) = VirtualMachine::run_apply_with_options(
+            chunk, interned_strings, Vec::new(), options,
+            |vm| (String::from_utf8(vm.output.clone()).unwrap(), vm.gc_stats().collections),
+        ).unwrap();
+        assert_eq!(output, "hello ab\n1\n");
+        assert!(collections > 1);
+    }
+
+    #[test]
+    fn memoize_errors() {
+        assert_eq!(single_error("memoize(1);").msg, "memoize() expects a function, got 1");
+        assert_eq!(
+            single_error("fun f(x) { return x; } memoize(f)(1, 2);").msg,
+            "Expected 1 arguments but got 2",
+        );
+    }
+
+    fn run_with_panicking_native(catch_native_panics: bool) -> Result<String, VmError> {
+        fn boom(_: &mut NativeContext, _: &[Value]) -> Result<Value, String> {
+            panic!("host bug")
+        }
+        let (chunk, interned_strings) = unsafe_compile(vec!["print 1; boom(); print 2;"]);
+        let options = VmOptions { catch_native_panics, ..Default::default() };
+        let mut vm = VirtualMachine::new(chunk, interned_strings, Vec::new(), options);
+        vm.define_native("boom", NativeArity::Exactly(0), boom);
+        vm.run_to_end()?;
+        Ok(String::from_utf8(vm.output).unwrap())
+    }
+
+    #[test]
+    fn native_panics_can_be_caught() {
+        let err = run_with_panicking_native(true).unwrap_err();
+        assert_eq!(err.msg, "Native function boom() panicked: host bug");
+        assert_eq!(err.stack_trace.unwrap_single().1, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "host bug")]
+    fn native_panics_unwind_by_default() {
+        let _ = run_with_panicking_native(false);
+    }
+
+    fn run_with_time_natives(code: &str) -> Result<String, VmError> {
+        let (chunk, interned_strings) = unsafe_compile(vec![code]);
+        let options = VmOptions { time_natives: true, ..Default::default() };
+        let mut vm = VirtualMachine::new(chunk, interned_strings, Vec::new(), options);
+        vm.run_to_end()?;
+        Ok(String::from_utf8(vm.output).unwrap())
+    }
+
+    #[test]
+    fn time_and_sleep() {
+        // 2020-01-01.
+        assert_eq!(run_with_time_natives("print time() > 1577836800;").unwrap(), "true\n");
+        let start = std::time::Instant::now();
+        assert_eq!(
+            run_with_time_natives("var t = time(); print sleep(0); print time() >= t;").unwrap(),
+            "nil\ntrue\n",
+        );
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn clock_measures_elapsed_time() {
+        assert_printed(
+            r#"
+fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 2) + fib(n - 1);
+}
+var start = clock();
+print fib(10);
+var elapsed = clock() - start;
+print elapsed >= 0;
+print clock() >= start + elapsed;
+           "#,
+            "55\ntrue\ntrue\n",
+        );
+        assert_eq!(single_error("clock(1);").msg, "Expected 0 arguments but got 1");
+    }
+
+    #[test]
+    fn time_natives_are_opt_in() {
+        assert_eq!(single_error("time();").msg, "Unrecognized identifier 'time'");
+    }
+
+    #[test]
+    fn sleep_errors() {
+        assert_eq!(
+            run_with_time_natives("sleep(-1);").unwrap_err().msg,
+            "sleep() expects a non-negative number of seconds, got -1",
+        );
+        assert_eq!(
+            run_with_time_natives("sleep(nil);").unwrap_err().msg,
+            "sleep() expects a non-negative number of seconds, got nil",
+        );
+    }
+
+    fn run_chunk(ops: Vec<OpCode>) -> Result<Vec<Value>, VmError> {
+        let mut chunk = Chunk::default();
+        for op in ops {
+            chunk.write(op, 1);
+        }
+        VirtualMachine::run(chunk, InternedStrings::default(), sink())
+    }
+
+    #[test]
+    fn stack_underflow_in_malformed_chunks() {
+        assert_eq!(
+            run_chunk(vec![OpCode::Number(1.0), OpCode::Pop, OpCode::Pop]).unwrap_err().msg,
+            "Stack underflow: POP needs 1 values, but the frame only has 0",
+        );
+        assert_eq!(
+            run_chunk(vec![OpCode::Number(1.0), OpCode::Add]).unwrap_err().msg,
+            "Stack underflow: ADD needs 2 values, but the frame only has 1",
+        );
+        assert_eq!(
+            run_chunk(vec![OpCode::Nil, OpCode::Return]).unwrap_err().msg,
+            "Can't return from the script",
+        );
+    }
+
+    #[test]
+    fn loop_offsets_before_the_chunk_are_runtime_errors() {
+        let err = run_chunk(vec![OpCode::Nil, OpCode::Loop(2)]).unwrap_err();
+        assert_eq!(err.msg, "Loop offset 2 jumps before the start of the chunk");
     }
-    return bar("c", "d");
-  }
-  f = foo;
-}
-f("a", "b");
-        "#,
-                       "abcdef",
-        )
+
+    #[test]
+    fn conversions() {
+        assert_printed(
+            r#"
+print toInt(3.9);
+print toInt(-3.9);
+print toInt(" 42 ");
+print toInt("2.5");
+print toFloat("2.5");
+print toFloat(7);
+print toBool(nil);
+print toBool(false);
+print toBool(0);
+print toBool("");
+           "#,
+            "3\n-3\n42\n2\n2.5\n7\nfalse\nfalse\ntrue\ntrue\n",
+        );
     }
 
     #[test]
-    fn non_local_closure() {
-        assert_printed(r#"
-fun foo(x) {
-  fun bar() {
-      fun bazz() {
-        print x;
-      }
-      bazz();
-  }
-  bar();
-}
-foo(42);
-        "#,
-                       "42",
-        )
+    fn conversion_errors() {
+        assert_eq!(
+            single_error(r#"toInt("x");"#).msg,
+            "toInt() can't convert \"x\" to a number",
+        );
+        assert_eq!(
+            single_error("toFloat(nil);").msg,
+            "toFloat() expects a number or a string, got nil",
+        );
+        assert_eq!(
+            single_error("toInt(1 / 0);").msg,
+            "toInt() can't convert inf to an integer",
+        );
     }
 
     #[test]
-    fn upvalues_book_example() {
+    fn weakref_of_a_non_instance() {
+        assert_eq!(single_error("weakref(1);").msg, "weakref() expects an instance, got 1");
+        assert_eq!(single_error("deref(1);").msg, "deref() expects a weakref, got 1");
+    }
+
+    #[test]
+    fn lists() {
         assert_printed(
             r#"
-fun outer() {
-  var x = "outside";
-  fun inner() {
-    print x;
-  }
-  inner();
-}
-outer();
+var list = [1, "two", [3], nil,];
+print list;
+print list[1];
+list[0] = list[0] + 10;
+print list[0];
+print len(list);
+print [];
+var copy = list;
+copy[3] = true;
+print list[3];
+print list == copy;
+print [1] == [1];
            "#,
-            "outside",
+            "[1, \"two\", [3], nil]\ntwo\n11\n4\n[]\ntrue\ntrue\nfalse\n",
         )
     }
 
     #[test]
-    fn closed_upvalues_book_example() {
+    fn membership() {
         assert_printed(
             r#"
-fun outer() {
-  var x = "outside";
-  fun inner() {
-    print x;
-  }
-
-  return inner;
-}
-
-
-closure = outer();
-closure();
+var numbers = [1, 2.5, 3];
+print contains(numbers, 2.5);
+print contains(numbers, 4);
+print contains(numbers, "1");
+var inner = [1];
+print contains([inner], inner);
+print contains([inner], [1]);
+var table = {"a": nil};
+print has(table, "a");
+print has(table, "b");
            "#,
-            "outside",
-        )
+            "true\nfalse\nfalse\ntrue\nfalse\ntrue\nfalse\n",
+        );
+        assert_eq!(single_error("contains({}, 1);").msg, "contains() expects a list, got {}");
     }
 
     #[test]
-    fn values_and_variables_book_example() {
+    fn frozen_collections() {
         assert_printed(
             r#"
-var globalSet;
-var globalGet;
-
-fun main() {
-  var a = "initial";
-
-  fun set() { a = "updated"; }
-  fun get() { print a; }
-
-  globalSet = set;
-  globalGet = get;
-}
-
-main();
-globalSet();
-globalGet();
+var list = freeze([1, [2]]);
+print list[0] + len(list);
+list[1][0] = 3;
+print list;
+var table = {"a": 1};
+print freeze(table) == table;
+print table["a"];
            "#,
-            "updated",
-        )
+            "3\n[1, [3]]\ntrue\n1\n",
+        );
+        assert_eq!(
+            single_error("var list = freeze([1]); list[0] = 2;").msg,
+            "Cannot modify a frozen collection.",
+        );
+        assert_eq!(
+            single_error(r#"var table = freeze({}); table["a"] = 1;"#).msg,
+            "Cannot modify a frozen collection.",
+        );
+        assert_eq!(single_error("freeze(1);").msg, "freeze() expects a list or a table, got 1");
     }
 
     #[test]
-    fn sharing_closures() {
+    fn joining_strings() {
         assert_printed(
             r#"
-{
-    fun foo(x) {
-      fun bar() {
-        x = x + 1;
-        print x;
-      }
-      f = bar;
-      g = bar;
+print join(["a", "b", "c"], "-");
+print len(join([], ", "));
+print join(["only"], ", ");
+print join(["x", "", "y"], "");
+           "#,
+            "a-b-c\n0\nonly\nxy\n",
+        );
+        assert_eq!(
+            single_error(r#"join(["a", 1], "-");"#).msg,
+            "join() expects a list of strings, got 1",
+        );
+        assert_eq!(
+            single_error(r#"join(["a"], nil);"#).msg, "join() separator must be a string, got nil");
+        assert_eq!(single_error(r#"join("ab", "-");"#).msg, "join() expects a list, got ab");
     }
-    foo(42);
-}
-f();
-g();
+
+    #[test]
+    fn preallocated_tables() {
+        assert_printed(
+            r#"
+var preallocated = withCapacity(100);
+var initial = capacity(preallocated);
+for (i in 0..100) preallocated[i] = i;
+print initial >= 100;
+print capacity(preallocated) == initial;
+print len(preallocated);
+var table = {};
+initial = capacity(table);
+for (i in 0..100) table[i] = i;
+print capacity(table) > initial;
            "#,
-            "4344",
-        )
+            "true\ntrue\n100\ntrue\n",
+        );
+        assert_eq!(
+            single_error("withCapacity(-1);").msg,
+            "withCapacity() expects a non-negative integer, got -1",
+        );
+        assert_eq!(
+            single_error("withCapacity(1.5);").msg,
+            "withCapacity() expects a non-negative integer, got 1.5",
+        );
+        assert_eq!(single_error("capacity([]);").msg, "capacity() expects a table, got []");
     }
 
     #[test]
-    fn basic_class_declaration() {
+    fn enumerating_lists() {
         assert_printed(
             r#"
-class Foo {}
-print Foo;
+print enumerate(["a", "b"]);
+print enumerate([]);
+for (pair in enumerate(["x", "y"])) print "${pair[0]}${pair[1]}";
            "#,
-            "Foo",
-        )
+            "[[0, \"a\"], [1, \"b\"]]\n[]\n0x\n1y\n",
+        );
+        assert_eq!(single_error("enumerate(1..2);").msg, "enumerate() expects a list, got 1..2");
     }
 
     #[test]
-    fn basic_class_instantiation() {
+    fn lists_containing_themselves() {
         assert_printed(
             r#"
-class Foo {}
-print Foo();
+var list = [1, [2]];
+list[1][0] = list;
+print list;
+print list[1][0][0];
            "#,
-            "Foo instance",
+            "[1, [[...]]]\n1\n",
         )
     }
 
     #[test]
-    fn basic_set_and_get_property() {
+    fn list_index_errors() {
+        assert_eq!(
+            single_error("[1, 2][2];").msg,
+            "List index 2 is out of bounds for a list of length 2",
+        );
+        assert_eq!(
+            single_error("[1, 2][-1] = 3;").msg,
+            "List index -1 is out of bounds for a list of length 2",
+        );
+        assert_eq!(single_error("[1][0.5];").msg, "List indices must be integers, got 0.5");
+        assert_eq!(single_error(r#"[1]["0"];"#).msg, "List indices must be integers, got 0");
+        assert_eq!(
+            single_error("(1)[0];").msg,
+            "Only lists, tables and bytes can be indexed, got number.",
+        );
+    }
+
+    #[test]
+    fn tables() {
         assert_printed(
             r#"
-class Foo {}
-foo = Foo();
-foo.x = 3;
-print foo.x;
+var table = {"one": 1, 2: "two", "nested": {}, -0: "zero",};
+print table;
+print table["one"] + 10;
+print table[2];
+print table[0];
+table["one"] = 11;
+table["three"] = [3];
+print table;
+print len(table);
+print keys(table);
+print has(table, "three");
+print has(table, "four");
+var key = "o" + "ne";
+print table[key];
+print {} == {};
+print {"a": 1, "a": 2};
            "#,
-            "3",
+            "{\"one\": 1, 2: \"two\", \"nested\": {}, 0: \"zero\"}\n11\ntwo\nzero\n\
+            {\"one\": 11, 2: \"two\", \"nested\": {}, 0: \"zero\", \"three\": [3]}\n5\n\
+            [\"one\", 2, \"nested\", 0, \"three\"]\ntrue\nfalse\n11\nfalse\n{\"a\": 2}\n",
         )
     }
 
     #[test]
-    fn get_and_set_book_example() {
+    fn tables_keep_their_insertion_order() {
+        let code = r#"
+var table = {};
+table["a"] = 1;
+table["b"] = 2;
+table["c"] = 3;
+table["a"] = 4;
+print table;
+print keys(table);
+for (key in table) write key;
+print "";
+        "#;
+        let expected = "{\"a\": 4, \"b\": 2, \"c\": 3}\n[\"a\", \"b\", \"c\"]\nabc\n";
+        // String keys are hashed by their pointers, which differ between runs, unlike the order.
+        for _ in 0..5 {
+            assert_printed(code, expected);
+        }
+    }
+
+    #[test]
+    fn keys_and_values_line_up() {
         assert_printed(
             r#"
-class Pair {}
+var table = {"b": 1, "a": [2], 3: "c"};
+table["b"] = 4;
+var ks = keys(table);
+var vs = values(table);
+print vs;
+print len(ks) == len(table) and len(vs) == len(table);
+for (i in 0..len(ks)) write table[ks[i]] == vs[i];
+print "";
+print values({});
+           "#,
+            "[4, [2], \"c\"]\ntrue\ntruetruetrue\n[]\n",
+        );
+        assert_eq!(single_error("values([]);").msg, "values() expects a table, got []");
+    }
 
-var pair = Pair();
-pair.first = 1;
-pair.second = 2;
-print pair.first + pair.second; // 3.
+    #[test]
+    fn interpolated_strings() {
+        assert_printed(
+            r#"
+var a = 1;
+var b = 2;
+print "sum is ${a + b}";
+print "${a}${b}";
+print "nested ${"${a}" + "!"} and ${[a, "b"]}, ${{}}";
+var name = "bob";
+fun greet() { return "hi ${name}"; }
+print greet() + "${""}";
+print "${nil == false} in ${1
+  + 1} lines" == "false in 2 lines";
            "#,
-            "3",
+            "sum is 3\n12\nnested 1! and [1, \"b\"], {}\nhi bob\ntrue\n",
         )
     }
 
     #[test]
-    fn nested_getting_and_setting() {
+    fn string_operations() {
         assert_printed(
             r#"
-class Foo {}
+var s = "hello";
+print len(s);
+print substring(s, 1, 3);
+print substring(s, 0, len(s)) == s;
+print substring(s, 2, 2) == "";
+print toFloat(" 4.5 ") + 1;
+print "abc" < "abd";
+print "b" > "abc";
+           "#,
+            "5\nel\ntrue\ntrue\n5.5\ntrue\ntrue\n",
+        );
+        assert_eq!(
+            single_error(r#"substring("abc", 1, 4);"#).msg,
+            "substring() indices must be integers between 0 and 3, got 4",
+        );
+        assert_eq!(
+            single_error(r#"substring("abc", 2, 1);"#).msg,
+            "substring() start 2 is after its end 1",
+        );
+        assert_eq!(single_error("substring(1, 0, 0);").msg, "substring() expects a string, got 1");
+    }
 
-var foo = Foo();
-foo.foo = Foo();
-foo.foo.x = 42;
-var bar = foo.foo;
-bar.y = 43;
-print foo.foo.x;
-print foo.foo.y;
+    #[test]
+    fn tables_containing_themselves() {
+        assert_printed(
+            r#"
+var table = {"list": []};
+table["self"] = table;
+table["list"] = [table];
+print table;
+print table["self"]["self"]["list"][0] == table;
            "#,
-            "4243",
+            "{\"list\": [{...}], \"self\": {...}}\ntrue\n",
         )
     }
 
     #[test]
-    fn setting_closures() {
+    fn tables_and_hash_accept_the_same_keys() {
         assert_printed(
             r#"
+var table = {true: 1, nil: 2, -0: 3};
+print table;
+print table[true] + table[nil] + table[0];
+print has(table, false);
+print hash(nil) == hash(nil);
+           "#,
+            "{true: 1, nil: 2, 0: 3}
+6
+false
+true
+",
+        );
+        assert_eq!(
+            single_error("has({}, [1]);").msg,
+            "has() key must be a number, string, bool or nil, got [1]",
+        );
+    }
+
+    #[test]
+    fn table_errors() {
+        assert_eq!(single_error(r#"print {"a": 1}["b"];"#).msg, "Undefined key \"b\".");
+        assert_eq!(
+            single_error("print {}[[]] = 1;").msg,
+            "Table key must be a number, string, bool or nil, got []",
+        );
+        assert_eq!(
+            single_error("print {0 / 0: 1};").msg,
+            "Table key can't be nan, since it isn't equal to itself",
+        );
+        assert_eq!(
+            single_error("has({}, 0 / 0);").msg,
+            "has() key can't be nan, since it isn't equal to itself",
+        );
+        assert_eq!(single_error("keys([]);").msg, "keys() expects a table, got []");
+    }
+
+    #[test]
+    fn lists_survive_collection() {
+        let (chunk, interned_strings) = unsafe_compile(vec![r#"
 class Foo {}
+fun make() {
+  var foo = Foo();
+  foo.x = "kept";
+  return [foo, [foo]];
+}
+var list = make();
+list[1][0] = list;
+// Collections only happen between frames.
+fun collect() {}
+collect();
+print list[0].x;
+print len(list[1][0]);
+        "#]);
+        let options = VmOptions {
+            gc_policy: GcPolicy { initial_threshold: 1, growth_factor: 1.0, stress: false },
+            ..Default::default()
+        };
+        let (output, collections) = VirtualMachine::run_apply_with_options(
+            chunk, interned_strings, Vec::new(), options,
+            |vm| (String::from_utf8(vm.output.clone()).unwrap(), vm.gc_stats().collections),
+        ).unwrap();
+        assert_eq!(output, "kept\n2\n");
+        assert!(collections > 1);
+    }
 
-var foo = Foo();
-{
-    fun bar(x) {
-        fun bazz() {
-            return x + 1;
-        }
-        foo.bazz = bazz;
+    #[test]
+    fn tables_survive_collection() {
+        let (chunk, interned_strings) = unsafe_compile(vec![r#"
+fun make() {
+  var table = {"a" + "b": ["c" + "d"]};
+  table["self"] = table;
+  return table;
+}
+var table = make();
+var names = keys(table);
+// Collections only happen between frames.
+fun collect() {}
+collect();
+print table["self"]["ab"][0];
+print names;
+        "#]);
+        let options = VmOptions {
+            gc_policy: GcPolicy { initial_threshold: 1, growth_factor: 1.0, stress: false },
+            ..Default::default()
+        };
+        let (output, collections) = VirtualMachine::run_apply_with_options(
+            chunk, interned_strings, Vec::new(), options,
+            |vm| (String::from_utf8(vm.output.clone()).unwrap(), vm.gc_stats().collections),
+        ).unwrap();
+        assert_eq!(output, "cd\n[\"ab\", \"self\"]\n");
+        assert!(collections > 1);
     }
-    bar(42);
+
+    #[test]
+    fn inspecting_upvalues() {
+        let (chunk, interned_strings) = unsafe_compile(vec![r#"
+fun helper() {}
+fun outer() {
+  var x = 1;
+  fun inner() { return x; }
+  helper();
+  return inner;
 }
-print foo.bazz();
-           "#,
-            "43",
-        )
+var closure = outer();
+        "#]);
+        let mut vm = VirtualMachine::new(chunk, interned_strings, sink(), VmOptions::default());
+        // Run until helper is called, i.e., while x is still open.
+        while vm.frames.len() < 3 {
+            vm.go().unwrap();
+        }
+        assert_eq!(vm.open_upvalues(), vec![(1, "1".to_owned())]);
+        vm.run_to_end().unwrap();
+        assert_eq!(vm.open_upvalues(), vec![]);
+        let closure = vm.frames.head.globals.borrow().get_by_index(2).unwrap();
+        match closure {
+            Value::Closure(c) => assert_eq!(c.captured_upvalues(), vec!["closed: 1"]),
+            e => panic!("Expected a closure, got {:?}", e),
+        }
     }
 
     #[test]
@@ -1587,4 +5108,156 @@ class Foo {}
         ).0;
         assert!(vm.frames.head.objects.take().is_empty())
     }
+
+    #[test]
+    fn tracing_writes_the_stack_before_each_instruction() {
+        let (chunk, interned_strings) = unsafe_compile(vec!["fun f(x) { return x + 1; } print f(2);"]);
+        let options = VmOptions { trace: true, ..Default::default() };
+        let output = VirtualMachine::run_apply_with_options(
+            chunk, interned_strings, Vec::new(), options, |vm| vm.output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap().trim(), r#"
+<script> 00:  1 FUNCTION       f []
+          [ <fn f> ]
+<script> 01:  1 DEFINE_GLOBAL  'f'
+
+<script> 02:  1 GET_GLOBAL_IDX 0
+          [ <fn f> ]
+<script> 03:  1 NUMBER         2
+          [ <fn f> ][ 2 ]
+<script> 04:  1 CALL           1
+          [ <fn f> ][ 2 ]
+f 00:  1 GET_LOCAL      0
+          [ <fn f> ][ 2 ][ 2 ]
+f 01:  1 NUMBER         1
+          [ <fn f> ][ 2 ][ 2 ][ 1 ]
+f 02:  1 ADD
+          [ <fn f> ][ 2 ][ 3 ]
+f 03:  1 RETURN
+          [ 3 ]
+<script> 05:  1 PRINT
+3
+"#.trim());
+    }
+
+    // A VM about to run `code`, whose debug hook records the events it's called with, and returns
+    // `actions` one after the other, then Continue.
+    fn debugged(
+        code: &str, breakpoints: &[Line], actions: Vec<DebugAction>,
+    ) -> (VirtualMachine<Vec<u8>>, Rc<RefCell<Vec<String>>>) {
+        let (chunk, interned_strings) = unsafe_compile(vec![code]);
+        let mut vm = VirtualMachine::new(chunk, interned_strings, Vec::new(), VmOptions::default());
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let recorded = events.clone();
+        let mut actions = actions.into_iter();
+        vm.set_debug_hook(move |event| {
+            let locals: Vec<String> = event.locals.iter().map(|v| v.stringify()).collect();
+            recorded.borrow_mut().push(format!(
+                "{} {} {} {:?}{}",
+                event.line,
+                event.function,
+                event.depth,
+                locals,
+                if event.at_breakpoint { " *" } else { "" },
+            ));
+            actions.next().unwrap_or(DebugAction::Continue)
+        });
+        for line in breakpoints {
+            vm.add_breakpoint(*line);
+        }
+        (vm, events)
+    }
+
+    fn printed_so_far(vm: &VirtualMachine<Vec<u8>>) -> String {
+        String::from_utf8(vm.output.clone()).unwrap()
+    }
+
+    #[test]
+    fn debug_hook_is_called_at_breakpoints_and_while_stepping_lines() {
+        let code = r#"
+var a = 1;
+fun f(x) {
+  var y = x * 2;
+  return y + a;
+}
+print f(3);
+print a;
+print f(1);
+"#;
+        let (mut vm, events) = debugged(code, &[4], vec![DebugAction::StepLine; 3]);
+        vm.run_to_end().unwrap();
+        assert_eq!(printed_so_far(&vm), "7\n1\n3\n");
+        assert_eq!(
+            events.borrow().clone(),
+            vec![
+                r#"4 f 2 ["3"] *"#,
+                r#"5 f 2 ["3", "6"]"#,
+                r#"7 <script> 1 ["7"]"#,
+                r#"8 <script> 1 []"#,
+                r#"4 f 2 ["1"] *"#,
+            ],
+        );
+    }
+
+    #[test]
+    fn debug_hook_can_step_through_instructions() {
+        let (mut vm, events) =
+            debugged("print 1;\nprint 2 + 3;", &[2], vec![DebugAction::Step; 4]);
+        vm.run_to_end().unwrap();
+        assert_eq!(
+            events.borrow().clone(),
+            vec![
+                r#"2 <script> 1 [] *"#,
+                r#"2 <script> 1 ["2"]"#,
+                r#"2 <script> 1 ["2", "3"]"#,
+                r#"2 <script> 1 ["5"]"#,
+            ],
+        );
+    }
+
+    #[test]
+    fn debug_events_describe_the_next_instruction() {
+        let (chunk, interned_strings) = unsafe_compile(vec!["var x = 1;\nprint x;"]);
+        let mut vm = VirtualMachine::new(chunk, interned_strings, Vec::new(), VmOptions::default());
+        let instructions = Rc::new(RefCell::new(Vec::new()));
+        let recorded = instructions.clone();
+        vm.set_debug_hook(move |event| {
+            recorded.borrow_mut().push(event.instruction.clone());
+            DebugAction::Step
+        });
+        vm.add_breakpoint(2);
+        vm.run_to_end().unwrap();
+        assert_eq!(
+            instructions.borrow().clone(),
+            vec!["GET_GLOBAL_IDX 0", "PRINT"],
+        );
+    }
+
+    #[test]
+    fn debug_hook_can_pause_the_vm() {
+        let code = r#"
+fun f(n) {
+  print n;
+  return n;
+}
+var x = f(1) + f(2);
+print x;
+"#;
+        let (mut vm, events) = debugged(code, &[3], vec![DebugAction::Pause; 3]);
+        vm.run_to_end().unwrap();
+        assert!(vm.is_paused());
+        assert_eq!(printed_so_far(&vm), "");
+        // Resuming runs the instruction it paused at without reporting it again.
+        assert_eq!(vm.resume(DebugAction::Continue).unwrap(), vec![]);
+        assert!(vm.is_paused());
+        assert_eq!(printed_so_far(&vm), "1\n");
+        assert_eq!(vm.resume(DebugAction::StepLine).unwrap(), vec![]);
+        assert_eq!(printed_so_far(&vm), "1\n2\n");
+        vm.resume(DebugAction::Continue).unwrap();
+        assert!(!vm.is_paused());
+        assert_eq!(printed_so_far(&vm), "1\n2\n3\n");
+        assert_eq!(
+            events.borrow().clone(),
+            vec![r#"3 f 2 ["1"] *"#, r#"3 f 2 ["2"] *"#, r#"4 f 2 ["2"]"#],
+        );
+    }
 }
@@ -345,6 +345,27 @@ mod tests {
             ]), )
     }
 
+    #[test]
+    fn keywords_of_the_compiled_frontend_are_identifiers() {
+        assert_eq!(
+            "3",
+            printed_string(vec![
+                "var xor = 1;",
+                "var break = 2;",
+                "print xor + break;",
+            ]))
+    }
+
+    #[test]
+    fn strings_are_not_interpolated() {
+        assert_eq!(
+            "cost ${x} ${",
+            printed_string(vec![
+                "var x = 1;",
+                "print \"cost ${x} ${\";",
+            ]))
+    }
+
     #[test]
     fn printing_assignments() {
         assert_eq!(
@@ -1,3 +1,7 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
 use crate::rslox::common::error::{ErrorInfo, LoxError};
 use crate::rslox::interpreted::ast::{BinaryOperator, UnaryOperator};
 use crate::rslox::interpreted::interpreter::lox_value::{LoxRef, LoxValue};
@@ -40,6 +44,14 @@ impl LoxError for InterpreterErrorOrControlFlow {
     }
 }
 
+impl Display for InterpreterErrorOrControlFlow {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] Error: {}", self.get_info().line, self.get_message())
+    }
+}
+
+impl Error for InterpreterErrorOrControlFlow {}
+
 pub type InterpretResult<A> = Result<A, InterpreterErrorOrControlFlow>;
 
 pub fn unary_type_error<A>(
@@ -555,7 +555,7 @@ impl<'a> Parser<'a> {
 
 #[cfg(test)]
 mod tests {
-    use crate::rslox::common::lexer::tokenize;
+    use crate::rslox::common::lexer::tokenize_interpreted;
     use crate::rslox::common::utils::SliceExt;
     use crate::rslox::interpreted::ast::{Expression, FunctionDef, Program, Statement};
     use crate::rslox::interpreted::ast::Atom::Number;
@@ -570,7 +570,7 @@ mod tests {
     }
 
     fn parse_single_statement(line: &str) -> Statement {
-        let tokens = tokenize(line).unwrap();
+        let tokens = tokenize_interpreted(line).unwrap();
         let prog = Parser::parse(&tokens).unwrap();
         AnnotatedProgram::from(prog).statements.unwrap_single().into()
     }
@@ -601,6 +601,17 @@ mod tests {
         assert_eq!(expr, expected);
     }
 
+    #[test]
+    fn subtracting_a_negation() {
+        let expr = parse_expression("a--b;");
+        let expected = Binary(
+            BinaryOperator::Minus,
+            Box::new(Expression::identifier("a")),
+            Box::new(Unary(UnaryOperator::Minus, Box::new(Expression::identifier("b")))),
+        );
+        assert_eq!(expr, expected);
+    }
+
     #[test]
     fn comma_separated() {
         let expr = parse_expression("1, 2, 3;");
@@ -698,7 +709,7 @@ mod tests {
 
     #[test]
     fn multiple_errors() {
-        let tokens = tokenize("var 12 = 12;\nvar x = b ? 1 : \"foo\";\n 5x;").unwrap();
+        let tokens = tokenize_interpreted("var 12 = 12;\nvar x = b ? 1 : \"foo\";\n 5x;").unwrap();
         let program = parse(&tokens).unwrap_err();
         assert_eq!(
             program.into_iter().map(|e| e.get_info().line).collect::<Vec<usize>>(),
@@ -784,7 +795,7 @@ mod tests {
 
     #[test]
     fn missing_semicolon() {
-        let tokens = tokenize("print 42").unwrap();
+        let tokens = tokenize_interpreted("print 42").unwrap();
         let program = parse(&tokens).unwrap_err();
         assert_eq!(
             program.into_iter().map(|e| e.get_info().line).collect::<Vec<usize>>(),
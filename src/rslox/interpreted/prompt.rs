@@ -2,7 +2,7 @@ use std::io;
 use std::io::BufRead;
 
 use crate::rslox::common::error::LoxResult;
-use crate::rslox::common::lexer::tokenize;
+use crate::rslox::common::lexer::tokenize_interpreted;
 use crate::rslox::interpreted::interpreter::interpret;
 use crate::rslox::interpreted::parser::parse;
 use crate::rslox::interpreted::resolve::resolve;
@@ -30,7 +30,7 @@ pub fn run(line: &str) -> LoxResult<()> {
 }
 
 fn run_aux(line: &str, print_expr: bool) -> LoxResult<()> {
-    let tokens = tokenize(line)?;
+    let tokens = tokenize_interpreted(line)?;
     let program = parse(&tokens)?;
     let resolved = resolve(program)?;
     if let Some(e) = interpret(&resolved)? {
@@ -1,4 +1,7 @@
 use std::collections::{HashMap, LinkedList};
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
 
 use nonempty::NonEmpty;
 
@@ -50,6 +53,14 @@ impl LoxError for ResolverError {
     }
 }
 
+impl Display for ResolverError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] Error: {}", self.get_info().line, self.get_message())
+    }
+}
+
+impl Error for ResolverError {}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 enum CurrentFunction { Function }
 
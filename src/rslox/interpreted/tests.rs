@@ -1,6 +1,6 @@
 #[cfg(test)]
 use {
-  crate::rslox::common::tests::unsafe_tokenize,
+  crate::rslox::common::tests::unsafe_tokenize_interpreted,
   crate::rslox::interpreted::annotated_ast::AnnotatedProgram,
   crate::rslox::interpreted::parser::parse,
   crate::rslox::interpreted::resolve::resolve,
@@ -8,7 +8,7 @@ use {
 
 #[cfg(test)]
 pub fn unsafe_parse(program: Vec<&str>) -> AnnotatedProgram {
-    parse(&unsafe_tokenize(program)).expect("Failed to parse")
+    parse(&unsafe_tokenize_interpreted(program)).expect("Failed to parse")
 }
 
 #[cfg(test)]